@@ -0,0 +1,63 @@
+//! Compares the on-disk size of the record formats for small payloads.
+//!
+//! Run with `cargo bench --bench frame_size`. Sizes are deterministic, so this
+//! prints the average bytes per record instead of timing anything.
+
+use durable_log::codec::{
+    encode_batch, encode_frame, pack_batch, RecordHeader, FLAGS_NONE, FLAG_BATCH, VERSION_COMPACT,
+    VERSION_V1,
+};
+
+/// Offset of the first record, far enough into a log that compact offsets take
+/// three bytes.
+const FIRST_OFFSET: u64 = 1_000_000;
+
+/// Records per measurement, and per batch frame.
+const RECORDS: usize = 100;
+
+/// Returns the average size of `RECORDS` payloads of `len` bytes, one frame each.
+fn per_frame(version: u8, len: usize) -> usize {
+    let payload = vec![0x5A; len];
+    let total: usize = (0..RECORDS as u64)
+        .map(|i| {
+            let template = RecordHeader::new(FIRST_OFFSET + i, 0, 0).with_version(version);
+            encode_frame(template, FLAGS_NONE, &[], None, &payload)
+                .unwrap()
+                .len()
+        })
+        .sum();
+    total / RECORDS
+}
+
+/// Returns the average size of `RECORDS` payloads of `len` bytes in one batch frame.
+fn batched(version: u8, len: usize) -> usize {
+    let payload = vec![0x5A; len];
+    let payloads = vec![&payload[..]; RECORDS];
+    let frame = if version == VERSION_V1 {
+        encode_batch(FIRST_OFFSET, &payloads).unwrap()
+    } else {
+        let (count, body) = pack_batch(version, &payloads).unwrap();
+        let template = RecordHeader::new(FIRST_OFFSET, 0, 0)
+            .with_version(version)
+            .with_batch_count(count);
+        encode_frame(template, FLAG_BATCH, &[], None, &body).unwrap()
+    };
+    frame.len().div_ceil(RECORDS)
+}
+
+fn main() {
+    println!("bytes per record ({RECORDS} records from offset {FIRST_OFFSET})");
+    println!(
+        "{:>8} {:>8} {:>8} {:>8} {:>10} {:>10} {:>8}",
+        "payload", "v1", "compact", "saved", "v1 batch", "cmp batch", "saved"
+    );
+    for len in [8, 20, 64, 256, 1024] {
+        let (v1, compact) = (per_frame(VERSION_V1, len), per_frame(VERSION_COMPACT, len));
+        let (v1_batch, compact_batch) = (batched(VERSION_V1, len), batched(VERSION_COMPACT, len));
+        println!(
+            "{len:>8} {v1:>8} {compact:>8} {:>7}% {v1_batch:>10} {compact_batch:>10} {:>7}%",
+            100 - compact * 100 / v1,
+            100 - compact_batch * 100 / v1_batch,
+        );
+    }
+}
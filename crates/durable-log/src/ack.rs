@@ -0,0 +1,193 @@
+//! Durability acknowledgements: waiting for an appended record to reach disk.
+//!
+//! The log keeps a *durable watermark*: every offset below it has been fsynced.
+//! [`Log::append_with_ack`](crate::Log::append_with_ack) returns an [`AppendAck`]
+//! that resolves once the watermark passes the record's offset, whichever thread
+//! (or [`FsyncPolicy`](crate::FsyncPolicy)) performs the flush. A second
+//! watermark, of written offsets, wakes [`Tail`](crate::Tail)s on each append.
+//! Readers opened with [`Log::reader`](crate::Log::reader) stop at one of the two,
+//! so they never see a record the writer is still writing.
+
+use crate::error::Error;
+use crate::Result;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Shared offset watermark, advanced by the writer: after each successful fsync
+/// for durability, and after each append for tails.
+#[derive(Debug)]
+pub(crate) struct Watermark {
+    state: Mutex<WatermarkState>,
+    advanced: Condvar,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WatermarkState {
+    /// All offsets strictly below this value are durable (or written).
+    end: u64,
+    /// Set when the owning log is dropped; pending acks can no longer resolve.
+    closed: bool,
+    /// How many times the watermark was pulled back by [`Watermark::truncate`].
+    truncations: u64,
+}
+
+impl Watermark {
+    pub(crate) fn new(end: u64) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(WatermarkState {
+                end,
+                closed: false,
+                truncations: 0,
+            }),
+            advanced: Condvar::new(),
+        })
+    }
+
+    /// Returns the exclusive end of the covered prefix.
+    pub(crate) fn end(&self) -> u64 {
+        self.lock().end
+    }
+
+    /// Returns the exclusive end of the covered prefix, or `None` once the owning
+    /// log has been dropped and no longer advances it.
+    pub(crate) fn open_end(&self) -> Option<u64> {
+        let state = self.lock();
+        (!state.closed).then_some(state.end)
+    }
+
+    /// Moves the watermark forward to `end` (never backwards) and wakes waiters.
+    pub(crate) fn advance(&self, end: u64) {
+        let mut state = self.lock();
+        if end > state.end {
+            state.end = end;
+            drop(state);
+            self.advanced.notify_all();
+        }
+    }
+
+    /// Pulls the watermark back to `end` after the log discards records at or above it.
+    pub(crate) fn truncate(&self, end: u64) {
+        let mut state = self.lock();
+        state.end = state.end.min(end);
+        state.truncations += 1;
+    }
+
+    /// Returns how many times the watermark was truncated. Segment files a reader
+    /// opened before the count changed may since have been replaced.
+    pub(crate) fn truncations(&self) -> u64 {
+        self.lock().truncations
+    }
+
+    /// Blocks until the watermark passes `offset`, the watermark is closed, or
+    /// `timeout` (if any) elapses, and returns the end then. A returned end at or
+    /// below `offset` means the wait timed out.
+    ///
+    /// Returns [`Error::Closed`] if the watermark was closed without passing `offset`.
+    pub(crate) fn wait_past(&self, offset: u64, timeout: Option<Duration>) -> Result<u64> {
+        let pending = |s: &mut WatermarkState| s.end <= offset && !s.closed;
+        let state = timeout.map_or_else(
+            || {
+                *self
+                    .advanced
+                    .wait_while(self.lock(), pending)
+                    .unwrap_or_else(PoisonError::into_inner)
+            },
+            |timeout| {
+                *self
+                    .advanced
+                    .wait_timeout_while(self.lock(), timeout, pending)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            },
+        );
+        if state.end <= offset && state.closed {
+            return Err(Error::Closed("log closed".into()));
+        }
+        Ok(state.end)
+    }
+
+    /// Marks the watermark closed and wakes waiters.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.advanced.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WatermarkState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Acknowledgement for an appended record that resolves once the record is durable.
+///
+/// Handles are cheap to clone and `Send`, so they can be handed to the task that
+/// answers the client while the writer keeps appending.
+#[derive(Debug, Clone)]
+pub struct AppendAck {
+    offset: u64,
+    watermark: Arc<Watermark>,
+}
+
+impl AppendAck {
+    pub(crate) const fn new(offset: u64, watermark: Arc<Watermark>) -> Self {
+        Self { offset, watermark }
+    }
+
+    /// Returns the offset assigned to the record.
+    #[must_use]
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns true if the record has already been fsynced.
+    #[must_use]
+    pub fn is_durable(&self) -> bool {
+        self.offset < self.watermark.end()
+    }
+
+    /// Blocks until the record is durable and returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Closed`] if the log is dropped before the record was flushed.
+    pub fn wait(&self) -> Result<u64> {
+        let state = self
+            .watermark
+            .advanced
+            .wait_while(self.watermark.lock(), |s| s.end <= self.offset && !s.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        self.resolve(*state)
+    }
+
+    /// Blocks for at most `timeout` waiting for the record to become durable.
+    ///
+    /// Returns `Ok(true)` if the record is durable, `Ok(false)` on timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Closed`] if the log is dropped before the record was flushed.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        let state = *self
+            .watermark
+            .advanced
+            .wait_timeout_while(self.watermark.lock(), timeout, |s| {
+                s.end <= self.offset && !s.closed
+            })
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+        if state.end <= self.offset && !state.closed {
+            return Ok(false);
+        }
+        self.resolve(state).map(|_| true)
+    }
+
+    fn resolve(&self, state: WatermarkState) -> Result<u64> {
+        if state.end > self.offset {
+            Ok(self.offset)
+        } else {
+            Err(Error::Closed(format!(
+                "log closed before offset {} became durable",
+                self.offset
+            )))
+        }
+    }
+}
@@ -0,0 +1,632 @@
+//! Archival of sealed segments to object storage.
+//!
+//! [`Log::archive`] moves the oldest sealed segments that fall outside a local
+//! [`RetentionPolicy`] to an [`ObjectStore`]: each segment's `.log`, `.idx` and
+//! `.key` files are uploaded, read back and compared, recorded in the archive's
+//! manifest, and only then deleted locally. The local log then starts after the
+//! archived segments, as if retention had deleted them.
+//!
+//! An [`Archive`] keeps its objects under a key prefix, named after the segment
+//! files (`orders/segment_00000000000000000000.log` with prefix `orders/`), and
+//! lists what it holds in a `manifest` object. [`Archive::restore`] downloads a
+//! segment into a directory that a [`LogReader`](crate::LogReader) can open;
+//! a reader given an [`ArchiveCache`] instead reads archived segments in place
+//! of missing local ones, fetching them on demand. So does a log whose
+//! [`Config::archive`](crate::Config::archive) is set, in
+//! [`Log::read`] and the readers of [`Log::reader`].
+//!
+//! [`DirStore`] keeps objects as files under a directory, such as a network
+//! mount; with the `s3` feature, `S3Store` talks to an
+//! S3-compatible service.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::retention::RetentionPolicy;
+#[cfg(feature = "fs")]
+use crate::segment::discover_segments_in;
+use crate::segment::{remove_segment_files, SegmentFooter, SegmentId, SegmentInfo};
+#[cfg(feature = "fs")]
+use crate::storage::FsBackend;
+use crate::storage::{self, Backend, MemoryBackend};
+use crate::trace::event;
+use crate::Result;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Name of the manifest object, after the archive's prefix.
+const MANIFEST_NAME: &str = "manifest";
+
+/// Size of one manifest entry: base offset, end offset, length and CRC.
+const ENTRY_LEN: usize = 8 + 8 + 8 + 4;
+
+/// A flat store of objects addressed by string keys.
+pub trait ObjectStore: Debug + Send + Sync {
+    /// Stores `data` under `key`, replacing any object already there.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from the store.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Returns the object stored under `key`, or `None` if there is none.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from the store.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the keys of every object whose key starts with `prefix`, sorted.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from the store.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Deletes the object stored under `key`; a missing object is not an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from the store.
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// An [`ObjectStore`] keeping each object as a file under a root directory,
+/// with `/` in keys separating subdirectories.
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone)]
+pub struct DirStore {
+    root: PathBuf,
+}
+
+#[cfg(feature = "fs")]
+impl DirStore {
+    /// Opens the store rooted at `root`, creating the directory if missing.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from creating the directory.
+    pub fn open(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let valid = !key.is_empty()
+            && key
+                .split('/')
+                .all(|part| !part.is_empty() && part != "." && part != "..");
+        if !valid {
+            return Err(Error::InvalidFormat(format!("invalid object key {key:?}")));
+        }
+        Ok(self.root.join(key))
+    }
+
+    /// Appends the keys of the files under `dir`, named relative to the root.
+    fn walk(&self, dir: &Path, keys: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                self.walk(&path, keys)?;
+            } else if let Some(key) = path
+                .strip_prefix(&self.root)
+                .ok()
+                .filter(|_| path.extension().map_or(true, |ext| ext != "tmp"))
+                .and_then(Path::to_str)
+            {
+                keys.push(key.replace(std::path::MAIN_SEPARATOR, "/"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs")]
+impl ObjectStore for DirStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        storage::write_atomic(&FsBackend, &path, data)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(storage::read_file(&FsBackend, &self.path(key)?)?)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.walk(&self.root, &mut keys)?;
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        storage::delete_if_exists(&FsBackend, &self.path(key)?)?;
+        Ok(())
+    }
+}
+
+/// A segment recorded in an [`Archive`]'s manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchivedSegment {
+    /// Base offset of the segment.
+    pub base_offset: u64,
+    /// Offset after the segment's last record: the next segment's base offset.
+    pub end_offset: u64,
+    /// Length of the segment's `.log` file.
+    pub bytes: u64,
+    /// CRC-32 of the segment's `.log` file.
+    pub crc: u32,
+}
+
+/// Segments archived under one key prefix of an [`ObjectStore`]; see the
+/// [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Archive {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl Archive {
+    /// Creates an archive of the objects in `store` whose keys start with
+    /// `prefix`, such as `"orders/"`.
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: impl Into<String>) -> Self {
+        Self {
+            store,
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Returns the segments in the archive, sorted by base offset.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Corruption`] if the manifest is damaged.
+    /// - Errors from the store.
+    pub fn manifest(&self) -> Result<Vec<ArchivedSegment>> {
+        let Some(bytes) = self.store.get(&self.key(MANIFEST_NAME))? else {
+            return Ok(Vec::new());
+        };
+        decode_manifest(&self.key(MANIFEST_NAME), &bytes)
+    }
+
+    /// Replaces the manifest with `segments`.
+    ///
+    /// The object holds the number of segments (u32), then for each its base
+    /// offset, end offset and length (u64 each) and CRC (u32), followed by a
+    /// CRC-32 of everything before it, all little-endian.
+    fn write_manifest(&self, segments: &[ArchivedSegment]) -> Result<()> {
+        let count = u32::try_from(segments.len())
+            .map_err(|_| Error::InvalidFormat("too many archived segments".into()))?;
+        let mut contents = count.to_le_bytes().to_vec();
+        for segment in segments {
+            contents.extend_from_slice(&segment.base_offset.to_le_bytes());
+            contents.extend_from_slice(&segment.end_offset.to_le_bytes());
+            contents.extend_from_slice(&segment.bytes.to_le_bytes());
+            contents.extend_from_slice(&segment.crc.to_le_bytes());
+        }
+        contents.extend_from_slice(&crc32fast::hash(&contents).to_le_bytes());
+        self.store.put(&self.key(MANIFEST_NAME), &contents)
+    }
+
+    /// Uploads the files of `info`, whose records end before `end_offset`,
+    /// checks that the store returns them intact, and adds the segment to the
+    /// manifest.
+    fn upload(&self, info: &SegmentInfo, end_offset: u64) -> Result<ArchivedSegment> {
+        let log = storage::read_file(&*info.storage, &info.log_path)?
+            .ok_or_else(|| Error::Io(std::io::ErrorKind::NotFound.into()))?;
+        let archived = ArchivedSegment {
+            base_offset: info.base_offset,
+            end_offset,
+            bytes: log.len() as u64,
+            crc: crc32fast::hash(&log),
+        };
+        let index = storage::read_file(&*info.storage, &info.index_path())?;
+        let key_path = crate::encryption::key_path(info);
+        let key_file = storage::read_file(&*info.storage, &key_path)?;
+        for (path, data) in [
+            (&info.log_path, Some(log)),
+            (&info.index_path(), index),
+            (&key_path, key_file),
+        ] {
+            let Some(data) = data else { continue };
+            let key = self.key(&file_name(path));
+            self.store.put(&key, &data)?;
+            if self.store.get(&key)?.as_deref() != Some(&data[..]) {
+                return Err(Error::Corruption(format!(
+                    "archived object {key} does not match the uploaded file"
+                )));
+            }
+        }
+        let mut manifest = self.manifest()?;
+        manifest.retain(|s| s.base_offset != archived.base_offset);
+        manifest.push(archived);
+        manifest.sort_by_key(|s| s.base_offset);
+        self.write_manifest(&manifest)?;
+        event!(
+            info,
+            segment = archived.base_offset,
+            bytes = archived.bytes,
+            "segment archived"
+        );
+        Ok(archived)
+    }
+
+    /// Downloads the archived segment with base offset `base_offset` into the
+    /// directory `dir`, creating it if missing, so that a
+    /// [`LogReader`](crate::LogReader) opened on `dir` can read its records.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetOutOfRange`] if the manifest lists no such segment.
+    /// - [`Error::Corruption`] if the downloaded segment does not match the
+    ///   manifest.
+    /// - Errors from the store, and I/O errors from writing the files.
+    #[cfg(feature = "fs")]
+    pub fn restore(&self, base_offset: u64, dir: impl AsRef<Path>) -> Result<()> {
+        let manifest = self.manifest()?;
+        let Some(segment) = manifest.iter().find(|s| s.base_offset == base_offset) else {
+            return Err(Error::OffsetOutOfRange {
+                requested: base_offset,
+                earliest: manifest.first().map_or(0, |s| s.base_offset),
+                latest: manifest.last().and_then(|s| s.end_offset.checked_sub(1)),
+            });
+        };
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        self.fetch(segment, &storage::fs_backend(), dir)?;
+        Ok(())
+    }
+
+    /// Downloads `segment` into `dir` on `storage`, checking its `.log` file
+    /// against the manifest entry.
+    fn fetch(
+        &self,
+        segment: &ArchivedSegment,
+        storage: &Arc<dyn Backend>,
+        dir: &Path,
+    ) -> Result<SegmentInfo> {
+        let mut info = SegmentInfo {
+            base_offset: segment.base_offset,
+            log_path: dir.join(SegmentId(segment.base_offset).log_filename()),
+            footer: None,
+            storage: Arc::clone(storage),
+        };
+        for path in [
+            info.log_path.clone(),
+            info.index_path(),
+            crate::encryption::key_path(&info),
+        ] {
+            let key = self.key(&file_name(&path));
+            let Some(data) = self.store.get(&key)? else {
+                if path == info.log_path {
+                    return Err(Error::Corruption(format!(
+                        "archived object {key} is missing"
+                    )));
+                }
+                continue;
+            };
+            if path == info.log_path
+                && (data.len() as u64 != segment.bytes || crc32fast::hash(&data) != segment.crc)
+            {
+                return Err(Error::Corruption(format!(
+                    "archived object {key} does not match the manifest"
+                )));
+            }
+            storage::write_atomic(&**storage, &path, &data)?;
+        }
+        info.footer = SegmentFooter::read(&**storage, &info.log_path)?;
+        Ok(info)
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+}
+
+/// Archived segments downloaded for reading, bounded in size.
+///
+/// A [`LogReader`](crate::LogReader) given a cache with
+/// [`with_archive`](crate::LogReader::with_archive) reads offsets older than the
+/// local segments from the archive, downloading each segment into the cache on
+/// first use. Once the cached `.log` files exceed `max_bytes`, the least recently
+/// used segments are deleted, though the one just read is always kept. Iterators
+/// already reading an evicted segment keep their open file.
+#[derive(Debug)]
+pub struct ArchiveCache {
+    archive: Archive,
+    storage: Arc<dyn Backend>,
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Cached segments, least recently used first.
+    cached: Mutex<VecDeque<ArchivedSegment>>,
+}
+
+impl ArchiveCache {
+    /// Creates a cache of segments of `archive` in the directory `dir`, creating
+    /// it if missing and deleting segments left in it by an earlier cache.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from creating or clearing the directory.
+    #[cfg(feature = "fs")]
+    pub fn new(archive: Archive, dir: impl AsRef<Path>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let storage = storage::fs_backend();
+        storage.create_dir_all(&dir)?;
+        for stale in discover_segments_in(&storage, &dir)? {
+            remove_segment_files(&stale)?;
+        }
+        Ok(Self::with_storage(archive, storage, dir, max_bytes))
+    }
+
+    /// Creates a cache of segments of `archive` held in memory.
+    #[must_use]
+    pub fn in_memory(archive: Archive, max_bytes: u64) -> Self {
+        let storage: Arc<dyn Backend> = Arc::new(MemoryBackend::new());
+        Self::with_storage(archive, storage, PathBuf::from("archive-cache"), max_bytes)
+    }
+
+    fn with_storage(
+        archive: Archive,
+        storage: Arc<dyn Backend>,
+        dir: PathBuf,
+        max_bytes: u64,
+    ) -> Self {
+        Self {
+            archive,
+            storage,
+            dir,
+            max_bytes,
+            cached: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the archive the cache reads from.
+    #[must_use]
+    pub const fn archive(&self) -> &Archive {
+        &self.archive
+    }
+
+    /// Returns the total length of the cached segments' `.log` files.
+    #[must_use]
+    pub fn cached_bytes(&self) -> u64 {
+        self.lock().iter().map(|s| s.bytes).sum()
+    }
+
+    /// Returns `segment`, downloading it first unless it is cached, and evicts
+    /// the least recently used segments beyond the size bound.
+    pub(crate) fn segment(&self, segment: &ArchivedSegment) -> Result<SegmentInfo> {
+        let mut cached = self.lock();
+        let hit = cached
+            .iter()
+            .position(|s| s.base_offset == segment.base_offset)
+            .and_then(|i| cached.remove(i))
+            .is_some_and(|s| s == *segment);
+        let info = if hit {
+            let log_path = self.dir.join(SegmentId(segment.base_offset).log_filename());
+            SegmentInfo {
+                base_offset: segment.base_offset,
+                footer: SegmentFooter::read(&*self.storage, &log_path)?,
+                log_path,
+                storage: Arc::clone(&self.storage),
+            }
+        } else {
+            let info = self.archive.fetch(segment, &self.storage, &self.dir)?;
+            event!(
+                debug,
+                segment = segment.base_offset,
+                bytes = segment.bytes,
+                "archived segment cached"
+            );
+            info
+        };
+        cached.push_back(*segment);
+        while cached.len() > 1 && cached.iter().map(|s| s.bytes).sum::<u64>() > self.max_bytes {
+            let evicted = cached.pop_front().expect("more than one segment is cached");
+            let log_path = self.dir.join(SegmentId(evicted.base_offset).log_filename());
+            remove_segment_files(&SegmentInfo {
+                base_offset: evicted.base_offset,
+                log_path,
+                footer: None,
+                storage: Arc::clone(&self.storage),
+            })?;
+        }
+        drop(cached);
+        Ok(info)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<ArchivedSegment>> {
+        self.cached.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Decodes the manifest object `name`; see [`Archive::write_manifest`].
+fn decode_manifest(name: &str, bytes: &[u8]) -> Result<Vec<ArchivedSegment>> {
+    if bytes.len() < 8 || (bytes.len() - 8) % ENTRY_LEN != 0 {
+        return Err(Error::Corruption(format!("archive {name} is malformed")));
+    }
+    let (data, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(data).to_le_bytes() != crc {
+        return Err(Error::Corruption(format!(
+            "archive {name} checksum mismatch"
+        )));
+    }
+    let count = u32::from_le_bytes(data[..4].try_into().expect("4 bytes"));
+    if u64::from(count) != ((data.len() - 4) / ENTRY_LEN) as u64 {
+        return Err(Error::Corruption(format!("archive {name} is malformed")));
+    }
+    let u64_at = |entry: &[u8], pos: usize| {
+        u64::from_le_bytes(entry[pos..pos + 8].try_into().expect("8 bytes"))
+    };
+    Ok(data[4..]
+        .chunks_exact(ENTRY_LEN)
+        .map(|entry| ArchivedSegment {
+            base_offset: u64_at(entry, 0),
+            end_offset: u64_at(entry, 8),
+            bytes: u64_at(entry, 16),
+            crc: u32::from_le_bytes(entry[24..].try_into().expect("4 bytes")),
+        })
+        .collect())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+impl Log {
+    /// Moves the oldest sealed segments that fall outside `local` to `archive`,
+    /// and returns how many were moved; see the [module docs](self).
+    ///
+    /// Each segment is deleted locally only once the store has returned its files
+    /// intact and the manifest lists it, so a failure part-way leaves every
+    /// segment either local, archived, or both.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Corruption`] if the store returns an upload altered, or the
+    ///   manifest is damaged.
+    /// - Errors from the store, and I/O errors from reading or deleting segment
+    ///   files.
+    pub fn archive(&mut self, archive: &Archive, local: RetentionPolicy) -> Result<usize> {
+        let count = self.outside_retention(local)?;
+        for _ in 0..count {
+            let Some((oldest, end_offset)) = self.oldest_sealed() else {
+                break;
+            };
+            archive.upload(oldest, end_offset)?;
+            self.remove_oldest_segment()?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, LogReader};
+
+    #[test]
+    fn archives_and_restores_sealed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(DirStore::open(dir.path().join("store")).unwrap());
+        let archive = Archive::new(store.clone(), "orders/");
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        let local = RetentionPolicy {
+            max_age: None,
+            max_total_bytes: Some(250),
+        };
+        let archived = log.archive(&archive, local).unwrap();
+        assert!(archived > 0);
+        assert_eq!(log.archive(&archive, local).unwrap(), 0);
+
+        let manifest = archive.manifest().unwrap();
+        assert_eq!(manifest.len(), archived);
+        assert_eq!(manifest[0].base_offset, 0);
+        assert_eq!(manifest.last().unwrap().end_offset, log.first_offset());
+        assert!(store
+            .list("orders/")
+            .unwrap()
+            .contains(&"orders/segment_00000000000000000000.idx".to_owned()));
+
+        let restored = dir.path().join("restored");
+        for segment in &manifest {
+            archive.restore(segment.base_offset, &restored).unwrap();
+        }
+        let records: Vec<_> = LogReader::open(&restored)
+            .unwrap()
+            .iter()
+            .map(|r| r.unwrap().payload)
+            .collect();
+        assert_eq!(records.len() as u64, log.first_offset());
+        assert_eq!(records[3], [3; 30]);
+        assert!(archive.restore(log.first_offset(), &restored).is_err());
+    }
+
+    #[test]
+    fn reader_spans_local_and_archived_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(DirStore::open(dir.path().join("store")).unwrap());
+        let archive = Archive::new(store, "");
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        let local = RetentionPolicy {
+            max_age: None,
+            max_total_bytes: Some(250),
+        };
+        log.archive(&archive, local).unwrap();
+        let local_start = log.first_offset();
+        assert!(local_start > 2);
+
+        let largest = archive.manifest().unwrap().iter().map(|s| s.bytes).max();
+        let cache = Arc::new(ArchiveCache::in_memory(archive, largest.unwrap()));
+        let reader = LogReader::open(log.path())
+            .unwrap()
+            .with_archive(Arc::clone(&cache))
+            .unwrap();
+        assert_eq!(reader.first_offset(), 0);
+        let payloads: Vec<_> = reader.iter().map(|r| r.unwrap().payload).collect();
+        assert_eq!(payloads, (0..10u8).map(|i| vec![i; 30]).collect::<Vec<_>>());
+        assert_eq!(reader.read(0).unwrap(), [0; 30]);
+        assert_eq!(
+            reader.read(local_start - 1).unwrap(),
+            payloads[usize::try_from(local_start).unwrap() - 1]
+        );
+        assert_eq!(
+            reader.iter_from(local_start - 1).count() as u64,
+            11 - local_start
+        );
+        assert!(cache.cached_bytes() <= largest.unwrap());
+    }
+
+    #[test]
+    fn log_reads_archived_segments_through_its_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(DirStore::open(dir.path().join("store")).unwrap());
+        let cache = Arc::new(ArchiveCache::in_memory(Archive::new(store, ""), 1024));
+        let config = Config {
+            max_segment_bytes: 100,
+            archive: Some(Arc::clone(&cache)),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        let local = RetentionPolicy {
+            max_age: None,
+            max_total_bytes: Some(250),
+        };
+        log.archive(cache.archive(), local).unwrap();
+        assert!(log.first_offset() > 2);
+
+        assert_eq!(log.read(0).unwrap(), [0; 30]);
+        assert_eq!(log.read(log.first_offset() - 1).unwrap().len(), 30);
+        assert_eq!(log.reader().unwrap().read(1).unwrap(), [1; 30]);
+        assert!(log.read(10).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Async wrapper over [`Log`] (`async` feature).
+//!
+//! File I/O stays synchronous; each operation runs on tokio's blocking thread
+//! pool via [`tokio::task::spawn_blocking`], so async callers never block the
+//! runtime. Record streams are produced by a blocking [`LogReader`] or [`Tail`]
+//! task feeding a bounded channel.
+
+use crate::error::Error;
+use crate::log::{Config, Log};
+use crate::reader::{LogReader, Record};
+use crate::tail::Tail;
+use crate::Result;
+use futures_core::Stream;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Records buffered between the blocking reader task and a [`RecordStream`].
+const STREAM_BUFFER: usize = 64;
+
+/// How often an idle tail task checks whether its stream was dropped.
+const TAIL_IDLE_CHECK: Duration = Duration::from_millis(100);
+
+/// Async handle to a [`Log`]. Cheap to clone; clones share the same writer.
+#[derive(Debug, Clone)]
+pub struct AsyncLog {
+    log: Arc<Mutex<Log>>,
+}
+
+impl AsyncLog {
+    /// Opens (or creates) the log at `path` on the blocking pool.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::open`].
+    pub async fn open(path: impl AsRef<Path>, config: Config) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let log = run_blocking(move || Log::open(path, config)).await?;
+        Ok(Self::new(log))
+    }
+
+    /// Wraps an already open log.
+    #[must_use]
+    pub fn new(log: Log) -> Self {
+        Self {
+            log: Arc::new(Mutex::new(log)),
+        }
+    }
+
+    /// Appends a payload and returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::append`].
+    pub async fn append(&self, payload: impl Into<Vec<u8>>) -> Result<u64> {
+        let payload = payload.into();
+        self.with_log(move |log| log.append(&payload)).await
+    }
+
+    /// Appends several payloads with contiguous offsets.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::append_batch`].
+    pub async fn append_batch(&self, payloads: Vec<Vec<u8>>) -> Result<RangeInclusive<u64>> {
+        self.with_log(move |log| {
+            let slices: Vec<&[u8]> = payloads.iter().map(Vec::as_slice).collect();
+            log.append_batch(&slices)
+        })
+        .await
+    }
+
+    /// Flushes all pending writes to disk.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::flush`].
+    pub async fn flush(&self) -> Result<()> {
+        self.with_log(Log::flush).await
+    }
+
+    /// Reads the record at `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::read`].
+    pub async fn read(&self, offset: u64) -> Result<Vec<u8>> {
+        self.with_log(move |log| log.read(offset)).await
+    }
+
+    /// Streams records with offsets `>= offset`, oldest first, as they exist when
+    /// the stream starts reading each segment.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[must_use]
+    pub fn stream_from(&self, offset: u64) -> RecordStream {
+        let log = Arc::clone(&self.log);
+        RecordStream::spawn(
+            move || log.lock().unwrap_or_else(PoisonError::into_inner).reader(),
+            offset,
+        )
+    }
+
+    /// Streams records with offsets `>= offset`, oldest first, then waits for new
+    /// appends; see [`Log::tail`]. The stream ends once every record written
+    /// before the last clone of this handle was dropped has been yielded.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[must_use]
+    pub fn tail(&self, offset: u64) -> RecordStream {
+        let log = Arc::clone(&self.log);
+        RecordStream::spawn_tail(move || {
+            log.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .tail(offset)
+        })
+    }
+
+    async fn with_log<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Log) -> Result<T> + Send + 'static,
+    {
+        let log = Arc::clone(&self.log);
+        run_blocking(move || f(&mut log.lock().unwrap_or_else(PoisonError::into_inner))).await
+    }
+}
+
+/// Async stream of records, created by [`AsyncLog::stream_from`] or
+/// [`AsyncLog::tail`].
+#[derive(Debug)]
+pub struct RecordStream {
+    rx: mpsc::Receiver<Result<Record>>,
+}
+
+impl RecordStream {
+    fn spawn<F>(open: F, offset: u64) -> Self
+    where
+        F: FnOnce() -> Result<LogReader> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let records = match open() {
+                Ok(reader) => reader.iter_from(offset),
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            for record in records {
+                if tx.blocking_send(record).is_err() {
+                    break; // Stream dropped.
+                }
+            }
+        });
+        Self { rx }
+    }
+
+    fn spawn_tail<F>(open: F) -> Self
+    where
+        F: FnOnce() -> Result<Tail> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let mut tail = match open() {
+                Ok(tail) => tail,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            // Wake periodically while idle so a dropped stream frees the thread.
+            while !tx.is_closed() {
+                let record = match tail.next_timeout(TAIL_IDLE_CHECK) {
+                    Ok(None) => continue,
+                    Err(Error::Closed(_)) => break,
+                    Ok(Some(record)) => Ok(record),
+                    Err(e) => Err(e),
+                };
+                if tx.blocking_send(record).is_err() {
+                    break; // Stream dropped.
+                }
+            }
+        });
+        Self { rx }
+    }
+}
+
+impl Stream for RecordStream {
+    type Item = Result<Record>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+async fn run_blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+
+    async fn next(stream: &mut RecordStream) -> Option<Result<Record>> {
+        poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn append_flush_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AsyncLog::open(dir.path(), Config::default()).await.unwrap();
+        assert_eq!(log.append(b"one".to_vec()).await.unwrap(), 0);
+        let range = log
+            .append_batch(vec![b"two".to_vec(), b"three".to_vec()])
+            .await
+            .unwrap();
+        assert_eq!(range, 1..=2);
+        log.flush().await.unwrap();
+        assert_eq!(log.read(2).await.unwrap(), b"three");
+    }
+
+    #[tokio::test]
+    async fn stream_yields_records_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AsyncLog::open(dir.path(), Config::default()).await.unwrap();
+        for i in 0..5u8 {
+            log.append(vec![i]).await.unwrap();
+        }
+
+        let mut stream = log.stream_from(2);
+        let mut seen = Vec::new();
+        while let Some(record) = next(&mut stream).await {
+            let record = record.unwrap();
+            seen.push((record.offset, record.payload));
+        }
+        assert_eq!(seen, vec![(2, vec![2]), (3, vec![3]), (4, vec![4])]);
+    }
+
+    #[tokio::test]
+    async fn tail_waits_for_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AsyncLog::open(dir.path(), Config::default()).await.unwrap();
+        log.append(b"old".to_vec()).await.unwrap();
+
+        let mut tail = log.tail(0);
+        assert_eq!(next(&mut tail).await.unwrap().unwrap().payload, b"old");
+        log.append(b"new".to_vec()).await.unwrap();
+        let record = next(&mut tail).await.unwrap().unwrap();
+        assert_eq!((record.offset, record.payload), (1, b"new".to_vec()));
+
+        drop(log);
+        assert!(next(&mut tail).await.is_none());
+    }
+}
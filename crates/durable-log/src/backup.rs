@@ -0,0 +1,229 @@
+//! Online backups: consistent copies of a log taken while it keeps appending.
+//!
+//! [`Log::backup`] flushes the log and captures it as it stands: it opens every
+//! segment's `.log`, `.idx` and `.key` file and notes their lengths, and reads
+//! the small metadata files (start offset, committed watermark, snapshot and
+//! producer state) into memory. That takes the log's lock only briefly; the
+//! returned [`Backup`] then copies the captured bytes with
+//! [`write_to`](Backup::write_to) while the writer carries on. Records appended
+//! since are past the captured lengths and left out, and segments deleted by
+//! retention in the meantime stay readable through the open files.
+//!
+//! The copy is a log directory of its own, which [`Log::open`] recovers like one
+//! that crashed: the active segment has no footer, and its tail is scanned.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::storage::{self, FileCursor, OpenMode};
+use crate::trace::event;
+use crate::Result;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Files in a log directory that are never backed up: locks, the
+/// clean-shutdown marker (the copy is recovered instead), the published durable
+/// watermark (the copy's writer publishes its own), and temporaries.
+const SKIPPED_FILES: [&str; 4] = [
+    "write.lock",
+    "consumers.lock",
+    "clean.shutdown",
+    "durable.offset",
+];
+
+/// A log captured by [`Log::backup`], ready to be copied; see the
+/// [module docs](self).
+#[derive(Debug)]
+pub struct Backup {
+    files: Vec<BackupFile>,
+    next_offset: u64,
+}
+
+#[derive(Debug)]
+struct BackupFile {
+    name: String,
+    contents: Contents,
+}
+
+#[derive(Debug)]
+enum Contents {
+    /// The first `len` bytes of an open file.
+    File { file: FileCursor, len: u64 },
+    /// A metadata file, read whole when captured.
+    Bytes(Vec<u8>),
+}
+
+impl Backup {
+    /// Returns the log's next offset when it was captured: the backup holds every
+    /// record below it.
+    #[must_use]
+    pub const fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Copies the captured log into the directory `dest`, creating it if
+    /// missing, and fsyncs every file copied and the directory.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `dest` is not empty.
+    /// - [`Error::Corruption`] if a captured file was truncated since, as by
+    ///   [`Log::truncate_after`].
+    /// - I/O errors from reading the log or writing the copy.
+    pub fn write_to(self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(Error::InvalidFormat(format!(
+                "backup directory {} is not empty",
+                dest.display()
+            )));
+        }
+        for BackupFile { name, contents } in self.files {
+            let mut out = File::create(dest.join(&name))?;
+            match contents {
+                Contents::File { mut file, len } => {
+                    let copied = io::copy(&mut Read::take(&mut file, len), &mut out)?;
+                    if copied != len {
+                        return Err(Error::Corruption(format!(
+                            "{name} was truncated during the backup"
+                        )));
+                    }
+                }
+                Contents::Bytes(bytes) => out.write_all(&bytes)?,
+            }
+            out.sync_all()?;
+        }
+        storage::sync_dir(dest)?;
+        event!(
+            info,
+            dest = %dest.display(),
+            next_offset = self.next_offset,
+            "backup written"
+        );
+        Ok(())
+    }
+}
+
+impl Log {
+    /// Flushes the log and captures it for a backup; see the [module docs](self).
+    ///
+    /// Call this with the log locked, then [`Backup::write_to`] after releasing
+    /// it, so that appends are only held up for the capture.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from flushing the log or opening and reading its files.
+    pub fn backup(&mut self) -> Result<Backup> {
+        self.flush()?;
+        let storage = self.storage();
+        let mut files = Vec::new();
+        let mut segment_paths = BTreeSet::new();
+        for info in self.segment_infos() {
+            let key_path = crate::encryption::key_path(info);
+            for path in [info.log_path.clone(), info.index_path(), key_path] {
+                let file = match FileCursor::open(storage, &path, OpenMode::Read) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound && path != info.log_path => {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let len = file.size()?;
+                files.push(BackupFile {
+                    name: file_name(&path),
+                    contents: Contents::File { file, len },
+                });
+                segment_paths.insert(path);
+            }
+        }
+        for path in storage.list(self.path())? {
+            let name = file_name(&path);
+            if segment_paths.contains(&path)
+                || SKIPPED_FILES.contains(&name.as_str())
+                || path.extension().is_some_and(|ext| ext == "tmp")
+            {
+                continue;
+            }
+            if let Some(bytes) = storage::read_file(storage, &path)? {
+                files.push(BackupFile {
+                    name,
+                    contents: Contents::Bytes(bytes),
+                });
+            }
+        }
+        event!(
+            debug,
+            files = files.len(),
+            next_offset = self.next_offset(),
+            "backup captured"
+        );
+        Ok(Backup {
+            files,
+            next_offset: self.next_offset(),
+        })
+    }
+
+    /// Copies a consistent view of the log into the directory `dest` and returns
+    /// its next offset, as [`backup`](Self::backup) and [`Backup::write_to`] do.
+    /// The log is borrowed throughout the copy; to keep appending meanwhile, call
+    /// the two separately.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`backup`](Self::backup) and [`Backup::write_to`].
+    pub fn backup_to(&mut self, dest: impl AsRef<Path>) -> Result<u64> {
+        let backup = self.backup()?;
+        let next_offset = backup.next_offset();
+        backup.write_to(dest)?;
+        Ok(next_offset)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, LogReader};
+
+    #[test]
+    fn backup_holds_the_log_as_captured() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), config.clone()).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        log.delete_before(2).unwrap();
+        let backup = log.backup().unwrap();
+        assert_eq!(backup.next_offset(), 10);
+
+        // The writer carries on, and even deletes captured segments.
+        for i in 10..15u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        log.delete_before(12).unwrap();
+        let dest = dir.path().join("backup");
+        backup.write_to(&dest).unwrap();
+        assert!(log.backup_to(&dest).is_err());
+
+        let payloads: Vec<_> = LogReader::open(&dest)
+            .unwrap()
+            .iter()
+            .map(|r| r.unwrap().payload)
+            .collect();
+        assert_eq!(payloads, (2..10u8).map(|i| vec![i; 30]).collect::<Vec<_>>());
+        let mut restored = Log::open(&dest, config).unwrap();
+        assert_eq!(restored.first_offset(), 2);
+        assert_eq!(restored.append(b"next").unwrap(), 10);
+    }
+}
@@ -0,0 +1,211 @@
+//! Pluggable record checksum algorithms (`crc32c` and `xxhash` features).
+//!
+//! Every record's body checksum is computed with the algorithm selected by
+//! [`Config::checksum`](crate::Config::checksum). The choice is recorded per record
+//! in two header flag bits ([`FLAG_CHECKSUM_MASK`]), so logs may mix algorithms and
+//! readers always verify with the right one. CRC-32 (both bits clear) stays the
+//! default and is what every earlier release wrote.
+//!
+//! - [`ChecksumAlgorithm::Crc32c`] uses SSE4.2 or ARM CRC instructions when the
+//!   CPU has them (`crc32c` feature).
+//! - [`ChecksumAlgorithm::XxHash64`] stores the low 32 bits of an xxHash64 digest
+//!   (`xxhash` feature).
+//!
+//! An algorithm whose feature is disabled fails with [`Error::InvalidFormat`], both
+//! when writing and when verifying a record that uses it. The v2 header CRC is
+//! always CRC-32.
+
+use crate::error::Error;
+use crate::record::{FLAG_CHECKSUM_MASK, FLAG_CRC32C, FLAG_XXH64};
+use crate::Result;
+
+/// Algorithm used for record body checksums.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 (IEEE), as computed by `crc32fast`.
+    #[default]
+    Crc32,
+    /// CRC-32C (Castagnoli), hardware accelerated where available (requires `crc32c`).
+    Crc32c,
+    /// Low 32 bits of xxHash64 with seed 0 (requires `xxhash`).
+    XxHash64,
+}
+
+impl ChecksumAlgorithm {
+    /// Returns the record flag bits selecting this algorithm.
+    #[must_use]
+    pub const fn flag(self) -> u8 {
+        match self {
+            Self::Crc32 => 0,
+            Self::Crc32c => FLAG_CRC32C,
+            Self::XxHash64 => FLAG_XXH64,
+        }
+    }
+
+    /// Returns the algorithm selected by a record's `flags`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Corruption`] if the checksum bits hold an unassigned value.
+    pub fn from_flags(flags: u8) -> Result<Self> {
+        match flags & FLAG_CHECKSUM_MASK {
+            0 => Ok(Self::Crc32),
+            FLAG_CRC32C => Ok(Self::Crc32c),
+            FLAG_XXH64 => Ok(Self::XxHash64),
+            bits => Err(Error::Corruption(format!(
+                "unknown checksum algorithm bits: 0x{bits:02X}"
+            ))),
+        }
+    }
+
+    /// Computes the checksum of `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidFormat`] if the algorithm's feature is not enabled.
+    pub fn checksum(self, data: &[u8]) -> Result<u32> {
+        self.checksum_parts(&[data])
+    }
+
+    /// Computes the checksum of the concatenation of `parts`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidFormat`] if the algorithm's feature is not enabled.
+    pub fn checksum_parts(self, parts: &[&[u8]]) -> Result<u32> {
+        match self {
+            Self::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                for part in parts {
+                    hasher.update(part);
+                }
+                Ok(hasher.finalize())
+            }
+            Self::Crc32c => crc32c::checksum(parts),
+            Self::XxHash64 => xxhash::checksum(parts),
+        }
+    }
+}
+
+#[cfg(feature = "crc32c")]
+mod crc32c {
+    use crate::Result;
+
+    // Fallible to match the signature of the stub used when `crc32c` is disabled.
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn checksum(parts: &[&[u8]]) -> Result<u32> {
+        Ok(parts
+            .iter()
+            .fold(0, |crc, part| ::crc32c::crc32c_append(crc, part)))
+    }
+}
+
+#[cfg(not(feature = "crc32c"))]
+mod crc32c {
+    use crate::error::Error;
+    use crate::Result;
+
+    pub fn checksum(_parts: &[&[u8]]) -> Result<u32> {
+        Err(Error::InvalidFormat(
+            "crc32c checksums require the `crc32c` feature".into(),
+        ))
+    }
+}
+
+#[cfg(feature = "xxhash")]
+mod xxhash {
+    use crate::Result;
+    use xxhash_rust::xxh64::Xxh64;
+
+    // Fallible to match the signature of the stub used when `xxhash` is disabled.
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn checksum(parts: &[&[u8]]) -> Result<u32> {
+        let mut hasher = Xxh64::new(0);
+        for part in parts {
+            hasher.update(part);
+        }
+        let digest = hasher.digest().to_le_bytes();
+        Ok(u32::from_le_bytes([
+            digest[0], digest[1], digest[2], digest[3],
+        ]))
+    }
+}
+
+#[cfg(not(feature = "xxhash"))]
+mod xxhash {
+    use crate::error::Error;
+    use crate::Result;
+
+    pub fn checksum(_parts: &[&[u8]]) -> Result<u32> {
+        Err(Error::InvalidFormat(
+            "xxHash64 checksums require the `xxhash` feature".into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_record_checksum() {
+        let data = b"hello world";
+        assert_eq!(
+            ChecksumAlgorithm::Crc32.checksum(data).unwrap(),
+            crate::RecordHeader::checksum_of(data)
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Crc32
+                .checksum_parts(&[b"hello", b" world"])
+                .unwrap(),
+            ChecksumAlgorithm::Crc32.checksum(data).unwrap()
+        );
+    }
+
+    #[test]
+    fn flags_roundtrip() {
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash64,
+        ] {
+            assert_eq!(
+                ChecksumAlgorithm::from_flags(algorithm.flag() | 0x01).unwrap(),
+                algorithm
+            );
+        }
+        assert!(ChecksumAlgorithm::from_flags(FLAG_CHECKSUM_MASK).is_err());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn crc32c_known_vector() {
+        // Standard CRC-32C check value.
+        let crc = ChecksumAlgorithm::Crc32c.checksum(b"123456789").unwrap();
+        assert_eq!(crc, 0xE306_9283);
+        assert_eq!(
+            ChecksumAlgorithm::Crc32c
+                .checksum_parts(&[b"1234", b"56789"])
+                .unwrap(),
+            crc
+        );
+    }
+
+    #[cfg(feature = "xxhash")]
+    #[test]
+    fn xxhash64_is_low_half_of_digest() {
+        let digest = xxhash_rust::xxh64::xxh64(b"payload", 0);
+        let sum = ChecksumAlgorithm::XxHash64
+            .checksum_parts(&[b"pay", b"load"])
+            .unwrap();
+        assert_eq!(u64::from(sum), digest & 0xFFFF_FFFF);
+    }
+
+    #[cfg(not(feature = "crc32c"))]
+    #[test]
+    fn disabled_algorithm_errors() {
+        let err = ChecksumAlgorithm::Crc32c.checksum(b"x").unwrap_err();
+        assert!(matches!(err, Error::InvalidFormat(_)));
+    }
+}
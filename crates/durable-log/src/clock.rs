@@ -0,0 +1,66 @@
+//! Time sources for timestamps, expiry and latency measurements.
+//!
+//! `wasm32-unknown-unknown` has no clock: `SystemTime::now` and `Instant::now`
+//! panic there. The log reads the wall clock through this module instead, which
+//! uses the function given to [`set_clock`] when there is one, and on that target
+//! measures elapsed time with it too.
+
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// The clock installed with [`set_clock`], if any.
+static CLOCK: RwLock<Option<fn() -> u64>> = RwLock::new(None);
+
+/// Makes `now`, returning milliseconds since the Unix epoch, the wall clock of
+/// every log in the process.
+///
+/// It stamps records, decides TTL expiry and age-based retention, and dates
+/// [`MemoryBackend`](crate::MemoryBackend) files. The system clock is the
+/// default; on `wasm32-unknown-unknown`, which has none, time stands still at
+/// the epoch until a clock is set (in a browser, `|| js_sys::Date::now() as u64`).
+pub fn set_clock(now: fn() -> u64) {
+    *CLOCK.write().unwrap_or_else(PoisonError::into_inner) = Some(now);
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    let clock = *CLOCK.read().unwrap_or_else(PoisonError::into_inner);
+    clock.map_or_else(system_millis, |now| now())
+}
+
+/// Returns the current time, as [`now_millis`] does.
+pub fn system_now() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis())
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn system_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+const fn system_millis() -> u64 {
+    0
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub use std::time::Instant;
+
+/// A point in time for measuring how long something took, read from the wall
+/// clock where there is no monotonic one.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Instant {
+    pub fn now() -> Self {
+        Self(now_millis())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(now_millis().saturating_sub(self.0))
+    }
+}
@@ -0,0 +1,1110 @@
+//! Record frame encoding and decoding on byte slices, using only `core` and `alloc`.
+//!
+//! This is the layer [`crate::record`] is built on. It has no I/O and its own
+//! [`Error`] type, so it also builds without the default `std` feature: the crate
+//! is then `no_std` and exposes only this module, and embedded firmware can
+//! produce frames that a host-side log ingests verbatim, for example through
+//! [`Log::append_replicated`](crate::Log::append_replicated).
+//!
+//! Frames are checksummed with CRC-32 here; the alternative algorithms of
+//! [`crate::checksum`] need `std`. See the repository docs: `docs/file-format.md`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Magic number for durable-log segment files (ASCII "DLOG").
+pub const MAGIC: u32 = 0x444C_4F47;
+
+/// Original record format version.
+pub const VERSION_V1: u8 = 1;
+
+/// Record format version whose header carries a timestamp, user headers and a CRC
+/// of its own fields.
+pub const VERSION_V2: u8 = 2;
+
+/// Record format version with a variable-length header, for tiny payloads.
+///
+/// It holds the v1 fields, with the batch count, offset and body length stored
+/// as LEB128 varints. Length prefixes inside its bodies are varints too.
+pub const VERSION_COMPACT: u8 = 3;
+
+/// Every record format version this build decodes, oldest first. Readers
+/// dispatch on each frame's version byte, so logs may mix them freely.
+pub const SUPPORTED_VERSIONS: [u8; 3] = [VERSION_V1, VERSION_V2, VERSION_COMPACT];
+
+/// V1 record header size in bytes; also the common prefix of v1 and v2 headers.
+pub const HEADER_LEN: usize = 24;
+
+/// V2 record header size in bytes: the V1 fields, a u64 timestamp, the u32 length
+/// of the user headers block and a header CRC-32.
+pub const HEADER_LEN_V2: usize = HEADER_LEN + 16;
+
+/// Largest header size of any supported version.
+pub const MAX_HEADER_LEN: usize = HEADER_LEN_V2;
+
+/// Smallest header size of any supported version: a compact header whose three
+/// varints take one byte each.
+pub const MIN_HEADER_LEN: usize = 13;
+
+/// Index entry size in bytes (fixed): offset (8) + position (8).
+pub const INDEX_ENTRY_LEN: usize = 16;
+
+/// No flags set: the body is the plain payload.
+pub const FLAGS_NONE: u8 = 0;
+
+/// Flag bit: the body starts with a key (u32 length prefix + key bytes) before the value.
+pub const FLAG_KEYED: u8 = 0x01;
+
+/// Flag bit: the value is LZ4-compressed (see [`crate::compression`]).
+pub const FLAG_LZ4: u8 = 0x02;
+
+/// Flag bit: the value is Zstandard-compressed (see [`crate::compression`]).
+pub const FLAG_ZSTD: u8 = 0x04;
+
+/// Flag bit: the value is encrypted with the segment's data key (see [`crate::encryption`]).
+pub const FLAG_ENCRYPTED: u8 = 0x08;
+
+/// Flag bits holding the body checksum algorithm (see [`crate::checksum`]);
+/// both clear means CRC-32.
+pub const FLAG_CHECKSUM_MASK: u8 = 0x30;
+
+/// Checksum algorithm bits value: CRC-32C.
+pub const FLAG_CRC32C: u8 = 0x10;
+
+/// Checksum algorithm bits value: xxHash64 (low 32 bits).
+pub const FLAG_XXH64: u8 = 0x20;
+
+/// Flag bit: the body packs several payloads (see [`encode_batch`]) numbered
+/// consecutively from the header's offset.
+pub const FLAG_BATCH: u8 = 0x40;
+
+/// Flag bit: the record's value continues in the next frame, a chunk with the
+/// same offset. The chunk without this bit is the last one.
+pub const FLAG_CONTINUED: u8 = 0x80;
+
+/// Flag bits marking a control record: a transaction marker (see
+/// [`crate::txn`]) rather than user data.
+///
+/// No data frame sets both bits, since a batch frame cannot be chunked. The body
+/// holds the marker's code, and no other flags but the checksum algorithm may be
+/// set.
+pub const FLAG_CONTROL: u8 = FLAG_BATCH | FLAG_CONTINUED;
+
+/// All flag bits understood by this version; decoding rejects any others.
+pub const FLAGS_KNOWN: u8 = FLAG_KEYED
+    | FLAG_LZ4
+    | FLAG_ZSTD
+    | FLAG_ENCRYPTED
+    | FLAG_CHECKSUM_MASK
+    | FLAG_BATCH
+    | FLAG_CONTINUED;
+
+/// Largest value the log stores in one frame. Longer values are split into
+/// [`FLAG_CONTINUED`] chunks, leaving room below `u32::MAX` for a key and for
+/// compression or encryption overhead.
+pub const MAX_CHUNK_LEN: usize = 1 << 30;
+
+/// `batch_count` of a non-batch data frame written by
+/// [`Log::append_batch`](crate::Log::append_batch) that more frames of the same
+/// batch follow; the batch's final frame has 0.
+///
+/// Recovery discards a batch whose final frame is missing, so a batch survives a
+/// crash whole or not at all.
+pub const BATCH_CONTINUES: u16 = 1;
+
+/// Size of the key length prefix in a keyed v1 or v2 record body.
+pub const KEY_LEN_PREFIX: usize = 4;
+
+/// Size of the length prefix of each payload in a v1 or v2 batch body.
+pub const BATCH_LEN_PREFIX: usize = 4;
+
+/// Largest encoded size of a LEB128 varint of each header field: batch count
+/// (u16), offset (u64) and body length (u32).
+const VARINT_WIDTHS: [usize; 3] = [3, 10, 5];
+
+/// Result type for codec operations.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Errors from encoding or decoding a frame.
+///
+/// With `std`, each variant converts into the [`crate::Error`] variant of the
+/// same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A frame that cannot be encoded: unknown flag bits, or a key, value, header
+    /// or body that is too long.
+    InvalidFormat(String),
+    /// Frame bytes that are inconsistent or fail a check.
+    Corruption(String),
+    /// The input ended before a complete header or record.
+    Truncated {
+        /// Bytes needed to decode the header or record.
+        needed: usize,
+        /// Bytes available.
+        available: usize,
+    },
+    /// A frame does not start with the record magic number.
+    BadMagic(u32),
+    /// A frame carries a record format version this build cannot read.
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidFormat(msg) => write!(f, "invalid format: {msg}"),
+            Self::Corruption(msg) => write!(f, "data corruption: {msg}"),
+            Self::Truncated { needed, available } => write!(
+                f,
+                "data corruption: truncated input: need {needed} bytes, have {available}"
+            ),
+            Self::BadMagic(magic) => write!(f, "data corruption: invalid magic 0x{magic:08X}"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported record version {version}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Fixed-size header for a single log record (v1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecordHeader {
+    /// Must be [`MAGIC`].
+    pub magic: u32,
+    /// Format version: [`VERSION_V1`], [`VERSION_V2`] or [`VERSION_COMPACT`].
+    pub version: u8,
+    /// Flag bits (see [`FLAG_KEYED`]); unknown bits are rejected on decode.
+    pub flags: u8,
+    /// Number of payloads in a batch frame (see [`FLAG_BATCH`]). In other data
+    /// frames, [`BATCH_CONTINUES`] or 0.
+    pub batch_count: u16,
+    /// Logical offset of this record (monotonic).
+    pub offset: u64,
+    /// Length of the record body in bytes (the payload, plus the key for keyed records).
+    pub payload_len: u32,
+    /// Checksum of the body only, using the algorithm selected by `flags` (see docs).
+    pub checksum: u32,
+    /// Append time in milliseconds since the Unix epoch; `None` for v1 and
+    /// compact records, which do not store one.
+    pub timestamp: Option<u64>,
+    /// Length of the user headers block at the start of the body (see
+    /// [`decode_headers`]); always 0 for v1 and compact records.
+    pub headers_len: u32,
+}
+
+impl RecordHeader {
+    /// Build a header for encoding. Checksum must be computed from the payload.
+    #[must_use]
+    pub const fn new(offset: u64, payload_len: u32, checksum: u32) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION_V1,
+            flags: FLAGS_NONE,
+            batch_count: 0,
+            offset,
+            payload_len,
+            checksum,
+            timestamp: None,
+            headers_len: 0,
+        }
+    }
+
+    /// Returns this header with the format `version` set.
+    #[must_use]
+    pub const fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Returns the encoded size of this header, which depends on its version and,
+    /// for compact headers, on the values of its varint fields.
+    #[must_use]
+    pub const fn encoded_len(&self) -> usize {
+        match self.version {
+            VERSION_V1 => HEADER_LEN,
+            VERSION_COMPACT => {
+                6 + varint_len(self.batch_count as u64)
+                    + varint_len(self.offset)
+                    + varint_len(self.payload_len as u64)
+                    + 4
+            }
+            _ => HEADER_LEN_V2,
+        }
+    }
+
+    /// Returns this header with `timestamp` (milliseconds since the Unix epoch) set.
+    /// Only v2 headers store it.
+    #[must_use]
+    pub const fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Returns this header with `batch_count` set; see [`FLAG_BATCH`].
+    #[must_use]
+    pub const fn with_batch_count(mut self, batch_count: u16) -> Self {
+        self.batch_count = batch_count;
+        self
+    }
+
+    /// Returns this header with `flags` set.
+    #[must_use]
+    pub const fn with_flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Returns true if the record's value is compressed.
+    #[must_use]
+    pub const fn is_compressed(&self) -> bool {
+        self.flags & (FLAG_LZ4 | FLAG_ZSTD) != 0
+    }
+
+    /// Returns true if the record's value is encrypted.
+    #[must_use]
+    pub const fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// Returns true if the body packs several payloads (see [`FLAG_BATCH`]).
+    #[must_use]
+    pub const fn is_batch(&self) -> bool {
+        self.flags & FLAG_CONTROL == FLAG_BATCH
+    }
+
+    /// Returns true if the record's value continues in the next frame (see
+    /// [`FLAG_CONTINUED`]).
+    #[must_use]
+    pub const fn is_continued(&self) -> bool {
+        self.flags & FLAG_CONTROL == FLAG_CONTINUED
+    }
+
+    /// Returns true if more frames of the same [`Log::append_batch`](crate::Log::append_batch)
+    /// call follow this one (see [`BATCH_CONTINUES`]).
+    #[must_use]
+    pub const fn continues_batch(&self) -> bool {
+        !self.is_batch() && !self.is_control() && self.batch_count == BATCH_CONTINUES
+    }
+
+    /// Returns true if the frame is a control record (see [`FLAG_CONTROL`]).
+    #[must_use]
+    pub const fn is_control(&self) -> bool {
+        self.flags & FLAG_CONTROL == FLAG_CONTROL
+    }
+
+    /// Returns the number of offsets the frame occupies: its batch count for a
+    /// batch frame, 1 otherwise.
+    #[must_use]
+    pub const fn record_count(&self) -> u64 {
+        if self.is_batch() {
+            self.batch_count as u64
+        } else {
+            1
+        }
+    }
+
+    /// Returns true if the record body carries a key (see [`FLAG_KEYED`]).
+    #[must_use]
+    pub const fn is_keyed(&self) -> bool {
+        self.flags & FLAG_KEYED != 0
+    }
+
+    /// Compute CRC-32 of `payload` (used when encoding).
+    #[must_use]
+    pub fn checksum_of(payload: &[u8]) -> u32 {
+        crc32_of(&[payload])
+    }
+}
+
+/// Returns the CRC-32 of the concatenation of `parts`.
+fn crc32_of(parts: &[&[u8]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Encodes a frame from `template` (version, offset, timestamp and batch count)
+/// with a CRC-32 body checksum, filling in its flags, length and checksum.
+///
+/// `headers` is an encoded headers block (see [`encode_headers`]), which only v2
+/// frames can carry. [`FLAG_KEYED`] is set automatically when `key` is present;
+/// `value` is written as given.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidFormat`] if `flags` has unknown bits or selects another
+/// checksum algorithm, if a v1 or compact template is given headers, or if the
+/// key or the whole body exceeds `u32::MAX` bytes.
+pub fn encode_frame(
+    template: RecordHeader,
+    flags: u8,
+    headers: &[u8],
+    key: Option<&[u8]>,
+    value: &[u8],
+) -> Result<Vec<u8>> {
+    encode_frame_with(template, flags, headers, key, value, |parts| {
+        if flags & FLAG_CHECKSUM_MASK != 0 {
+            return Err(Error::InvalidFormat(format!(
+                "checksum algorithm bits 0x{:02X} need the `std` feature",
+                flags & FLAG_CHECKSUM_MASK
+            )));
+        }
+        Ok(crc32_of(parts))
+    })
+}
+
+/// Encodes a frame like [`encode_frame`], computing the body checksum with
+/// `checksum` over the parts of the body, in order.
+///
+/// # Errors
+///
+/// Same as [`encode_frame`], and any error from `checksum`.
+pub fn encode_frame_with<E: From<Error>>(
+    template: RecordHeader,
+    flags: u8,
+    headers: &[u8],
+    key: Option<&[u8]>,
+    value: &[u8],
+    checksum: impl FnOnce(&[&[u8]]) -> core::result::Result<u32, E>,
+) -> core::result::Result<Vec<u8>, E> {
+    let unknown = flags & !FLAGS_KNOWN;
+    if unknown != 0 {
+        return Err(Error::InvalidFormat(format!("unknown flag bits: 0x{unknown:02X}")).into());
+    }
+    if template.version != VERSION_V2 && !headers.is_empty() {
+        return Err(Error::InvalidFormat("record headers require a v2 frame".into()).into());
+    }
+    let template = RecordHeader {
+        headers_len: length_of("headers", headers.len())?,
+        ..template
+    };
+    let key_len;
+    let (header, parts): (_, &[&[u8]]) = match key {
+        None => (template.with_flags(flags), &[headers, value]),
+        Some(key) => {
+            key_len = LengthPrefix::new(template.version, "key", key.len())?;
+            (
+                template.with_flags(flags | FLAG_KEYED),
+                &[headers, key_len.as_bytes(), key, value],
+            )
+        }
+    };
+    let body_len = parts.iter().map(|part| part.len()).sum();
+    let header = RecordHeader {
+        payload_len: length_of("payload", body_len)?,
+        checksum: checksum(parts)?,
+        ..header
+    };
+    let mut out = Vec::with_capacity(header.encoded_len() + body_len);
+    let mut buf = [0u8; MAX_HEADER_LEN];
+    out.extend_from_slice(encode_header(&header, &mut buf));
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    Ok(out)
+}
+
+/// Converts the length of a `what` to its stored u32 form.
+fn length_of(what: &str, len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        Error::InvalidFormat(format!("{what} length {len} exceeds maximum {}", u32::MAX))
+    })
+}
+
+/// An encoded length prefix inside a record body (of a key, or of a batch
+/// payload): a u32, or a varint in compact frames.
+struct LengthPrefix {
+    buf: [u8; 5],
+    len: usize,
+}
+
+impl LengthPrefix {
+    /// Encodes the length of a `what` for a frame of `version`.
+    fn new(version: u8, what: &str, len: usize) -> Result<Self> {
+        let value = length_of(what, len)?;
+        let mut buf = [0u8; 5];
+        let len = if version == VERSION_COMPACT {
+            encode_varint(value.into(), &mut buf)
+        } else {
+            buf[..4].copy_from_slice(&value.to_le_bytes());
+            4
+        };
+        Ok(Self { buf, len })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Reads a prefix of a frame of `version` from the start of `bytes`, returning
+    /// the length and the bytes after the prefix, or `None` if it is truncated or
+    /// malformed.
+    fn read(version: u8, bytes: &[u8]) -> Option<(usize, &[u8])> {
+        if version == VERSION_COMPACT {
+            let mut pos = 0;
+            let len: u32 = read_varint(bytes, &mut pos).ok()?;
+            return Some((len as usize, &bytes[pos..]));
+        }
+        let prefix = bytes.get(..4)?;
+        Some((u32_at(prefix, 0) as usize, &bytes[4..]))
+    }
+}
+
+/// Returns the size of `value` as an unsigned LEB128 varint.
+const fn varint_len(value: u64) -> usize {
+    let bits = u64::BITS - (value | 1).leading_zeros();
+    bits.div_ceil(7) as usize
+}
+
+/// Encodes `value` as an unsigned LEB128 varint (7 bits per byte, least
+/// significant first, high bit set on all but the last byte) into `buf`,
+/// returning its size.
+fn encode_varint(mut value: u64, buf: &mut [u8]) -> usize {
+    let mut i = 0;
+    while value >= 0x80 {
+        buf[i] = value.to_le_bytes()[0] | 0x80;
+        value >>= 7;
+        i += 1;
+    }
+    buf[i] = value.to_le_bytes()[0];
+    i + 1
+}
+
+/// Decodes an unsigned LEB128 varint at `*pos` in `bytes` and advances `*pos`
+/// past it.
+///
+/// Only the shortest encoding of a value is accepted, so a decoded header's
+/// [`RecordHeader::encoded_len`] always matches the bytes it was read from.
+fn read_varint<T: TryFrom<u64>>(bytes: &[u8], pos: &mut usize) -> Result<T> {
+    let start = *pos;
+    let malformed = || Error::Corruption(format!("malformed varint at byte {start}"));
+    let mut value = 0u64;
+    for shift in (0..u64::BITS).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or_else(malformed)?;
+        *pos += 1;
+        if shift == 63 && byte > 1 {
+            break;
+        }
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            if byte == 0 && shift > 0 {
+                break;
+            }
+            return T::try_from(value).map_err(|_| malformed());
+        }
+    }
+    Err(malformed())
+}
+
+/// Encodes several payloads as one CRC-32 batch frame numbered from `offset`.
+///
+/// The frame is a v1 header with [`FLAG_BATCH`] and the payload count, then
+/// each payload prefixed by its length (u32). A single checksum covers the
+/// whole body; the payloads get offsets `offset`, `offset + 1`, and so on, so
+/// none is stored.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidFormat`] if `payloads` is empty or holds more than
+/// `u16::MAX` payloads, or if any payload or the whole body exceeds `u32::MAX`
+/// bytes.
+pub fn encode_batch(offset: u64, payloads: &[&[u8]]) -> Result<Vec<u8>> {
+    let (count, body) = pack_batch(VERSION_V1, payloads)?;
+    let template = RecordHeader::new(offset, 0, 0).with_batch_count(count);
+    encode_frame(template, FLAG_BATCH, &[], None, &body)
+}
+
+/// Builds a batch body for a frame of `version` from `payloads`, returning it
+/// with the payload count. Compact frames prefix each payload with a varint
+/// instead of a u32.
+///
+/// # Errors
+///
+/// Same as [`encode_batch`].
+pub fn pack_batch(version: u8, payloads: &[&[u8]]) -> Result<(u16, Vec<u8>)> {
+    let count = u16::try_from(payloads.len())
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| {
+            Error::InvalidFormat(format!(
+                "a batch needs 1 to {} payloads, got {}",
+                u16::MAX,
+                payloads.len()
+            ))
+        })?;
+    let body_len = payloads.iter().map(|p| BATCH_LEN_PREFIX + p.len()).sum();
+    let mut body = Vec::with_capacity(body_len);
+    for payload in payloads {
+        body.extend_from_slice(LengthPrefix::new(version, "payload", payload.len())?.as_bytes());
+        body.extend_from_slice(payload);
+    }
+    Ok((count, body))
+}
+
+/// Encodes user headers (name/value metadata) into a v2 headers block: for each
+/// header, the name length (u16) and UTF-8 name, then the value length (u32) and
+/// value.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidFormat`] if a name exceeds `u16::MAX` bytes, or a
+/// value or the whole block exceeds `u32::MAX` bytes.
+pub fn encode_headers(headers: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    let too_long = |what: &str, len: usize, max: u64| {
+        Error::InvalidFormat(format!("header {what} length {len} exceeds maximum {max}"))
+    };
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        let name_len =
+            u16::try_from(name.len()).map_err(|_| too_long("name", name.len(), u16::MAX.into()))?;
+        let value_len = u32::try_from(value.len())
+            .map_err(|_| too_long("value", value.len(), u32::MAX.into()))?;
+        block.extend_from_slice(&name_len.to_le_bytes());
+        block.extend_from_slice(name.as_bytes());
+        block.extend_from_slice(&value_len.to_le_bytes());
+        block.extend_from_slice(value);
+    }
+    u32::try_from(block.len()).map_err(|_| too_long("block", block.len(), u32::MAX.into()))?;
+    Ok(block)
+}
+
+/// Encodes only the header into `buf` and returns the encoded bytes
+/// ([`RecordHeader::encoded_len`] of them: [`HEADER_LEN`] for v1,
+/// [`HEADER_LEN_V2`] for v2). Little-endian.
+///
+/// A v2 header without a timestamp stores 0. V1 and compact headers cannot
+/// describe user headers and drop `headers_len`; compact headers drop the
+/// timestamp too.
+pub fn encode_header<'a>(header: &RecordHeader, buf: &'a mut [u8; MAX_HEADER_LEN]) -> &'a [u8] {
+    buf[0..4].copy_from_slice(&header.magic.to_le_bytes());
+    buf[4] = header.version;
+    buf[5] = header.flags;
+    if header.version == VERSION_COMPACT {
+        let mut pos = 6;
+        for value in [
+            header.batch_count.into(),
+            header.offset,
+            header.payload_len.into(),
+        ] {
+            pos += encode_varint(value, &mut buf[pos..]);
+        }
+        buf[pos..pos + 4].copy_from_slice(&header.checksum.to_le_bytes());
+        return &buf[..pos + 4];
+    }
+    buf[6..8].copy_from_slice(&header.batch_count.to_le_bytes());
+    buf[8..16].copy_from_slice(&header.offset.to_le_bytes());
+    buf[16..20].copy_from_slice(&header.payload_len.to_le_bytes());
+    buf[20..24].copy_from_slice(&header.checksum.to_le_bytes());
+    if header.version == VERSION_V1 {
+        return &buf[..HEADER_LEN];
+    }
+    buf[24..32].copy_from_slice(&header.timestamp.unwrap_or(0).to_le_bytes());
+    buf[32..36].copy_from_slice(&header.headers_len.to_le_bytes());
+    let crc = RecordHeader::checksum_of(&buf[..HEADER_LEN_V2 - 4]);
+    buf[HEADER_LEN_V2 - 4..].copy_from_slice(&crc.to_le_bytes());
+    &buf[..]
+}
+
+/// Returns the header size for format `version`, or `None` if it is unsupported
+/// or, like [`VERSION_COMPACT`], variable (see [`header_len_in`]).
+#[must_use]
+pub const fn header_len(version: u8) -> Option<usize> {
+    match version {
+        VERSION_V1 => Some(HEADER_LEN),
+        VERSION_V2 => Some(HEADER_LEN_V2),
+        _ => None,
+    }
+}
+
+/// Returns the size of the header at the start of `bytes`, as far as `bytes`
+/// shows it.
+///
+/// When `bytes` is too short to tell, the result is a lower bound above
+/// `bytes.len()`: read that many bytes and ask again. Reading
+/// [`MIN_HEADER_LEN`] bytes first is always safe. An unknown version reports
+/// [`HEADER_LEN`], leaving [`decode_header`] to reject it.
+#[must_use]
+pub fn header_len_in(bytes: &[u8]) -> usize {
+    match bytes.get(4) {
+        None => MIN_HEADER_LEN,
+        Some(&VERSION_COMPACT) => {
+            let mut pos = 6;
+            for (i, width) in VARINT_WIDTHS.into_iter().enumerate() {
+                let field = bytes.get(pos..).unwrap_or_default();
+                let field = &field[..field.len().min(width)];
+                match field.iter().position(|&b| b & 0x80 == 0) {
+                    Some(last) => pos += last + 1,
+                    // Too long for the field; `decode_header` rejects it.
+                    None if field.len() == width => pos += width,
+                    // At least one more byte of this varint, one per remaining
+                    // varint, and the checksum.
+                    None => return bytes.len().max(pos) + 1 + (2 - i) + 4,
+                }
+            }
+            pos + 4
+        }
+        Some(&version) => header_len(version).unwrap_or(HEADER_LEN),
+    }
+}
+
+/// Decodes a header of any supported version from the start of `bytes`. Fails if
+/// magic or version is invalid, or if a v2 header does not match its CRC.
+///
+/// Each frame is decoded according to its own version byte, so one segment may
+/// mix versions; see [`SUPPORTED_VERSIONS`].
+///
+/// # Errors
+///
+/// - [`Error::Truncated`] if `bytes` is shorter than the header.
+/// - [`Error::BadMagic`] for wrong magic.
+/// - [`Error::UnsupportedVersion`] for a version other than v1, v2 or compact.
+/// - [`Error::Corruption`] for unknown or contradictory flag bits, a malformed
+///   compact varint, or if a v2 header fails its CRC check; none of its fields
+///   (in particular `payload_len`) can be trusted.
+pub fn decode_header(bytes: &[u8]) -> Result<RecordHeader> {
+    let len = header_len_in(bytes);
+    if bytes.len() < len {
+        return Err(Error::Truncated {
+            needed: len,
+            available: bytes.len(),
+        });
+    }
+    let magic = u32_at(bytes, 0);
+    if magic != MAGIC {
+        return Err(Error::BadMagic(magic));
+    }
+    match bytes[4] {
+        VERSION_V1 => decode_v1_header(bytes),
+        VERSION_V2 => decode_v2_header(bytes),
+        VERSION_COMPACT => decode_compact_header(bytes),
+        version => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// Decodes a v1 header; `bytes` holds all of it.
+fn decode_v1_header(bytes: &[u8]) -> Result<RecordHeader> {
+    Ok(RecordHeader {
+        magic: MAGIC,
+        version: bytes[4],
+        flags: bytes[5],
+        batch_count: batch_count_of(bytes[5], u16::from_le_bytes([bytes[6], bytes[7]]))?,
+        offset: u64_at(bytes, 8),
+        payload_len: u32_at(bytes, 16),
+        checksum: u32_at(bytes, 20),
+        timestamp: None,
+        headers_len: 0,
+    })
+}
+
+/// Decodes a v2 header, the v1 fields plus its own, once it matches its CRC;
+/// `bytes` holds all of it.
+fn decode_v2_header(bytes: &[u8]) -> Result<RecordHeader> {
+    let (expected, actual) = (
+        u32_at(bytes, HEADER_LEN_V2 - 4),
+        RecordHeader::checksum_of(&bytes[..HEADER_LEN_V2 - 4]),
+    );
+    if expected != actual {
+        return Err(Error::Corruption(format!(
+            "header checksum mismatch: expected 0x{expected:08X}, got 0x{actual:08X}"
+        )));
+    }
+    Ok(RecordHeader {
+        timestamp: Some(u64_at(bytes, HEADER_LEN)),
+        headers_len: u32_at(bytes, HEADER_LEN + 8),
+        ..decode_v1_header(bytes)?
+    })
+}
+
+/// Decodes a compact header; `bytes` holds all of it.
+fn decode_compact_header(bytes: &[u8]) -> Result<RecordHeader> {
+    let flags = bytes[5];
+    let mut pos = 6;
+    let stored_count = read_varint(bytes, &mut pos)?;
+    let offset = read_varint(bytes, &mut pos)?;
+    let payload_len = read_varint(bytes, &mut pos)?;
+    let batch_count = batch_count_of(flags, stored_count)?;
+    // Other values would make `encoded_len` disagree with the stored header.
+    if batch_count != stored_count {
+        return Err(Error::Corruption(format!(
+            "compact header has batch count {stored_count} for flags 0x{flags:02X}"
+        )));
+    }
+    Ok(RecordHeader {
+        magic: MAGIC,
+        version: VERSION_COMPACT,
+        flags,
+        batch_count,
+        offset,
+        payload_len,
+        checksum: u32_at(bytes, pos),
+        timestamp: None,
+        headers_len: 0,
+    })
+}
+
+/// Checks `flags` and returns the batch count a header with `stored_count` in
+/// its batch count field means for them.
+fn batch_count_of(flags: u8, stored_count: u16) -> Result<u16> {
+    let unknown = flags & !FLAGS_KNOWN;
+    if unknown != 0 {
+        return Err(Error::Corruption(format!(
+            "unknown flag bits: 0x{unknown:02X}"
+        )));
+    }
+    let bits = flags & FLAG_CHECKSUM_MASK;
+    if bits == FLAG_CHECKSUM_MASK {
+        return Err(Error::Corruption(format!(
+            "unknown checksum algorithm bits: 0x{bits:02X}"
+        )));
+    }
+    Ok(if flags & FLAG_CONTROL == FLAG_CONTROL {
+        if flags & (FLAG_KEYED | FLAG_LZ4 | FLAG_ZSTD | FLAG_ENCRYPTED) != 0 {
+            return Err(Error::Corruption(
+                "control records cannot be keyed, compressed or encrypted".into(),
+            ));
+        }
+        0
+    } else if flags & FLAG_BATCH == 0 {
+        // Other data frames use these bytes only for the batch mark.
+        if stored_count == 0 {
+            0
+        } else {
+            BATCH_CONTINUES
+        }
+    } else if flags & FLAG_KEYED != 0 {
+        return Err(Error::Corruption("batch frames cannot be keyed".into()));
+    } else {
+        match stored_count {
+            0 => return Err(Error::Corruption("empty batch frame".into())),
+            n => n,
+        }
+    })
+}
+
+/// Decodes a full record (header + body) from `bytes`. Checksum validation is
+/// left to the caller.
+///
+/// # Errors
+///
+/// - Same as [`decode_header`].
+/// - [`Error::Truncated`] if `bytes` ends before the body does.
+pub fn decode_record(bytes: &[u8]) -> Result<(RecordHeader, &[u8])> {
+    let header = decode_header(bytes)?;
+    let body_start = header.encoded_len();
+    let end = body_start.saturating_add(header.payload_len as usize);
+    if bytes.len() < end {
+        return Err(Error::Truncated {
+            needed: end,
+            available: bytes.len(),
+        });
+    }
+    Ok((header, &bytes[body_start..end]))
+}
+
+/// Splits a record body into its key (for keyed records) and value, skipping any
+/// user headers.
+///
+/// # Errors
+///
+/// Returns [`Error::Corruption`] if the body is too short for its headers block,
+/// or a keyed body for its key length.
+pub fn split_key<'a>(
+    header: &RecordHeader,
+    body: &'a [u8],
+) -> Result<(Option<&'a [u8]>, &'a [u8])> {
+    let body = body.get(header.headers_len as usize..).ok_or_else(|| {
+        Error::Corruption(format!(
+            "record at offset {}: headers length {} exceeds body ({} bytes)",
+            header.offset,
+            header.headers_len,
+            body.len()
+        ))
+    })?;
+    if !header.is_keyed() {
+        return Ok((None, body));
+    }
+    let Some((key_len, rest)) = LengthPrefix::read(header.version, body) else {
+        return Err(Error::Corruption(format!(
+            "keyed record at offset {} has no key length prefix",
+            header.offset
+        )));
+    };
+    if rest.len() < key_len {
+        return Err(Error::Corruption(format!(
+            "keyed record at offset {}: key length {key_len} exceeds body ({} bytes)",
+            header.offset,
+            rest.len()
+        )));
+    }
+    let (key, value) = rest.split_at(key_len);
+    Ok((Some(key), value))
+}
+
+/// Decodes the user headers block at the start of a record body as `(name, value)`
+/// pairs, in the order they were written.
+///
+/// # Errors
+///
+/// Returns [`Error::Corruption`] if the block is truncated, overruns the body, or
+/// holds a name that is not UTF-8.
+pub fn decode_headers<'a>(
+    header: &RecordHeader,
+    body: &'a [u8],
+) -> Result<Vec<(&'a str, &'a [u8])>> {
+    let corrupt = |what: &str| {
+        Error::Corruption(format!(
+            "record at offset {} has {what} in its headers block",
+            header.offset
+        ))
+    };
+    let mut rest = body
+        .get(..header.headers_len as usize)
+        .ok_or_else(|| corrupt("a length past the body"))?;
+    let mut headers = Vec::new();
+    while !rest.is_empty() {
+        let name_len = rest.get(..2).ok_or_else(|| corrupt("a truncated name"))?;
+        let name_end = 2 + usize::from(u16::from_le_bytes([name_len[0], name_len[1]]));
+        let name = rest
+            .get(2..name_end)
+            .ok_or_else(|| corrupt("a truncated name"))?;
+        let name = core::str::from_utf8(name).map_err(|_| corrupt("a non-UTF-8 name"))?;
+        if rest.len() < name_end + 4 {
+            return Err(corrupt("a truncated value"));
+        }
+        let value_end = name_end + 4 + u32_at(rest, name_end) as usize;
+        let value = rest
+            .get(name_end + 4..value_end)
+            .ok_or_else(|| corrupt("a truncated value"))?;
+        headers.push((name, value));
+        rest = &rest[value_end..];
+    }
+    Ok(headers)
+}
+
+/// Splits a batch body (already decrypted and decompressed) into its payloads.
+///
+/// # Errors
+///
+/// Returns [`Error::Corruption`] if the body does not hold exactly
+/// `header.batch_count` length-prefixed payloads.
+pub fn split_batch<'a>(header: &RecordHeader, body: &'a [u8]) -> Result<Vec<&'a [u8]>> {
+    let corrupt =
+        |what: &str| Error::Corruption(format!("batch frame at offset {} {what}", header.offset));
+    let mut payloads = Vec::with_capacity(usize::from(header.batch_count));
+    let mut rest = body;
+    for _ in 0..header.batch_count {
+        let (len, after) = LengthPrefix::read(header.version, rest)
+            .ok_or_else(|| corrupt("ends before its last payload"))?;
+        let payload = after
+            .get(..len)
+            .ok_or_else(|| corrupt("has a payload longer than its body"))?;
+        payloads.push(payload);
+        rest = &after[len..];
+    }
+    if !rest.is_empty() {
+        return Err(corrupt("has trailing bytes after its last payload"));
+    }
+    Ok(payloads)
+}
+
+/// Reads a little-endian u32 at `at`; the caller has checked the length.
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(b)
+}
+
+/// Reads a little-endian u64 at `at`; the caller has checked the length.
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_match_the_std_encoder() {
+        let template = RecordHeader::new(5, 0, 0)
+            .with_version(VERSION_V2)
+            .with_timestamp(1_700_000_000_000);
+        let headers = encode_headers(&[("device", b"sensor-7")]).unwrap();
+        let frame = encode_frame(template, FLAGS_NONE, &headers, Some(b"k"), b"reading").unwrap();
+        let expected = crate::record::encode_frame_v2(
+            5,
+            1_700_000_000_000,
+            FLAGS_NONE,
+            &[("device", b"sensor-7")],
+            Some(b"k"),
+            b"reading",
+        )
+        .unwrap();
+        assert_eq!(frame, expected);
+
+        let (header, body) = decode_record(&frame).unwrap();
+        assert_eq!(header.checksum, RecordHeader::checksum_of(body));
+        assert_eq!(
+            split_key(&header, body).unwrap(),
+            (Some(&b"k"[..]), &b"reading"[..])
+        );
+        assert_eq!(
+            decode_headers(&header, body).unwrap(),
+            [("device", &b"sensor-7"[..])]
+        );
+
+        let batch = encode_batch(9, &[b"a", b"bc"]).unwrap();
+        assert_eq!(
+            batch,
+            crate::record::encode_batch(9, &[b"a", b"bc"]).unwrap()
+        );
+        let (header, body) = decode_record(&batch).unwrap();
+        assert_eq!(split_batch(&header, body).unwrap(), [&b"a"[..], b"bc"]);
+    }
+
+    #[test]
+    fn other_checksum_algorithms_are_rejected() {
+        let template = RecordHeader::new(0, 0, 0);
+        let err = encode_frame(template, FLAG_CRC32C, &[], None, b"x").unwrap_err();
+        assert!(matches!(err, Error::InvalidFormat(_)), "{err}");
+
+        let mut frame = encode_frame(template, FLAGS_NONE, &[], None, b"x").unwrap();
+        frame[5] = FLAG_CHECKSUM_MASK;
+        assert!(matches!(decode_header(&frame), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn compact_frames_match_golden_bytes() {
+        let template = RecordHeader::new(300, 0, 0).with_version(VERSION_COMPACT);
+        let frame = encode_frame(template, FLAGS_NONE, &[], Some(b"k"), b"hello").unwrap();
+        #[rustfmt::skip]
+        assert_eq!(frame, [
+            0x47, 0x4F, 0x4C, 0x44, // magic
+            VERSION_COMPACT, FLAG_KEYED,
+            0x00,                   // batch count
+            0xAC, 0x02,             // offset 300
+            0x07,                   // body length
+            0xC8, 0x7A, 0x1A, 0xA9, // CRC-32 of the body
+            0x01, b'k', b'h', b'e', b'l', b'l', b'o',
+        ]);
+        let (header, body) = decode_record(&frame).unwrap();
+        assert_eq!((header.offset, header.encoded_len()), (300, 14));
+        assert_eq!(header.timestamp, None);
+        assert_eq!(
+            split_key(&header, body).unwrap(),
+            (Some(&b"k"[..]), &b"hello"[..])
+        );
+
+        let (count, body) = pack_batch(VERSION_COMPACT, &[b"a", b"bc"]).unwrap();
+        let template = template.with_batch_count(count);
+        let template = RecordHeader {
+            offset: 9,
+            ..template
+        };
+        let batch = encode_frame(template, FLAG_BATCH, &[], None, &body).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(batch, [
+            0x47, 0x4F, 0x4C, 0x44,
+            VERSION_COMPACT, FLAG_BATCH,
+            0x02, 0x09, 0x05,
+            0x62, 0xA3, 0x2C, 0xF8,
+            0x01, b'a', 0x02, b'b', b'c',
+        ]);
+        let (header, body) = decode_record(&batch).unwrap();
+        assert_eq!(split_batch(&header, body).unwrap(), [&b"a"[..], b"bc"]);
+    }
+
+    #[test]
+    fn compact_headers_round_trip_at_varint_boundaries() {
+        for offset in [0, 127, 128, 16_383, 16_384, u64::from(u32::MAX), u64::MAX] {
+            for payload_len in [0, 127, 128, u32::MAX] {
+                let header = RecordHeader::new(offset, payload_len, 0xDEAD_BEEF)
+                    .with_version(VERSION_COMPACT)
+                    .with_flags(FLAG_BATCH)
+                    .with_batch_count(u16::MAX);
+                let mut buf = [0u8; MAX_HEADER_LEN];
+                let encoded = encode_header(&header, &mut buf);
+                assert_eq!(encoded.len(), header.encoded_len());
+                assert_eq!(header_len_in(encoded), encoded.len());
+                // Each prefix asks for more bytes than it has.
+                for len in 0..encoded.len() {
+                    let needed = header_len_in(&encoded[..len]);
+                    assert!(needed > len && needed <= encoded.len(), "{len}: {needed}");
+                }
+                assert_eq!(decode_header(encoded).unwrap(), header);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_compact_headers_are_rejected() {
+        let header = RecordHeader::new(1, 2, 0).with_version(VERSION_COMPACT);
+        let mut buf = [0u8; MAX_HEADER_LEN];
+        let encoded = encode_header(&header, &mut buf).to_vec();
+
+        // Offset 1 padded to two bytes: not the shortest encoding.
+        let mut overlong = encoded.clone();
+        overlong.splice(7..8, [0x81, 0x00]);
+        assert!(matches!(
+            decode_header(&overlong),
+            Err(Error::Corruption(_))
+        ));
+
+        // A batch count that does not fit in a u16.
+        let mut too_big = encoded.clone();
+        too_big[5] = FLAG_BATCH;
+        too_big.splice(6..7, [0xFF, 0xFF, 0x7F]);
+        assert!(matches!(decode_header(&too_big), Err(Error::Corruption(_))));
+
+        // A varint that never ends within its field's width.
+        let mut endless = encoded[..6].to_vec();
+        endless.extend_from_slice(&[0xFF; 40]);
+        assert!(matches!(decode_header(&endless), Err(Error::Corruption(_))));
+
+        // A batch mark other than 0 or 1 on a plain frame.
+        let mut marked = encoded;
+        marked[6] = 2;
+        assert!(matches!(decode_header(&marked), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn compact_frames_are_smaller_than_v1() {
+        let payload = [0x5A; 20];
+        let v1 = encode_frame(RecordHeader::new(1 << 20, 0, 0), 0, &[], None, &payload).unwrap();
+        let template = RecordHeader::new(1 << 20, 0, 0).with_version(VERSION_COMPACT);
+        let compact = encode_frame(template, 0, &[], None, &payload).unwrap();
+        assert_eq!(v1.len(), HEADER_LEN + 20);
+        assert_eq!(compact.len(), MIN_HEADER_LEN + 2 + 20);
+
+        // In a batch, each extra record costs its one-byte length prefix.
+        let payloads = [&payload[..]; 100];
+        let v1 = encode_batch(0, &payloads).unwrap();
+        let (count, body) = pack_batch(VERSION_COMPACT, &payloads).unwrap();
+        let template = template.with_batch_count(count);
+        let compact = encode_frame(template, FLAG_BATCH, &[], None, &body).unwrap();
+        assert_eq!(v1.len(), HEADER_LEN + 100 * (BATCH_LEN_PREFIX + 20));
+        assert_eq!(compact.len(), MIN_HEADER_LEN + 3 + 100 * (1 + 20));
+    }
+
+    #[test]
+    fn errors_convert_to_the_crate_error() {
+        let err = crate::Error::from(decode_header(&[0u8; 4]).unwrap_err());
+        assert!(matches!(
+            err,
+            crate::Error::Truncated {
+                needed: MIN_HEADER_LEN,
+                available: 4
+            }
+        ));
+        assert!(err.is_corruption());
+    }
+}
@@ -0,0 +1,230 @@
+//! Per-record value compression (`lz4` and `zstd` features).
+//!
+//! When [`Config::compression`](crate::Config::compression) is set, the writer
+//! compresses values of at least [`Compression::min_bytes`] and marks the record
+//! with [`FLAG_LZ4`] or [`FLAG_ZSTD`]. Keys are never compressed. The checksum is
+//! computed over the stored (compressed) bytes, so corruption is detected before
+//! decompression. Readers decompress transparently.
+//!
+//! A codec whose feature is disabled fails with [`Error::InvalidFormat`], both when
+//! writing and when reading a record compressed with it.
+
+use crate::error::Error;
+use crate::record::{FLAG_LZ4, FLAG_ZSTD};
+use crate::Result;
+use std::borrow::Cow;
+
+/// Compression algorithm for record values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Codec {
+    /// LZ4 block format with the uncompressed size prepended (requires `lz4`).
+    Lz4,
+    /// Zstandard at the given level (requires `zstd`).
+    Zstd {
+        /// Compression level; `0` selects zstd's default.
+        level: i32,
+    },
+}
+
+impl Codec {
+    /// Returns the record flag bit marking values compressed with this codec.
+    #[must_use]
+    pub const fn flag(self) -> u8 {
+        match self {
+            Self::Lz4 => FLAG_LZ4,
+            Self::Zstd { .. } => FLAG_ZSTD,
+        }
+    }
+}
+
+/// Writer-side compression settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Compression {
+    /// Algorithm used for values that qualify.
+    pub codec: Codec,
+    /// Values shorter than this are stored uncompressed.
+    pub min_bytes: usize,
+}
+
+impl Compression {
+    /// Compresses `value` if it qualifies and actually shrinks, returning the record
+    /// flag to set (or 0) and the bytes to store.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidFormat`] if the codec's feature is not enabled.
+    pub fn apply<'a>(&self, value: &'a [u8]) -> Result<(u8, Cow<'a, [u8]>)> {
+        if value.len() < self.min_bytes {
+            return Ok((0, Cow::Borrowed(value)));
+        }
+        let compressed = compress(self.codec, value)?;
+        if compressed.len() >= value.len() {
+            return Ok((0, Cow::Borrowed(value)));
+        }
+        Ok((self.codec.flag(), Cow::Owned(compressed)))
+    }
+}
+
+/// Compresses `value` with `codec`.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidFormat`] if the codec's feature is not enabled, or I/O
+/// errors from the zstd encoder.
+pub fn compress(codec: Codec, value: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        Codec::Lz4 => lz4::compress(value),
+        Codec::Zstd { level } => zstd::compress(value, level),
+    }
+}
+
+/// Undoes the compression indicated by `flags`, borrowing `stored` when the record
+/// is not compressed.
+///
+/// # Errors
+///
+/// - [`Error::Corruption`] if both compression bits are set or the data does not
+///   decompress.
+/// - [`Error::InvalidFormat`] if the codec's feature is not enabled.
+pub fn decompress(flags: u8, stored: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match (flags & FLAG_LZ4 != 0, flags & FLAG_ZSTD != 0) {
+        (false, false) => Ok(Cow::Borrowed(stored)),
+        (true, false) => lz4::decompress(stored).map(Cow::Owned),
+        (false, true) => zstd::decompress(stored).map(Cow::Owned),
+        (true, true) => Err(Error::Corruption(
+            "record flags select both lz4 and zstd".into(),
+        )),
+    }
+}
+
+#[cfg(feature = "lz4")]
+mod lz4 {
+    use crate::error::Error;
+    use crate::Result;
+
+    // Fallible to match the signature of the stub used when `lz4` is disabled.
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn compress(value: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(value))
+    }
+
+    pub fn decompress(stored: &[u8]) -> Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(stored)
+            .map_err(|e| Error::Corruption(format!("lz4 decompression failed: {e}")))
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+mod lz4 {
+    use crate::error::Error;
+    use crate::Result;
+
+    fn disabled() -> Error {
+        Error::InvalidFormat("lz4 compression requires the `lz4` feature".into())
+    }
+
+    pub fn compress(_value: &[u8]) -> Result<Vec<u8>> {
+        Err(disabled())
+    }
+
+    pub fn decompress(_stored: &[u8]) -> Result<Vec<u8>> {
+        Err(disabled())
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use crate::error::Error;
+    use crate::Result;
+
+    pub fn compress(value: &[u8], level: i32) -> Result<Vec<u8>> {
+        Ok(::zstd::bulk::compress(value, level)?)
+    }
+
+    pub fn decompress(stored: &[u8]) -> Result<Vec<u8>> {
+        ::zstd::stream::decode_all(stored)
+            .map_err(|e| Error::Corruption(format!("zstd decompression failed: {e}")))
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+mod zstd {
+    use crate::error::Error;
+    use crate::Result;
+
+    fn disabled() -> Error {
+        Error::InvalidFormat("zstd compression requires the `zstd` feature".into())
+    }
+
+    pub fn compress(_value: &[u8], _level: i32) -> Result<Vec<u8>> {
+        Err(disabled())
+    }
+
+    pub fn decompress(_stored: &[u8]) -> Result<Vec<u8>> {
+        Err(disabled())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed_values_are_borrowed() {
+        let value = b"plain";
+        assert!(matches!(decompress(0, value).unwrap(), Cow::Borrowed(_)));
+        assert!(decompress(FLAG_LZ4 | FLAG_ZSTD, value).is_err());
+    }
+
+    #[test]
+    fn small_values_skip_compression() {
+        let compression = Compression {
+            codec: Codec::Lz4,
+            min_bytes: 64,
+        };
+        let (flag, stored) = compression.apply(b"short").unwrap();
+        assert_eq!(flag, 0);
+        assert_eq!(&*stored, b"short");
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_roundtrip() {
+        let value = b"abcabcabc".repeat(100);
+        let compression = Compression {
+            codec: Codec::Lz4,
+            min_bytes: 0,
+        };
+        let (flag, stored) = compression.apply(&value).unwrap();
+        assert_eq!(flag, FLAG_LZ4);
+        assert!(stored.len() < value.len());
+        assert_eq!(decompress(flag, &stored).unwrap(), &value[..]);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_roundtrip() {
+        let value = b"{\"event\":\"login\"}".repeat(50);
+        let compression = Compression {
+            codec: Codec::Zstd { level: 3 },
+            min_bytes: 0,
+        };
+        let (flag, stored) = compression.apply(&value).unwrap();
+        assert_eq!(flag, FLAG_ZSTD);
+        assert_eq!(decompress(flag, &stored).unwrap(), &value[..]);
+        assert!(decompress(flag, b"not zstd").is_err());
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn disabled_codec_errors() {
+        let compression = Compression {
+            codec: Codec::Lz4,
+            min_bytes: 0,
+        };
+        assert!(compression.apply(b"value").is_err());
+        assert!(decompress(FLAG_LZ4, b"value").is_err());
+    }
+}
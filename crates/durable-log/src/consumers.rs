@@ -0,0 +1,215 @@
+//! Named consumer positions stored alongside a log.
+//!
+//! [`ConsumerOffsets`] keeps, for each named consumer, the offset of the next
+//! record it should read, in a `consumers.offsets` file in the log directory.
+//! Every [`commit`](ConsumerOffsets::commit) rewrites the file atomically (write
+//! temp, fsync, rename) while holding `consumers.lock`, so consumers in several
+//! processes can share one store without losing each other's updates.
+//!
+//! The file holds a count of entries (u32), then for each the name length (u16),
+//! UTF-8 name and offset (u64), followed by a CRC-32 of everything before it, all
+//! little-endian.
+
+use crate::error::Error;
+use crate::storage;
+use crate::Result;
+use fs2::FileExt;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the file holding consumer positions.
+const OFFSETS_FILE_NAME: &str = "consumers.offsets";
+
+/// Name of the lock file serialising updates to the positions file.
+const LOCK_FILE_NAME: &str = "consumers.lock";
+
+/// Persistent positions of named consumers of a log; see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct ConsumerOffsets {
+    dir: PathBuf,
+    /// Positions as of the last load or commit.
+    positions: BTreeMap<String, u64>,
+}
+
+impl ConsumerOffsets {
+    /// Opens the consumer positions of the log in `dir`, which must exist.
+    ///
+    /// # Errors
+    ///
+    /// - I/O errors from reading the positions file.
+    /// - [`Error::Corruption`] if the file is malformed or fails its checksum.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let positions = read_positions(&dir)?;
+        Ok(Self { dir, positions })
+    }
+
+    /// Returns the offset `consumer` should read next, or `None` if it has never
+    /// committed a position.
+    #[must_use]
+    pub fn position(&self, consumer: &str) -> Option<u64> {
+        self.positions.get(consumer).copied()
+    }
+
+    /// Iterates over every consumer and its position, ordered by name.
+    pub fn consumers(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.positions
+            .iter()
+            .map(|(name, &offset)| (name.as_str(), offset))
+    }
+
+    /// Durably records `offset` as the next offset `consumer` should read.
+    ///
+    /// Positions committed by other handles since this one was loaded are picked
+    /// up and kept.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `consumer` is empty or longer than
+    ///   `u16::MAX` bytes.
+    /// - I/O errors from locking, reading or writing the positions file, and
+    ///   [`Error::Corruption`] if it is damaged.
+    pub fn commit(&mut self, consumer: &str, offset: u64) -> Result<()> {
+        if consumer.is_empty() || u16::try_from(consumer.len()).is_err() {
+            return Err(Error::InvalidFormat(format!(
+                "consumer names must be 1 to {} bytes long",
+                u16::MAX
+            )));
+        }
+        self.update(|positions| {
+            positions.insert(consumer.to_owned(), offset);
+        })
+    }
+
+    /// Forgets `consumer`, returning true if it had a position.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`commit`](Self::commit).
+    pub fn remove(&mut self, consumer: &str) -> Result<bool> {
+        let mut removed = false;
+        self.update(|positions| removed = positions.remove(consumer).is_some())?;
+        Ok(removed)
+    }
+
+    /// Reloads positions committed by other handles.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`open`](Self::open).
+    pub fn refresh(&mut self) -> Result<()> {
+        self.positions = read_positions(&self.dir)?;
+        Ok(())
+    }
+
+    /// Applies `change` to the latest positions on disk and writes them back,
+    /// holding the lock file throughout.
+    fn update(&mut self, change: impl FnOnce(&mut BTreeMap<String, u64>)) -> Result<()> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(LOCK_FILE_NAME))?;
+        lock.lock_exclusive()?;
+        let mut positions = read_positions(&self.dir)?;
+        change(&mut positions);
+        write_positions(&self.dir, &positions)?;
+        self.positions = positions;
+        // Dropping the file releases the lock.
+        Ok(())
+    }
+}
+
+fn read_positions(dir: &Path) -> Result<BTreeMap<String, u64>> {
+    let bytes = match fs::read(dir.join(OFFSETS_FILE_NAME)) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let malformed = || Error::Corruption(format!("{OFFSETS_FILE_NAME} is malformed"));
+    if bytes.len() < 4 {
+        return Err(malformed());
+    }
+    let (data, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(data).to_le_bytes() != crc {
+        return Err(Error::Corruption(format!(
+            "{OFFSETS_FILE_NAME} checksum mismatch"
+        )));
+    }
+    let mut rest = data;
+    let mut take = |n: usize| -> Result<&[u8]> {
+        if rest.len() < n {
+            return Err(malformed());
+        }
+        let (head, tail) = rest.split_at(n);
+        rest = tail;
+        Ok(head)
+    };
+    let count = u32::from_le_bytes(take(4)?.try_into().expect("4 bytes"));
+    let mut positions = BTreeMap::new();
+    for _ in 0..count {
+        let name_len = u16::from_le_bytes(take(2)?.try_into().expect("2 bytes"));
+        let name = std::str::from_utf8(take(usize::from(name_len))?).map_err(|_| malformed())?;
+        let offset = u64::from_le_bytes(take(8)?.try_into().expect("8 bytes"));
+        positions.insert(name.to_owned(), offset);
+    }
+    if !rest.is_empty() {
+        return Err(malformed());
+    }
+    Ok(positions)
+}
+
+fn write_positions(dir: &Path, positions: &BTreeMap<String, u64>) -> Result<()> {
+    let count = u32::try_from(positions.len())
+        .map_err(|_| Error::InvalidFormat("too many consumers".into()))?;
+    let mut contents = count.to_le_bytes().to_vec();
+    for (name, offset) in positions {
+        let name_len = u16::try_from(name.len()).expect("checked on commit");
+        contents.extend_from_slice(&name_len.to_le_bytes());
+        contents.extend_from_slice(name.as_bytes());
+        contents.extend_from_slice(&offset.to_le_bytes());
+    }
+    contents.extend_from_slice(&crc32fast::hash(&contents).to_le_bytes());
+    let tmp_path = dir.join(format!("{OFFSETS_FILE_NAME}.tmp"));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&contents)?;
+    tmp.sync_all()?;
+    drop(tmp);
+    fs::rename(tmp_path, dir.join(OFFSETS_FILE_NAME))?;
+    storage::sync_dir(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_survive_reopen_and_merge_across_handles() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = ConsumerOffsets::open(dir.path()).unwrap();
+        let mut second = ConsumerOffsets::open(dir.path()).unwrap();
+        assert_eq!(first.position("indexer"), None);
+
+        first.commit("indexer", 10).unwrap();
+        second.commit("shipper", 3).unwrap();
+        first.commit("indexer", 12).unwrap();
+        assert!(second.remove("nobody").is_ok_and(|removed| !removed));
+
+        let reopened = ConsumerOffsets::open(dir.path()).unwrap();
+        let all: Vec<(&str, u64)> = reopened.consumers().collect();
+        assert_eq!(all, [("indexer", 12), ("shipper", 3)]);
+        assert!(first.commit("", 1).is_err());
+
+        let path = dir.path().join(OFFSETS_FILE_NAME);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[6] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            ConsumerOffsets::open(dir.path()),
+            Err(Error::Corruption(_))
+        ));
+    }
+}
@@ -0,0 +1,386 @@
+//! Direct I/O for segment writes (`direct-io` feature).
+//!
+//! [`DirectBackend`] is the local filesystem, like [`FsBackend`], except that
+//! segment `.log` files opened for writing bypass the page cache: `O_DIRECT` on
+//! Linux, Android and FreeBSD, `FILE_FLAG_NO_BUFFERING` on Windows. Appended
+//! records then stop evicting the application's working set from memory, at the
+//! cost of a disk write per append with no write-behind, so it pays to append in
+//! batches. Indexes, metadata files and all reads still use the page cache.
+//! Other platforms fail to open segment files for writing with
+//! [`ErrorKind::Unsupported`](io::ErrorKind::Unsupported), and so do filesystems without direct I/O, with
+//! an error of their choosing.
+//!
+//! A direct write must cover whole blocks, at an aligned position, from an
+//! aligned buffer. Each file keeps such a buffer: a write is copied into it after
+//! the bytes that precede it in its first block, padded with the bytes that
+//! follow it in its last block, written, and the file truncated back to its
+//! length if the padding extended it. The last block written is kept, so that
+//! the next append need not read it back. A crash between the write and the
+//! truncation leaves zeros past the last record, which recovery drops like the
+//! space [`Config::preallocate`](crate::Config::preallocate) reserves.
+
+use crate::storage::{Advice, Backend, FsBackend, OpenMode, StorageFile};
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Alignment of direct writes: of their file position, length and buffer. 4 KiB
+/// is a multiple of the logical block size of common disks.
+const BLOCK: usize = 4096;
+
+/// Capacity of each file's aligned buffer; larger writes go out in pieces.
+const BUFFER_LEN: usize = 64 * BLOCK;
+
+/// The local filesystem, writing segment files with direct I/O; see the
+/// [module docs](self).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectBackend;
+
+impl Backend for DirectBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        if mode == OpenMode::Read || path.extension().map_or(true, |ext| ext != "log") {
+            return FsBackend.open(path, mode);
+        }
+        Ok(Box::new(DirectFile::open(path, mode)?))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        FsBackend.list(dir)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        FsBackend.rename(from, to)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        FsBackend.delete(path)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        FsBackend.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        FsBackend.sync_dir(dir)
+    }
+
+    fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+        FsBackend.lock(dir)
+    }
+}
+
+/// A segment file of [`DirectBackend`]: written through `direct`, and read,
+/// resized and synced through the buffered `file`.
+#[derive(Debug)]
+struct DirectFile {
+    file: Box<dyn StorageFile>,
+    direct: File,
+    buf: AlignedBuf,
+    size: u64,
+    /// Every byte from this position on is zero.
+    zeros_from: u64,
+    /// Position and contents of the last block written.
+    last_block: Option<(u64, Vec<u8>)>,
+}
+
+impl DirectFile {
+    fn open(path: &Path, mode: OpenMode) -> io::Result<Self> {
+        let file = FsBackend.open(path, mode)?;
+        let direct = open_direct(path)?;
+        let size = file.size()?;
+        Ok(Self {
+            file,
+            direct,
+            buf: AlignedBuf::new(),
+            size,
+            zeros_from: size,
+            last_block: None,
+        })
+    }
+
+    /// Writes `data` at `pos` in whole blocks; see the [module docs](self).
+    fn write_blocks(&mut self, mut data: &[u8], pos: u64) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = pos + data.len() as u64;
+        let mut block_pos = pos - pos % BLOCK as u64;
+        self.buf.clear();
+        self.fill(block_pos, pos)?;
+        loop {
+            let n = data.len().min(BUFFER_LEN - self.buf.len);
+            self.buf.extend(&data[..n]);
+            data = &data[n..];
+            if data.is_empty() {
+                break;
+            }
+            write_all_at(&self.direct, self.buf.as_slice(), block_pos)?;
+            block_pos += BUFFER_LEN as u64;
+            self.buf.clear();
+        }
+        let padded_end = block_pos + self.buf.len.next_multiple_of(BLOCK) as u64;
+        self.fill(end, padded_end)?;
+        write_all_at(&self.direct, self.buf.as_slice(), block_pos)?;
+
+        let last = self.buf.as_slice()[self.buf.len - BLOCK..].to_vec();
+        self.last_block = Some((padded_end - BLOCK as u64, last));
+        let size = self.size.max(end);
+        if padded_end > size {
+            self.file.set_len(size)?;
+        }
+        self.size = size;
+        self.zeros_from = self.zeros_from.max(end);
+        Ok(())
+    }
+
+    /// Appends the file's bytes from `from` to `to`, all within one block, to the
+    /// buffer.
+    fn fill(&mut self, from: u64, to: u64) -> io::Result<()> {
+        if from >= self.zeros_from {
+            self.buf.extend_zeros(in_block(to - from));
+            return Ok(());
+        }
+        let start = self.buf.len;
+        self.buf.extend_zeros(in_block(to - from));
+        let dest = &mut self.buf.as_mut_slice()[start..];
+        match &self.last_block {
+            Some((block_pos, block)) if *block_pos <= from && to <= block_pos + BLOCK as u64 => {
+                let at = in_block(from - block_pos);
+                dest.copy_from_slice(&block[at..at + dest.len()]);
+            }
+            // Bytes past the end of the file stay zero.
+            _ => {
+                let mut read = 0;
+                while read < dest.len() {
+                    match self.file.read_at(&mut dest[read..], from + read as u64)? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageFile for DirectFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_blocks(buf, self.size)
+    }
+
+    fn append_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        let bufs: Vec<&[u8]> = bufs.iter().map(|buf| &**buf).collect();
+        self.append(&bufs.concat())
+    }
+
+    fn write_at(&mut self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.write_blocks(buf, pos)
+    }
+
+    fn allocate(&mut self, len: u64) -> io::Result<()> {
+        self.file.allocate(len)?;
+        self.size = self.file.size()?;
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.file.read_at(buf, pos)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.size = len;
+        self.zeros_from = self.zeros_from.min(len);
+        if let Some((block_pos, block)) = &mut self.last_block {
+            if len <= *block_pos {
+                self.last_block = None;
+            } else if let Some(cut) = block.get_mut(in_block(len - *block_pos)..) {
+                cut.fill(0);
+            }
+        }
+        Ok(())
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        self.file.fsync()
+    }
+
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        self.file.modified()
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> io::Result<()> {
+        // Reads go through the buffered file.
+        self.file.advise(offset, len, advice)
+    }
+}
+
+/// Converts `n`, a length within one block, to `usize`.
+fn in_block(n: u64) -> usize {
+    usize::try_from(n).unwrap_or(BLOCK)
+}
+
+/// A fixed-capacity buffer whose first byte is `BLOCK`-aligned in memory.
+struct AlignedBuf {
+    bytes: Vec<u8>,
+    /// Index in `bytes` of the first aligned byte.
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new() -> Self {
+        let bytes = vec![0; BUFFER_LEN + BLOCK];
+        // Any byte pointer can be aligned by moving it at most `BLOCK - 1` bytes.
+        let start = bytes.as_ptr().align_offset(BLOCK);
+        Self {
+            bytes,
+            start,
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        let at = self.start + self.len;
+        self.bytes[at..at + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    fn extend_zeros(&mut self, n: usize) {
+        let at = self.start + self.len;
+        self.bytes[at..at + n].fill(0);
+        self.len += n;
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[self.start..self.start + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.start..self.start + self.len]
+    }
+}
+
+impl Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(windows)]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(FILE_FLAG_NO_BUFFERING)
+        .open(path)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    windows
+)))]
+fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut pos: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                pos += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "linux", target_os = "android", target_os = "freebsd")
+))]
+mod tests {
+    use super::*;
+    use crate::{Config, Log, LogReader};
+    use std::sync::Arc;
+
+    #[test]
+    fn log_written_directly_reads_back_through_the_page_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 1 << 20,
+            storage: Arc::new(DirectBackend),
+            ..Config::default()
+        };
+        let payloads: Vec<Vec<u8>> = [1, 5000, 3, BUFFER_LEN + 7, 4096, 0, 100]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| vec![u8::try_from(i).unwrap(); len])
+            .collect();
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        for payload in &payloads[..4] {
+            log.append(payload).unwrap();
+        }
+        log.truncate_after(2).unwrap();
+        for payload in &payloads[4..] {
+            log.append(payload).unwrap();
+        }
+        log.flush().unwrap();
+
+        let expected: Vec<_> = payloads[..3].iter().chain(&payloads[4..]).collect();
+        let records: Vec<_> = LogReader::open(dir.path())
+            .unwrap()
+            .iter()
+            .map(|r| r.unwrap().payload)
+            .collect();
+        assert_eq!(records.iter().collect::<Vec<_>>(), expected);
+        assert!(log.verify().unwrap().is_ok());
+        drop(log);
+
+        let config = Config {
+            preallocate: true,
+            ..config
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.append(b"preallocated").unwrap(), 6);
+        assert_eq!(log.read(6).unwrap(), b"preallocated");
+        assert_eq!(log.read(5).unwrap(), payloads[6]);
+    }
+}
@@ -1,5 +1,8 @@
 //! Error types for durable-log.
 
+use crate::codec;
+use crate::reader::CorruptRange;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// Errors that can occur when using durable-log.
@@ -9,7 +12,8 @@ pub enum Error {
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 
-    /// Invalid or unsupported record format (e.g. wrong magic or version).
+    /// An invalid request or unsupported option: an oversized payload, a feature
+    /// that is not enabled, a missing key, or an offset that cannot be addressed.
     #[error("invalid format: {0}")]
     InvalidFormat(String),
 
@@ -17,7 +21,156 @@ pub enum Error {
     #[error("log directory is locked: {0}")]
     Locked(String),
 
-    /// Checksum mismatch or invalid file structure.
+    /// Invalid file structure or otherwise inconsistent data.
     #[error("data corruption: {0}")]
     Corruption(String),
+
+    /// A record body does not match the checksum stored in its header.
+    #[error("data corruption: checksum mismatch at offset {offset}: expected 0x{expected:08X}, got 0x{actual:08X}")]
+    ChecksumMismatch {
+        /// Offset of the corrupt record.
+        offset: u64,
+        /// Checksum stored in the record header.
+        expected: u32,
+        /// Checksum computed over the body as read.
+        actual: u32,
+    },
+
+    /// The input ended before a complete header or record.
+    #[error("data corruption: truncated input: need {needed} bytes, have {available}")]
+    Truncated {
+        /// Bytes needed to decode the header or record.
+        needed: usize,
+        /// Bytes available.
+        available: usize,
+    },
+
+    /// A frame does not start with the record magic number.
+    #[error("data corruption: invalid magic 0x{0:08X}")]
+    BadMagic(u32),
+
+    /// A frame carries a record format version this build cannot read.
+    #[error("unsupported record version {0}")]
+    UnsupportedVersion(u8),
+
+    /// The requested offset is outside the range of records held by the log.
+    #[error("offset {requested} out of range (earliest {earliest}, latest {latest:?})")]
+    OffsetOutOfRange {
+        /// Offset that was asked for.
+        requested: u64,
+        /// Oldest offset still readable.
+        earliest: u64,
+        /// Newest offset written, or `None` if the log holds no records.
+        latest: Option<u64>,
+    },
+
+    /// A replicated record's offset is past the next offset of the log, so
+    /// records in between are missing.
+    #[error("offset gap: expected offset {expected}, got {found}")]
+    OffsetGap {
+        /// The log's next offset.
+        expected: u64,
+        /// Offset of the rejected record.
+        found: u64,
+    },
+
+    /// A replicated record's offset is below the next offset of the log, which
+    /// already holds a record there.
+    #[error("offset regression: expected offset {expected}, got {found}")]
+    OffsetRegression {
+        /// The log's next offset.
+        expected: u64,
+        /// Offset of the rejected record.
+        found: u64,
+    },
+
+    /// An idempotent append repeats a sequence number older than its producer's
+    /// last one.
+    #[error("duplicate sequence: producer {producer} sent {sequence}, last appended {last}")]
+    DuplicateSequence {
+        /// ID of the producer.
+        producer: u64,
+        /// Sequence number of the rejected append.
+        sequence: u64,
+        /// The producer's last appended sequence number.
+        last: u64,
+    },
+
+    /// An idempotent append skips sequence numbers after its producer's last one.
+    #[error("sequence gap: producer {producer} expected {expected}, got {found}")]
+    SequenceGap {
+        /// ID of the producer.
+        producer: u64,
+        /// The sequence number that should come next.
+        expected: u64,
+        /// Sequence number of the rejected append.
+        found: u64,
+    },
+
+    /// A damaged range of a segment was skipped by a reader in
+    /// [`OnCorruption::Skip`](crate::OnCorruption::Skip) mode; reading continues after it.
+    #[error("data corruption: skipped bytes {}..{} of segment {}", .0.start, .0.end, .0.segment)]
+    Skipped(CorruptRange),
+
+    /// An append would take the log past its [`DiskQuota`](crate::DiskQuota).
+    #[error(
+        "disk quota exceeded: {requested} more bytes on top of {used} exceed the limit of {limit}"
+    )]
+    QuotaExceeded {
+        /// Bytes the rejected append needed.
+        requested: u64,
+        /// Bytes the log's segment and index files take.
+        used: u64,
+        /// The quota's `max_bytes`.
+        limit: u64,
+    },
+
+    /// The log was closed while an operation was still waiting on it.
+    #[error("log closed: {0}")]
+    Closed(String),
+
+    /// A replication peer broke the protocol or reported an error.
+    #[error("replication error: {0}")]
+    Replication(String),
+
+    /// One failure reported to every operation it affected, such as the appends
+    /// of a group-committed batch whose flush failed.
+    #[error(transparent)]
+    Shared(Arc<Self>),
+}
+
+impl Error {
+    /// Returns true if the error reports stored data that is damaged or cannot be
+    /// decoded, as opposed to an I/O failure or a misuse of the API.
+    ///
+    /// Such errors are confined to the frames concerned: recovery truncates them
+    /// from the tail of the active segment, and a reader in
+    /// [`OnCorruption::Skip`](crate::OnCorruption::Skip) mode skips past them.
+    #[must_use]
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            Self::Shared(err) => err.is_corruption(),
+            _ => matches!(
+                self,
+                Self::Corruption(_)
+                    | Self::ChecksumMismatch { .. }
+                    | Self::Truncated { .. }
+                    | Self::BadMagic(_)
+                    | Self::UnsupportedVersion(_)
+                    | Self::Skipped(_)
+            ),
+        }
+    }
+}
+
+impl From<codec::Error> for Error {
+    fn from(err: codec::Error) -> Self {
+        match err {
+            codec::Error::InvalidFormat(msg) => Self::InvalidFormat(msg),
+            codec::Error::Corruption(msg) => Self::Corruption(msg),
+            codec::Error::Truncated { needed, available } => Self::Truncated { needed, available },
+            codec::Error::BadMagic(magic) => Self::BadMagic(magic),
+            codec::Error::UnsupportedVersion(version) => Self::UnsupportedVersion(version),
+        }
+    }
 }
@@ -0,0 +1,264 @@
+//! Cross-process tailing (`follow` feature).
+//!
+//! A [`FollowReader`] follows a log written by another process. It reads the
+//! records already present, then watches the log directory through the
+//! platform's file notification API (inotify, `FSEvents` or
+//! `ReadDirectoryChangesW`, via the [`notify`](https://docs.rs/notify) crate) and
+//! resumes decoding from the next offset whenever segment files change. Within
+//! one process, prefer [`Log::tail`](crate::Log::tail), which the writer wakes
+//! directly.
+//!
+//! Without a writer to coordinate with, a frame that fails to decode at the end
+//! of the newest, unsealed segment is taken to be still in the middle of being
+//! written: the reader waits for the next change and tries again. Damage
+//! anywhere else is reported as usual.
+
+use crate::error::Error;
+use crate::reader::{LogReader, Record, Records};
+use crate::Result;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Default for [`FollowReader::with_poll_interval`].
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+impl LogReader {
+    /// Follows the log from `offset`, waiting for another process to append
+    /// more once the existing records have been read; see the
+    /// [module docs](crate::follow).
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from starting to watch the log directory.
+    pub fn follow(self, offset: u64) -> Result<FollowReader> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).map_err(watch_error)?;
+        watcher
+            .watch(self.path(), RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+        Ok(FollowReader {
+            reader: self,
+            records: None,
+            next: offset,
+            events,
+            _watcher: watcher,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            done: false,
+        })
+    }
+}
+
+/// Blocking iterator over the records of a log written by another process,
+/// created by [`LogReader::follow`]. At the end of the log it waits for more
+/// instead of finishing.
+///
+/// Iteration ends only after an error.
+#[derive(Debug)]
+pub struct FollowReader {
+    reader: LogReader,
+    /// Records being read; dropped whenever the reader catches up.
+    records: Option<Records>,
+    /// Offset of the next record to yield.
+    next: u64,
+    /// Notifications of changes in the log directory.
+    events: Receiver<notify::Result<notify::Event>>,
+    /// Kept alive for as long as notifications are wanted.
+    _watcher: RecommendedWatcher,
+    poll_interval: Duration,
+    done: bool,
+}
+
+impl FollowReader {
+    /// Sets how long the reader waits for a notification before checking the
+    /// log anyway (default: one second), covering filesystems that do not
+    /// report changes made by other machines.
+    #[must_use]
+    pub const fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Returns the offset of the next record the reader will yield, unless it
+    /// is below the log start.
+    #[must_use]
+    pub const fn next_offset(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns the next record, waiting at most `timeout` for one to be appended.
+    ///
+    /// Returns `Ok(None)` if none arrived in time.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Closed`] if the reader already ended with an error.
+    /// - Errors from reading the log, as for [`LogReader::iter`], and from the
+    ///   directory watch.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Record>> {
+        self.advance(Some(Instant::now() + timeout))
+    }
+
+    fn advance(&mut self, deadline: Option<Instant>) -> Result<Option<Record>> {
+        if self.done {
+            return Err(Error::Closed("follow reader ended".into()));
+        }
+        let result = self.read_next(deadline);
+        self.done = result.is_err();
+        result
+    }
+
+    fn read_next(&mut self, deadline: Option<Instant>) -> Result<Option<Record>> {
+        loop {
+            if let Some(record) = self.try_read()? {
+                return Ok(Some(record));
+            }
+            let wait = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(None);
+                    }
+                    left.min(self.poll_interval)
+                }
+                None => self.poll_interval,
+            };
+            self.wait_for_change(wait)?;
+        }
+    }
+
+    /// Returns the next record, or `None` if the reader has caught up with the
+    /// writer (including a frame that is still being written).
+    fn try_read(&mut self) -> Result<Option<Record>> {
+        if self.records.is_none() {
+            // Pick up segments rolled, sealed or deleted since the last read.
+            self.reader.refresh()?;
+            self.records = Some(self.reader.iter_from(self.next));
+        }
+        let Some(records) = &mut self.records else {
+            return Ok(None);
+        };
+        match records.next() {
+            Some(Ok(record)) => {
+                self.next = record.offset + 1;
+                Ok(Some(record))
+            }
+            Some(Err(e)) if e.is_corruption() && self.at_unsealed_tail() => {
+                self.records = None;
+                Ok(None)
+            }
+            Some(Err(e)) => Err(e),
+            None => {
+                self.records = None;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns true if the next record falls in the newest segment and that
+    /// segment had no footer when the segments were last listed.
+    fn at_unsealed_tail(&self) -> bool {
+        self.reader
+            .segments()
+            .last()
+            .is_some_and(|last| last.footer.is_none() && last.base_offset <= self.next)
+    }
+
+    /// Waits up to `timeout` for a change in the log directory, then drains any
+    /// further notifications already queued.
+    fn wait_for_change(&self, timeout: Duration) -> Result<()> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => {
+                event.map_err(watch_error)?;
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(()),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::Closed("directory watch stopped".into()))
+            }
+        }
+        while let Ok(event) = self.events.try_recv() {
+            event.map_err(watch_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for FollowReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        self.advance(None).transpose()
+    }
+}
+
+fn watch_error(e: notify::Error) -> Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => Error::Io(e),
+        _ => Error::Io(std::io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log};
+
+    #[test]
+    fn follows_appends_and_rolls_by_another_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"zero").unwrap();
+
+        let mut follow = LogReader::open(dir.path()).unwrap().follow(0).unwrap();
+        let timeout = Duration::from_secs(5);
+        let record = follow.next_timeout(timeout).unwrap().unwrap();
+        assert_eq!(record.payload, b"zero");
+        assert!(follow
+            .next_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        // Enough to roll the active segment, sealing it with a footer.
+        for i in 1..6u8 {
+            log.append(&[i; 20]).unwrap();
+        }
+        for i in 1..6u8 {
+            let record = follow.next_timeout(timeout).unwrap().unwrap();
+            assert_eq!((record.offset, record.payload), (u64::from(i), vec![i; 20]));
+        }
+        assert_eq!(follow.next_offset(), 6);
+    }
+
+    #[test]
+    fn waits_out_a_partly_written_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"whole").unwrap();
+        drop(log);
+        let segment = crate::discover_segments(dir.path()).unwrap().remove(0);
+        let frame = crate::encode_record(1, b"late").unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&segment.log_path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, &frame[..10]).unwrap();
+
+        let mut follow = LogReader::open(dir.path()).unwrap().follow(0).unwrap();
+        let timeout = Duration::from_millis(50);
+        assert_eq!(follow.next_timeout(timeout).unwrap().unwrap().offset, 0);
+        assert!(follow.next_timeout(timeout).unwrap().is_none());
+        std::io::Write::write_all(&mut file, &frame[10..]).unwrap();
+        let record = follow
+            .next_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.payload, b"late");
+    }
+}
@@ -0,0 +1,244 @@
+//! Group commit: concurrent appenders share a single fsync per batch.
+//!
+//! Callers stage their payloads in a shared buffer. Whichever caller finds no
+//! commit in progress becomes the *leader*: it takes every staged payload, writes
+//! them to the log, and issues one [`Log::flush`] for the whole batch. Each caller
+//! returns only once the batch containing its record is durable.
+
+use crate::error::Error;
+use crate::log::{Config, Log};
+use crate::Result;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// A [`Log`] shared between threads, where concurrent appends are made durable
+/// together by a single fsync.
+///
+/// `GroupCommitLog` is `Send + Sync`; share it with `Arc` and call
+/// [`append`](Self::append) from any number of threads.
+#[derive(Debug)]
+pub struct GroupCommitLog {
+    /// The underlying log; held by the leader while writing and flushing a batch.
+    log: Mutex<Log>,
+    /// Staging buffer and per-ticket results.
+    state: Mutex<State>,
+    /// Signalled whenever a batch finishes committing.
+    committed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Payloads waiting for the next leader, in ticket order.
+    staged: Vec<Vec<u8>>,
+    /// Ticket of `staged[0]`.
+    staged_base: u64,
+    /// True while a leader is writing and flushing a batch.
+    leader_active: bool,
+    /// Outcomes of committed records, keyed by ticket, until their caller collects them.
+    results: HashMap<u64, Result<u64>>,
+    /// Number of batches committed (one fsync each).
+    batches: u64,
+}
+
+impl GroupCommitLog {
+    /// Wraps an open log for group commit.
+    #[must_use]
+    pub fn new(log: Log) -> Self {
+        Self {
+            log: Mutex::new(log),
+            state: Mutex::new(State::default()),
+            committed: Condvar::new(),
+        }
+    }
+
+    /// Opens the log at `path` and wraps it for group commit.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::open`].
+    pub fn open(path: impl AsRef<Path>, config: Config) -> Result<Self> {
+        Log::open(path, config).map(Self::new)
+    }
+
+    /// Appends a payload and blocks until it is durable on disk.
+    ///
+    /// Appends that arrive while another batch is being flushed are staged and
+    /// committed together by the next leader with a single fsync.
+    ///
+    /// # Errors
+    ///
+    /// - Errors from [`Log::append`] for this payload.
+    /// - [`Error::Shared`] holding the error from the batch fsync, which every
+    ///   record in the batch reports.
+    pub fn append(&self, payload: &[u8]) -> Result<u64> {
+        let ticket = self.stage(payload);
+        loop {
+            let mut state = self
+                .committed
+                .wait_while(self.lock_state(), |s| {
+                    s.leader_active && !s.results.contains_key(&ticket)
+                })
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
+            }
+
+            // Become the leader for everything staged so far (including our own record).
+            state.leader_active = true;
+            let batch = std::mem::take(&mut state.staged);
+            let first_ticket = state.staged_base;
+            state.staged_base += batch.len() as u64;
+            drop(state);
+
+            let outcomes = self.commit_batch(&batch);
+            self.publish(first_ticket, outcomes);
+        }
+    }
+
+    /// Reads a record at the given offset.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::read`].
+    pub fn read(&self, offset: u64) -> Result<Vec<u8>> {
+        self.lock_log().read(offset)
+    }
+
+    /// Returns the number of batches committed so far (one fsync per batch).
+    #[must_use]
+    pub fn batches_committed(&self) -> u64 {
+        self.lock_state().batches
+    }
+
+    /// Unwraps the underlying log.
+    #[must_use]
+    pub fn into_inner(self) -> Log {
+        self.log
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds `payload` to the staging buffer and returns its ticket.
+    fn stage(&self, payload: &[u8]) -> u64 {
+        let mut state = self.lock_state();
+        let ticket = state.staged_base + state.staged.len() as u64;
+        state.staged.push(payload.to_vec());
+        ticket
+    }
+
+    /// Records the outcomes of a committed batch and wakes waiting callers.
+    fn publish(&self, first_ticket: u64, outcomes: Vec<Result<u64>>) {
+        let mut state = self.lock_state();
+        for (ticket, outcome) in (first_ticket..).zip(outcomes) {
+            state.results.insert(ticket, outcome);
+        }
+        state.batches += 1;
+        state.leader_active = false;
+        drop(state);
+        self.committed.notify_all();
+    }
+
+    /// Writes `batch` and flushes once, returning one outcome per payload.
+    fn commit_batch(&self, batch: &[Vec<u8>]) -> Vec<Result<u64>> {
+        let mut log = self.lock_log();
+        let mut outcomes: Vec<Result<u64>> = batch.iter().map(|p| log.append(p)).collect();
+        let flushed = log.flush();
+        drop(log);
+        if let Err(e) = flushed {
+            let e = Arc::new(e);
+            for outcome in outcomes.iter_mut().filter(|o| o.is_ok()) {
+                *outcome = Err(Error::Shared(Arc::clone(&e)));
+            }
+        }
+        outcomes
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_log(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_thread_append_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = GroupCommitLog::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.append(b"a").unwrap(), 0);
+        assert_eq!(log.append(b"b").unwrap(), 1);
+        assert_eq!(log.read(1).unwrap(), b"b");
+        assert_eq!(log.batches_committed(), 2);
+    }
+
+    #[test]
+    fn concurrent_appends_get_distinct_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(GroupCommitLog::open(dir.path(), Config::default()).unwrap());
+        let threads = 8;
+        let per_thread = 50;
+
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let log = Arc::clone(&log);
+                std::thread::spawn(move || {
+                    (0..per_thread)
+                        .map(|i| {
+                            let payload = format!("t{t}-r{i}");
+                            (log.append(payload.as_bytes()).unwrap(), payload)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut written: Vec<(u64, String)> = Vec::new();
+        for handle in handles {
+            written.extend(handle.join().unwrap());
+        }
+        written.sort();
+
+        let total = threads * per_thread;
+        let offsets: Vec<u64> = written.iter().map(|(o, _)| *o).collect();
+        assert_eq!(offsets, (0..total).collect::<Vec<_>>());
+        for (offset, payload) in &written {
+            assert_eq!(log.read(*offset).unwrap(), payload.as_bytes());
+        }
+        assert!(log.batches_committed() <= total);
+    }
+
+    #[test]
+    fn records_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let log = GroupCommitLog::open(dir.path(), Config::default()).unwrap();
+            log.append(b"durable").unwrap();
+        }
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.read(0).unwrap(), b"durable");
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn failed_flush_is_shared_with_the_batch() {
+        let backend = crate::FaultyBackend::new();
+        let config = Config {
+            fsync: crate::FsyncPolicy::Manual,
+            write_buffer: Some(crate::WriteBufferPolicy::default()),
+            storage: Arc::new(backend.clone()),
+            ..Config::default()
+        };
+        let log = GroupCommitLog::open("log", config).unwrap();
+        backend.tear_next_write(0);
+        match log.append(b"a").unwrap_err() {
+            Error::Shared(err) => assert!(matches!(*err, Error::Io(_))),
+            err => panic!("expected a shared flush error, got {err:?}"),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Open files of recently read segments, kept for
+//! [`LogReader`](crate::LogReader) point reads; see
+//! [`LogReader::with_handle_cache`](crate::LogReader::with_handle_cache).
+
+use crate::encryption::{load_cipher, KeyProvider, SegmentCipher};
+use crate::metrics::LogObserver;
+use crate::segment::SegmentInfo;
+use crate::storage::{FileCursor, OpenMode};
+use crate::trace::event;
+use crate::Result;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// A segment's open `.log` and `.idx` files, and its data key if encrypted.
+#[derive(Debug)]
+pub struct SegmentHandles {
+    pub base_offset: u64,
+    pub log_file: FileCursor,
+    pub idx_file: FileCursor,
+    pub cipher: Option<SegmentCipher>,
+}
+
+impl SegmentHandles {
+    /// Opens the files of `info` for reading.
+    pub fn open(info: &SegmentInfo, keys: Option<&dyn KeyProvider>) -> Result<Self> {
+        Ok(Self {
+            base_offset: info.base_offset,
+            log_file: info.open_file(&info.log_path, OpenMode::Read)?,
+            idx_file: info.open_file(&info.index_path(), OpenMode::Read)?,
+            cipher: load_cipher(info, keys)?,
+        })
+    }
+}
+
+/// At most `capacity` segments' handles, least recently used first.
+///
+/// Handles are taken out while in use, so reads of one segment from several
+/// threads each open their own, and only one set goes back.
+///
+/// Handles belong to an epoch, the writer's truncation count: a truncated
+/// segment can be replaced by a new file with the same base offset, so a new
+/// epoch closes every handle opened in an older one.
+#[derive(Debug)]
+pub struct HandleCache {
+    capacity: usize,
+    open: Mutex<Cached>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    epoch: u64,
+    handles: VecDeque<SegmentHandles>,
+}
+
+impl HandleCache {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            open: Mutex::new(Cached {
+                epoch: 0,
+                handles: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Takes the handles of the segment at `base_offset` out of the cache, if
+    /// they were opened in `epoch`; a newer epoch closes every cached handle.
+    pub fn take(&self, base_offset: u64, epoch: u64) -> Option<SegmentHandles> {
+        let mut open = self.lock();
+        if open.epoch != epoch {
+            open.epoch = open.epoch.max(epoch);
+            open.handles.clear();
+            return None;
+        }
+        let i = open
+            .handles
+            .iter()
+            .position(|h| h.base_offset == base_offset)?;
+        open.handles.remove(i)
+    }
+
+    /// Puts `handles`, opened in `epoch`, back as the most recently used,
+    /// closing the least recently used beyond the capacity and reporting each
+    /// to `observer`. Handles from another epoch are closed instead.
+    pub fn put(&self, handles: SegmentHandles, epoch: u64, observer: Option<&dyn LogObserver>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let evicted: Vec<SegmentHandles> = {
+            let mut open = self.lock();
+            if open.epoch != epoch
+                || open
+                    .handles
+                    .iter()
+                    .any(|h| h.base_offset == handles.base_offset)
+            {
+                return;
+            }
+            open.handles.push_back(handles);
+            let over = open.handles.len().saturating_sub(self.capacity);
+            open.handles.drain(..over).collect()
+        };
+        // Closes the files outside the lock.
+        for evicted in evicted {
+            event!(
+                debug,
+                segment = evicted.base_offset,
+                "segment handles evicted"
+            );
+            if let Some(observer) = observer {
+                observer.on_handle_eviction(evicted.base_offset);
+            }
+        }
+    }
+
+    /// Closes every cached handle.
+    pub fn clear(&self) {
+        self.lock().handles.clear();
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.lock().handles.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cached> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,6 @@
+//! Conversion between durable-log records and other systems' wire formats.
+//!
+//! Each format lives in its own submodule behind a feature of the same name.
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
@@ -0,0 +1,494 @@
+//! Kafka's `RecordBatch` wire format (message format v2, magic byte 2).
+//!
+//! [`to_record_batch`] encodes records as one uncompressed batch, as a Kafka
+//! producer or a broker's fetch response would carry them, and
+//! [`from_record_batch`] decodes one back. [`Log::append_record_batch`] appends
+//! every record of a run of batches. Keys, timestamps and headers carry over in
+//! both directions:
+//!
+//! - A record's offset is the batch's base offset plus its offset delta, so
+//!   offsets survive a round trip; appending assigns the log's own offsets.
+//! - A record without a timestamp is encoded with Kafka's "no timestamp", -1,
+//!   and decoded back as `None`. Batches with `LogAppendTime` set give every
+//!   record the batch's max timestamp.
+//! - Kafka's null values and null header values decode as empty payloads and
+//!   header values.
+//!
+//! Transaction markers (see [`crate::txn`]) have no counterpart in a data batch
+//! and are left out by [`to_record_batch`], leaving a gap in the offset deltas
+//! as compaction does in Kafka. Compressed and control batches are rejected;
+//! the batch's producer ID, epoch and sequence are written as -1 (none) and
+//! ignored when decoding.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::reader::Record;
+use crate::Result;
+
+/// Length of a batch's header, the bytes before its first record.
+///
+/// The header holds the base offset (i64), batch length (i32), partition leader
+/// epoch (i32), magic (i8), CRC (u32), attributes (i16), last offset delta
+/// (i32), base and max timestamps (i64), producer ID (i64), producer epoch
+/// (i16), base sequence (i32) and record count (i32), all big-endian.
+pub const BATCH_HEADER_LEN: usize = 61;
+
+/// The batch format version this module reads and writes.
+pub const MAGIC_V2: u8 = 2;
+
+/// Bytes before the batch length field counts from.
+const LENGTH_PREFIX_LEN: usize = 12;
+/// Start of the bytes the CRC covers: the attributes onwards.
+const CRC_START: usize = 21;
+/// Attribute bits holding the compression codec.
+const COMPRESSION_MASK: i16 = 0x07;
+/// Attribute bit set when timestamps are the broker's append time.
+const LOG_APPEND_TIME: i16 = 0x08;
+/// Attribute bit set on batches of control records.
+const CONTROL: i16 = 0x20;
+/// Kafka's timestamp for "none".
+const NO_TIMESTAMP: i64 = -1;
+
+/// Encodes `records` as one uncompressed Kafka record batch; see the
+/// [module docs](self).
+///
+/// # Errors
+///
+/// [`Error::InvalidFormat`] if no record is a data record, the offsets are not
+/// increasing or span more than `i32::MAX`, or an offset, timestamp, key,
+/// payload or header does not fit Kafka's signed fields.
+pub fn to_record_batch(records: &[Record]) -> Result<Vec<u8>> {
+    let records: Vec<&Record> = records.iter().filter(|r| r.marker.is_none()).collect();
+    let first = records
+        .first()
+        .ok_or_else(|| Error::InvalidFormat("a record batch needs a data record".into()))?;
+    let base_offset = to_i64(first.offset, "offset")?;
+    let timestamps = records
+        .iter()
+        .map(|r| r.timestamp.map(|t| to_i64(t, "timestamp")).transpose())
+        .collect::<Result<Vec<_>>>()?;
+    let base_timestamp = timestamps.iter().flatten().min().copied();
+    let max_timestamp = timestamps.iter().flatten().max().copied();
+    let base_timestamp = base_timestamp.unwrap_or(NO_TIMESTAMP);
+
+    let mut body = Vec::new();
+    let mut last_delta: Option<i32> = None;
+    for (record, timestamp) in records.iter().zip(timestamps) {
+        let delta = record
+            .offset
+            .checked_sub(first.offset)
+            .and_then(|d| i32::try_from(d).ok())
+            .filter(|&d| last_delta.map_or(true, |last| d > last))
+            .ok_or_else(|| {
+                Error::InvalidFormat(format!(
+                    "offset {} does not follow the batch's previous offsets",
+                    record.offset
+                ))
+            })?;
+        last_delta = Some(delta);
+        let mut encoded = vec![0]; // attributes, unused
+        put_varlong(
+            &mut encoded,
+            timestamp.unwrap_or(NO_TIMESTAMP) - base_timestamp,
+        );
+        put_varlong(&mut encoded, delta.into());
+        put_bytes(&mut encoded, record.key.as_deref())?;
+        put_bytes(&mut encoded, Some(&record.payload))?;
+        put_varlong(&mut encoded, to_len(record.headers().len())?.into());
+        for (name, value) in record.headers() {
+            put_bytes(&mut encoded, Some(name.as_bytes()))?;
+            put_bytes(&mut encoded, Some(value))?;
+        }
+        put_varlong(&mut body, to_len(encoded.len())?.into());
+        body.extend_from_slice(&encoded);
+    }
+
+    let mut batch = Vec::with_capacity(BATCH_HEADER_LEN + body.len());
+    batch.extend_from_slice(&base_offset.to_be_bytes());
+    let batch_len = to_len(BATCH_HEADER_LEN - LENGTH_PREFIX_LEN + body.len())?;
+    batch.extend_from_slice(&batch_len.to_be_bytes());
+    batch.extend_from_slice(&(-1i32).to_be_bytes()); // partition leader epoch
+    batch.push(MAGIC_V2);
+    batch.extend_from_slice(&[0; 4]); // CRC, filled in below
+    batch.extend_from_slice(&0i16.to_be_bytes()); // attributes
+    batch.extend_from_slice(&last_delta.unwrap_or(0).to_be_bytes());
+    batch.extend_from_slice(&base_timestamp.to_be_bytes());
+    batch.extend_from_slice(&max_timestamp.unwrap_or(NO_TIMESTAMP).to_be_bytes());
+    batch.extend_from_slice(&(-1i64).to_be_bytes()); // producer ID
+    batch.extend_from_slice(&(-1i16).to_be_bytes()); // producer epoch
+    batch.extend_from_slice(&(-1i32).to_be_bytes()); // base sequence
+    batch.extend_from_slice(&to_len(records.len())?.to_be_bytes());
+    batch.extend_from_slice(&body);
+    let crc = crc32c::crc32c(&batch[CRC_START..]);
+    batch[CRC_START - 4..CRC_START].copy_from_slice(&crc.to_be_bytes());
+    Ok(batch)
+}
+
+/// Decodes the Kafka record batch at the start of `bytes`, returning its
+/// records and the number of bytes it takes up.
+///
+/// A run of batches, such as a fetch response's record set, can be decoded one
+/// after another by skipping that many bytes.
+///
+/// # Errors
+///
+/// - [`Error::Truncated`] if `bytes` ends before the batch does.
+/// - [`Error::UnsupportedVersion`] if the batch is not in format v2.
+/// - [`Error::ChecksumMismatch`] if the batch does not match its CRC.
+/// - [`Error::InvalidFormat`] if the batch is compressed or holds control
+///   records, or a record has a negative timestamp other than -1.
+/// - [`Error::Corruption`] if the batch or one of its records is malformed.
+pub fn from_record_batch(bytes: &[u8]) -> Result<(Vec<Record>, usize)> {
+    if bytes.len() < BATCH_HEADER_LEN {
+        return Err(Error::Truncated {
+            needed: BATCH_HEADER_LEN,
+            available: bytes.len(),
+        });
+    }
+    let batch_len = usize::try_from(i32::from_be_bytes(be(bytes, 8)))
+        .ok()
+        .map(|len| len + LENGTH_PREFIX_LEN)
+        .filter(|&len| len >= BATCH_HEADER_LEN)
+        .ok_or_else(|| Error::Corruption("record batch length is too small".into()))?;
+    if bytes.len() < batch_len {
+        return Err(Error::Truncated {
+            needed: batch_len,
+            available: bytes.len(),
+        });
+    }
+    if bytes[16] != MAGIC_V2 {
+        return Err(Error::UnsupportedVersion(bytes[16]));
+    }
+    let base_offset = u64::try_from(i64::from_be_bytes(be(bytes, 0)))
+        .map_err(|_| Error::Corruption("record batch has a negative base offset".into()))?;
+    let expected = u32::from_be_bytes(be(bytes, 17));
+    let actual = crc32c::crc32c(&bytes[CRC_START..batch_len]);
+    if expected != actual {
+        return Err(Error::ChecksumMismatch {
+            offset: base_offset,
+            expected,
+            actual,
+        });
+    }
+    let attributes = i16::from_be_bytes(be(bytes, 21));
+    if attributes & COMPRESSION_MASK != 0 {
+        return Err(Error::InvalidFormat(
+            "compressed record batches are not supported".into(),
+        ));
+    }
+    if attributes & CONTROL != 0 {
+        return Err(Error::InvalidFormat(
+            "control record batches are not supported".into(),
+        ));
+    }
+    let base_timestamp = i64::from_be_bytes(be(bytes, 27));
+    let max_timestamp = i64::from_be_bytes(be(bytes, 35));
+    let count = i32::from_be_bytes(be(bytes, 57));
+
+    let mut cursor = Cursor {
+        bytes: &bytes[BATCH_HEADER_LEN..batch_len],
+        pos: 0,
+    };
+    let append_time = (attributes & LOG_APPEND_TIME != 0).then_some(max_timestamp);
+    let mut records = Vec::new();
+    for _ in 0..count {
+        records.push(cursor.record(base_offset, base_timestamp, append_time)?);
+    }
+    if cursor.pos != cursor.bytes.len() {
+        return Err(Error::Corruption(
+            "record batch is longer than its records".into(),
+        ));
+    }
+    Ok((records, batch_len))
+}
+
+impl Log {
+    /// Appends the records of every Kafka record batch in `bytes`, one after
+    /// another, and returns the number of records appended; see the
+    /// [module docs](crate::interop::kafka).
+    ///
+    /// Records get the log's next offsets, not the batches', and keep their
+    /// keys, timestamps and headers. Each batch is decoded in full before its
+    /// records are appended, and the batches before a failing one stay
+    /// appended.
+    ///
+    /// # Errors
+    ///
+    /// - Errors from [`from_record_batch`] decoding a batch.
+    /// - [`Error::InvalidFormat`] if a record carries a timestamp or headers
+    ///   and [`Config::format`](crate::Config::format) is not
+    ///   [`RecordFormat::V2`](crate::RecordFormat::V2).
+    /// - I/O errors from writing the log.
+    pub fn append_record_batch(&mut self, mut bytes: &[u8]) -> Result<u64> {
+        let mut count = 0;
+        while !bytes.is_empty() {
+            let (records, len) = from_record_batch(bytes)?;
+            for record in records {
+                let headers: Vec<(&str, &[u8])> = record
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_slice()))
+                    .collect();
+                self.append_parts(
+                    record.timestamp,
+                    &headers,
+                    record.key.as_deref(),
+                    &record.payload,
+                )?;
+                count += 1;
+            }
+            bytes = &bytes[len..];
+        }
+        Ok(count)
+    }
+}
+
+/// Reads the big-endian field of `N` bytes at `at`, which the caller has
+/// checked is in bounds.
+fn be<const N: usize>(bytes: &[u8], at: usize) -> [u8; N] {
+    let mut field = [0; N];
+    field.copy_from_slice(&bytes[at..at + N]);
+    field
+}
+
+fn to_i64(value: u64, what: &str) -> Result<i64> {
+    i64::try_from(value)
+        .map_err(|_| Error::InvalidFormat(format!("{what} {value} does not fit a Kafka i64")))
+}
+
+fn to_len(len: usize) -> Result<i32> {
+    i32::try_from(len)
+        .map_err(|_| Error::InvalidFormat(format!("length {len} does not fit a Kafka i32")))
+}
+
+/// Appends `value` as a zigzag varint, as Kafka encodes record fields.
+fn put_varlong(buf: &mut Vec<u8>, value: i64) {
+    #[allow(clippy::cast_sign_loss)] // zigzag encoding maps the sign to the low bit
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        #[allow(clippy::cast_possible_truncation)] // the low 7 bits
+        buf.push(zigzag as u8 | 0x80);
+        zigzag >>= 7;
+    }
+    #[allow(clippy::cast_possible_truncation)] // less than 0x80
+    buf.push(zigzag as u8);
+}
+
+/// Appends a varint length and `bytes`, or a length of -1 for `None`.
+fn put_bytes(buf: &mut Vec<u8>, bytes: Option<&[u8]>) -> Result<()> {
+    match bytes {
+        Some(bytes) => {
+            put_varlong(buf, to_len(bytes.len())?.into());
+            buf.extend_from_slice(bytes);
+        }
+        None => put_varlong(buf, -1),
+    }
+    Ok(())
+}
+
+/// A read position in the records of a batch.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| Error::Corruption("record batch ends inside a record".into()))?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    /// Reads a zigzag varint of up to 64 bits.
+    fn varlong(&mut self) -> Result<i64> {
+        let mut zigzag = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            zigzag |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                #[allow(clippy::cast_possible_wrap)] // zigzag decoding
+                return Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64));
+            }
+        }
+        Err(Error::Corruption("malformed varint in record batch".into()))
+    }
+
+    /// Reads a non-negative varint length or count.
+    fn len(&mut self) -> Result<usize> {
+        usize::try_from(self.varlong()?)
+            .map_err(|_| Error::Corruption("negative length in record batch".into()))
+    }
+
+    /// Reads a varint length and that many bytes, or `None` for a length of -1.
+    fn bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        match self.varlong()? {
+            -1 => Ok(None),
+            len => {
+                let len = usize::try_from(len)
+                    .map_err(|_| Error::Corruption("negative length in record batch".into()))?;
+                self.take(len).map(Some)
+            }
+        }
+    }
+
+    /// Reads a length-prefixed record of a batch with the given base offset
+    /// and timestamp; `append_time`, if set, replaces every record's timestamp.
+    fn record(
+        &mut self,
+        base_offset: u64,
+        base_timestamp: i64,
+        append_time: Option<i64>,
+    ) -> Result<Record> {
+        let len = self.len()?;
+        let mut record = Cursor {
+            bytes: self.take(len)?,
+            pos: 0,
+        };
+        record.take(1)?; // attributes, unused
+        let timestamp_delta = record.varlong()?;
+        let offset_delta = u64::try_from(record.varlong()?)
+            .map_err(|_| Error::Corruption("record has a negative offset delta".into()))?;
+        let key = record.bytes()?.map(<[u8]>::to_vec);
+        let payload = record.bytes()?.map(<[u8]>::to_vec).unwrap_or_default();
+        let header_count = record.len()?;
+        let mut headers = Vec::with_capacity(header_count.min(record.bytes.len()));
+        for _ in 0..header_count {
+            let name = record
+                .bytes()?
+                .ok_or_else(|| Error::Corruption("record header has a null name".into()))?;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| Error::Corruption("record header name is not UTF-8".into()))?;
+            headers.push((
+                name,
+                record.bytes()?.map(<[u8]>::to_vec).unwrap_or_default(),
+            ));
+        }
+        if record.pos != record.bytes.len() {
+            return Err(Error::Corruption("record is longer than its fields".into()));
+        }
+        let timestamp = match append_time.or_else(|| base_timestamp.checked_add(timestamp_delta)) {
+            Some(NO_TIMESTAMP) => None,
+            Some(t) => Some(u64::try_from(t).map_err(|_| {
+                Error::InvalidFormat(format!("record has a negative timestamp {t}"))
+            })?),
+            None => return Err(Error::Corruption("record timestamp overflows".into())),
+        };
+        let offset = base_offset
+            .checked_add(offset_delta)
+            .ok_or_else(|| Error::Corruption("record offset overflows".into()))?;
+        Ok(Record::from_parts(offset, key, payload, timestamp, headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, RecordFormat};
+
+    fn v2() -> Config {
+        Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn record_batches_round_trip_keys_timestamps_and_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), v2()).unwrap();
+        log.append_with_timestamp(b"plain", 1_700_000_000_000)
+            .unwrap();
+        log.append_keyed(b"user-1", b"keyed").unwrap();
+        log.append_with_headers(b"traced", &[("trace", b"abc"), ("empty", b"")])
+            .unwrap();
+        let records: Vec<Record> = log.reader().unwrap().iter().map(|r| r.unwrap()).collect();
+
+        let batch = to_record_batch(&records).unwrap();
+        let (decoded, len) = from_record_batch(&batch).unwrap();
+        assert_eq!((decoded, len), (records.clone(), batch.len()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut copy = Log::open(dir.path(), v2()).unwrap();
+        copy.append(b"already here").unwrap();
+        let twice = [batch.clone(), batch].concat();
+        assert_eq!(copy.append_record_batch(&twice).unwrap(), 6);
+        let copied: Vec<Record> = copy.reader().unwrap().iter().map(|r| r.unwrap()).collect();
+        for (copied, original) in copied[1..].iter().zip(records.iter().cycle()) {
+            let mut original = original.clone();
+            original.offset = copied.offset;
+            assert_eq!(copied, &original);
+        }
+    }
+
+    #[test]
+    fn record_batches_follow_the_kafka_layout() {
+        let mut records = Vec::new();
+        for (offset, timestamp) in [(5, Some(1_000)), (7, None)] {
+            records.push(Record::from_parts(
+                offset,
+                Some(b"k".to_vec()),
+                b"v".to_vec(),
+                timestamp,
+                Vec::new(),
+            ));
+        }
+        let batch = to_record_batch(&records).unwrap();
+        assert_eq!(i64::from_be_bytes(be(&batch, 0)), 5);
+        assert_eq!(
+            usize::try_from(i32::from_be_bytes(be(&batch, 8))).unwrap(),
+            batch.len() - 12
+        );
+        assert_eq!(batch[16], MAGIC_V2);
+        assert_eq!(i32::from_be_bytes(be(&batch, 23)), 2); // last offset delta
+        assert_eq!(i64::from_be_bytes(be(&batch, 27)), 1_000);
+        assert_eq!(i64::from_be_bytes(be(&batch, 35)), 1_000);
+        assert_eq!(i32::from_be_bytes(be(&batch, 57)), 2);
+        // Length 8, attributes, timestamp delta 0, offset delta 0, key "k",
+        // value "v", no headers; then the second record's length, 9.
+        assert_eq!(
+            &batch[BATCH_HEADER_LEN..BATCH_HEADER_LEN + 10],
+            &[16, 0, 0, 0, 2, b'k', 2, b'v', 0, 18]
+        );
+        assert_eq!(from_record_batch(&batch).unwrap().0, records);
+    }
+
+    #[test]
+    fn damaged_or_unsupported_batches_are_rejected() {
+        let record = Record::from_parts(0, None, b"value".to_vec(), Some(1), Vec::new());
+        let batch = to_record_batch(&[record]).unwrap();
+
+        let mut corrupt = batch.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            from_record_batch(&corrupt),
+            Err(Error::ChecksumMismatch { offset: 0, .. })
+        ));
+        assert!(matches!(
+            from_record_batch(&batch[..batch.len() - 1]),
+            Err(Error::Truncated { .. })
+        ));
+
+        let mut compressed = batch;
+        compressed[22] |= 1; // gzip
+        let crc = crc32c::crc32c(&compressed[CRC_START..]);
+        compressed[17..21].copy_from_slice(&crc.to_be_bytes());
+        assert!(matches!(
+            from_record_batch(&compressed),
+            Err(Error::InvalidFormat(_))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        let timestamped = Record::from_parts(0, None, b"v".to_vec(), Some(1), Vec::new());
+        let batch = to_record_batch(&[timestamped]).unwrap();
+        assert!(matches!(
+            log.append_record_batch(&batch),
+            Err(Error::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,670 @@
+//! JSON Lines export and import of records.
+//!
+//! [`Log::export_jsonl`] writes one JSON object per record and
+//! [`Log::import_jsonl`] appends them back, so a log can be moved between
+//! environments, diffed with text tools, or seeded for tests:
+//!
+//! ```text
+//! {"offset":0,"timestamp":1700000000000,"key":"user-1","headers":[{"name":"trace","value":"abc"}],"payload":"hello"}
+//! {"offset":1,"timestamp":null,"key":null,"headers":[],"payload_b64":"AAH+/w=="}
+//! ```
+//!
+//! Byte fields (the key, header values and the payload) are written as JSON
+//! strings when they are valid UTF-8, and as standard padded base64 under the
+//! same name with a `_b64` suffix otherwise, so every payload round-trips
+//! exactly. `timestamp` and `key` are `null` when the record has none. A
+//! transaction marker (see [`crate::txn`]) adds a `marker` field (`"begin"`,
+//! `"commit"` or `"abort"`) and has an empty payload.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::reader::Record;
+use crate::txn::TxnMarker;
+use crate::Result;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+impl Log {
+    /// Writes every retained record to `writer` as JSON Lines (see the
+    /// [module docs](crate::jsonl)) and returns the number of records written.
+    ///
+    /// Encrypted values are written decrypted, using [`Config::encryption`](crate::Config::encryption).
+    ///
+    /// # Errors
+    ///
+    /// - Errors from reading records, as for [`LogReader::iter`](crate::LogReader::iter).
+    /// - I/O errors from writing to `writer`.
+    pub fn export_jsonl(&self, writer: impl Write) -> Result<u64> {
+        let mut out = BufWriter::new(writer);
+        let mut count = 0;
+        for record in &self.reader()? {
+            let line = encode_line(&record?);
+            out.write_all(line.as_bytes())?;
+            out.write_all(b"\n")?;
+            count += 1;
+        }
+        out.flush()?;
+        Ok(count)
+    }
+
+    /// Appends the records of a JSON Lines stream (see the [module docs](crate::jsonl))
+    /// and returns the number of records appended. Blank lines are ignored.
+    ///
+    /// A line's `offset`, when present, must equal the log's next offset, so that
+    /// an export imported into an empty log reproduces it exactly; omit it to
+    /// append records wherever the log ends. Records are appended with the log's
+    /// own compression, encryption and fsync settings, and those before a failing
+    /// line stay appended.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if a line is not a valid record object, its
+    ///   offset does not match, or it carries a timestamp or headers and
+    ///   [`Config::format`](crate::Config::format) is not
+    ///   [`RecordFormat::V2`](crate::RecordFormat::V2).
+    /// - I/O errors from reading `reader` (including invalid UTF-8) or from writing
+    ///   the log.
+    pub fn import_jsonl(&mut self, reader: impl Read) -> Result<u64> {
+        let mut count = 0;
+        for (i, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |what: String| Error::InvalidFormat(format!("line {}: {what}", i + 1));
+            let record = decode_line(&line).map_err(invalid)?;
+            if let Some(offset) = record.offset.filter(|&o| o != self.next_offset()) {
+                return Err(invalid(format!(
+                    "record offset {offset} does not match the log's next offset {}",
+                    self.next_offset()
+                )));
+            }
+            count += 1;
+            if let Some(marker) = record.marker {
+                self.append_marker(marker, record.timestamp)?;
+                continue;
+            }
+            let headers: Vec<(&str, &[u8])> = record
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_slice()))
+                .collect();
+            self.append_parts(
+                record.timestamp,
+                &headers,
+                record.key.as_deref(),
+                &record.payload,
+            )?;
+        }
+        Ok(count)
+    }
+}
+
+/// Encodes `record` as one JSON object, without the trailing newline.
+fn encode_line(record: &Record) -> String {
+    let mut line = format!("{{\"offset\":{},\"timestamp\":", record.offset);
+    match record.timestamp {
+        Some(timestamp) => line.push_str(&timestamp.to_string()),
+        None => line.push_str("null"),
+    }
+    match &record.key {
+        Some(key) => push_bytes(&mut line, "key", key),
+        None => line.push_str(",\"key\":null"),
+    }
+    line.push_str(",\"headers\":[");
+    for (i, (name, value)) in record.headers().iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line.push_str("{\"name\":");
+        push_string(&mut line, name);
+        push_bytes(&mut line, "value", value);
+        line.push('}');
+    }
+    line.push(']');
+    if let Some(marker) = record.marker {
+        line.push_str(",\"marker\":");
+        push_string(&mut line, marker.name());
+    }
+    push_bytes(&mut line, "payload", &record.payload);
+    line.push('}');
+    line
+}
+
+/// Appends `,"name":"text"` if `bytes` is UTF-8, otherwise `,"name_b64":"base64"`.
+fn push_bytes(line: &mut String, name: &str, bytes: &[u8]) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => {
+            let _ = write!(line, ",\"{name}\":");
+            push_string(line, text);
+        }
+        Err(_) => {
+            let _ = write!(line, ",\"{name}_b64\":\"{}\"", base64_encode(bytes));
+        }
+    }
+}
+
+/// Appends `s` as a quoted, escaped JSON string.
+fn push_string(line: &mut String, s: &str) {
+    line.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(line, "\\u{:04x}", u32::from(c));
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// A record as read from one line.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Line {
+    offset: Option<u64>,
+    timestamp: Option<u64>,
+    key: Option<Vec<u8>>,
+    headers: Vec<(String, Vec<u8>)>,
+    marker: Option<TxnMarker>,
+    payload: Vec<u8>,
+}
+
+/// Decodes one line, describing the first problem found.
+fn decode_line(line: &str) -> std::result::Result<Line, String> {
+    let mut record = Line::default();
+    let mut payload = None;
+    let mut fields = Vec::new();
+    for (name, value) in parse_json(line)?.into_object("record")? {
+        if fields.contains(&name) {
+            return Err(format!("duplicate field `{name}`"));
+        }
+        match name.as_str() {
+            "offset" => record.offset = Some(value.into_number(&name)?),
+            "timestamp" => record.timestamp = value.into_nullable_number(&name)?,
+            "key" | "key_b64" => {
+                record.key = value
+                    .into_nullable_string(&name)?
+                    .map(|key| decode_bytes(&name, key))
+                    .transpose()?;
+            }
+            "headers" => {
+                for header in value.into_array(&name)? {
+                    record.headers.push(decode_header(header)?);
+                }
+            }
+            "marker" => {
+                let marker = value.into_string(&name)?;
+                record.marker = Some(
+                    TxnMarker::from_name(&marker)
+                        .ok_or_else(|| format!("unknown transaction marker `{marker}`"))?,
+                );
+            }
+            "payload" | "payload_b64" => {
+                if payload.is_some() {
+                    return Err("both `payload` and `payload_b64` given".into());
+                }
+                payload = Some(decode_bytes(&name, value.into_string(&name)?)?);
+            }
+            _ => return Err(format!("unknown field `{name}`")),
+        }
+        fields.push(name);
+    }
+    if fields.iter().any(|f| f == "key") && fields.iter().any(|f| f == "key_b64") {
+        return Err("both `key` and `key_b64` given".into());
+    }
+    record.payload = payload.ok_or("missing `payload` or `payload_b64`")?;
+    if record.marker.is_some() && !record.payload.is_empty() {
+        return Err("transaction markers have an empty payload".into());
+    }
+    Ok(record)
+}
+
+/// Decodes a `{"name": ..., "value" | "value_b64": ...}` header object.
+fn decode_header(header: Value) -> std::result::Result<(String, Vec<u8>), String> {
+    let (mut name, mut value) = (None, None);
+    for (field, v) in header.into_object("header")? {
+        match field.as_str() {
+            "name" if name.is_none() => name = Some(v.into_string(&field)?),
+            "value" | "value_b64" if value.is_none() => {
+                value = Some(decode_bytes(&field, v.into_string(&field)?)?);
+            }
+            _ => return Err(format!("unexpected header field `{field}`")),
+        }
+    }
+    Ok((
+        name.ok_or("header without `name`")?,
+        value.ok_or("header without `value` or `value_b64`")?,
+    ))
+}
+
+/// Returns the bytes of field `name`: base64-decoded for `_b64` fields.
+fn decode_bytes(name: &str, value: String) -> std::result::Result<Vec<u8>, String> {
+    if name.ends_with("_b64") {
+        base64_decode(&value).ok_or_else(|| format!("`{name}` is not valid base64"))
+    } else {
+        Ok(value.into_bytes())
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(BASE64[(n >> (18 - 6 * i)) as usize & 0x3F]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes padded standard base64, or returns `None` if `text` is not valid.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if text.len() % 4 != 0 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for (i, chunk) in text.chunks(4).enumerate() {
+        let last = i == text.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            n = n << 6 | base64_digit(c)?;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+fn base64_digit(c: u8) -> Option<u32> {
+    let digit = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(u32::from(digit))
+}
+
+/// The JSON values a record line is made of.
+#[derive(Debug)]
+enum Value {
+    Null,
+    Number(u64),
+    String(String),
+    Array(Vec<Self>),
+    Object(Vec<(String, Self)>),
+}
+
+impl Value {
+    fn into_object(self, what: &str) -> std::result::Result<Vec<(String, Self)>, String> {
+        match self {
+            Self::Object(fields) => Ok(fields),
+            _ => Err(format!("{what} is not an object")),
+        }
+    }
+
+    fn into_array(self, what: &str) -> std::result::Result<Vec<Self>, String> {
+        match self {
+            Self::Array(items) => Ok(items),
+            _ => Err(format!("`{what}` is not an array")),
+        }
+    }
+
+    fn into_string(self, what: &str) -> std::result::Result<String, String> {
+        match self {
+            Self::String(s) => Ok(s),
+            _ => Err(format!("`{what}` is not a string")),
+        }
+    }
+
+    fn into_nullable_string(self, what: &str) -> std::result::Result<Option<String>, String> {
+        match self {
+            Self::Null => Ok(None),
+            value => value.into_string(what).map(Some),
+        }
+    }
+
+    fn into_number(self, what: &str) -> std::result::Result<u64, String> {
+        match self {
+            Self::Number(n) => Ok(n),
+            _ => Err(format!("`{what}` is not a non-negative integer")),
+        }
+    }
+
+    fn into_nullable_number(self, what: &str) -> std::result::Result<Option<u64>, String> {
+        match self {
+            Self::Null => Ok(None),
+            value => value.into_number(what).map(Some),
+        }
+    }
+}
+
+/// Nesting deeper than any record line needs is rejected rather than recursed into.
+const MAX_DEPTH: usize = 8;
+
+/// Parses `text` as a single JSON value. Numbers must be non-negative integers
+/// that fit in a `u64`; booleans are not used by the format and are rejected.
+fn parse_json(text: &str) -> std::result::Result<Value, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(format!(
+            "unexpected trailing input at column {}",
+            parser.pos + 1
+        ));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, what: &str) -> String {
+        format!("{what} at column {}", self.pos + 1)
+    }
+
+    fn expect(&mut self, byte: u8) -> std::result::Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", char::from(byte))))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> std::result::Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'0'..=b'9') => self.number().map(Value::Number),
+            Some(b'n') if self.text[self.pos..].starts_with("null") => {
+                self.pos += 4;
+                Ok(Value::Null)
+            }
+            _ => Err(self.error("expected a string, integer, null, array or object")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> std::result::Result<Value, String> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a field name"));
+            }
+            let name = self.string()?;
+            self.expect(b':')?;
+            fields.push((name, self.value(depth + 1)?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> std::result::Result<Value, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> std::result::Result<u64, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        let digits = &self.text[start..self.pos];
+        if matches!(self.peek(), Some(b'.' | b'e' | b'E'))
+            || (digits.len() > 1 && digits.starts_with('0'))
+        {
+            return Err(self.error("expected a non-negative integer"));
+        }
+        digits
+            .parse()
+            .map_err(|_| self.error("integer out of range"))
+    }
+
+    fn string(&mut self) -> std::result::Result<String, String> {
+        self.pos += 1; // opening quote
+        let mut s = String::new();
+        loop {
+            let rest = &self.text[self.pos..];
+            let run = rest
+                .find(|c: char| c == '"' || c == '\\' || c < ' ')
+                .ok_or_else(|| self.error("unterminated string"))?;
+            s.push_str(&rest[..run]);
+            self.pos += run;
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    s.push(self.escape()?);
+                }
+                _ => return Err(self.error("control character in string")),
+            }
+        }
+    }
+
+    /// Decodes the escape sequence after a backslash.
+    fn escape(&mut self) -> std::result::Result<char, String> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("unterminated string"))?;
+        self.pos += 1;
+        Ok(match c {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let high = self.hex4()?;
+                let code = if (0xD800..0xDC00).contains(&high) {
+                    if !self.text[self.pos..].starts_with("\\u") {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    self.pos += 2;
+                    let low = self.hex4()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    high
+                };
+                char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate"))?
+            }
+            _ => return Err(self.error("invalid escape")),
+        })
+    }
+
+    fn hex4(&mut self) -> std::result::Result<u32, String> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid \\u escape"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, RecordFormat};
+
+    #[test]
+    fn base64_roundtrip() {
+        for len in 0..8 {
+            let bytes: Vec<u8> = (0..len).map(|i| 0xFB ^ (i * 37)).collect();
+            let encoded = base64_encode(&bytes);
+            assert_eq!(base64_decode(&encoded).unwrap(), bytes, "{encoded}");
+        }
+        assert_eq!(base64_encode(b"\x00\x01\xfe\xff"), "AAH+/w==");
+        for bad in ["A", "AB=C", "A===", "AA==AAAA", "AA*A"] {
+            assert!(base64_decode(bad).is_none(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn export_import_roundtrip() {
+        let config = Config {
+            format: RecordFormat::V2,
+            max_segment_bytes: 200,
+            ..Config::default()
+        };
+        let source = tempfile::tempdir().unwrap();
+        let mut log = Log::open(source.path(), config.clone()).unwrap();
+        log.append_with_timestamp(b"plain \"text\"\n\x01", 7)
+            .unwrap();
+        log.append_keyed(b"k\xff", &[0, 1, 0xFE, 0xFF]).unwrap();
+        log.append_with_headers("é".as_bytes(), &[("trace", b"t-1"), ("raw", b"\x80")])
+            .unwrap();
+        log.append_batch_frame(&[b"a", b""]).unwrap();
+        let mut txn = log.begin_txn().unwrap();
+        txn.append(b"in txn").unwrap();
+        txn.commit().unwrap();
+        let mut exported = Vec::new();
+        assert_eq!(log.export_jsonl(&mut exported).unwrap(), 8);
+        let text = String::from_utf8(exported.clone()).unwrap();
+        assert!(text.starts_with(
+            "{\"offset\":0,\"timestamp\":7,\"key\":null,\"headers\":[],\"payload\":\"plain \\\"text\\\"\\n\\u0001\"}\n"
+        ));
+        assert!(text.contains("\"key_b64\":\"a/8=\""), "{text}");
+        assert!(
+            text.contains("\"marker\":\"commit\",\"payload\":\"\""),
+            "{text}"
+        );
+
+        let target = tempfile::tempdir().unwrap();
+        let mut copy = Log::open(target.path(), config).unwrap();
+        assert_eq!(copy.import_jsonl(&exported[..]).unwrap(), 8);
+        let mut reexported = Vec::new();
+        copy.export_jsonl(&mut reexported).unwrap();
+        assert_eq!(reexported, exported);
+        assert_eq!(copy.read_record(1).unwrap().payload, [0, 1, 0xFE, 0xFF]);
+    }
+
+    #[test]
+    fn import_rejects_bad_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        let imported = log
+            .import_jsonl(&b"{\"payload\":\"x\"}\n\n{\"offset\":1,\"payload_b64\":\"eQ==\"}\n"[..])
+            .unwrap();
+        assert_eq!(imported, 2);
+        assert_eq!(log.read(1).unwrap(), b"y");
+
+        for line in [
+            "{\"offset\":5,\"payload\":\"x\"}",
+            "{\"payload\":\"x\",\"payload_b64\":\"eA==\"}",
+            "{\"payload\":\"x\",\"extra\":1}",
+            "{\"payload\":\"x\",\"timestamp\":1}",
+            "{\"payload\":\"x\"",
+            "{\"payload\":1.5}",
+            "{\"key\":\"k\"}",
+            "[]",
+        ] {
+            let err = log.import_jsonl(line.as_bytes()).unwrap_err();
+            assert!(matches!(err, Error::InvalidFormat(_)), "{line}: {err}");
+        }
+        assert_eq!(log.next_offset(), 2);
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let Value::String(s) = parse_json(r#""a\"\\\/\b\f\n\r\t\u00e9\ud83d\ude00""#).unwrap()
+        else {
+            panic!("not a string");
+        };
+        assert_eq!(s, "a\"\\/\u{8}\u{c}\n\r\té\u{1F600}");
+        for bad in [
+            r#""\ud83d""#,
+            r#""\x""#,
+            "\"a",
+            "01",
+            "-1",
+            "true",
+            "[[[[[[[[[[]]]]]]]]]]",
+        ] {
+            assert!(parse_json(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Bloom filters over the keys of sealed segments, written alongside their
+//! index so [`Log::latest_for_key`](crate::Log::latest_for_key) can skip
+//! segments that cannot hold a key; see `docs/file-format.md` for the layout.
+
+use crate::error::Error;
+use crate::segment::SegmentInfo;
+use crate::storage;
+use crate::Result;
+use std::path::PathBuf;
+
+/// Magic bytes at the start of a filter file.
+const FILTER_MAGIC: [u8; 4] = *b"DLBF";
+
+/// Filter file version written by this release.
+const FILTER_VERSION: u8 = 1;
+
+/// Length of the fixed fields before the bits.
+const HEADER_LEN: usize = 16;
+
+/// Bits per key, for a false positive rate of about 1%.
+const BITS_PER_KEY: u64 = 10;
+
+/// Bit positions set per key.
+const HASHES: u8 = 7;
+
+/// Two 32-bit hashes of a key, from which every bit position is derived
+/// (`h1 + i * h2`): the halves of its CRC-32 and length, mixed by the
+/// splitmix64 finalizer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyHash(u64);
+
+impl KeyHash {
+    pub fn of(key: &[u8]) -> Self {
+        let mut z = u64::from(crc32fast::hash(key)) | (key.len() as u64) << 32;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Self(z ^ (z >> 31))
+    }
+
+    fn positions(self, hashes: u8, bits: u64) -> impl Iterator<Item = u64> {
+        let (h1, h2) = (self.0 & 0xFFFF_FFFF, self.0 >> 32 | 1);
+        (0..u64::from(hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+    }
+}
+
+/// A bloom filter over the keys of one segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyFilter {
+    hashes: u8,
+    words: Vec<u64>,
+}
+
+impl KeyFilter {
+    /// Builds a filter holding `keys`.
+    pub fn new(keys: &[KeyHash]) -> Self {
+        let bits = (keys.len() as u64 * BITS_PER_KEY).max(64);
+        let words = usize::try_from(bits.div_ceil(64)).unwrap_or(usize::MAX);
+        let mut filter = Self {
+            hashes: HASHES,
+            words: vec![0; words],
+        };
+        let bits = filter.bits();
+        for key in keys {
+            for bit in key.positions(filter.hashes, bits) {
+                let (word, mask) = locate(bit);
+                filter.words[word] |= mask;
+            }
+        }
+        filter
+    }
+
+    /// Returns false if no key hashing to `key` was added.
+    pub fn may_contain(&self, key: KeyHash) -> bool {
+        key.positions(self.hashes, self.bits()).all(|bit| {
+            let (word, mask) = locate(bit);
+            self.words[word] & mask != 0
+        })
+    }
+
+    fn bits(&self) -> u64 {
+        self.words.len() as u64 * 64
+    }
+
+    /// Reads the filter of `info`, or `None` if it has none.
+    ///
+    /// # Errors
+    ///
+    /// - I/O errors other than the file not existing.
+    /// - [`Error::Corruption`] if the file is malformed or its checksum does not match.
+    pub fn read(info: &SegmentInfo) -> Result<Option<Self>> {
+        storage::read_file(&*info.storage, &path(info))?
+            .map(|bytes| Self::decode(&bytes))
+            .transpose()
+    }
+
+    /// Atomically writes the filter of `info` (write temp, fsync, rename).
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing, syncing, or renaming the file.
+    pub fn write(&self, info: &SegmentInfo) -> Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.words.len() * 8 + 4);
+        buf.extend_from_slice(&FILTER_MAGIC);
+        buf.extend_from_slice(&[FILTER_VERSION, self.hashes, 0, 0]);
+        buf.extend_from_slice(&self.bits().to_le_bytes());
+        for word in &self.words {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+        storage::write_atomic(&*info.storage, &path(info), &buf)?;
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let malformed = || Error::Corruption("key filter is malformed".into());
+        if bytes.len() < HEADER_LEN + 4 || bytes[0..4] != FILTER_MAGIC {
+            return Err(malformed());
+        }
+        let (contents, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(contents).to_le_bytes() != crc {
+            return Err(Error::Corruption("key filter checksum mismatch".into()));
+        }
+        if contents[4] != FILTER_VERSION {
+            return Err(Error::InvalidFormat(format!(
+                "unsupported key filter version {}",
+                contents[4]
+            )));
+        }
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(&contents[8..16]);
+        let bits = u64::from_le_bytes(bits);
+        let words: Vec<u64> = contents[HEADER_LEN..]
+            .chunks_exact(8)
+            .map(|word| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(word);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+        if words.is_empty()
+            || (contents.len() - HEADER_LEN) % 8 != 0
+            || bits != words.len() as u64 * 64
+        {
+            return Err(malformed());
+        }
+        Ok(Self {
+            hashes: contents[5],
+            words,
+        })
+    }
+}
+
+/// Returns the index of the word holding `bit` and the bit's mask in it.
+fn locate(bit: u64) -> (usize, u64) {
+    (
+        usize::try_from(bit / 64).unwrap_or(usize::MAX),
+        1 << (bit % 64),
+    )
+}
+
+/// Path to the `.bloom` file of `info`.
+pub fn path(info: &SegmentInfo) -> PathBuf {
+    info.log_path.with_extension("bloom")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log};
+
+    #[test]
+    fn filter_holds_its_keys_and_roundtrips() {
+        let keys: Vec<KeyHash> = (0..1000u32)
+            .map(|i| KeyHash::of(&i.to_le_bytes()))
+            .collect();
+        let filter = KeyFilter::new(&keys);
+        assert!(keys.iter().all(|&key| filter.may_contain(key)));
+        let false_positives = (1000..11_000u32)
+            .filter(|i| filter.may_contain(KeyHash::of(&i.to_le_bytes())))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        let bytes = {
+            let log = Log::open_in_memory(Config::default()).unwrap();
+            let info = log.segment_infos().next().unwrap().clone();
+            filter.write(&info).unwrap();
+            storage::read_file(&*info.storage, &path(&info))
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(KeyFilter::decode(&bytes).unwrap(), filter);
+        let mut damaged = bytes;
+        damaged[20] ^= 1;
+        assert!(matches!(
+            KeyFilter::decode(&damaged),
+            Err(Error::Corruption(_))
+        ));
+    }
+}
@@ -3,18 +3,207 @@
 //! Crash-safe, segmented commit log (WAL) with checksums and index.
 //!
 //! See [README](https://github.com/your-org/durable-log#readme) for overview and examples.
+//!
+//! Everything but [`codec`] needs the default `std` feature. Without it the crate
+//! is `no_std` (with `alloc`) and only encodes and decodes record frames.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
+#[cfg(feature = "std")]
+pub mod ack;
+#[cfg(feature = "std")]
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_log;
+#[cfg(feature = "fs")]
+pub mod backup;
+#[cfg(feature = "std")]
+pub mod checksum;
+#[cfg(feature = "std")]
+mod clock;
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod compression;
+#[cfg(feature = "fs")]
+pub mod consumers;
+#[cfg(feature = "direct-io")]
+pub mod direct;
+#[cfg(feature = "std")]
+pub mod encryption;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "follow")]
+pub mod follow;
+#[cfg(feature = "std")]
+pub mod group_commit;
+#[cfg(feature = "std")]
+mod handles;
+#[cfg(feature = "kafka")]
+pub mod interop;
+#[cfg(feature = "std")]
+pub mod jsonl;
+#[cfg(feature = "std")]
+mod key_filter;
+#[cfg(feature = "std")]
 pub mod log;
+#[cfg(feature = "std")]
 pub mod log_dir;
+#[cfg(feature = "std")]
+pub mod maintenance;
+#[cfg(feature = "fs")]
+pub mod manager;
+#[cfg(feature = "std")]
+pub mod manifest;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "std")]
+pub mod producer;
+#[cfg(feature = "std")]
+pub mod raft;
+#[cfg(feature = "std")]
+mod read_ahead;
+#[cfg(feature = "std")]
+pub mod reader;
+#[cfg(feature = "std")]
 pub mod record;
+#[cfg(feature = "std")]
+pub mod recovery;
+#[cfg(feature = "std")]
+pub mod replication;
+#[cfg(feature = "std")]
+pub mod reservation;
+#[cfg(feature = "std")]
+pub mod retention;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "std")]
 pub mod segment;
+#[cfg(feature = "std")]
+pub mod snapshots;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(feature = "std")]
+pub mod tail;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+pub mod ttl;
+#[cfg(feature = "std")]
+pub mod txn;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "std")]
+pub mod write_buffer;
+#[cfg(feature = "std")]
+pub mod writer;
 
+#[cfg(feature = "std")]
+pub use ack::AppendAck;
+#[cfg(feature = "fs")]
+pub use archive::DirStore;
+#[cfg(feature = "std")]
+pub use archive::{Archive, ArchiveCache, ArchivedSegment, ObjectStore};
+#[cfg(feature = "async")]
+pub use async_log::{AsyncLog, RecordStream};
+#[cfg(feature = "fs")]
+pub use backup::Backup;
+#[cfg(feature = "std")]
+pub use checksum::ChecksumAlgorithm;
+#[cfg(feature = "std")]
+pub use clock::set_clock;
+#[cfg(feature = "std")]
+pub use compression::{Codec, Compression};
+#[cfg(feature = "fs")]
+pub use consumers::ConsumerOffsets;
+#[cfg(feature = "direct-io")]
+pub use direct::DirectBackend;
+#[cfg(feature = "std")]
+pub use encryption::{Encryption, KeyId, KeyProvider, MasterKey};
+#[cfg(feature = "std")]
 pub use error::Error;
-pub use log::{Config, Log};
+#[cfg(feature = "follow")]
+pub use follow::FollowReader;
+#[cfg(feature = "std")]
+pub use group_commit::GroupCommitLog;
+#[cfg(feature = "std")]
+pub use log::{Config, FsyncPolicy, Log, LogStats, RecordFormat};
+#[cfg(feature = "std")]
 pub use log_dir::LogDir;
-pub use record::{decode_record, encode_record, RecordHeader, HEADER_LEN, MAGIC, VERSION_V1};
-pub use segment::{discover_segments, SegmentId, SegmentInfo};
+#[cfg(feature = "std")]
+pub use maintenance::{
+    CustomTask, MaintenanceConfig, MaintenanceHooks, MaintenanceScheduler, MaintenanceTask,
+    ScheduledTask, Trigger,
+};
+#[cfg(feature = "fs")]
+pub use manager::{LogManager, SharedLog};
+#[cfg(feature = "std")]
+pub use manifest::Manifest;
+#[cfg(feature = "std")]
+pub use metrics::LogObserver;
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsObserver;
+#[cfg(feature = "mmap")]
+pub use mmap::{MappedRecords, MappedSegment, RecordRef};
+#[cfg(feature = "std")]
+pub use producer::PRODUCER_HEADER;
+#[cfg(feature = "std")]
+pub use raft::{AppendEntriesOutcome, TERM_HEADER};
+#[cfg(feature = "std")]
+pub use reader::{
+    ChecksumMode, CorruptRange, Isolation, LogReader, OnCorruption, Record, Records,
+    DEFAULT_HANDLE_CACHE,
+};
+#[cfg(feature = "std")]
+pub use record::{
+    decode_batch, decode_headers, decode_keyed_record, decode_record, decode_record_verified,
+    decode_value, encode_batch, encode_frame, encode_frame_v2, encode_headers, encode_keyed_record,
+    encode_record, split_batch, split_key, RecordHeader, BATCH_CONTINUES, FLAG_BATCH,
+    FLAG_CONTINUED, FLAG_CONTROL, FLAG_KEYED, HEADER_LEN, HEADER_LEN_V2, MAGIC, MAX_CHUNK_LEN,
+    MIN_HEADER_LEN, VERSION_COMPACT, VERSION_V1, VERSION_V2,
+};
+#[cfg(feature = "std")]
+pub use recovery::{RecoveryObserver, RecoveryReport};
+#[cfg(feature = "std")]
+pub use replication::{replicate, ReplicationClient, ReplicationServer};
+#[cfg(feature = "std")]
+pub use retention::{DiskQuota, QuotaAction, RetentionPolicy, RetentionTask};
+#[cfg(feature = "s3")]
+pub use s3::S3Store;
+#[cfg(feature = "std")]
+pub use segment::{
+    discover_segments, discover_segments_in, SegmentFooter, SegmentId, SegmentInfo, FOOTER_LEN,
+};
+#[cfg(feature = "std")]
+pub use snapshots::Snapshot;
+#[cfg(feature = "fs")]
+pub use storage::FsBackend;
+#[cfg(feature = "std")]
+pub use storage::{Advice, Backend, MemoryBackend, OpenMode, StorageFile};
+#[cfg(feature = "std")]
+pub use tail::Tail;
+#[cfg(feature = "testing")]
+pub use testing::FaultyBackend;
+#[cfg(feature = "std")]
+pub use ttl::EXPIRY_HEADER;
+#[cfg(feature = "std")]
+pub use txn::{Transaction, TxnMarker};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use uring::UringBackend;
+#[cfg(feature = "std")]
+pub use verify::{Problem, ProblemKind, VerifyReport};
+#[cfg(feature = "std")]
+pub use write_buffer::WriteBufferPolicy;
+#[cfg(feature = "std")]
+pub use writer::{AppendHandle, LogWriter, PendingAppend, WriterConfig};
 
 /// Result type for durable-log operations.
+#[cfg(feature = "std")]
 pub type Result<T> = std::result::Result<T, Error>;
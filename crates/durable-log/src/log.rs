@@ -1,301 +1,3436 @@
 //! Core log management: append, segments, and index.
 
+use crate::ack::{AppendAck, Watermark};
+use crate::archive::{ArchiveCache, ArchivedSegment};
+use crate::checksum::ChecksumAlgorithm;
+use crate::clock::{self, now_millis, Instant};
+use crate::compression::Compression;
+use crate::encryption::{
+    key_path, load_cipher, Encryption, KeyId, KeyProvider, MasterKey, SegmentCipher,
+};
 use crate::error::Error;
-use crate::log_dir::LogDir;
+use crate::key_filter::{self, KeyFilter, KeyHash};
+use crate::log_dir::{
+    read_committed_offset, read_start_offset, take_clean_shutdown, write_clean_shutdown,
+    write_committed_offset, write_start_offset, CleanShutdown, DurableOffsetFile, LogDir,
+};
+use crate::manifest::{config_fingerprint, Manifest};
+use crate::metrics::LogObserver;
+use crate::producer::{discard_snapshot_past, ProducerState, PRODUCER_HEADER};
+use crate::raft::TERM_HEADER;
+use crate::reader::{
+    index_position, observe_read, read_header, read_indexed, ChecksumMode, LogReader, Record,
+};
 use crate::record::{
-    decode_header, encode_record, HEADER_LEN, INDEX_ENTRY_LEN,
+    encode_frame_as, encode_frame_header, encode_headers, pack_batch, RecordHeader,
+    BATCH_CONTINUES, FLAGS_NONE, FLAG_BATCH, FLAG_CONTINUED, FLAG_CONTROL, FLAG_ENCRYPTED,
+    INDEX_ENTRY_LEN, MAX_CHUNK_LEN, VERSION_COMPACT, VERSION_V1, VERSION_V2,
+};
+use crate::recovery::{RecoveryObserver, RecoveryReport};
+use crate::reservation::Reservations;
+use crate::retention::{DiskQuota, QuotaAction, RetentionPolicy};
+use crate::segment::{
+    hash_prefix, remove_segment_files, write_footer, SegmentFooter, SegmentId, SegmentInfo,
+    FOOTER_LEN,
 };
-use crate::segment::{SegmentId, SegmentInfo};
+use crate::snapshots::{read_snapshot, Snapshot};
+use crate::storage::{self, Advice, Backend, FileCursor, MemoryBackend, OpenMode};
+use crate::tail::Tail;
+use crate::trace::event;
+use crate::ttl::{decode_expiry, SegmentExpiry, EXPIRY_HEADER};
+use crate::txn::{Transaction, TxnMarker};
+use crate::verify::{verify_segments, VerifyReport};
+use crate::write_buffer::{WriteBuffer, WriteBufferPolicy};
 use crate::Result;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::{Path};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{IoSlice, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Directory of logs opened by [`Log::open_in_memory`], as [`Log::path`] reports it.
+const MEMORY_LOG_PATH: &str = "memory";
+
+/// When the log fsyncs appended records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FsyncPolicy {
+    /// Only [`Log::flush`] (and segment rolls) fsync; callers decide when.
+    #[default]
+    Manual,
+    /// Every append is fsynced before it returns.
+    Always,
+}
+
+/// Record frame version written by the log. Readers accept every version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecordFormat {
+    /// 24-byte header, readable by every release of this crate.
+    #[default]
+    V1,
+    /// 40-byte header carrying the record's append timestamp, optional user
+    /// headers and a CRC of its own fields, so a corrupt length or offset is
+    /// detected before it is trusted.
+    V2,
+    /// Variable-length header with the v1 fields, whose batch count, offset and
+    /// length are varints, and varint length prefixes inside keyed and batch
+    /// bodies: 13 bytes of overhead for most records instead of 24, for logs of
+    /// tiny payloads. Pair it with [`Log::append_batch_frame`] to share one header
+    /// among many records. Requires a reader from this release or later.
+    Compact,
+}
+
+impl RecordFormat {
+    /// Returns the version byte of the frames this format writes.
+    #[must_use]
+    pub const fn version(self) -> u8 {
+        match self {
+            Self::V1 => VERSION_V1,
+            Self::V2 => VERSION_V2,
+            Self::Compact => VERSION_COMPACT,
+        }
+    }
+
+    /// Returns true if frames of this format store timestamps and user headers.
+    pub(crate) const fn stores_headers(self) -> bool {
+        matches!(self, Self::V2)
+    }
+}
 
 /// Configuration for the log.
+///
+/// With the `serde` feature it can be deserialized from a configuration file.
+/// Fields left out take their default; `encryption`, `observer`,
+/// `recovery_observer`, `storage` and `archive` are never (de)serialized and must
+/// be set in code.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Config {
     /// Maximum size of a segment file in bytes before rolling to a new one.
     pub max_segment_bytes: u64,
+    /// Allocates each new segment file to `max_segment_bytes` up front, so that
+    /// appends overwrite reserved space instead of growing the file. Needs a
+    /// backend with positional writes, such as the filesystem or memory ones.
+    /// Off by default: some filesystems handle preallocated files poorly.
+    pub preallocate: bool,
+    /// When appended records are fsynced.
+    pub fsync: FsyncPolicy,
+    /// Limits applied by [`Log::enforce_retention`].
+    pub retention: RetentionPolicy,
+    /// Value compression for appended records; `None` stores values as given.
+    pub compression: Option<Compression>,
+    /// Frame version for appended records.
+    pub format: RecordFormat,
+    /// Body checksum algorithm for appended records.
+    pub checksum: ChecksumAlgorithm,
+    /// Master keys for encrypting appended values at rest (`encryption` feature);
+    /// `None` writes plaintext. Also required to read back encrypted records.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub encryption: Option<Encryption>,
+    /// Receives append, fsync, segment roll and read events; `None` reports nothing.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub observer: Option<Arc<dyn LogObserver>>,
+    /// Told about the repairs made by [`Log::open`]; `None` reports nothing.
+    /// See [`crate::recovery`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub recovery_observer: Option<Arc<dyn RecoveryObserver>>,
+    /// Where the log's files are kept; the local filesystem by default, or a
+    /// [`MemoryBackend`] without the `fs` feature.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub storage: Arc<dyn Backend>,
+    /// Advises the OS to drop a segment's pages from the page cache once it is
+    /// sealed (`POSIX_FADV_DONTNEED`, with the `fadvise` feature on Linux), so
+    /// that a busy writer does not crowd other processes out of the cache.
+    /// Reads of the sealed segment then go to disk. Off by default.
+    pub evict_sealed: bool,
+    /// Buffers appends in memory and writes them in batches; `None` writes each
+    /// append as it happens. See [`crate::write_buffer`].
+    pub write_buffer: Option<WriteBufferPolicy>,
+    /// Hard cap on the size of the log's segment and index files, checked
+    /// before each append; `None` lets the log grow until the disk is full.
+    pub quota: Option<DiskQuota>,
+    /// Where [`Log::read`], [`Log::read_record`] and [`Log::reader`] find
+    /// offsets older than the local segments, such as those moved away by
+    /// [`Log::archive`]; `None` leaves them out of range. See [`crate::archive`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub archive: Option<Arc<ArchiveCache>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_segment_bytes: 64 * 1024 * 1024, // 64MB
+            preallocate: false,
+            fsync: FsyncPolicy::Manual,
+            retention: RetentionPolicy::default(),
+            compression: None,
+            format: RecordFormat::V1,
+            checksum: ChecksumAlgorithm::Crc32,
+            encryption: None,
+            observer: None,
+            recovery_observer: None,
+            storage: storage::default_backend(),
+            evict_sealed: false,
+            write_buffer: None,
+            quota: None,
+            archive: None,
+        }
+    }
+}
+
+/// A crash-safe, segmented log.
+#[derive(Debug)]
+pub struct Log {
+    dir: LogDir,
+    config: Config,
+    /// Sealed (read-only) segments, sorted by base offset.
+    sealed: Vec<SegmentInfo>,
+    active_segment: ActiveSegment,
+    /// Offsets below this watermark have been fsynced.
+    durable: Arc<Watermark>,
+    /// Offsets below this watermark have been written; wakes [`Tail`]s.
+    written: Arc<Watermark>,
+    /// Publishes `durable` to readers in other processes; `None` while opening.
+    durable_file: Option<DurableOffsetFile>,
+    /// Logical start set by [`Log::delete_before`]; may lie inside the first segment.
+    start_offset: u64,
+    /// Offsets below this were marked committed by [`Log::commit`].
+    committed: u64,
+    /// Largest value written in one frame; see [`MAX_CHUNK_LEN`].
+    chunk_len: usize,
+    /// When [`Log::flush`] last completed, if it has since opening.
+    last_flush: Option<SystemTime>,
+    /// Offset of the begin marker of the open transaction, if any.
+    txn: Option<u64>,
+    /// The installed snapshot, if any (see [`crate::snapshots`]).
+    snapshot: Option<Snapshot>,
+    /// Idempotent producer state, loaded on first use (see [`crate::producer`]).
+    producers: Option<ProducerState>,
+    /// Offsets handed out by [`Log::reserve`] but not yet written (see
+    /// [`crate::reservation`]).
+    reserved: Reservations,
+    /// Key filters of sealed segments by base offset, loaded on first use by
+    /// [`Log::latest_for_key`]; `None` for segments without one.
+    key_filters: BTreeMap<u64, Option<KeyFilter>>,
+    /// Latest record expiry of each sealed segment, by base offset, whose
+    /// records all have a TTL; see [`crate::ttl`].
+    expiries: BTreeMap<u64, u64>,
+    /// Size of the sealed segments' `.log` and `.idx` files, for
+    /// [`Config::quota`]; `None` until measured after they change.
+    sealed_bytes: Option<u64>,
+    /// What [`Log::open`] repaired.
+    recovery: RecoveryReport,
+    /// The segments in [`Config::archive`]'s manifest when last read; reread
+    /// when an offset is not found in them.
+    archived: Vec<ArchivedSegment>,
+}
+
+/// A point-in-time summary of a log, returned by [`Log::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogStats {
+    /// Number of segments, sealed and active.
+    pub segments: usize,
+    /// Combined size of every segment's `.log` and `.idx` files.
+    pub disk_bytes: u64,
+    /// Offset of the oldest retained record (see [`Log::first_offset`]).
+    pub first_offset: u64,
+    /// Offset of the newest record, or `None` if the log holds none.
+    pub last_offset: Option<u64>,
+    /// Bytes of records in the active segment.
+    pub active_segment_bytes: u64,
+    /// When the log was last fsynced by this handle, or `None` if it has not been.
+    pub last_flush: Option<SystemTime>,
+    /// Combined size of every segment's `.idx` file.
+    pub index_bytes: u64,
+}
+
+/// What [`Log::recover`] found wrong with the tail of the active segment.
+struct TailRepair {
+    /// Bytes of torn or corrupt records truncated.
+    truncated_bytes: u64,
+    /// Position of the corrupt frame the scan stopped at, if it stopped at one
+    /// rather than at a torn or missing one.
+    corrupt_at: Option<u64>,
+}
+
+#[derive(Debug)]
+struct ActiveSegment {
+    info: SegmentInfo,
+    log_file: FileCursor,
+    idx_file: FileCursor,
+    current_size: u64,
+    next_offset: u64,
+    /// Encrypts appended values when [`Config::encryption`] is set.
+    cipher: Option<SegmentCipher>,
+    /// Running CRC-32 of the segment's bytes for its footer; `None` after
+    /// reopening a non-empty segment, in which case sealing rereads the file.
+    crc: Option<crc32fast::Hasher>,
+    /// Append time of the segment's first record, if known.
+    first_timestamp: Option<u64>,
+    /// Append time of the segment's latest record, if known.
+    last_timestamp: Option<u64>,
+    /// Records appended but not yet written, with [`Config::write_buffer`] set.
+    /// `current_size` and `next_offset` already count them.
+    buffer: WriteBuffer,
+    /// Hashes of the keys of the segment's records, for its key filter; `None`
+    /// after reopening a non-empty segment, in which case sealing rereads them.
+    keys: Option<Vec<KeyHash>>,
+    /// Latest expiry of the segment's records while all have a TTL; see [`crate::ttl`].
+    expiry: SegmentExpiry,
+}
+
+impl ActiveSegment {
+    /// Accounts for `frames`, holding `records` records appended at `timestamp`,
+    /// having been written at the end of the segment.
+    fn advance(&mut self, frames: &[impl AsRef<[u8]>], records: u64, timestamp: Option<u64>) {
+        if self.current_size == 0 {
+            self.first_timestamp = timestamp;
+        }
+        for frame in frames.iter().map(AsRef::as_ref) {
+            if let Some(crc) = &mut self.crc {
+                crc.update(frame);
+            }
+            self.current_size += frame.len() as u64;
+        }
+        self.next_offset += records;
+        self.last_timestamp = timestamp;
+    }
+
+    /// Restores the state recorded by [`Log::close`], returning false (and
+    /// changing nothing) if the segment's files no longer match it.
+    fn resume(&mut self, state: &CleanShutdown) -> Result<bool> {
+        let summary = &state.summary;
+        let idx_len = summary.record_count * INDEX_ENTRY_LEN as u64;
+        if summary.first_offset != self.info.base_offset
+            || state.log_len != self.current_size
+            || idx_len != self.idx_file.size()?
+        {
+            return Ok(false);
+        }
+        self.next_offset = self.info.base_offset + summary.record_count;
+        self.first_timestamp = summary.first_timestamp;
+        self.last_timestamp = summary.last_timestamp;
+        self.crc = Some(crc32fast::Hasher::new_with_initial_len(
+            summary.segment_crc,
+            state.log_len,
+        ));
+        self.log_file.seek(SeekFrom::End(0))?;
+        self.idx_file.seek(SeekFrom::End(0))?;
+        Ok(true)
+    }
+
+    /// Summarises the segment for the footer written when it is sealed.
+    fn footer(&mut self) -> Result<SegmentFooter> {
+        let segment_crc = match self.crc.take() {
+            Some(crc) => crc.finalize(),
+            None => hash_prefix(&mut self.log_file, self.current_size)?,
+        };
+        Ok(SegmentFooter {
+            record_count: self.next_offset - self.info.base_offset,
+            first_offset: self.info.base_offset,
+            last_offset: self.next_offset.saturating_sub(1),
+            first_timestamp: self.first_timestamp,
+            last_timestamp: self.last_timestamp,
+            segment_crc,
+        })
+    }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            max_segment_bytes: 64 * 1024 * 1024, // 64MB
+impl Log {
+    /// Opens the log in the given directory. Creates it if missing.
+    ///
+    /// Unless the log was last [`close`](Self::close)d cleanly, the last segment is
+    /// scanned and any torn or corrupt tail is truncated, and a transaction left
+    /// open is aborted. Index files that are missing or do not match their segment
+    /// are rebuilt by scanning it. The directory is checked against its
+    /// [`Manifest`], which is then rewritten to describe the opened log.
+    ///
+    /// [`recovery`](Self::recovery) reports what was repaired, as does
+    /// [`Config::recovery_observer`] while it happens.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Locked`] if another writer holds the directory lock.
+    /// - [`Error::Corruption`] if segments were lost or replaced since the
+    ///   manifest was written; see the [`manifest`](crate::manifest) module.
+    /// - I/O errors from opening or scanning segment files.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", name = "log_open", skip_all, fields(path = %path.as_ref().display()))
+    )]
+    pub fn open(path: impl AsRef<Path>, config: Config) -> Result<Self> {
+        let started = Instant::now();
+        let dir = LogDir::open_with_storage(path, Arc::clone(&config.storage))?;
+        let storage = &**dir.storage();
+        let (start_offset, expiries) = Self::check_manifest(&dir, &config)?;
+        let committed = read_committed_offset(storage, dir.path())?.unwrap_or(0);
+        let snapshot = read_snapshot(storage, dir.path())?;
+        let mut sealed = dir.segments().to_vec();
+
+        let active_segment = match sealed.pop() {
+            Some(last_info) => Self::open_active_segment(last_info, config.encryption.as_ref())?,
+            None => Self::create_segment(&dir, 0, config.encryption.as_ref())?,
+        };
+        let mut recovery = RecoveryReport::default();
+        for info in &sealed {
+            let footer_len = if info.footer.is_some() { FOOTER_LEN } else { 0 };
+            let data_len =
+                info.open_file(&info.log_path, OpenMode::Read)?.size()? - footer_len as u64;
+            if !info.index_matches(data_len, info.footer.map(|f| f.record_count))? {
+                info.rebuild_index()?;
+                recovery.repaired_indexes.push(info.base_offset);
+                if let Some(observer) = &config.recovery_observer {
+                    observer.on_index_repaired(info.base_offset);
+                }
+            }
+        }
+
+        let mut log = Self {
+            dir,
+            config,
+            sealed,
+            active_segment,
+            durable: Watermark::new(0),
+            written: Watermark::new(0),
+            durable_file: None,
+            start_offset,
+            committed,
+            chunk_len: MAX_CHUNK_LEN,
+            last_flush: None,
+            txn: None,
+            snapshot,
+            producers: None,
+            reserved: Reservations::default(),
+            key_filters: BTreeMap::new(),
+            expiries,
+            sealed_bytes: None,
+            recovery,
+            archived: Vec::new(),
+        };
+
+        match take_clean_shutdown(&**log.dir.storage(), log.dir.path())? {
+            Some(state) if log.active_segment.resume(&state)? => {
+                event!(debug, "clean shutdown recorded; recovery skipped");
+            }
+            _ => log.recover_tail()?,
+        }
+        if log.repair_active_index()? {
+            let segment = log.active_segment.info.base_offset;
+            log.recovery.repaired_indexes.push(segment);
+            if let Some(observer) = &log.config.recovery_observer {
+                observer.on_index_repaired(segment);
+            }
+        }
+        if log.committed > log.active_segment.next_offset {
+            // Only possible if committed records were lost outside the log's control.
+            event!(
+                warn,
+                committed = log.committed,
+                next_offset = log.active_segment.next_offset,
+                "committed watermark beyond the end of the log"
+            );
+            log.committed = log.active_segment.next_offset;
+        }
+        // Records that survived recovery may be only in the page cache, if the
+        // process crashed before fsyncing them; sealed segments were fsynced
+        // when they rolled.
+        log.active_segment.log_file.fsync()?;
+        log.active_segment.idx_file.fsync()?;
+        log.durable.advance(log.active_segment.next_offset);
+        log.written.advance(log.active_segment.next_offset);
+        // Only now that they are fsynced may readers bounded by the published
+        // watermark count these records as durable.
+        log.publish_durable()?;
+        log.recovery.aborted_txn = log.txn;
+        log.abort_open_txn()?;
+        log.recovery.duration = started.elapsed();
+        if let Some(observer) = &log.config.recovery_observer {
+            observer.on_recovered(&log.recovery);
+        }
+        // Finishes installing a snapshot interrupted by a crash.
+        log.apply_snapshot()?;
+        log.preallocate_active()?;
+        log.write_manifest()?;
+        event!(
+            info,
+            segments = log.sealed.len() + 1,
+            first_offset = log.first_offset(),
+            next_offset = log.active_segment.next_offset,
+            "log opened"
+        );
+        Ok(log)
+    }
+
+    /// Opens a new, empty log kept entirely in memory, on a [`MemoryBackend`] of
+    /// its own, ignoring [`Config::storage`]. It behaves exactly like a log on
+    /// disk, down to the bytes of its segments, and its records are lost when it
+    /// is dropped. Read it through [`reader`](Self::reader) or [`tail`](Self::tail).
+    ///
+    /// # Errors
+    ///
+    /// Same as [`open`](Self::open); in practice only configuration errors.
+    pub fn open_in_memory(config: Config) -> Result<Self> {
+        let config = Config {
+            storage: Arc::new(MemoryBackend::new()),
+            ..config
+        };
+        Self::open(MEMORY_LOG_PATH, config)
+    }
+
+    /// Checks the segments of `dir` against its manifest, if it has one, and
+    /// returns the log start offset and the segment expiries it records.
+    fn check_manifest(dir: &LogDir, config: &Config) -> Result<(u64, BTreeMap<u64, u64>)> {
+        let storage = &**dir.storage();
+        let mut start_offset = read_start_offset(storage, dir.path())?.unwrap_or(0);
+        let Some(manifest) = Manifest::read(storage, dir.path())? else {
+            return Ok((start_offset, BTreeMap::new()));
+        };
+        if !manifest.check(dir.segments())? {
+            event!(
+                warn,
+                listed = manifest.segments.len(),
+                found = dir.segments().len(),
+                "segments differ from the manifest after an interrupted operation; repairing"
+            );
+        }
+        if manifest.start_offset > start_offset {
+            // Never resurrect records the manifest already recorded as deleted.
+            event!(
+                warn,
+                start_offset,
+                manifest_start_offset = manifest.start_offset,
+                "log start behind the manifest; repairing"
+            );
+            start_offset = manifest.start_offset;
+            write_start_offset(storage, dir.path(), start_offset)?;
+        }
+        if manifest.config_fingerprint != config_fingerprint(config) {
+            event!(info, "configuration changed since the log was last opened");
+        }
+        let expiries = manifest
+            .segments
+            .iter()
+            .filter_map(|s| Some((s.base_offset, s.expires_at?)))
+            .collect();
+        Ok((start_offset, expiries))
+    }
+
+    /// Accounts for sealed segments having been added or deleted.
+    fn segments_changed(&mut self) -> Result<()> {
+        self.sealed_bytes = None;
+        self.write_manifest()
+    }
+
+    /// Rewrites the manifest to describe the log's current segments.
+    fn write_manifest(&self) -> Result<()> {
+        Manifest::describe(
+            self.segment_infos(),
+            self.start_offset,
+            &self.config,
+            &self.expiries,
+        )
+        .write(self.storage(), self.dir.path())
+    }
+
+    fn open_active_segment(
+        mut info: SegmentInfo,
+        encryption: Option<&Encryption>,
+    ) -> Result<ActiveSegment> {
+        let mut log_file = info.open_file(&info.log_path, OpenMode::Write)?;
+        let idx_file = info.open_file(&info.index_path(), OpenMode::Create)?;
+
+        // The active segment has no key filter; it gets one when sealed.
+        storage::delete_if_exists(&*info.storage, &key_filter::path(&info))?;
+        let mut current_size = log_file.size()?;
+        if info.footer.take().is_some() {
+            // The segment is writable again; later appends go where the footer was.
+            current_size -= FOOTER_LEN as u64;
+            log_file.set_len(current_size)?;
+        }
+
+        event!(
+            debug,
+            segment = info.base_offset,
+            bytes = current_size,
+            "active segment opened"
+        );
+        // next_offset, timestamps and the CRC are determined during recovery.
+        let next_offset = info.base_offset;
+        let cipher = encryption
+            .map(|e| SegmentCipher::load_or_create(&info, e))
+            .transpose()?;
+        Ok(ActiveSegment {
+            info,
+            log_file,
+            idx_file,
+            current_size,
+            next_offset,
+            cipher,
+            crc: None,
+            first_timestamp: None,
+            last_timestamp: None,
+            buffer: WriteBuffer::default(),
+            keys: None,
+            expiry: SegmentExpiry::Never,
+        })
+    }
+
+    fn create_segment(
+        dir: &LogDir,
+        base_offset: u64,
+        encryption: Option<&Encryption>,
+    ) -> Result<ActiveSegment> {
+        let id = SegmentId(base_offset);
+        let log_path = dir.path().join(id.log_filename());
+        let idx_path = log_path.with_extension("idx");
+
+        let info = SegmentInfo {
+            base_offset,
+            log_path,
+            footer: None,
+            storage: Arc::clone(dir.storage()),
+        };
+        let log_file = info.open_file(&info.log_path, OpenMode::CreateNew)?;
+        let idx_file = info.open_file(&idx_path, OpenMode::CreateNew)?;
+        let cipher = encryption
+            .map(|e| SegmentCipher::load_or_create(&info, e))
+            .transpose()?;
+        // The segment's records are only as durable as its directory entry.
+        dir.storage().sync_dir(dir.path())?;
+        event!(debug, segment = base_offset, "segment created");
+        Ok(ActiveSegment {
+            info,
+            log_file,
+            idx_file,
+            current_size: 0,
+            next_offset: base_offset,
+            cipher,
+            crc: Some(crc32fast::Hasher::new()),
+            first_timestamp: None,
+            last_timestamp: None,
+            buffer: WriteBuffer::default(),
+            keys: Some(Vec::new()),
+            expiry: SegmentExpiry::Empty,
+        })
+    }
+
+    /// Appends a payload to the log and returns its assigned offset.
+    ///
+    /// Payloads larger than [`MAX_CHUNK_LEN`] are split into chunk frames that
+    /// share the offset and are reassembled by readers.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if the payload is too large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append(&mut self, payload: &[u8]) -> Result<u64> {
+        self.append_value(self.frame(now_millis()), &[], None, payload, None)
+    }
+
+    /// Appends a payload stamped with `timestamp` (milliseconds since the Unix
+    /// epoch) instead of the current time, and returns its assigned offset.
+    ///
+    /// Timestamps are stored by [`RecordFormat::V2`] frames only.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if [`Config::format`] is not [`RecordFormat::V2`]
+    ///   or the payload is too large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_with_timestamp(&mut self, payload: &[u8], timestamp: u64) -> Result<u64> {
+        if !self.config.format.stores_headers() {
+            return Err(Error::InvalidFormat(
+                "record timestamps require RecordFormat::V2".into(),
+            ));
+        }
+        self.append_value(self.frame(timestamp), &[], None, payload, None)
+    }
+
+    /// Appends a payload with user headers (small name/value metadata such as
+    /// trace IDs) and returns its assigned offset. Readers expose the headers as
+    /// [`Record::headers`]; they are stored uncompressed and unencrypted.
+    ///
+    /// Headers are stored by [`RecordFormat::V2`] frames only.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if [`Config::format`] is not [`RecordFormat::V2`],
+    ///   or the headers or payload are too large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_with_headers(
+        &mut self,
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> Result<u64> {
+        if !self.config.format.stores_headers() {
+            return Err(Error::InvalidFormat(
+                "record headers require RecordFormat::V2".into(),
+            ));
+        }
+        if let Some((name, _)) = headers
+            .iter()
+            .find(|(name, _)| [PRODUCER_HEADER, TERM_HEADER, EXPIRY_HEADER].contains(name))
+        {
+            return Err(Error::InvalidFormat(format!(
+                "header name {name} is reserved"
+            )));
+        }
+        let block = encode_headers(headers)?;
+        self.append_value(self.frame(now_millis()), &block, None, payload, None)
+    }
+
+    /// Appends a keyed record and returns its assigned offset.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if the key or record is too large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_keyed(&mut self, key: &[u8], value: &[u8]) -> Result<u64> {
+        self.append_value(self.frame(now_millis()), &[], Some(key), value, None)
+    }
+
+    /// Appends a payload made of the concatenation of `payload`, such as a
+    /// message still spread over network buffers, and returns its offset.
+    ///
+    /// The frame header is encoded on its own and written with the buffers in
+    /// one vectored write, so the payload is never copied into a frame. Values
+    /// that are compressed, encrypted, or long enough to be split into chunks
+    /// are transformed anyway and take the [`append`](Self::append) path.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`append`](Self::append).
+    pub fn append_vectored(&mut self, payload: &[IoSlice<'_>]) -> Result<u64> {
+        self.check_unreserved()?;
+        let len = payload.iter().map(|buf| buf.len()).sum();
+        if self.config.compression.is_some()
+            || self.config.encryption.is_some()
+            || len > self.chunk_len
+        {
+            let mut value = Vec::with_capacity(len);
+            for buf in payload {
+                value.extend_from_slice(buf);
+            }
+            return self.append(&value);
+        }
+        let started = Instant::now();
+        let frame = RecordHeader {
+            offset: self.active_segment.next_offset,
+            ..self.frame(now_millis())
+        };
+        let mut bufs: Vec<&[u8]> = payload.iter().map(|buf| &**buf).collect();
+        let header = encode_frame_header(frame, frame.flags | self.config.checksum.flag(), &bufs)?;
+        bufs.insert(0, &header);
+        let frame_len = (header.len() + len) as u64;
+        self.make_room(frame_len, 1)?;
+        if self.needs_roll(frame_len) {
+            self.roll()?;
+        }
+        let offset = self.append_frames(&bufs, 1, frame.timestamp)?;
+        self.active_segment.expiry = self.active_segment.expiry.add(None);
+        self.observe_append(1, &bufs, started);
+        Ok(offset)
+    }
+
+    /// Appends a payload held in a [`bytes::Bytes`] without copying it into a
+    /// frame; see [`append_vectored`](Self::append_vectored).
+    ///
+    /// # Errors
+    ///
+    /// Same as [`append`](Self::append).
+    #[cfg(feature = "bytes")]
+    pub fn append_bytes(&mut self, payload: &bytes::Bytes) -> Result<u64> {
+        self.append_vectored(&[IoSlice::new(payload)])
+    }
+
+    /// Appends several payloads as a single batch frame (see
+    /// [`encode_batch`](crate::encode_batch)) and returns the assigned range.
+    ///
+    /// Unlike [`append_batch`](Self::append_batch), the payloads share one header
+    /// and checksum, which saves space for tiny records; compression and
+    /// encryption apply to the batch as a whole. Readers expand the frame back
+    /// into individual records.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `payloads` is empty, holds more than
+    ///   `u16::MAX` payloads, or is too large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_batch_frame(&mut self, payloads: &[&[u8]]) -> Result<RangeInclusive<u64>> {
+        let frame = self.frame(now_millis());
+        let (count, body) = pack_batch(frame.version, payloads)?;
+        let frame = frame.with_flags(FLAG_BATCH).with_batch_count(count);
+        let first = self.append_value(frame, &[], None, &body, None)?;
+        Ok(first..=first + (u64::from(count) - 1))
+    }
+
+    /// Appends a record received from a leader log, keeping its offset, for
+    /// follower replicas. `header` and `payload` are a frame as split by
+    /// [`decode_record`](crate::decode_record); a batch frame appends each of its
+    /// records. Returns the offset of the first record appended.
+    ///
+    /// The frame must continue this log exactly: its offset must equal
+    /// [`next_offset`](Self::next_offset). Its timestamp, headers and key are kept,
+    /// while compression, encryption and the checksum algorithm follow this log's
+    /// [`Config`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetGap`] or [`Error::OffsetRegression`] if the frame's offset
+    ///   is above or below the next offset.
+    /// - [`Error::Corruption`] or [`Error::ChecksumMismatch`] if `payload` does not
+    ///   match `header`.
+    /// - [`Error::InvalidFormat`] if the frame is encrypted or a chunk of a larger
+    ///   record, or carries a timestamp or headers and this log does not write
+    ///   [`RecordFormat::V2`].
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_replicated(&mut self, header: &RecordHeader, payload: &[u8]) -> Result<u64> {
+        let expected = self.active_segment.next_offset;
+        if header.offset > expected {
+            return Err(Error::OffsetGap {
+                expected,
+                found: header.offset,
+            });
+        }
+        if header.offset < expected {
+            return Err(Error::OffsetRegression {
+                expected,
+                found: header.offset,
+            });
+        }
+        if payload.len() != header.payload_len as usize {
+            return Err(Error::Corruption(format!(
+                "payload is {} bytes but its header says {}",
+                payload.len(),
+                header.payload_len
+            )));
+        }
+        header.validate_checksum(payload)?;
+        if header.is_encrypted() || header.is_continued() {
+            return Err(Error::InvalidFormat(
+                "replicated frames must be unencrypted and unchunked".into(),
+            ));
+        }
+        for record in Record::from_frame(header, payload.to_vec(), None)? {
+            if let Some(marker) = record.marker {
+                self.append_marker(marker, record.timestamp)?;
+                continue;
+            }
+            let headers: Vec<(&str, &[u8])> = record
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_slice()))
+                .collect();
+            self.append_parts(
+                record.timestamp,
+                &headers,
+                record.key.as_deref(),
+                &record.payload,
+            )?;
+        }
+        Ok(expected)
+    }
+
+    /// Appends a record with an optional timestamp (default: now), headers and
+    /// key, and returns its assigned offset.
+    pub(crate) fn append_parts(
+        &mut self,
+        timestamp: Option<u64>,
+        headers: &[(&str, &[u8])],
+        key: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<u64> {
+        if !self.config.format.stores_headers() && (timestamp.is_some() || !headers.is_empty()) {
+            return Err(Error::InvalidFormat(
+                "record timestamps and headers require RecordFormat::V2".into(),
+            ));
+        }
+        let block = encode_headers(headers)?;
+        let frame = self.frame(timestamp.unwrap_or_else(now_millis));
+        let expires_at = headers
+            .iter()
+            .find(|(name, _)| *name == EXPIRY_HEADER)
+            .and_then(|(_, value)| decode_expiry(value));
+        let offset = self.append_value(frame, &block, key, value, expires_at)?;
+        if let Some(producers) = &mut self.producers {
+            for (_, value) in headers.iter().filter(|(name, _)| *name == PRODUCER_HEADER) {
+                producers.observe(offset, value);
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Fails if offsets are reserved: appends would take the next one, which
+    /// belongs to the first reservation.
+    fn check_unreserved(&self) -> Result<()> {
+        if let Some(first) = self.reserved.first() {
+            return Err(Error::InvalidFormat(format!(
+                "offsets from {first} are reserved; commit or abandon them first"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the reservations not yet written.
+    pub(crate) fn reservations(&mut self) -> &mut Reservations {
+        &mut self.reserved
+    }
+
+    /// Returns the record format this log writes.
+    pub(crate) const fn format(&self) -> RecordFormat {
+        self.config.format
+    }
+
+    /// Returns the backend holding the log's files.
+    pub(crate) fn storage(&self) -> &dyn Backend {
+        &*self.config.storage
+    }
+
+    /// Returns the idempotent producer state, loading it on first use.
+    pub(crate) fn producer_state(&mut self) -> Result<&mut ProducerState> {
+        let state = match self.producers.take() {
+            Some(state) => state,
+            None => ProducerState::load(self, self.active_segment.next_offset)?,
+        };
+        Ok(self.producers.insert(state))
+    }
+
+    /// Opens a reader over this log's directory, in the log's storage backend,
+    /// that decrypts with the log's keys and reports to its observer.
+    ///
+    /// While this log is open, the reader stops at the records it has written
+    /// (or fsynced, with [`LogReader::with_durable_only`]), and each iterator at
+    /// those as of its creation, so it never sees a record half-written or
+    /// appended after it started.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`LogReader::open`].
+    pub fn reader(&self) -> Result<LogReader> {
+        let mut reader =
+            LogReader::open_with_storage(self.dir.path(), Arc::clone(&self.config.storage))?;
+        if let Some(encryption) = &self.config.encryption {
+            reader = reader.with_key_provider(Arc::new(encryption.clone()));
+        }
+        if let Some(observer) = &self.config.observer {
+            reader = reader.with_observer(Arc::clone(observer));
+        }
+        if let Some(cache) = &self.config.archive {
+            reader = reader.with_archive(Arc::clone(cache))?;
+        }
+        Ok(reader.with_watermarks(Arc::clone(&self.written), Arc::clone(&self.durable)))
+    }
+
+    /// Lowers the chunk length so tests can produce chunked records cheaply.
+    #[cfg(test)]
+    pub(crate) fn set_chunk_len(&mut self, chunk_len: usize) {
+        self.chunk_len = chunk_len;
+    }
+
+    /// Returns the header template for appended records: [`Config::format`]'s
+    /// version and `timestamp`. The offset is filled in when encoding.
+    const fn frame(&self, timestamp: u64) -> RecordHeader {
+        RecordHeader::new(0, 0, 0)
+            .with_timestamp(timestamp)
+            .with_version(self.config.format.version())
+    }
+
+    /// Encodes `frame` at the next offset and appends it, rolling first if the
+    /// active segment is full. All chunks of a record land in one segment.
+    fn append_value(
+        &mut self,
+        frame: RecordHeader,
+        headers: &[u8],
+        key: Option<&[u8]>,
+        value: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        self.check_unreserved()?;
+        let started = Instant::now();
+        let at_next = |log: &Self| RecordHeader {
+            offset: log.active_segment.next_offset,
+            ..frame
+        };
+        let mut frames = self.encode(at_next(self), headers, key, value)?;
+        let len = frames.iter().map(|f| f.len() as u64).sum();
+        self.make_room(len, frame.record_count())?;
+        if self.needs_roll(len) {
+            self.roll()?;
+            if self.active_segment.cipher.is_some() {
+                // Values are encrypted with the data key of the segment they land in.
+                frames = self.encode(at_next(self), headers, key, value)?;
+            }
+        }
+        let offset = self.append_frames(&frames, frame.record_count(), frame.timestamp)?;
+        if let (Some(keys), Some(key)) = (&mut self.active_segment.keys, key) {
+            keys.push(KeyHash::of(key));
+        }
+        self.active_segment.expiry = self.active_segment.expiry.add(expires_at);
+        self.observe_append(frame.record_count(), &frames, started);
+        Ok(offset)
+    }
+
+    /// Reports an append of `records` records as `frames`, begun at `started`.
+    fn observe_append(&self, records: u64, frames: &[impl AsRef<[u8]>], started: Instant) {
+        if let Some(observer) = &self.config.observer {
+            let bytes = frames.iter().map(|f| f.as_ref().len() as u64).sum();
+            observer.on_append(records, bytes, started.elapsed());
+        }
+    }
+
+    /// Encodes a record from `frame`, splitting a value longer than the chunk
+    /// length into [`FLAG_CONTINUED`] chunks; only the first carries the headers
+    /// block and key.
+    fn encode(
+        &self,
+        frame: RecordHeader,
+        headers: &[u8],
+        key: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<Vec<Vec<u8>>> {
+        if value.len() <= self.chunk_len || frame.is_batch() {
+            return self
+                .encode_frame(frame, headers, key, value)
+                .map(|f| vec![f]);
+        }
+        let mut chunks = value.chunks(self.chunk_len).peekable();
+        let mut frames = Vec::new();
+        let (mut headers, mut key) = (headers, key);
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_some() {
+                frame.flags | FLAG_CONTINUED
+            } else {
+                frame.flags
+            };
+            let frame = frame.with_flags(flags);
+            frames.push(self.encode_frame(frame, headers, key.take(), chunk)?);
+            headers = &[];
+        }
+        Ok(frames)
+    }
+
+    /// Encodes one frame with [`Config::checksum`], compressing the value per
+    /// [`Config::compression`] and then encrypting it with the active segment's
+    /// data key, if any.
+    fn encode_frame(
+        &self,
+        frame: RecordHeader,
+        headers: &[u8],
+        key: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<Vec<u8>> {
+        let (flags, stored) = match &self.config.compression {
+            Some(compression) => compression.apply(value)?,
+            None => (FLAGS_NONE, Cow::Borrowed(value)),
+        };
+        let flags = frame.flags | flags | self.config.checksum.flag();
+        match &self.active_segment.cipher {
+            Some(cipher) => {
+                let sealed = cipher.encrypt(frame.offset, &stored)?;
+                encode_frame_as(frame, flags | FLAG_ENCRYPTED, headers, key, &sealed)
+            }
+            None => encode_frame_as(frame, flags, headers, key, &stored),
+        }
+    }
+
+    /// Returns true if `len` more bytes do not fit in a non-empty active segment.
+    /// Never true while a transaction is open, which keeps it in one segment.
+    const fn needs_roll(&self, len: u64) -> bool {
+        self.active_segment.current_size > 0
+            && self.active_segment.current_size + len > self.config.max_segment_bytes
+            && self.txn.is_none()
+    }
+
+    /// Writes the encoded frames of one record or batch (numbered from the current
+    /// next offset) holding `records` records, and an index entry per record, to
+    /// the active segment.
+    fn append_frames(
+        &mut self,
+        frames: &[impl AsRef<[u8]>],
+        records: u64,
+        timestamp: Option<u64>,
+    ) -> Result<u64> {
+        let offset = self.active_segment.next_offset;
+        let pos = self.active_segment.current_size;
+
+        // Every record of a batch frame points at the frame.
+        let index: Vec<u8> = (offset..offset + records)
+            .flat_map(|record| [record.to_le_bytes(), pos.to_le_bytes()])
+            .flatten()
+            .collect();
+        self.write_records(frames, &index, records, timestamp)?;
+
+        if self.config.fsync == FsyncPolicy::Always {
+            self.flush()?;
+        }
+
+        Ok(offset)
+    }
+
+    /// Appends a payload and returns an [`AppendAck`] that resolves once the record
+    /// is durable, without forcing an fsync now.
+    ///
+    /// The ack resolves on the next [`flush`](Self::flush), segment roll, or
+    /// policy-driven fsync that covers the record.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`append`](Self::append).
+    pub fn append_with_ack(&mut self, payload: &[u8]) -> Result<AppendAck> {
+        let offset = self.append(payload)?;
+        Ok(AppendAck::new(offset, Arc::clone(&self.durable)))
+    }
+
+    /// Follows the log from `offset`: the returned [`Tail`] yields the records
+    /// already written at or after `offset`, then blocks until this log appends
+    /// more. Offsets below the log start are skipped.
+    ///
+    /// Records are visible to tails as soon as they are written, before they are
+    /// necessarily durable. Iteration ends once the tail has caught up after this
+    /// log is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the log directory.
+    pub fn tail(&self, offset: u64) -> Result<Tail> {
+        Ok(Tail::new(self.reader()?, Arc::clone(&self.written), offset))
+    }
+
+    /// Returns the durable watermark, for tails that only yield durable records.
+    pub(crate) fn durable_watermark(&self) -> Arc<Watermark> {
+        Arc::clone(&self.durable)
+    }
+
+    /// Returns the durable watermark: every offset below it has been fsynced.
+    #[must_use]
+    pub fn durable_offset(&self) -> u64 {
+        self.durable.end()
+    }
+
+    /// Returns the committed watermark: every offset below it was marked
+    /// committed by [`commit`](Self::commit) and can no longer be truncated.
+    #[must_use]
+    pub const fn committed_offset(&self) -> u64 {
+        self.committed
+    }
+
+    /// Marks every record below `end` committed, flushing first if they are not
+    /// all durable, and persists the watermark so it survives restarts.
+    ///
+    /// Committed records are never discarded by [`truncate_after`](Self::truncate_after),
+    /// and readers set to [`with_committed_only`](LogReader::with_committed_only)
+    /// read only committed records. In a replicated setup, commit a record once
+    /// enough replicas hold it. Values at or below the current watermark are a no-op.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetOutOfRange`] if `end` is beyond the next offset to be written.
+    /// - I/O errors from flushing or persisting the watermark.
+    pub fn commit(&mut self, end: u64) -> Result<()> {
+        if end > self.active_segment.next_offset {
+            return Err(self.out_of_range(end));
+        }
+        if end <= self.committed {
+            return Ok(());
+        }
+        if end > self.durable.end() {
+            self.flush()?;
+        }
+        write_committed_offset(self.storage(), self.dir.path(), end)?;
+        self.committed = end;
+        Ok(())
+    }
+
+    /// Writes a begin marker and returns a [`Transaction`] whose appends become
+    /// visible to [read-committed](crate::Isolation::ReadCommitted) readers
+    /// together, once it commits; see the [`txn`](crate::txn) module.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing the marker, or from aborting a transaction
+    /// left open earlier.
+    pub fn begin_txn(&mut self) -> Result<Transaction<'_>> {
+        self.abort_open_txn()?;
+        let begin = self.append_marker(TxnMarker::Begin, None)?;
+        Ok(Transaction::new(self, begin))
+    }
+
+    /// Appends a transaction marker as a control record, opening or closing the
+    /// current transaction, and returns its offset. `timestamp` defaults to now.
+    pub(crate) fn append_marker(
+        &mut self,
+        marker: TxnMarker,
+        timestamp: Option<u64>,
+    ) -> Result<u64> {
+        self.check_unreserved()?;
+        let started = Instant::now();
+        let frame = self.frame(timestamp.unwrap_or_else(now_millis));
+        let encode = |log: &Self| {
+            let header = RecordHeader {
+                offset: log.active_segment.next_offset,
+                ..frame
+            };
+            let flags = FLAG_CONTROL | log.config.checksum.flag();
+            encode_frame_as(header, flags, &[], None, &marker.encode())
+        };
+        let mut frames = vec![encode(self)?];
+        if self.needs_roll(frames[0].len() as u64) {
+            self.roll()?;
+            frames = vec![encode(self)?];
+        }
+        let offset = self.append_frames(&frames, 1, frame.timestamp)?;
+        self.observe_append(1, &frames, started);
+        self.txn = match marker {
+            TxnMarker::Begin => Some(offset),
+            TxnMarker::Commit | TxnMarker::Abort => None,
+        };
+        Ok(offset)
+    }
+
+    /// Aborts the open transaction, if any: one whose [`Transaction`] failed to
+    /// finish, or found open by recovery.
+    fn abort_open_txn(&mut self) -> Result<()> {
+        if self.txn.is_some() {
+            event!(warn, begin = self.txn, "aborting unfinished transaction");
+            self.append_marker(TxnMarker::Abort, None)?;
+        }
+        Ok(())
+    }
+
+    /// Appends several payloads with contiguous offsets and returns the assigned range.
+    ///
+    /// All records are encoded up front and written to the segment with a single
+    /// vectored write; their index entries follow in one write. The whole batch is
+    /// placed in one segment, rolling first if it would not fit in the active one.
+    ///
+    /// The batch is atomic with respect to crashes: every frame but the last is
+    /// marked with [`BATCH_CONTINUES`], and recovery
+    /// discards a batch whose last frame did not reach the disk.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `payloads` is empty or any payload is too large.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_batch(&mut self, payloads: &[&[u8]]) -> Result<RangeInclusive<u64>> {
+        if payloads.is_empty() {
+            return Err(Error::InvalidFormat(
+                "append_batch needs at least one payload".into(),
+            ));
+        }
+        self.check_unreserved()?;
+
+        let started = Instant::now();
+        let first = self.active_segment.next_offset;
+        let frame = self.frame(now_millis());
+        let mut frames = self.encode_batch(first, frame, payloads)?;
+        let batch_len: u64 = frames.iter().flatten().map(|f| f.len() as u64).sum();
+        self.make_room(batch_len, frames.len() as u64)?;
+
+        if self.needs_roll(batch_len) {
+            self.roll()?;
+            if self.active_segment.cipher.is_some() {
+                frames = self.encode_batch(first, frame, payloads)?;
+            }
+        }
+
+        let mut index = Vec::with_capacity(frames.len() * INDEX_ENTRY_LEN);
+        let mut pos = self.active_segment.current_size;
+        for (offset, chunks) in (first..).zip(&frames) {
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&pos.to_le_bytes());
+            pos += chunks.iter().map(|f| f.len() as u64).sum::<u64>();
+        }
+
+        let last = first + (frames.len() as u64 - 1);
+        let frames: Vec<Vec<u8>> = frames.into_iter().flatten().collect();
+        self.write_records(&frames, &index, last + 1 - first, frame.timestamp)?;
+        self.active_segment.expiry = self.active_segment.expiry.add(None);
+
+        if self.config.fsync == FsyncPolicy::Always {
+            self.flush()?;
+        }
+
+        self.observe_append(last + 1 - first, &frames, started);
+        Ok(first..=last)
+    }
+
+    /// Encodes each payload at its offset, returning the frames of each record.
+    /// All but the last record are marked as continuing the batch.
+    fn encode_batch(
+        &self,
+        first: u64,
+        frame: RecordHeader,
+        payloads: &[&[u8]],
+    ) -> Result<Vec<Vec<Vec<u8>>>> {
+        let last = first + (payloads.len() as u64 - 1);
+        (first..)
+            .zip(payloads)
+            .map(|(offset, payload)| {
+                let mark = if offset < last { BATCH_CONTINUES } else { 0 };
+                let frame = RecordHeader {
+                    offset,
+                    ..frame.with_batch_count(mark)
+                };
+                self.encode(frame, &[], None, payload)
+            })
+            .collect()
+    }
+
+    /// Writes the frames of `records` records appended at `timestamp`, and their
+    /// `index` entries, to the active segment, or buffers them if
+    /// [`Config::write_buffer`] is set.
+    fn write_records(
+        &mut self,
+        frames: &[impl AsRef<[u8]>],
+        index: &[u8],
+        records: u64,
+        timestamp: Option<u64>,
+    ) -> Result<()> {
+        if let Some(policy) = &self.config.write_buffer {
+            let segment = &mut self.active_segment;
+            segment.buffer.push(frames, index, records);
+            segment.advance(frames, records, timestamp);
+            if segment.buffer.is_due(policy) {
+                self.write_buffered()?;
+            }
+            return Ok(());
+        }
+        let segment = &mut self.active_segment;
+        Self::write_frames(
+            segment,
+            frames,
+            segment.current_size,
+            self.config.preallocate,
+        )?;
+        segment.idx_file.seek(SeekFrom::End(0))?;
+        segment.idx_file.write_all(index)?;
+        segment.advance(frames, records, timestamp);
+        self.written.advance(segment.next_offset);
+        Ok(())
+    }
+
+    /// Writes the records held by the active segment's write buffer, if any.
+    fn write_buffered(&mut self) -> Result<()> {
+        let segment = &mut self.active_segment;
+        if segment.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = std::mem::take(&mut segment.buffer);
+        let pos = segment.current_size - buffer.frames.len() as u64;
+        let frames = std::slice::from_ref(&buffer.frames);
+        Self::write_frames(segment, frames, pos, self.config.preallocate)?;
+        segment.idx_file.seek(SeekFrom::End(0))?;
+        segment.idx_file.write_all(&buffer.index)?;
+        buffer.clear();
+        segment.buffer = buffer;
+        self.written.advance(segment.next_offset);
+        Ok(())
+    }
+
+    /// Writes `frames` at position `pos` of `segment`, the end of its records:
+    /// the end of its file unless [`Config::preallocate`] reserved space past them.
+    fn write_frames(
+        segment: &mut ActiveSegment,
+        frames: &[impl AsRef<[u8]>],
+        pos: u64,
+        preallocate: bool,
+    ) -> Result<()> {
+        if preallocate {
+            segment.log_file.write_all_at(frames, pos)?;
+        } else {
+            segment.log_file.append_all(frames)?;
+        }
+        Ok(())
+    }
+
+    /// Reserves `max_segment_bytes` for the active segment if
+    /// [`Config::preallocate`] is set.
+    fn preallocate_active(&mut self) -> Result<()> {
+        if self.config.preallocate {
+            let segment = &mut self.active_segment;
+            segment.log_file.allocate(self.config.max_segment_bytes)?;
+            event!(
+                debug,
+                segment = segment.info.base_offset,
+                bytes = self.config.max_segment_bytes,
+                "segment preallocated"
+            );
+        }
+        Ok(())
+    }
+
+    /// Releases the space preallocated past the active segment's last record.
+    fn trim_active(&mut self) -> Result<()> {
+        if self.config.preallocate {
+            let segment = &mut self.active_segment;
+            segment.log_file.set_len(segment.current_size)?;
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(segment = self.active_segment.info.base_offset))
+    )]
+    fn roll(&mut self) -> Result<()> {
+        // The outgoing segment is never written again; make it durable before sealing.
+        self.flush()?;
+        // Written before the footer, so a sealed segment never has a stale filter.
+        self.write_key_filter()?;
+        let footer = self.active_segment.footer()?;
+        self.trim_active()?;
+        write_footer(&mut self.active_segment.log_file, &footer)?;
+        let next_offset = self.active_segment.next_offset;
+        let new_segment =
+            Self::create_segment(&self.dir, next_offset, self.config.encryption.as_ref())?;
+        let mut old = std::mem::replace(&mut self.active_segment, new_segment);
+        self.preallocate_active()?;
+        if self.config.evict_sealed {
+            // Only a hint: a failure leaves the pages cached, nothing worse.
+            let _ = old.log_file.advise(0, 0, Advice::DontNeed);
+            let _ = old.idx_file.advise(0, 0, Advice::DontNeed);
+        }
+        event!(
+            info,
+            segment = old.info.base_offset,
+            records = footer.record_count,
+            bytes = old.current_size + FOOTER_LEN as u64,
+            next_segment = next_offset,
+            "segment rolled"
+        );
+        old.info.footer = Some(footer);
+        match old.expiry {
+            SegmentExpiry::At(expires_at) => self.expiries.insert(old.info.base_offset, expires_at),
+            SegmentExpiry::Empty | SegmentExpiry::Never => {
+                self.expiries.remove(&old.info.base_offset)
+            }
+        };
+        self.sealed.push(old.info);
+        self.segments_changed()?;
+        if let Some(producers) = &self.producers {
+            producers.write_snapshot(self.storage(), self.dir.path(), next_offset)?;
+        }
+        if let Some(observer) = &self.config.observer {
+            observer.on_segment_roll(next_offset);
+        }
+        Ok(())
+    }
+
+    /// Writes the key filter of the active segment, about to be sealed, if any
+    /// of its records has a key.
+    fn write_key_filter(&mut self) -> Result<()> {
+        let base_offset = self.active_segment.info.base_offset;
+        let keys = if let Some(keys) = self.active_segment.keys.take() {
+            keys
+        } else {
+            // Reopened with records whose keys were not seen: reread them.
+            let mut keys = Vec::new();
+            for record in self.reader()?.iter_from(base_offset) {
+                let record = record?;
+                if record.offset >= self.active_segment.next_offset {
+                    break;
+                }
+                keys.extend(record.key.as_deref().map(KeyHash::of));
+            }
+            keys
+        };
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let filter = KeyFilter::new(&keys);
+        filter.write(&self.active_segment.info)?;
+        self.key_filters.insert(base_offset, Some(filter));
+        Ok(())
+    }
+
+    /// Makes `key` (identified by `key_id`) the master key for new segments.
+    ///
+    /// The active segment is sealed first if it holds any records, so every
+    /// record appended afterwards is encrypted under a data key wrapped by `key`.
+    /// Existing segments are not rewritten: they stay wrapped by their original
+    /// key, which this log keeps in memory. After reopening, older key IDs must be
+    /// resolvable through [`Encryption::with_provider`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if [`Config::encryption`] is unset.
+    /// - I/O errors from rolling the segment or writing its key file.
+    pub fn rotate_key(&mut self, key_id: KeyId, key: MasterKey) -> Result<()> {
+        let Some(encryption) = self.config.encryption.as_mut() else {
+            return Err(Error::InvalidFormat(
+                "rotate_key requires Config::encryption".into(),
+            ));
+        };
+        encryption.rotate(key_id, key);
+        let segment = &mut self.active_segment;
+        if segment.current_size > 0 {
+            return self.roll();
+        }
+        // Nothing has been encrypted under the empty active segment's data key yet;
+        // replace it with one wrapped by the new key.
+        if segment
+            .cipher
+            .as_ref()
+            .is_some_and(|c| c.key_id() != key_id)
+        {
+            segment.info.storage.delete(&key_path(&segment.info))?;
+            segment.cipher = Some(SegmentCipher::load_or_create(&segment.info, encryption)?);
+        }
+        Ok(())
+    }
+
+    /// Flushes all pending writes to disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from syncing the active segment and index files.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_buffered()?;
+        let started = Instant::now();
+        self.active_segment.log_file.fsync()?;
+        self.active_segment.idx_file.fsync()?;
+        if let Some(observer) = &self.config.observer {
+            observer.on_fsync(started.elapsed());
+        }
+        event!(
+            debug,
+            segment = self.active_segment.info.base_offset,
+            durable_offset = self.active_segment.next_offset,
+            elapsed = ?started.elapsed(),
+            "fsync"
+        );
+        // Published only once fsynced, so no reader ever stops inside a record
+        // that a crash could still tear.
+        self.durable.advance(self.active_segment.next_offset);
+        self.publish_durable()?;
+        self.last_flush = Some(clock::system_now());
+        Ok(())
+    }
+
+    /// Publishes the durable watermark to readers in other processes.
+    fn publish_durable(&mut self) -> Result<()> {
+        let storage = &**self.dir.storage();
+        let end = self.durable.end();
+        if let Some(file) = &mut self.durable_file {
+            return file.publish(storage, end);
+        }
+        self.durable_file = Some(DurableOffsetFile::create(storage, self.dir.path(), end)?);
+        Ok(())
+    }
+
+    /// Flushes the log and records a clean shutdown, so that the next
+    /// [`open`](Self::open) can skip scanning the active segment.
+    ///
+    /// Dropping a log without closing it is always safe; the next open then
+    /// validates the active segment from its start.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from flushing, reading the active segment, or writing
+    /// the shutdown marker.
+    pub fn close(mut self) -> Result<()> {
+        self.abort_open_txn()?;
+        self.flush()?;
+        if let Some(producers) = &self.producers {
+            producers.write_snapshot(
+                self.storage(),
+                self.dir.path(),
+                self.active_segment.next_offset,
+            )?;
+        }
+        let summary = self.active_segment.footer()?;
+        // A clean shutdown is only trusted if the file ends at the last record.
+        self.trim_active()?;
+        let state = CleanShutdown {
+            log_len: self.active_segment.current_size,
+            summary,
+        };
+        write_clean_shutdown(self.storage(), self.dir.path(), &state)
+    }
+
+    /// Scans every segment, sealed and active, and reports all damage found:
+    /// invalid headers, checksum mismatches, gaps or repeats in offsets, torn
+    /// tails, index entries that disagree with the records, and sealed segments
+    /// that no longer match their footer.
+    ///
+    /// Verification only reads; it never repairs. Scanning stops within a segment
+    /// at an invalid header, since the frames after it cannot be located.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading segment or index files.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let segments: Vec<SegmentInfo> = self
+            .sealed
+            .iter()
+            .chain([&self.active_segment.info])
+            .cloned()
+            .collect();
+        verify_segments(&segments)
+    }
+
+    /// Returns the format version of every frame in the log, such as `{1, 2}` for a
+    /// log that switched from [`RecordFormat::V1`] to [`RecordFormat::V2`].
+    ///
+    /// Changing [`Config::format`] never rewrites existing segments: they keep the
+    /// versions they were written with, and readers decode each frame by its own
+    /// version byte (see [`SUPPORTED_VERSIONS`](crate::codec::SUPPORTED_VERSIONS)).
+    /// This reads every frame header, but no bodies. A version this build cannot
+    /// decode, left by a newer release, is reported too; the frames after it in
+    /// its segment cannot be located and are not. Frames still held by
+    /// [`Config::write_buffer`] are not on disk yet and not counted.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading segment files.
+    pub fn format_versions(&self) -> Result<BTreeSet<u8>> {
+        let mut versions = BTreeSet::new();
+        for info in self.sealed.iter().chain([&self.active_segment.info]) {
+            info.frame_versions(&mut versions)?;
+        }
+        Ok(versions)
+    }
+
+    /// Summarises the log's segments, offsets and on-disk size.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading segment or index file metadata.
+    pub fn stats(&self) -> Result<LogStats> {
+        let active = &self.active_segment;
+        let mut index_bytes = active.idx_file.size()?;
+        let mut disk_bytes = active.log_file.size()? + index_bytes;
+        for info in &self.sealed {
+            let bytes = info.disk_bytes()?;
+            disk_bytes += bytes;
+            index_bytes += bytes - info.open_file(&info.log_path, OpenMode::Read)?.size()?;
+        }
+        Ok(LogStats {
+            segments: self.sealed.len() + 1,
+            disk_bytes,
+            first_offset: self.first_offset(),
+            last_offset: self.last_offset(),
+            active_segment_bytes: active.current_size,
+            last_flush: self.last_flush,
+            index_bytes,
+        })
+    }
+
+    /// Returns the offset of the oldest record still retained by the log, or the
+    /// next offset if it holds none. Like [`last_offset`](Self::last_offset) and
+    /// [`next_offset`](Self::next_offset), this is kept in memory and never reads
+    /// a segment.
+    #[must_use]
+    pub fn first_offset(&self) -> u64 {
+        let first_segment = self
+            .sealed
+            .first()
+            .map_or(self.active_segment.info.base_offset, |s| s.base_offset);
+        first_segment.max(self.start_offset)
+    }
+
+    /// Discards every record below `offset`: deletes sealed segments that lie
+    /// entirely below it and persists `offset` as the new log start, so older
+    /// offsets read as [`Error::OffsetOutOfRange`] even if their segment remains.
+    ///
+    /// Returns the number of segments deleted. Offsets at or below the current
+    /// start are a no-op.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetOutOfRange`] if `offset` is beyond the next offset to be written.
+    /// - I/O errors from persisting the start offset or deleting segment files.
+    pub fn delete_before(&mut self, offset: u64) -> Result<usize> {
+        if offset > self.active_segment.next_offset {
+            return Err(self.out_of_range(offset));
+        }
+        if offset <= self.first_offset() {
+            return Ok(0);
+        }
+
+        // Persist the new start first so a crash mid-deletion cannot resurrect records.
+        write_start_offset(self.storage(), self.dir.path(), offset)?;
+        self.start_offset = offset;
+
+        let mut deleted = 0;
+        while self.sealed.len() > deleted {
+            let end = self
+                .sealed
+                .get(deleted + 1)
+                .map_or(self.active_segment.info.base_offset, |s| s.base_offset);
+            if end > offset {
+                break;
+            }
+            remove_segment_files(&self.sealed[deleted])?;
+            deleted += 1;
+        }
+        self.sealed.drain(..deleted);
+        self.segments_changed()?;
+        event!(
+            info,
+            offset,
+            deleted_segments = deleted,
+            "log start advanced"
+        );
+        Ok(deleted)
+    }
+
+    /// Discards every record with an offset greater than `offset`, so the next
+    /// append receives `offset + 1`.
+    ///
+    /// The segment holding `offset` is truncated (becoming the active segment,
+    /// on fresh copies of its files, if it was sealed) and all later segments are
+    /// deleted, newest first, so a crash part-way through leaves a contiguous log. A transaction whose
+    /// end marker is cut off is aborted. The result is fsynced before returning.
+    /// Outstanding [`AppendAck`]s for discarded offsets will only
+    /// resolve once new records at those offsets are flushed.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetOutOfRange`] if `offset` is below the log start.
+    /// - [`Error::InvalidFormat`] if `offset + 1` is below the
+    ///   [committed watermark](Self::commit), or `offset` and `offset + 1` share a
+    ///   batch frame (see [`append_batch_frame`](Self::append_batch_frame)) or
+    ///   were appended by one [`append_batch`](Self::append_batch) call.
+    /// - [`Error::Corruption`] if the truncation point cannot be located.
+    /// - I/O errors from truncating or deleting files.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip(self)))]
+    pub fn truncate_after(&mut self, offset: u64) -> Result<()> {
+        // Nothing lies after `u64::MAX`.
+        let Some(new_end) = offset
+            .checked_add(1)
+            .filter(|&end| end < self.active_segment.next_offset)
+        else {
+            return Ok(());
+        };
+        self.write_buffered()?;
+        if offset < self.first_offset() {
+            return Err(self.out_of_range(offset));
+        }
+        if new_end < self.committed {
+            return Err(Error::InvalidFormat(format!(
+                "cannot truncate after offset {offset}: offsets below {} are committed",
+                self.committed
+            )));
+        }
+        // Fail before touching any file if the cut would split a batch frame, or
+        // a batch that recovery would then discard whole.
+        let segment_of = |offset: u64| {
+            if offset >= self.active_segment.info.base_offset {
+                &self.active_segment.info
+            } else {
+                let idx = self.sealed.partition_point(|s| s.base_offset <= offset);
+                &self.sealed[idx - 1]
+            }
+        };
+        record_position(segment_of(new_end), new_end)?;
+        let last_kept = segment_of(offset);
+        if let Some(pos) = index_position(last_kept, offset)? {
+            let mut file = last_kept.open_file(&last_kept.log_path, OpenMode::Read)?;
+            file.seek(SeekFrom::Start(pos))?;
+            if read_header(&mut file)?.is_some_and(|h| h.continues_batch()) {
+                return Err(Error::InvalidFormat(format!(
+                    "offsets {offset} and {new_end} were appended by one append_batch call"
+                )));
+            }
+        }
+
+        // Reserved offsets lie past the end, so they go with the removed records.
+        self.reserved.clear();
+        // Pull readers back before cutting, so none reads a record being removed.
+        self.durable.truncate(new_end);
+        self.written.truncate(new_end);
+        if let Some(file) = &mut self.durable_file {
+            file.replace(&**self.dir.storage(), self.durable.end())?;
+        }
+
+        if offset < self.active_segment.info.base_offset {
+            let keep = self.sealed.partition_point(|s| s.base_offset <= offset);
+            remove_segment_files(&self.active_segment.info)?;
+            for info in self.sealed.drain(keep..).rev() {
+                remove_segment_files(&info)?;
+            }
+            let info = self.sealed.pop().ok_or_else(|| self.out_of_range(offset))?;
+            event!(
+                info,
+                segment = info.base_offset,
+                "deleted later segments; reopening sealed segment"
+            );
+            // Readers may have the sealed segment mapped (see `crate::mmap`), so
+            // its files are replaced with copies rather than cut in place.
+            for path in [info.log_path.clone(), info.index_path()] {
+                if let Some(contents) = storage::read_file(&*info.storage, &path)? {
+                    storage::write_atomic(&*info.storage, &path, &contents)?;
+                }
+            }
+            self.active_segment = Self::open_active_segment(info, self.config.encryption.as_ref())?;
+            self.active_segment.next_offset = self.segment_end_offset()?;
+        }
+
+        let pos = if new_end < self.active_segment.next_offset {
+            record_position(&self.active_segment.info, new_end)?
+        } else {
+            // `offset` was the last record of a previously sealed segment.
+            self.active_segment.current_size
+        };
+        let segment = &mut self.active_segment;
+        segment.log_file.set_len(pos)?;
+        segment
+            .idx_file
+            .set_len((new_end - segment.info.base_offset) * INDEX_ENTRY_LEN as u64)?;
+        segment.current_size = pos;
+        event!(
+            info,
+            segment = segment.info.base_offset,
+            bytes = pos,
+            next_offset = new_end,
+            "segment truncated"
+        );
+        // Rescan the shortened segment so its footer summary starts afresh.
+        self.recover()?;
+
+        self.producers = None;
+        discard_snapshot_past(self.storage(), self.dir.path(), new_end)?;
+        self.abort_open_txn()?;
+        self.flush()?;
+        self.preallocate_active()?;
+        self.segments_changed()
+    }
+
+    /// Returns the snapshot installed by [`install_snapshot`](Self::install_snapshot),
+    /// if any.
+    #[must_use]
+    pub const fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    pub(crate) fn set_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Discards every record and continues the log at `offset`, which lies past
+    /// its end.
+    ///
+    /// The new start is persisted first, and segments are deleted newest first,
+    /// so a crash part-way leaves a contiguous log that the caller finishes
+    /// resetting on the next open.
+    pub(crate) fn restart_at(&mut self, offset: u64) -> Result<()> {
+        self.reserved.clear();
+        write_start_offset(self.storage(), self.dir.path(), offset)?;
+        self.start_offset = offset;
+        remove_segment_files(&self.active_segment.info)?;
+        for info in self.sealed.drain(..).rev() {
+            remove_segment_files(&info)?;
+        }
+        self.active_segment =
+            Self::create_segment(&self.dir, offset, self.config.encryption.as_ref())?;
+        self.preallocate_active()?;
+        self.segments_changed()?;
+        if self.committed < offset {
+            write_committed_offset(self.storage(), self.dir.path(), offset)?;
+            self.committed = offset;
+        }
+        self.durable.advance(offset);
+        self.written.advance(offset);
+        self.publish_durable()?;
+        self.producers = None;
+        discard_snapshot_past(self.storage(), self.dir.path(), 0)?;
+        self.txn = None;
+        event!(info, offset, "log restarted at snapshot");
+        Ok(())
+    }
+
+    /// Returns the offset after the last index entry of the active segment.
+    fn segment_end_offset(&self) -> Result<u64> {
+        let entries = self.active_segment.idx_file.size()? / INDEX_ENTRY_LEN as u64;
+        Ok(self.active_segment.info.base_offset + entries)
+    }
+
+    fn out_of_range(&self, requested: u64) -> Error {
+        Error::OffsetOutOfRange {
+            requested,
+            earliest: self.first_offset(),
+            latest: self.last_offset(),
+        }
+    }
+
+    /// Deletes sealed segments that fall outside [`Config::retention`], oldest
+    /// first, and returns how many were deleted.
+    ///
+    /// A segment's age is the time since its last record was appended, taken from
+    /// its footer when known and otherwise from when its files were last modified.
+    /// The active segment is never deleted, so the log may stay above
+    /// `max_total_bytes` if the active segment alone exceeds it. Segments whose
+    /// records have all outlived their TTL are deleted too; see [`crate::ttl`].
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading metadata or deleting segment files.
+    pub fn enforce_retention(&mut self) -> Result<usize> {
+        let count = self.outside_retention(self.config.retention)?;
+        self.delete_oldest(count)?;
+        Ok(count)
+    }
+
+    /// Deletes the `count` oldest sealed segments.
+    pub(crate) fn delete_oldest(&mut self, count: usize) -> Result<()> {
+        for _ in 0..count {
+            let info = self.sealed.remove(0);
+            remove_segment_files(&info)?;
+            self.expiries.remove(&info.base_offset);
+            event!(
+                info,
+                segment = info.base_offset,
+                "retention deleted segment"
+            );
+        }
+        if count > 0 {
+            self.segments_changed()?;
+        }
+        Ok(())
+    }
+
+    /// Makes room under [`Config::quota`] for appending `len` bytes of frames
+    /// holding `records` records, their index entries, and the footer of the
+    /// active segment if the append rolls it. Deletes the oldest sealed segments
+    /// first if the quota's [`QuotaAction`] says so.
+    fn make_room(&mut self, len: u64, records: u64) -> Result<()> {
+        let Some(quota) = self.config.quota else {
+            return Ok(());
+        };
+        let footer = if self.needs_roll(len) { FOOTER_LEN } else { 0 };
+        let len = len + records * INDEX_ENTRY_LEN as u64 + footer as u64;
+        let mut used = self.disk_usage()?;
+        if used + len > quota.max_bytes && quota.on_exceeded == QuotaAction::EnforceRetention {
+            // Whatever the retention policy would delete anyway, then the
+            // oldest of the rest until the append fits.
+            let mut count = self.outside_retention(self.config.retention)?;
+            let mut freed = 0;
+            for info in &self.sealed[..count] {
+                freed += info.disk_bytes()?;
+            }
+            while used.saturating_sub(freed) + len > quota.max_bytes && count < self.sealed.len() {
+                freed += self.sealed[count].disk_bytes()?;
+                count += 1;
+            }
+            event!(
+                info,
+                used,
+                len,
+                deleted_segments = count,
+                "disk quota reached; enforcing retention"
+            );
+            self.delete_oldest(count)?;
+            used = self.disk_usage()?;
+        }
+        if used + len > quota.max_bytes {
+            return Err(Error::QuotaExceeded {
+                requested: len,
+                used,
+                limit: quota.max_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the log's `.log` and `.idx` files, counting the
+    /// active segment's preallocated space and buffered appends.
+    fn disk_usage(&mut self) -> Result<u64> {
+        let sealed = if let Some(bytes) = self.sealed_bytes {
+            bytes
+        } else {
+            let mut bytes = 0;
+            for info in &self.sealed {
+                bytes += info.disk_bytes()?;
+            }
+            *self.sealed_bytes.insert(bytes)
+        };
+        let active = &self.active_segment;
+        let mut log_bytes = active.current_size;
+        if self.config.preallocate {
+            log_bytes = log_bytes.max(self.config.max_segment_bytes);
+        }
+        let idx_bytes = (active.next_offset - active.info.base_offset) * INDEX_ENTRY_LEN as u64;
+        Ok(sealed + log_bytes + idx_bytes)
+    }
+
+    /// Returns how many of the oldest sealed segments fall outside `policy`; see
+    /// [`enforce_retention`](Self::enforce_retention).
+    pub(crate) fn outside_retention(&self, policy: RetentionPolicy) -> Result<usize> {
+        if policy.is_unbounded() && self.expiries.is_empty() {
+            return Ok(0);
+        }
+        let now = clock::system_now();
+        let now_ms = now_millis();
+        let mut total = self.active_segment.current_size + self.active_segment.idx_file.size()?;
+        for info in &self.sealed {
+            total += info.disk_bytes()?;
+        }
+
+        let mut count = 0;
+        for oldest in &self.sealed {
+            let bytes = oldest.disk_bytes()?;
+            let last_write = match oldest.footer.and_then(|f| f.last_timestamp) {
+                Some(ms) => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(ms)),
+                None => oldest
+                    .open_file(&oldest.log_path, OpenMode::Read)?
+                    .modified()?,
+            };
+            // A segment of unknown age never expires.
+            let expired = policy.max_age.is_some_and(|age| {
+                last_write.is_some_and(|t| now.duration_since(t).is_ok_and(|d| d > age))
+            });
+            let oversize = policy.max_total_bytes.is_some_and(|max| total > max);
+            // Every record of the segment has outlived its TTL.
+            let ttl_expired = self
+                .expiries
+                .get(&oldest.base_offset)
+                .is_some_and(|&at| at <= now_ms);
+            if !expired && !oversize && !ttl_expired {
+                break;
+            }
+            event!(
+                debug,
+                segment = oldest.base_offset,
+                bytes,
+                expired,
+                oversize,
+                ttl_expired,
+                "segment outside retention"
+            );
+            total = total.saturating_sub(bytes);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns every segment, oldest first, ending with the active one.
+    pub(crate) fn segment_infos(&self) -> impl Iterator<Item = &SegmentInfo> {
+        self.sealed
+            .iter()
+            .chain(std::iter::once(&self.active_segment.info))
+    }
+
+    /// Returns the oldest sealed segment and the offset after its last record,
+    /// or `None` if every segment but the active one is gone.
+    pub(crate) fn oldest_sealed(&self) -> Option<(&SegmentInfo, u64)> {
+        let oldest = self.sealed.first()?;
+        let end = self
+            .sealed
+            .get(1)
+            .map_or(self.active_segment.info.base_offset, |s| s.base_offset);
+        Some((oldest, end))
+    }
+
+    /// Removes the oldest sealed segment from the log and deletes its files.
+    pub(crate) fn remove_oldest_segment(&mut self) -> Result<()> {
+        let info = self.sealed.remove(0);
+        remove_segment_files(&info)?;
+        self.segments_changed()
+    }
+
+    /// Returns the root path of the log directory.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Returns the offset of the newest record, or `None` if the log holds no
+    /// records (it is empty, or every record was deleted).
+    #[must_use]
+    pub fn last_offset(&self) -> Option<u64> {
+        let next = self.active_segment.next_offset;
+        (next > self.first_offset()).then(|| next - 1)
+    }
+
+    /// Returns the offset that the next appended record will receive.
+    #[must_use]
+    pub const fn next_offset(&self) -> u64 {
+        self.active_segment.next_offset
+    }
+
+    /// Rebuilds the active segment's index if it does not match the records that
+    /// recovery found, and reopens it. Returns whether it was rebuilt.
+    fn repair_active_index(&mut self) -> Result<bool> {
+        let segment = &mut self.active_segment;
+        let records = segment.next_offset - segment.info.base_offset;
+        if segment
+            .info
+            .index_matches(segment.current_size, Some(records))?
+        {
+            return Ok(false);
+        }
+        segment.info.rebuild_index()?;
+        segment.idx_file = segment
+            .info
+            .open_file(&segment.info.index_path(), OpenMode::Write)?;
+        segment.idx_file.seek(SeekFrom::End(0))?;
+        Ok(true)
+    }
+
+    /// Returns what [`open`](Self::open) repaired; see [`crate::recovery`].
+    #[must_use]
+    pub const fn recovery(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    /// Recovers the last segment at open, recording and reporting what was cut.
+    fn recover_tail(&mut self) -> Result<()> {
+        let segment = self.active_segment.info.base_offset;
+        let repair = self.recover()?;
+        self.recovery.scanned = true;
+        let observer = self.config.recovery_observer.as_deref();
+        if let Some(position) = repair.corrupt_at {
+            self.recovery.corrupt_records += 1;
+            if let Some(observer) = observer {
+                observer.on_corrupt_record(segment, position);
+            }
+        }
+        if repair.truncated_bytes > 0 {
+            self.recovery.truncated_bytes += repair.truncated_bytes;
+            if let Some(observer) = observer {
+                observer.on_truncated(segment, repair.truncated_bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Scans the last segment to find the last valid record and truncate corruption,
+    /// and rebuilds the summary its footer will be written from and whether a
+    /// transaction is open.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(segment = self.active_segment.info.base_offset))
+    )]
+    fn recover(&mut self) -> Result<TailRepair> {
+        let file = &mut self.active_segment.log_file;
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut last_valid_pos = 0;
+        let mut pos = 0;
+        let mut next_offset = self.active_segment.info.base_offset;
+        // The next offset as of `last_valid_pos`, short of any unfinished batch.
+        let mut valid_offset = next_offset;
+        let (mut first_timestamp, mut last_timestamp) = (None, None);
+        let mut txn = None;
+        let mut corrupt_at = None;
+
+        loop {
+            match read_header(file) {
+                Ok(Some(header)) => {
+                    if header.offset != next_offset {
+                        // Offset mismatch, possible corruption
+                        corrupt_at = Some(pos);
+                        break;
+                    }
+
+                    // A payload that runs past the end of the file is a torn write.
+                    let end = pos + header.encoded_len() as u64 + u64::from(header.payload_len);
+                    if end > self.active_segment.current_size {
+                        break;
+                    }
+                    if header.is_control() {
+                        let mut body = vec![0; header.payload_len as usize];
+                        file.read_exact(&mut body)?;
+                        let marker = header
+                            .validate_checksum(&body)
+                            .and_then(|()| TxnMarker::decode(&body));
+                        match marker {
+                            Ok(TxnMarker::Begin) => txn = Some(header.offset),
+                            Ok(TxnMarker::Commit | TxnMarker::Abort) => txn = None,
+                            Err(_) => {
+                                corrupt_at = Some(pos);
+                                break;
+                            }
+                        }
+                    }
+                    file.seek(SeekFrom::Start(end))?;
+                    pos = end;
+
+                    // A chunked record is only complete once its last chunk is, and
+                    // a batch once its last record is.
+                    if !header.is_continued() {
+                        next_offset += header.record_count();
+                    }
+                    if !header.is_continued() && !header.continues_batch() {
+                        if last_valid_pos == 0 {
+                            first_timestamp = header.timestamp;
+                        }
+                        last_valid_pos = end;
+                        last_timestamp = header.timestamp;
+                        valid_offset = next_offset;
+                    }
+                }
+                // End of file, a partial header, or an invalid header: the tail is torn.
+                Ok(None) => break,
+                Err(e) if e.is_corruption() => {
+                    corrupt_at = Some(pos);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut truncated_bytes = 0;
+        if last_valid_pos < self.active_segment.current_size {
+            // Space reserved by `Config::preallocate` is zeros and held no records.
+            truncated_bytes = if self.config.preallocate {
+                data_end(file, last_valid_pos, self.active_segment.current_size)?
+            } else {
+                self.active_segment.current_size
+            } - last_valid_pos;
+            event!(
+                warn,
+                segment = self.active_segment.info.base_offset,
+                valid_bytes = last_valid_pos,
+                truncated_bytes,
+                "truncating torn tail"
+            );
+            // Truncate corrupted tail
+            self.active_segment.log_file.set_len(last_valid_pos)?;
+            self.active_segment.current_size = last_valid_pos;
+
+            // Also truncate index to match
+            let idx_len =
+                (valid_offset - self.active_segment.info.base_offset) * INDEX_ENTRY_LEN as u64;
+            self.active_segment.idx_file.set_len(idx_len)?;
+        }
+
+        self.active_segment.next_offset = valid_offset;
+        self.txn = txn;
+        self.active_segment.first_timestamp = first_timestamp;
+        self.active_segment.last_timestamp = last_timestamp;
+        self.active_segment.crc = (last_valid_pos == 0).then(crc32fast::Hasher::new);
+        self.active_segment.keys = (last_valid_pos == 0).then(Vec::new);
+        if last_valid_pos == 0 {
+            self.active_segment.expiry = SegmentExpiry::Empty;
+        }
+        event!(
+            debug,
+            records = valid_offset - self.active_segment.info.base_offset,
+            bytes = last_valid_pos,
+            "recovery scanned segment"
+        );
+        self.active_segment.log_file.seek(SeekFrom::End(0))?;
+        self.active_segment.idx_file.seek(SeekFrom::End(0))?;
+
+        Ok(TailRepair {
+            truncated_bytes,
+            corrupt_at,
+        })
+    }
+
+    /// Returns the newest retained record appended with `key`, or `None` if
+    /// there is none.
+    ///
+    /// Segments are searched newest first. A sealed segment is skipped without
+    /// being read when its key filter, a bloom filter over its keys written next
+    /// to its index when it is sealed, rules `key` out; the active segment and
+    /// segments without a filter are scanned.
+    ///
+    /// # Errors
+    ///
+    /// Same as iterating a [`reader`](Self::reader).
+    pub fn latest_for_key(&mut self, key: &[u8]) -> Result<Option<Record>> {
+        self.write_buffered()?;
+        let hash = KeyHash::of(key);
+        let first = self.first_offset();
+        let reader = self.reader()?;
+        let bases: Vec<u64> = self.segment_infos().map(|s| s.base_offset).collect();
+        self.key_filters
+            .retain(|&base, _| bases.first().is_some_and(|&first| base >= first));
+        let mut end = self.active_segment.next_offset;
+        for (i, &base) in bases.iter().enumerate().rev() {
+            if end <= first {
+                break;
+            }
+            if i < self.sealed.len() && !self.may_hold_key(i, hash) {
+                event!(trace, segment = base, "segment skipped by its key filter");
+                end = base;
+                continue;
+            }
+            let mut latest = None;
+            for record in reader.iter_from(base.max(first)) {
+                let record = record?;
+                if record.offset >= end {
+                    break;
+                }
+                if record.key.as_deref() == Some(key) {
+                    latest = Some(record);
+                }
+            }
+            if latest.is_some() {
+                return Ok(latest);
+            }
+            end = base;
+        }
+        Ok(None)
+    }
+
+    /// Returns false if the key filter of the sealed segment at `index` rules
+    /// out a key hashing to `hash`.
+    fn may_hold_key(&mut self, index: usize, hash: KeyHash) -> bool {
+        let info = &self.sealed[index];
+        let filter = self.key_filters.entry(info.base_offset).or_insert_with(|| {
+            KeyFilter::read(info).unwrap_or_else(|_| {
+                // Filters are derived data; the segment is scanned instead.
+                event!(warn, segment = info.base_offset, "unreadable key filter");
+                None
+            })
+        });
+        filter.as_ref().map_or(true, |f| f.may_contain(hash))
+    }
+
+    /// Reads the payload at the given offset (the value, for keyed records).
+    ///
+    /// # Errors
+    ///
+    /// Same as [`read_record`](Self::read_record).
+    pub fn read(&mut self, offset: u64) -> Result<Vec<u8>> {
+        self.read_record(offset).map(|r| r.payload)
+    }
+
+    /// Reads the record (key and payload) at the given offset.
+    ///
+    /// The segment containing `offset` is located by base offset; its index gives
+    /// the record position, and the payload checksum is verified before returning.
+    /// Offsets older than the local segments are read from [`Config::archive`],
+    /// if set.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetOutOfRange`] if `offset` was deleted or not yet written.
+    /// - Errors from the archive's store, when reading an archived segment.
+    /// - [`Error::InvalidFormat`] if the record is encrypted and
+    ///   [`Config::encryption`] is unset.
+    /// - [`Error::ChecksumMismatch`] if the record fails checksum verification.
+    /// - [`Error::Corruption`] on index mismatch or decryption failure.
+    /// - I/O errors from reading segment or index files.
+    pub fn read_record(&mut self, offset: u64) -> Result<Record> {
+        let record = self.read_unobserved(offset);
+        observe_read(self.config.observer.as_deref(), &record);
+        record
+    }
+
+    fn read_unobserved(&mut self, offset: u64) -> Result<Record> {
+        if offset < self.first_offset() {
+            return self.read_archived(offset);
+        }
+        if offset >= self.active_segment.next_offset {
+            return Err(self.out_of_range(offset));
+        }
+        let active_base = self.active_segment.info.base_offset;
+        if offset >= active_base {
+            self.write_buffered()?;
+            let segment = &mut self.active_segment;
+            return read_indexed(
+                &mut segment.log_file,
+                &mut segment.idx_file,
+                active_base,
+                offset,
+                segment.cipher.as_ref(),
+                ChecksumMode::Verify,
+            );
+        }
+
+        let idx = self.sealed.partition_point(|s| s.base_offset <= offset);
+        let Some(info) = idx.checked_sub(1).map(|i| &self.sealed[i]) else {
+            return Err(self.out_of_range(offset));
+        };
+        self.read_sealed(info, offset)
+    }
+
+    /// Reads `offset`, older than the local segments, from [`Config::archive`].
+    fn read_archived(&mut self, offset: u64) -> Result<Record> {
+        let Some(cache) = self.config.archive.clone() else {
+            return Err(self.out_of_range(offset));
+        };
+        if !self.archived.last().is_some_and(|s| offset < s.end_offset) {
+            self.archived = cache.archive().manifest()?;
+        }
+        let idx = self.archived.partition_point(|s| s.base_offset <= offset);
+        let Some(archived) = idx
+            .checked_sub(1)
+            .map(|i| self.archived[i])
+            .filter(|s| offset < s.end_offset)
+        else {
+            return Err(self.out_of_range(offset));
+        };
+        let info = cache.segment(&archived)?;
+        self.read_sealed(&info, offset)
+    }
+
+    /// Reads `offset` from the sealed segment `info`.
+    fn read_sealed(&self, info: &SegmentInfo, offset: u64) -> Result<Record> {
+        let mut log_file = info.open_file(&info.log_path, OpenMode::Read)?;
+        let mut idx_file = info.open_file(&info.index_path(), OpenMode::Read)?;
+        let keys = self
+            .config
+            .encryption
+            .as_ref()
+            .map(|e| e as &dyn KeyProvider);
+        let cipher = load_cipher(info, keys)?;
+        read_indexed(
+            &mut log_file,
+            &mut idx_file,
+            info.base_offset,
+            offset,
+            cipher.as_ref(),
+            ChecksumMode::Verify,
+        )
+    }
+}
+
+/// Returns the position just past the last nonzero byte of `file` between
+/// `start` and `end`, or `start` if they are all zeros.
+fn data_end(file: &mut FileCursor, start: u64, end: u64) -> Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut data_end = start;
+    let mut pos = start;
+    file.seek(SeekFrom::Start(start))?;
+    while pos < end {
+        let len = buf
+            .len()
+            .min(usize::try_from(end - pos).unwrap_or(usize::MAX));
+        file.read_exact(&mut buf[..len])?;
+        if let Some(last) = buf[..len].iter().rposition(|&b| b != 0) {
+            data_end = pos + last as u64 + 1;
+        }
+        pos += len as u64;
+    }
+    Ok(data_end)
+}
+
+/// Returns the file position of the frame starting at `offset` in the segment,
+/// using the index when possible and scanning record headers otherwise.
+///
+/// Fails with [`Error::InvalidFormat`] if `offset` lies inside a batch frame.
+fn record_position(info: &SegmentInfo, offset: u64) -> Result<u64> {
+    let mut file = info.open_file(&info.log_path, OpenMode::Read)?;
+    let mut pos = index_position(info, offset)?.unwrap_or(0);
+    file.seek(SeekFrom::Start(pos))?;
+    loop {
+        let Some(header) = read_header(&mut file)? else {
+            return Err(Error::Corruption(format!(
+                "offset {offset} not found in segment {}",
+                info.base_offset
+            )));
+        };
+        if header.offset == offset {
+            return Ok(pos);
+        }
+        if header.offset > offset {
+            return Err(Error::Corruption(format!(
+                "offset {offset} not found in segment {}",
+                info.base_offset
+            )));
+        }
+        if offset - header.offset < header.record_count() {
+            return Err(Error::InvalidFormat(format!(
+                "offset {offset} is inside the batch frame at offset {}",
+                header.offset
+            )));
+        }
+        pos += header.encoded_len() as u64 + u64::from(header.payload_len);
+        file.seek(SeekFrom::Start(pos))?;
+    }
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        // Best effort: the records are no less durable than before buffering.
+        let _ = self.write_buffered();
+        self.durable.close();
+        self.written.close();
+    }
+}
+
+#[cfg(test)]
+mod log_tests {
+    use super::*;
+    use crate::record::{HEADER_LEN, HEADER_LEN_V2, MAGIC};
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_append_and_read() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+
+        let offset0 = log.append(b"first").unwrap();
+        let offset1 = log.append(b"second").unwrap();
+
+        assert_eq!(offset0, 0);
+        assert_eq!(offset1, 1);
+
+        assert_eq!(log.read(0).unwrap(), b"first");
+        assert_eq!(log.read(1).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_append_batch() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"single").unwrap();
+
+        let range = log.append_batch(&[b"a", b"bb", b"ccc"]).unwrap();
+        assert_eq!(range, 1..=3);
+        assert_eq!(log.read(2).unwrap(), b"bb");
+        assert_eq!(log.append(b"after").unwrap(), 4);
+
+        drop(log);
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.read(3).unwrap(), b"ccc");
+        assert_eq!(log.next_offset(), 5);
+        assert!(log.append_batch(&[]).is_err());
+    }
+
+    #[test]
+    fn test_append_batch_rolls_as_a_unit() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 40,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"first").unwrap();
+        let range = log.append_batch(&[b"x", b"y"]).unwrap();
+        assert_eq!(range, 1..=2);
+
+        let segments = crate::discover_segments(dir.path()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].base_offset, 1);
+        assert_eq!(log.read(0).unwrap(), b"first");
+        assert_eq!(log.read(2).unwrap(), b"y");
+    }
+
+    #[test]
+    fn test_append_batch_is_atomic_across_crashes() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"a").unwrap();
+        log.append_batch(&[b"x", b"y", b"z"]).unwrap();
+        assert!(matches!(
+            log.truncate_after(2),
+            Err(Error::InvalidFormat(_))
+        ));
+        let log_path = log.active_segment.info.log_path.clone();
+        drop(log);
+
+        // A crash before the batch's last frame reached the disk.
+        let file = OpenOptions::new().write(true).open(&log_path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - (HEADER_LEN as u64 + 1)).unwrap();
+        drop(file);
+
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.next_offset(), 1);
+        assert_eq!(log.append_batch(&[b"p", b"q"]).unwrap(), 1..=2);
+        assert_eq!(log.read(2).unwrap(), b"q");
+    }
+
+    #[test]
+    fn test_batch_frame_expands_on_read() {
+        let dir = tempdir().unwrap();
+        {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"single").unwrap();
+            let range = log.append_batch_frame(&[b"a", b"bb", b"ccc"]).unwrap();
+            assert_eq!(range, 1..=3);
+            assert_eq!(log.append(b"after").unwrap(), 4);
+            assert_eq!(log.read(2).unwrap(), b"bb");
+        }
+
+        // Recovery counts every record of the frame.
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.next_offset(), 5);
+        assert_eq!(log.read(3).unwrap(), b"ccc");
+
+        let reader = crate::LogReader::open(dir.path()).unwrap();
+        let records: Vec<Record> = reader.iter_from(2).collect::<Result<_>>().unwrap();
+        let offsets: Vec<u64> = records.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, [2, 3, 4]);
+        assert_eq!(records[0].payload, b"bb");
+        assert_eq!(reader.read(1).unwrap(), b"a");
+
+        // A batch is discarded as a whole or not at all.
+        assert!(matches!(
+            log.truncate_after(1),
+            Err(Error::InvalidFormat(_))
+        ));
+        assert_eq!(log.next_offset(), 5);
+        log.truncate_after(3).unwrap();
+        assert_eq!(log.next_offset(), 4);
+        log.truncate_after(0).unwrap();
+        assert_eq!(log.append(b"new").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_chunked_records_reassemble() {
+        let dir = tempdir().unwrap();
+        let big: Vec<u8> = (0..20u8).collect();
+        let log_path = {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.set_chunk_len(8);
+            log.append(&big).unwrap();
+            log.append_keyed(b"key", &big).unwrap();
+            log.append_batch(&[b"small", &big]).unwrap();
+            assert_eq!(log.read(0).unwrap(), big);
+            assert_eq!(log.read(3).unwrap(), big);
+            log.active_segment.info.log_path.clone()
+        };
+
+        let reader = crate::LogReader::open(dir.path()).unwrap();
+        let records: Vec<Record> = reader.iter().collect::<Result<_>>().unwrap();
+        let offsets: Vec<u64> = records.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, [0, 1, 2, 3]);
+        assert_eq!(records[1].key.as_deref(), Some(&b"key"[..]));
+        assert_eq!(records[1].payload, big);
+        assert_eq!(records[2].payload, b"small");
+        assert_eq!(reader.read(1).unwrap(), big);
+
+        // Losing the last chunk tears the whole record, and with it the batch.
+        let len = std::fs::metadata(&log_path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&log_path).unwrap();
+        file.set_len(len - 4).unwrap();
+        drop(file);
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.next_offset(), 2);
+        assert_eq!(log.append(b"next").unwrap(), 2);
+        assert_eq!(log.read(1).unwrap(), big);
+    }
+
+    #[test]
+    fn test_append_vectored_matches_append() {
+        let (a, b) = (tempdir().unwrap(), tempdir().unwrap());
+        let big: Vec<u8> = (0..20u8).collect();
+        let segment = |dir: &Path, vectored: bool| {
+            let mut log = Log::open(dir, Config::default()).unwrap();
+            log.set_chunk_len(8);
+            if vectored {
+                let parts = [
+                    IoSlice::new(b"hello, "),
+                    IoSlice::new(b""),
+                    IoSlice::new(b"world"),
+                ];
+                assert_eq!(log.append_vectored(&parts).unwrap(), 0);
+                // Too long for one chunk, so it is gathered and split.
+                let (head, tail) = big.split_at(5);
+                let parts = [IoSlice::new(head), IoSlice::new(tail)];
+                assert_eq!(log.append_vectored(&parts).unwrap(), 1);
+            } else {
+                log.append(b"hello, world").unwrap();
+                log.append(&big).unwrap();
+            }
+            assert_eq!(log.read(0).unwrap(), b"hello, world");
+            assert_eq!(log.read(1).unwrap(), big);
+            std::fs::read(&log.active_segment.info.log_path).unwrap()
+        };
+        assert_eq!(segment(a.path(), true), segment(b.path(), false));
+        let log = Log::open(a.path(), Config::default()).unwrap();
+        assert_eq!(log.next_offset(), 2);
+    }
+
+    #[test]
+    fn test_append_ack_resolves_on_flush() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        let ack = log.append_with_ack(b"pending").unwrap();
+        assert_eq!(ack.offset(), 0);
+        assert!(!ack.is_durable());
+        assert!(!ack
+            .wait_timeout(std::time::Duration::from_millis(10))
+            .unwrap());
+
+        let waiter = {
+            let ack = ack.clone();
+            std::thread::spawn(move || ack.wait())
+        };
+        log.flush().unwrap();
+        assert_eq!(waiter.join().unwrap().unwrap(), 0);
+        assert!(ack.is_durable());
+        assert_eq!(log.durable_offset(), 1);
+    }
+
+    #[test]
+    fn test_append_ack_fails_when_log_dropped() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        let ack = log.append_with_ack(b"lost").unwrap();
+        drop(log);
+        assert!(matches!(ack.wait(), Err(Error::Closed(_))));
+    }
+
+    #[test]
+    fn test_fsync_always_acks_immediately() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            fsync: FsyncPolicy::Always,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        let ack = log.append_with_ack(b"synced").unwrap();
+        assert!(ack.is_durable());
+        log.append_batch(&[b"a", b"b"]).unwrap();
+        assert_eq!(log.durable_offset(), 3);
+    }
+
+    fn fill_segments(log: &mut Log, records: u8) {
+        for i in 0..records {
+            log.append(&[i; 16]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_retention_by_total_size() {
+        let dir = tempdir().unwrap();
+        // 40-byte records, one per segment; each sealed segment is 40 + 16 bytes on
+        // disk plus its footer, the active one 40 + 16.
+        let config = Config {
+            max_segment_bytes: 50,
+            retention: RetentionPolicy {
+                max_total_bytes: Some(300),
+                ..RetentionPolicy::default()
+            },
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        fill_segments(&mut log, 6);
+        assert_eq!(crate::discover_segments(dir.path()).unwrap().len(), 6);
+
+        let deleted = log.enforce_retention().unwrap();
+        assert_eq!(deleted, 3);
+        assert_eq!(log.first_offset(), 3);
+        assert!(log.read(2).is_err());
+        assert_eq!(log.read(3).unwrap(), [3u8; 16]);
+        assert_eq!(crate::discover_segments(dir.path()).unwrap().len(), 3);
+        assert_eq!(log.enforce_retention().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_disk_quota() {
+        let dir = tempdir().unwrap();
+        let quota = DiskQuota {
+            max_bytes: 300,
+            on_exceeded: QuotaAction::Fail,
+        };
+        let config = Config {
+            max_segment_bytes: 50,
+            quota: Some(quota),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        let err = loop {
+            match log.append(&[7u8; 16]) {
+                Ok(_) => {}
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(
+            err,
+            Error::QuotaExceeded {
+                requested: 112,
+                used: 280,
+                limit: 300
+            }
+        ));
+        // Two sealed segments of 40 + 16 bytes plus a footer and the active
+        // one take 280 bytes; rolling it for a fourth record would need 112.
+        let next = log.next_offset();
+        assert_eq!(next, 3);
+        assert_eq!(log.first_offset(), 0);
+        drop(log);
+
+        // Reopened to delete what it must, the log takes the append.
+        let config = Config {
+            quota: Some(DiskQuota {
+                on_exceeded: QuotaAction::EnforceRetention,
+                ..quota
+            }),
+            ..config
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.append(&[8u8; 16]).unwrap(), next);
+        assert_eq!(log.first_offset(), 1);
+        assert_eq!(log.read(next).unwrap(), [8u8; 16]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_config_deserializes_with_defaults() {
+        use serde::de::value::{Error as ValueError, MapDeserializer};
+        use serde::Deserialize;
+
+        let fields = [("max_segment_bytes", 1024u64)];
+        let map = MapDeserializer::<_, ValueError>::new(fields.into_iter());
+        let config = Config::deserialize(map).unwrap();
+        assert_eq!(config.max_segment_bytes, 1024);
+        assert_eq!(config.retention, RetentionPolicy::default());
+        assert!(config.encryption.is_none() && config.observer.is_none());
+
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"configured").unwrap();
+        assert_eq!(log.read(0).unwrap(), b"configured");
+    }
+
+    #[test]
+    fn test_preallocated_segments() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            preallocate: true,
+            ..Config::default()
+        };
+        let log_len = |log: &Log| {
+            let path = &log.active_segment.info.log_path;
+            std::fs::metadata(path).unwrap().len()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        for i in 0..5u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        assert_eq!(log_len(&log), 100);
+        assert_eq!(log.reader().unwrap().iter().count(), 5);
+        assert!(log.verify().unwrap().is_ok());
+        let sealed = crate::discover_segments(dir.path()).unwrap();
+        assert!(sealed[0].verify_footer().is_ok());
+
+        // A crash leaves the reserved space behind; recovery stops at it.
+        drop(log);
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        assert_eq!(log.append(b"after crash").unwrap(), 5);
+        log.close().unwrap();
+
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log_len(&log), 100);
+        assert_eq!(log.read(5).unwrap(), b"after crash");
+        assert_eq!(log.append(b"next").unwrap(), 6);
+        assert_eq!(log.reader().unwrap().iter().count(), 7);
+    }
+
+    #[test]
+    fn test_open_rebuilds_missing_or_damaged_indexes() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        {
+            let mut log = Log::open(dir.path(), config.clone()).unwrap();
+            fill_segments(&mut log, 5);
+        }
+        let segments = crate::discover_segments(dir.path()).unwrap();
+        let indexes: Vec<Vec<u8>> = segments
+            .iter()
+            .map(|s| std::fs::read(s.index_path()).unwrap())
+            .collect();
+        std::fs::remove_file(segments[0].index_path()).unwrap();
+        std::fs::write(segments[1].index_path(), &indexes[1][..20]).unwrap();
+        std::fs::remove_file(segments[2].index_path()).unwrap();
+
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for (segment, index) in segments.iter().zip(&indexes) {
+            assert_eq!(&std::fs::read(segment.index_path()).unwrap(), index);
         }
+        assert_eq!(log.read(1).unwrap(), [1u8; 16]);
+        assert_eq!(log.read(4).unwrap(), [4u8; 16]);
+        assert_eq!(log.append(b"next").unwrap(), 5);
+        assert_eq!(log.read(5).unwrap(), b"next");
     }
-}
 
-/// A crash-safe, segmented log.
-#[derive(Debug)]
-pub struct Log {
-    dir: LogDir,
-    config: Config,
-    active_segment: ActiveSegment,
-}
+    #[test]
+    fn test_clean_close_skips_recovery() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        fill_segments(&mut log, 3);
+        log.close().unwrap();
 
-#[derive(Debug)]
-struct ActiveSegment {
-    info: SegmentInfo,
-    log_file: File,
-    idx_file: File,
-    current_size: u64,
-    next_offset: u64,
-}
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        assert_eq!(log.next_offset(), 3);
+        log.append(b"more").unwrap();
+        assert_eq!(log.read(2).unwrap(), [2u8; 16]);
+        assert_eq!(log.read(3).unwrap(), b"more");
+        // The marker is consumed on open; a crash now must recover as usual.
+        assert!(!dir.path().join("clean.shutdown").exists());
+        fill_segments(&mut log, 1);
+        drop(log);
+        let segments = crate::discover_segments(dir.path()).unwrap();
+        segments[1].verify_footer().unwrap();
 
-impl Log {
-    /// Opens the log in the given directory. Creates it if missing.
-    /// Performs recovery if the last segment is corrupted.
-    pub fn open(path: impl AsRef<Path>, config: Config) -> Result<Self> {
-        let dir = LogDir::open(path)?;
-        let segments = dir.segments();
+        // A marker that no longer matches the active segment is ignored.
+        let log = Log::open(dir.path(), config.clone()).unwrap();
+        log.close().unwrap();
+        let active = &crate::discover_segments(dir.path()).unwrap()[2];
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&active.log_path)
+            .unwrap();
+        file.write_all(b"torn").unwrap();
+        let log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.next_offset(), 5);
+        assert_eq!(
+            std::fs::metadata(&active.log_path).unwrap().len(),
+            40,
+            "torn tail truncated by recovery"
+        );
+    }
 
-        let active_segment = if let Some(last_info) = segments.last() {
-            Self::open_active_segment(last_info.clone())?
-        } else {
-            Self::create_segment(&dir, 0)?
+    #[test]
+    fn test_rolled_segments_carry_footers() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
         };
+        let before = now_millis();
+        {
+            let mut log = Log::open(dir.path(), config.clone()).unwrap();
+            fill_segments(&mut log, 5);
+        }
+        let after = now_millis();
 
-        let mut log = Self {
-            dir,
-            config,
-            active_segment,
+        let segments = crate::discover_segments(dir.path()).unwrap();
+        assert_eq!(segments.len(), 3);
+        let footer = segments[0].footer.unwrap();
+        assert_eq!(footer.record_count, 2);
+        assert_eq!((footer.first_offset, footer.last_offset), (0, 1));
+        assert!((before..=after).contains(&footer.first_timestamp.unwrap()));
+        assert!(footer.first_timestamp <= footer.last_timestamp);
+        assert_eq!(segments[1].footer.unwrap().first_offset, 2);
+        assert!(segments[2].footer.is_none());
+        for segment in &segments {
+            segment.verify_footer().unwrap();
+        }
+
+        let records: Vec<Record> = crate::LogReader::open(dir.path())
+            .unwrap()
+            .iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 5);
+
+        // Reopening a sealed segment for writing drops its footer; sealing it again
+        // writes a new one, without timestamps the v1 records never stored.
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.truncate_after(1).unwrap();
+        assert!(crate::discover_segments(dir.path()).unwrap()[0]
+            .footer
+            .is_none());
+        log.append(b"after").unwrap();
+        assert_eq!(log.read(1).unwrap(), [1u8; 16]);
+        assert_eq!(log.read(2).unwrap(), b"after");
+        let segments = crate::discover_segments(dir.path()).unwrap();
+        let footer = segments[0].footer.unwrap();
+        assert_eq!((footer.record_count, footer.first_timestamp), (2, None));
+        segments[0].verify_footer().unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_sealed_segment_fails_footer_check() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 50,
+            ..Config::default()
         };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        fill_segments(&mut log, 2);
+        let sealed = crate::discover_segments(dir.path()).unwrap().remove(0);
+        let mut bytes = std::fs::read(&sealed.log_path).unwrap();
+        bytes[HEADER_LEN] ^= 0xFF;
+        std::fs::write(&sealed.log_path, bytes).unwrap();
+        assert!(matches!(sealed.verify_footer(), Err(Error::Corruption(_))));
+    }
 
-        log.recover()?;
-        Ok(log)
+    #[test]
+    fn test_retention_by_age_keeps_active_segment() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 50,
+            retention: RetentionPolicy {
+                max_age: Some(std::time::Duration::ZERO),
+                ..RetentionPolicy::default()
+            },
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        fill_segments(&mut log, 4);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+
+        assert_eq!(log.enforce_retention().unwrap(), 3);
+        assert_eq!(log.first_offset(), 3);
+        assert_eq!(log.read(3).unwrap(), [3u8; 16]);
+        assert_eq!(log.append(b"next").unwrap(), 4);
     }
 
-    fn open_active_segment(info: SegmentInfo) -> Result<ActiveSegment> {
-        let log_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&info.log_path)?;
-        
-        let idx_path = info.log_path.with_extension("idx");
-        let idx_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&idx_path)?;
+    #[test]
+    fn test_append_replicated() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        let apply = |log: &mut Log, frame: &[u8]| {
+            let (header, body) = crate::decode_record(frame)?;
+            log.append_replicated(&header, body)
+        };
 
-        let current_size = log_file.metadata()?.len();
-        
-        // We will determine next_offset during recovery.
-        // For now, placeholder.
-        Ok(ActiveSegment {
-            info,
-            log_file,
-            idx_file,
-            current_size,
-            next_offset: 0, 
-        })
+        let frame =
+            crate::encode_frame_v2(0, 1_000, 0, &[("h", b"v")], Some(b"k"), b"zero").unwrap();
+        assert_eq!(apply(&mut log, &frame).unwrap(), 0);
+        let batch = crate::encode_batch(1, &[b"one", b"two"]).unwrap();
+        assert_eq!(apply(&mut log, &batch).unwrap(), 1);
+        assert!(matches!(
+            apply(&mut log, &frame),
+            Err(Error::OffsetRegression {
+                expected: 3,
+                found: 0
+            })
+        ));
+        let gap = crate::encode_record(4, b"four").unwrap();
+        assert!(matches!(
+            apply(&mut log, &gap),
+            Err(Error::OffsetGap {
+                expected: 3,
+                found: 4
+            })
+        ));
+        let mut corrupt = crate::encode_record(3, b"three").unwrap();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            apply(&mut log, &corrupt),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        assert_eq!(log.next_offset(), 3);
+        let record = log.read_record(0).unwrap();
+        assert_eq!(record.timestamp, Some(1_000));
+        assert_eq!(record.header("h"), Some(&b"v"[..]));
+        assert_eq!(record.key.as_deref(), Some(&b"k"[..]));
+        assert_eq!(log.read(2).unwrap(), b"two");
     }
 
-    fn create_segment(dir: &LogDir, base_offset: u64) -> Result<ActiveSegment> {
-        let id = SegmentId(base_offset);
-        let log_path = dir.path().join(id.log_filename());
-        let idx_path = log_path.with_extension("idx");
+    #[test]
+    fn test_commit() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        for payload in [b"a", b"b", b"c", b"d"] {
+            log.append(payload).unwrap();
+        }
+        assert_eq!(log.committed_offset(), 0);
+        log.commit(2).unwrap();
+        assert_eq!(log.durable_offset(), 4);
+        log.commit(1).unwrap();
+        assert_eq!(log.committed_offset(), 2);
+        assert!(log.commit(5).is_err());
+        assert!(matches!(
+            log.truncate_after(0),
+            Err(Error::InvalidFormat(_))
+        ));
+        log.truncate_after(1).unwrap();
+        log.append(b"c2").unwrap();
 
-        let log_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(&log_path)?;
+        let reader = LogReader::open(dir.path())
+            .unwrap()
+            .with_committed_only(true);
+        let payloads: Vec<Vec<u8>> = reader.iter().map(|r| r.unwrap().payload).collect();
+        assert_eq!(payloads, [b"a", b"b"]);
+        assert!(matches!(
+            reader.read(2),
+            Err(Error::OffsetOutOfRange {
+                latest: Some(1),
+                ..
+            })
+        ));
 
-        let idx_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(&idx_path)?;
+        drop(log);
+        let log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.committed_offset(), 2);
+    }
 
-        Ok(ActiveSegment {
-            info: SegmentInfo {
-                base_offset,
-                log_path,
-            },
-            log_file,
-            idx_file,
-            current_size: 0,
-            next_offset: base_offset,
-        })
+    #[test]
+    fn test_offset_accessors() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        assert_eq!(
+            (log.first_offset(), log.last_offset(), log.next_offset()),
+            (0, None, 0)
+        );
+        fill_segments(&mut log, 5);
+        assert_eq!(log.last_offset(), Some(4));
+        log.close().unwrap();
+
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(
+            (log.first_offset(), log.last_offset(), log.next_offset()),
+            (0, Some(4), 5)
+        );
+        log.delete_before(5).unwrap();
+        assert_eq!(
+            (log.first_offset(), log.last_offset(), log.next_offset()),
+            (5, None, 5)
+        );
     }
 
-    /// Appends a payload to the log.
-    pub fn append(&mut self, payload: &[u8]) -> Result<u64> {
-        let encoded = encode_record(self.active_segment.next_offset, payload)?;
-        let record_len = encoded.len() as u64;
+    #[test]
+    fn test_delete_before() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        {
+            // 40-byte records, two per segment: segments start at 0, 2, 4.
+            let mut log = Log::open(dir.path(), config.clone()).unwrap();
+            fill_segments(&mut log, 5);
 
-        if self.active_segment.current_size + record_len > self.config.max_segment_bytes {
-            self.roll()?;
+            assert_eq!(log.delete_before(3).unwrap(), 1);
+            assert_eq!(log.first_offset(), 3);
+            assert_eq!(crate::discover_segments(dir.path()).unwrap().len(), 2);
+            let err = log.read(2).unwrap_err();
+            assert!(matches!(
+                err,
+                Error::OffsetOutOfRange {
+                    requested: 2,
+                    earliest: 3,
+                    latest: Some(4)
+                }
+            ));
+            assert_eq!(log.read(3).unwrap(), [3u8; 16]);
+            assert_eq!(log.delete_before(1).unwrap(), 0);
+            assert!(log.delete_before(6).is_err());
         }
 
-        let offset = self.active_segment.next_offset;
-        let pos = self.active_segment.current_size;
-
-        // Write record to .log
-        self.active_segment.log_file.seek(SeekFrom::End(0))?;
-        self.active_segment.log_file.write_all(&encoded)?;
-        
-        // Write index entry to .idx
-        self.active_segment.idx_file.seek(SeekFrom::End(0))?;
-        self.write_index_entry(offset, pos)?;
+        // The start offset survives reopen even though offset 2's segment remains.
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.first_offset(), 3);
+        assert!(matches!(log.read(2), Err(Error::OffsetOutOfRange { .. })));
+        assert!(matches!(log.read(5), Err(Error::OffsetOutOfRange { .. })));
+        assert_eq!(log.delete_before(5).unwrap(), 1);
+        assert_eq!(log.first_offset(), 5);
+        assert_eq!(log.append(b"next").unwrap(), 5);
+    }
 
-        self.active_segment.current_size += record_len;
-        self.active_segment.next_offset += 1;
+    #[test]
+    fn test_stats() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        let stats = log.stats().unwrap();
+        assert_eq!(
+            (stats.segments, stats.disk_bytes, stats.last_offset),
+            (1, 0, None)
+        );
+        assert_eq!(stats.last_flush, None);
 
-        Ok(offset)
+        // 40-byte records, two per segment: two sealed segments and one active.
+        fill_segments(&mut log, 5);
+        log.delete_before(1).unwrap();
+        let stats = log.stats().unwrap();
+        let index_bytes = 5 * INDEX_ENTRY_LEN as u64;
+        assert_eq!(
+            stats,
+            LogStats {
+                segments: 3,
+                disk_bytes: 5 * 40 + 2 * FOOTER_LEN as u64 + index_bytes,
+                first_offset: 1,
+                last_offset: Some(4),
+                active_segment_bytes: 40,
+                last_flush: stats.last_flush,
+                index_bytes,
+            }
+        );
+        // Each roll fsynced the segment it sealed.
+        assert!(stats.last_flush.is_some());
     }
 
-    fn write_index_entry(&mut self, offset: u64, pos: u64) -> Result<()> {
-        self.active_segment.idx_file.write_all(&offset.to_le_bytes())?;
-        self.active_segment.idx_file.write_all(&pos.to_le_bytes())?;
-        Ok(())
-    }
+    #[test]
+    fn test_truncate_after_within_active_segment() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        fill_segments(&mut log, 5);
+        log.truncate_after(u64::MAX).unwrap();
+        assert_eq!(log.next_offset(), 5);
 
-    fn roll(&mut self) -> Result<()> {
-        let next_offset = self.active_segment.next_offset;
-        self.active_segment = Self::create_segment(&self.dir, next_offset)?;
-        Ok(())
+        log.truncate_after(2).unwrap();
+        assert_eq!(log.next_offset(), 3);
+        assert!(log.read(3).is_err());
+        assert_eq!(log.read(2).unwrap(), [2u8; 16]);
+        assert_eq!(log.append(b"replaced").unwrap(), 3);
+        assert_eq!(log.read(3).unwrap(), b"replaced");
+
+        drop(log);
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.next_offset(), 4);
+        assert_eq!(log.read(3).unwrap(), b"replaced");
     }
 
-    /// Flushes all pending writes to disk.
-    pub fn flush(&mut self) -> Result<()> {
-        self.active_segment.log_file.sync_all()?;
-        self.active_segment.idx_file.sync_all()?;
-        Ok(())
+    #[test]
+    fn test_truncate_after_across_segments() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        // Two 40-byte records per segment: segments start at 0, 2, 4, 6.
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        fill_segments(&mut log, 7);
+        assert_eq!(crate::discover_segments(dir.path()).unwrap().len(), 4);
+
+        log.truncate_after(2).unwrap();
+        assert_eq!(crate::discover_segments(dir.path()).unwrap().len(), 2);
+        assert_eq!(log.next_offset(), 3);
+        assert_eq!(log.read(2).unwrap(), [2u8; 16]);
+        assert!(log.read(3).is_err());
+        assert_eq!(log.append(b"new").unwrap(), 3);
+
+        drop(log);
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.read(3).unwrap(), b"new");
+        assert_eq!(log.read(0).unwrap(), [0u8; 16]);
+        assert_eq!(log.next_offset(), 4);
     }
 
-    /// Scans the last segment to find the last valid record and truncate corruption.
-    fn recover(&mut self) -> Result<()> {
-        let mut file = &self.active_segment.log_file;
-        file.seek(SeekFrom::Start(0))?;
-        
-        let mut last_valid_pos = 0;
-        let mut next_offset = self.active_segment.info.base_offset;
-        let mut buf = [0u8; HEADER_LEN];
+    #[test]
+    fn test_truncate_after_at_segment_boundary() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        fill_segments(&mut log, 5);
 
-        loop {
-            match file.read_exact(&mut buf) {
-                Ok(_) => {
-                    let header = match decode_header(&buf) {
-                        Ok(h) => h,
-                        Err(_) => break, // Likely partial record or EOF
-                    };
-                    
-                    if header.offset != next_offset {
-                        // Offset mismatch, possible corruption
-                        break;
-                    }
+        log.truncate_after(1).unwrap();
+        assert_eq!(log.next_offset(), 2);
+        assert_eq!(crate::discover_segments(dir.path()).unwrap().len(), 1);
+        assert_eq!(log.append(b"x").unwrap(), 2);
+        assert_eq!(log.read(2).unwrap(), b"x");
+        log.truncate_after(10).unwrap();
+        assert_eq!(log.next_offset(), 3);
+    }
 
-                    // Seek past payload
-                    if let Err(_) = file.seek(SeekFrom::Current(header.payload_len as i64)) {
-                        break;
-                    }
+    #[test]
+    fn test_append_keyed() {
+        let dir = tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.append_keyed(b"user-7", b"logged in").unwrap(), 0);
+        assert_eq!(log.append(b"unkeyed").unwrap(), 1);
 
-                    last_valid_pos = file.stream_position()?;
-                    next_offset += 1;
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e.into()),
-            }
+        let record = log.read_record(0).unwrap();
+        assert_eq!(record.key.as_deref(), Some(&b"user-7"[..]));
+        assert_eq!(record.payload, b"logged in");
+        assert_eq!(log.read(0).unwrap(), b"logged in");
+        assert_eq!(log.read_record(1).unwrap().key, None);
+    }
+
+    #[test]
+    fn test_latest_for_key_uses_segment_key_filters() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 200,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        for i in 0..30u8 {
+            log.append_keyed(&[b'k', i % 5], &[i; 20]).unwrap();
+        }
+        log.append(b"unkeyed").unwrap();
+        let segments: Vec<SegmentInfo> = log.segment_infos().cloned().collect();
+        assert!(segments.len() > 2);
+        let (active, sealed) = segments.split_last().unwrap();
+        assert!(!key_filter::path(active).exists());
+        for info in sealed {
+            let filter = KeyFilter::read(info).unwrap().unwrap();
+            assert!(!filter.may_contain(KeyHash::of(b"missing")));
         }
 
-        if last_valid_pos < self.active_segment.current_size {
-            // Truncate corrupted tail
-            self.active_segment.log_file.set_len(last_valid_pos)?;
-            self.active_segment.current_size = last_valid_pos;
-            
-            // Also truncate index to match
-            let idx_len = (next_offset - self.active_segment.info.base_offset) * INDEX_ENTRY_LEN as u64;
-            self.active_segment.idx_file.set_len(idx_len)?;
+        let latest = log.latest_for_key(b"k\x03").unwrap().unwrap();
+        assert_eq!((latest.offset, latest.payload), (28, vec![28; 20]));
+        assert!(log.latest_for_key(b"missing").unwrap().is_none());
+
+        // Keys appended before a reopen are reread when the segment is sealed.
+        drop(log);
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append_keyed(b"late", b"v").unwrap();
+        while log.segment_infos().count() == segments.len() {
+            log.append(&[0; 20]).unwrap();
         }
+        let filter = KeyFilter::read(active).unwrap().unwrap();
+        assert!(filter.may_contain(KeyHash::of(&[b'k', 4])));
+        assert!(filter.may_contain(KeyHash::of(b"late")));
+        assert_eq!(log.latest_for_key(b"late").unwrap().unwrap().payload, b"v");
 
-        self.active_segment.next_offset = next_offset;
-        self.active_segment.log_file.seek(SeekFrom::End(0))?;
-        self.active_segment.idx_file.seek(SeekFrom::End(0))?;
-        
-        Ok(())
+        // Deleted records are not found.
+        log.delete_before(29).unwrap();
+        assert!(log.latest_for_key(b"k\x03").unwrap().is_none());
+        assert_eq!(log.latest_for_key(b"k\x04").unwrap().unwrap().offset, 29);
     }
 
-    /// Reads a record at the given offset.
-    pub fn read(&mut self, offset: u64) -> Result<Vec<u8>> {
-        // Simple implementation: scan segments to find the one containing offset.
-        // Then use index if it's the active segment, or linear scan for now.
-        // Real implementation should use index for all segments.
-        
-        // For now, let's assume it's in the active segment for Day 7 basic test.
-        if offset < self.active_segment.info.base_offset {
-             return Err(Error::InvalidFormat(format!("Offset {} is before base offset {}", offset, self.active_segment.info.base_offset)));
-        }
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compressed_appends_read_back_transparently() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            compression: Some(Compression {
+                codec: crate::Codec::Lz4,
+                min_bytes: 32,
+            }),
+            ..Config::default()
+        };
+        let big = b"{\"k\":\"v\"}".repeat(100);
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(&big).unwrap();
+        log.append(b"tiny").unwrap();
+        log.append_keyed(b"key", &big).unwrap();
+        log.flush().unwrap();
+
+        let on_disk = std::fs::metadata(&log.active_segment.info.log_path)
+            .unwrap()
+            .len();
+        assert!(on_disk < big.len() as u64);
+        assert_eq!(log.read(0).unwrap(), big);
+        assert_eq!(log.read(1).unwrap(), b"tiny");
+        let record = log.read_record(2).unwrap();
+        assert_eq!(record.key.as_deref(), Some(&b"key"[..]));
+        assert_eq!(record.payload, big);
 
-        let idx_pos = (offset - self.active_segment.info.base_offset) * INDEX_ENTRY_LEN as u64;
-        if idx_pos + INDEX_ENTRY_LEN as u64 > self.active_segment.idx_file.metadata()?.len() {
-             return Err(Error::InvalidFormat(format!("Offset {} not found in index", offset)));
+        drop(log);
+        let reader = crate::LogReader::open(dir.path()).unwrap();
+        let payloads: Vec<Vec<u8>> = reader.iter().map(|r| r.unwrap().payload).collect();
+        assert_eq!(payloads, vec![big.clone(), b"tiny".to_vec(), big]);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_appends_across_segments() {
+        let dir = tempdir().unwrap();
+        let master = MasterKey::new([3; 32]);
+        let config = Config {
+            max_segment_bytes: 128,
+            encryption: Some(Encryption::new(KeyId(0), master.clone())),
+            ..Config::default()
+        };
+        let payload = |i: u8| format!("secret-{i}").into_bytes();
+        {
+            let mut log = Log::open(dir.path(), config.clone()).unwrap();
+            for i in 0..6 {
+                log.append(&payload(i)).unwrap();
+            }
+            log.append_batch(&[b"secret-6", b"secret-7"]).unwrap();
+            log.append_keyed(b"user", b"secret-8").unwrap();
+            log.flush().unwrap();
+            assert!(!log.sealed.is_empty());
         }
 
-        self.active_segment.idx_file.seek(SeekFrom::Start(idx_pos))?;
-        let mut entry_buf = [0u8; INDEX_ENTRY_LEN];
-        self.active_segment.idx_file.read_exact(&mut entry_buf)?;
-        
-        let mut cursor = std::io::Cursor::new(&entry_buf);
-        let mut b8 = [0u8; 8];
-        cursor.read_exact(&mut b8)?;
-        let entry_offset = u64::from_le_bytes(b8);
-        cursor.read_exact(&mut b8)?;
-        let entry_pos = u64::from_le_bytes(b8);
+        for segment in crate::discover_segments(dir.path()).unwrap() {
+            let bytes = std::fs::read(&segment.log_path).unwrap();
+            assert!(!bytes.windows(6).any(|w| w == b"secret"));
+            assert!(crate::encryption::key_path(&segment).exists());
+        }
 
-        if entry_offset != offset {
-            return Err(Error::Corruption(format!("Index entry offset mismatch: expected {}, got {}", offset, entry_offset)));
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for i in 0..8 {
+            assert_eq!(log.read(u64::from(i)).unwrap(), payload(i));
         }
+        let record = log.read_record(8).unwrap();
+        assert_eq!(record.key.as_deref(), Some(&b"user"[..]));
+        assert_eq!(record.payload, b"secret-8");
+        drop(log);
 
-        self.active_segment.log_file.seek(SeekFrom::Start(entry_pos))?;
-        let mut header_buf = [0u8; HEADER_LEN];
-        self.active_segment.log_file.read_exact(&mut header_buf)?;
-        let header = decode_header(&header_buf)?;
-        
-        let mut payload = vec![0u8; header.payload_len as usize];
-        self.active_segment.log_file.read_exact(&mut payload)?;
-        
-        header.validate_checksum(&payload)?;
-        
-        Ok(payload)
+        let reader = crate::LogReader::open(dir.path())
+            .unwrap()
+            .with_master_key(master);
+        assert_eq!(reader.iter().count(), 9);
+        assert_eq!(reader.read(7).unwrap(), b"secret-7");
+        let err = crate::LogReader::open(dir.path())
+            .unwrap()
+            .read(0)
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidFormat(_)), "{err}");
+        let wrong = crate::LogReader::open(dir.path())
+            .unwrap()
+            .with_master_key(MasterKey::new([4; 32]));
+        assert!(wrong.read(0).is_err());
     }
-}
-
-#[cfg(test)]
-mod log_tests {
-    use super::*;
-    use tempfile::tempdir;
 
+    #[cfg(feature = "encryption")]
     #[test]
-    fn test_append_and_read() {
+    fn test_rotate_key_keeps_old_segments_readable() {
         let dir = tempdir().unwrap();
-        let mut log = Log::open(dir.path(), Config::default()).unwrap();
-        
-        let offset0 = log.append(b"first").unwrap();
-        let offset1 = log.append(b"second").unwrap();
-        
-        assert_eq!(offset0, 0);
-        assert_eq!(offset1, 1);
-        
-        assert_eq!(log.read(0).unwrap(), b"first");
-        assert_eq!(log.read(1).unwrap(), b"second");
+        let (old, new) = (MasterKey::new([1; 32]), MasterKey::new([2; 32]));
+        let config = |encryption| Config {
+            encryption: Some(encryption),
+            ..Config::default()
+        };
+        {
+            let mut log =
+                Log::open(dir.path(), config(Encryption::new(KeyId(1), old.clone()))).unwrap();
+            log.append(b"before").unwrap();
+            log.rotate_key(KeyId(2), new.clone()).unwrap();
+            assert_eq!(log.sealed.len(), 1);
+            // An empty active segment is re-keyed in place rather than rolled again.
+            log.rotate_key(KeyId(3), MasterKey::new([3; 32])).unwrap();
+            log.rotate_key(KeyId(2), new.clone()).unwrap();
+            assert_eq!(log.sealed.len(), 1);
+            log.append(b"after").unwrap();
+            assert_eq!(log.read(0).unwrap(), b"before");
+            assert_eq!(
+                log.active_segment.cipher.as_ref().unwrap().key_id(),
+                KeyId(2)
+            );
+        }
+
+        let err = Log::open(dir.path(), config(Encryption::new(KeyId(2), new.clone())))
+            .unwrap()
+            .read(0)
+            .unwrap_err();
+        assert!(err.to_string().contains("no master key with id 1"), "{err}");
+
+        let provider: std::collections::BTreeMap<_, _> = [(KeyId(1), old)].into();
+        let encryption = Encryption::new(KeyId(2), new).with_provider(Arc::new(provider));
+        let mut log = Log::open(dir.path(), config(encryption)).unwrap();
+        assert_eq!(log.read(0).unwrap(), b"before");
+        assert_eq!(log.read(1).unwrap(), b"after");
+        assert!(Log::open(tempdir().unwrap().path(), Config::default())
+            .unwrap()
+            .rotate_key(KeyId(1), MasterKey::new([0; 32]))
+            .is_err());
     }
 
     #[test]
     fn test_segment_rolling() {
         let dir = tempdir().unwrap();
         // Tiny max_segment_bytes to force rolling
-        let config = Config { max_segment_bytes: 30 }; 
+        let config = Config {
+            max_segment_bytes: 30,
+            ..Config::default()
+        };
         let path = dir.path().to_path_buf();
 
         {
             let mut log = Log::open(&path, config).unwrap();
-            log.append(b"first").unwrap(); 
-            log.append(b"second").unwrap(); 
+            log.append(b"first").unwrap();
+            log.append(b"second").unwrap();
         }
-        
+
         let segments = crate::discover_segments(&path).unwrap();
         assert_eq!(segments.len(), 2);
         assert_eq!(segments[0].base_offset, 0);
@@ -312,9 +3447,10 @@ mod log_tests {
         let dir = tempdir().unwrap();
         let path = dir.path().to_path_buf();
         let log_path;
-        
+
         {
-            let mut log = Log::open(&path, Config::default()).expect("Failed to open log first time");
+            let mut log =
+                Log::open(&path, Config::default()).expect("Failed to open log first time");
             log.append(b"valid").expect("Failed to append valid");
             log.flush().expect("Failed to flush");
             log_path = log.active_segment.info.log_path.clone();
@@ -332,7 +3468,7 @@ mod log_tests {
                 Err(_) if i < 49 => {
                     std::thread::sleep(std::time::Duration::from_millis(10));
                 }
-                Err(e) => panic!("Failed to open manually: {:?}", e),
+                Err(e) => panic!("Failed to open manually: {e:?}"),
             }
         }
         let mut f = f.unwrap();
@@ -340,7 +3476,7 @@ mod log_tests {
         f.write_all(&[0x44, 0x4C, 0x4F, 0x47]).unwrap(); // Magic only
         drop(f);
         std::thread::sleep(std::time::Duration::from_millis(100));
-        
+
         // Reopen should truncate the partial write
         // On Windows, we might need a retry because the OS takes time to release handles
         let mut log = None;
@@ -353,18 +3489,269 @@ mod log_tests {
                 Err(_) if i < 49 => {
                     std::thread::sleep(std::time::Duration::from_millis(10));
                 }
-                Err(e) => panic!("Failed to open for recovery: {:?}", e),
+                Err(e) => panic!("Failed to open for recovery: {e:?}"),
             }
         }
         let mut log = log.unwrap();
         assert_eq!(log.active_segment.next_offset, 1);
         assert_eq!(log.read(0).unwrap(), b"valid");
-        
+
         // Should be able to append normally now
         log.append(b"new").unwrap();
         assert_eq!(log.read(1).unwrap(), b"new");
     }
 
+    #[test]
+    fn test_format_versions_reports_mixed_segments() {
+        let dir = tempdir().unwrap();
+        let mut sealed_v1 = Vec::new();
+        for (i, format) in [
+            (0, RecordFormat::V1),
+            (1, RecordFormat::V2),
+            (2, RecordFormat::Compact),
+        ] {
+            let config = Config {
+                format,
+                max_segment_bytes: 100,
+                ..Config::default()
+            };
+            let mut log = Log::open(dir.path(), config).unwrap();
+            for j in 0..5u8 {
+                log.append(&[i * 10 + j; 10]).unwrap();
+            }
+            if format == RecordFormat::V1 {
+                sealed_v1 = log
+                    .sealed
+                    .iter()
+                    .map(|info| {
+                        (
+                            info.log_path.clone(),
+                            std::fs::read(&info.log_path).unwrap(),
+                        )
+                    })
+                    .collect();
+            }
+            log.close().unwrap();
+        }
+        assert!(!sealed_v1.is_empty());
+
+        let log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.format_versions().unwrap(), BTreeSet::from([1, 2, 3]));
+        // Upgrading never rewrites the segments written before it.
+        for (path, bytes) in sealed_v1 {
+            assert_eq!(std::fs::read(path).unwrap(), bytes);
+        }
+        let payloads: Vec<u8> = log
+            .reader()
+            .unwrap()
+            .iter()
+            .map(|record| record.unwrap().payload[0])
+            .collect();
+        assert_eq!(
+            payloads,
+            [0, 1, 2, 3, 4, 10, 11, 12, 13, 14, 20, 21, 22, 23, 24]
+        );
+
+        // A frame from a newer release is reported even though it cannot be read.
+        let mut future = MAGIC.to_le_bytes().to_vec();
+        future.extend_from_slice(&[9; HEADER_LEN - 4]);
+        let path = &log.active_segment.info.log_path;
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&future).unwrap();
+        assert_eq!(log.format_versions().unwrap(), BTreeSet::from([1, 2, 3, 9]));
+    }
+
+    #[test]
+    fn test_v2_format_mixes_with_v1_and_guards_headers() {
+        let dir = tempdir().unwrap();
+        let v2 = Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        };
+        {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"v1").unwrap();
+            log.flush().unwrap();
+        }
+        let log_path = {
+            let mut log = Log::open(dir.path(), v2.clone()).unwrap();
+            log.append_batch(&[b"v2-a", b"v2-b"]).unwrap();
+            log.flush().unwrap();
+            assert_eq!(log.read(0).unwrap(), b"v1");
+            assert_eq!(log.read(2).unwrap(), b"v2-b");
+            log.active_segment.info.log_path.clone()
+        };
+
+        // Corrupt the payload_len of the last (v2) record: recovery must not trust it.
+        let mut data = std::fs::read(&log_path).unwrap();
+        let last = data.len() - (HEADER_LEN_V2 + 4);
+        data[last + 16] ^= 0x01;
+        std::fs::write(&log_path, data).unwrap();
+
+        // The torn record takes the rest of its batch with it.
+        let mut log = Log::open(dir.path(), v2).unwrap();
+        assert_eq!(log.next_offset(), 1);
+        assert_eq!(log.read(0).unwrap(), b"v1");
+        let reader = crate::LogReader::open(dir.path()).unwrap();
+        assert_eq!(reader.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_compact_format_shrinks_frames_and_recovers() {
+        let write = |dir: &Path, format| {
+            let mut log = Log::open(
+                dir,
+                Config {
+                    format,
+                    ..Config::default()
+                },
+            )
+            .unwrap();
+            for i in 0..200u32 {
+                log.append(&i.to_le_bytes()).unwrap();
+            }
+            log.append_keyed(b"key", b"value").unwrap();
+            log.append_batch_frame(&[b"a", b"bc", b"def"]).unwrap();
+            log.flush().unwrap();
+            log
+        };
+        let (v1_dir, dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let v1_len = write(v1_dir.path(), RecordFormat::V1)
+            .stats()
+            .unwrap()
+            .active_segment_bytes;
+        let mut log = write(dir.path(), RecordFormat::Compact);
+        let len = log.stats().unwrap().active_segment_bytes;
+        // 17 or 18 bytes for each 4-byte record rather than 28.
+        assert!(len * 3 < v1_len * 2, "{len} vs {v1_len}");
+
+        let err = log.append_with_timestamp(b"x", 5).unwrap_err();
+        assert!(err.to_string().contains("V2"), "{err}");
+        assert_eq!(log.read(150).unwrap(), 150u32.to_le_bytes());
+        let records: Vec<_> = log.reader().unwrap().iter().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 204);
+        assert_eq!(records[200].key.as_deref(), Some(&b"key"[..]));
+        assert_eq!(records[203].payload, b"def");
+        assert_eq!(records[0].timestamp, None);
+        let log_path = log.active_segment.info.log_path.clone();
+        drop(log);
+
+        // A torn batch frame is cut off, leaving the records before it.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+        let log = Log::open(
+            dir.path(),
+            Config {
+                format: RecordFormat::Compact,
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(log.next_offset(), 201);
+    }
+
+    #[test]
+    fn test_v2_records_carry_timestamps() {
+        let dir = tempdir().unwrap();
+        {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"v1").unwrap();
+            let err = log.append_with_timestamp(b"v1", 5).unwrap_err();
+            assert!(err.to_string().contains("V2"), "{err}");
+        }
+        let v2 = Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        };
+        let before = now_millis();
+        let mut log = Log::open(dir.path(), v2).unwrap();
+        log.append(b"now").unwrap();
+        log.append_with_timestamp(b"then", 1_000).unwrap();
+        log.flush().unwrap();
+        let after = now_millis();
+
+        let records: Vec<Record> = crate::LogReader::open(dir.path())
+            .unwrap()
+            .iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(records[0].timestamp, None);
+        let stamped = records[1].timestamp.unwrap();
+        assert!((before..=after).contains(&stamped));
+        assert_eq!(records[2].timestamp, Some(1_000));
+    }
+
+    #[test]
+    fn test_v2_records_carry_headers() {
+        let dir = tempdir().unwrap();
+        {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            let err = log
+                .append_with_headers(b"v1", &[("trace", b"1")])
+                .unwrap_err();
+            assert!(err.to_string().contains("V2"), "{err}");
+        }
+        let v2 = Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), v2).unwrap();
+        let headers: &[(&str, &[u8])] = &[("trace", b"abc"), ("tenant", b""), ("trace", b"def")];
+        log.append_with_headers(b"payload", headers).unwrap();
+        log.append(b"plain").unwrap();
+        log.flush().unwrap();
+        assert_eq!(log.read(0).unwrap(), b"payload");
+
+        let records: Vec<Record> = crate::LogReader::open(dir.path())
+            .unwrap()
+            .iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(records[0].payload, b"payload");
+        assert_eq!(records[0].headers().len(), 3);
+        assert_eq!(records[0].header("trace"), Some(&b"abc"[..]));
+        assert_eq!(records[0].header("tenant"), Some(&b""[..]));
+        assert_eq!(records[0].header("missing"), None);
+        assert!(records[1].headers().is_empty());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn test_checksum_algorithm_is_recorded_per_record() {
+        let dir = tempdir().unwrap();
+        let crc32c = Config {
+            checksum: ChecksumAlgorithm::Crc32c,
+            ..Config::default()
+        };
+        {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"crc32").unwrap();
+        }
+        let log_path = {
+            let mut log = Log::open(dir.path(), crc32c).unwrap();
+            log.append(b"crc32c").unwrap();
+            log.flush().unwrap();
+            log.active_segment.info.log_path.clone()
+        };
+
+        let reader = crate::LogReader::open(dir.path()).unwrap();
+        let records: Vec<_> = reader.iter().map(|r| r.unwrap().payload).collect();
+        assert_eq!(records, [&b"crc32"[..], b"crc32c"]);
+
+        let mut data = std::fs::read(&log_path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        std::fs::write(&log_path, data).unwrap();
+        assert!(matches!(
+            reader.read(1),
+            Err(Error::ChecksumMismatch { offset: 1, .. })
+        ));
+    }
+
     #[test]
     fn test_corruption_detection() {
         let dir = tempdir().unwrap();
@@ -380,7 +3767,7 @@ mod log_tests {
 
         // Flip a bit in the payload (offset 24 + something)
         let mut data = std::fs::read(&log_file_path).unwrap();
-        data[25] ^= 0xFF; 
+        data[25] ^= 0xFF;
         std::fs::write(&log_file_path, data).unwrap();
 
         let mut log = Log::open(&path, Config::default()).unwrap();
@@ -1,26 +1,51 @@
 //! Log directory open and exclusive writer lock.
 
 use crate::error::Error;
-use crate::segment::{discover_segments, SegmentInfo};
+use crate::segment::{discover_segments_in, SegmentFooter, SegmentInfo, FOOTER_LEN};
+use crate::storage::{self, Backend, OpenMode, StorageFile};
 use crate::Result;
-use fs2::FileExt;
-use std::fs::{self, File, OpenOptions};
+use std::fmt::Debug;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-/// Name of the lock file used to ensure a single writer per log directory.
-const LOCK_FILE_NAME: &str = "write.lock";
+/// Name of the file recording the logical start offset after prefix truncation.
+const START_OFFSET_FILE_NAME: &str = "start.offset";
+
+/// Name of the file recording the committed watermark set by
+/// [`Log::commit`](crate::Log::commit).
+const COMMITTED_OFFSET_FILE_NAME: &str = "committed.offset";
+
+/// Name of the file left by [`Log::close`](crate::Log::close) describing the
+/// active segment, so that the next open can skip recovery.
+const CLEAN_SHUTDOWN_FILE_NAME: &str = "clean.shutdown";
+
+/// Name of the file where the writer publishes its durable watermark for
+/// readers in other processes; see [`DurableOffsetFile`].
+const DURABLE_OFFSET_FILE_NAME: &str = "durable.offset";
+
+/// Size of an entry of the durable watermark file: offset and CRC.
+const DURABLE_OFFSET_ENTRY_LEN: usize = 12;
+
+/// Entries the durable watermark file holds before it is rewritten with one.
+const DURABLE_OFFSET_MAX_ENTRIES: u64 = 1024;
+
+/// Size of the clean-shutdown file: segment length, summary and CRC.
+const CLEAN_SHUTDOWN_LEN: usize = 8 + FOOTER_LEN + 4;
 
 /// An open log directory with exclusive write lock held.
 ///
-/// Creating a `LogDir` acquires an OS-level exclusive lock on `write.lock`.
-/// Only one `LogDir` (per process or per machine, depending on OS) can exist
-/// for a given path at a time. Drop releases the lock.
+/// Creating a `LogDir` takes the storage backend's writer lock; on the
+/// filesystem, an OS-level exclusive lock on `write.lock`. Only one `LogDir`
+/// (per process or per machine, depending on OS) can exist for a given path at
+/// a time. Drop releases the lock.
 #[derive(Debug)]
 pub struct LogDir {
     /// Root path of the log directory.
     path: PathBuf,
-    /// Lock file held open for the duration; lock is released on drop.
-    _lock: File,
+    /// Backend holding the directory's files.
+    storage: Arc<dyn Backend>,
+    /// Writer lock held for the duration; released on drop.
+    _lock: Box<dyn Debug + Send + Sync>,
     /// Discovered segments sorted by base offset.
     segments: Vec<SegmentInfo>,
 }
@@ -34,42 +59,35 @@ impl LogDir {
     /// - I/O errors when creating the directory or reading it.
     /// - [`Error::Locked`] if the lock is already held (e.g. another process or holder).
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_storage(path, storage::default_backend())
+    }
+
+    /// Like [`open`](Self::open), with the directory's files in `storage`.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`open`](Self::open).
+    pub fn open_with_storage(path: impl AsRef<Path>, storage: Arc<dyn Backend>) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
-        fs::create_dir_all(&path).map_err(Error::from)?;
-
-        let lock_path = path.join(LOCK_FILE_NAME);
-        
-        let mut file = None;
-        for i in 0..10 {
-            match OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(false)
-                .open(&lock_path)
-            {
-                Ok(f) => {
-                    file = Some(f);
-                    break;
-                }
-                Err(e) if i < 9 && e.kind() == std::io::ErrorKind::PermissionDenied => {
-                    std::thread::sleep(std::time::Duration::from_millis(20));
-                }
-                Err(e) => return Err(Error::from(e)),
-            }
-        }
-        let file = file.unwrap();
+        storage.create_dir_all(&path).map_err(Error::from)?;
+        storage.sync_dir(storage::parent_dir(&path))?;
 
-        file.try_lock_exclusive().map_err(|e| {
-            Error::Locked(format!(
-                "log directory is already locked (single writer required): {e}"
-            ))
+        let lock = storage.lock(&path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                Error::Locked(format!(
+                    "log directory is already locked (single writer required): {e}"
+                ))
+            } else {
+                Error::from(e)
+            }
         })?;
 
-        let segments = discover_segments(&path)?;
+        let segments = discover_segments_in(&storage, &path)?;
 
         Ok(Self {
             path,
-            _lock: file,
+            storage,
+            _lock: lock,
             segments,
         })
     }
@@ -80,6 +98,12 @@ impl LogDir {
         &self.path
     }
 
+    /// Returns the backend holding the directory's files.
+    #[must_use]
+    pub fn storage(&self) -> &Arc<dyn Backend> {
+        &self.storage
+    }
+
     /// Returns discovered segments in order of base offset (ascending).
     #[must_use]
     pub fn segments(&self) -> &[SegmentInfo] {
@@ -87,9 +111,253 @@ impl LogDir {
     }
 }
 
+/// Reads the persisted log start offset, if prefix truncation has ever recorded one.
+///
+/// # Errors
+///
+/// Same as [`read_offset_file`].
+pub(crate) fn read_start_offset(storage: &dyn Backend, dir: &Path) -> Result<Option<u64>> {
+    read_offset_file(storage, dir, START_OFFSET_FILE_NAME)
+}
+
+/// Atomically replaces the persisted log start offset.
+///
+/// # Errors
+///
+/// Same as [`write_offset_file`].
+pub(crate) fn write_start_offset(storage: &dyn Backend, dir: &Path, offset: u64) -> Result<()> {
+    write_offset_file(storage, dir, START_OFFSET_FILE_NAME, offset)
+}
+
+/// Reads the persisted committed watermark, if [`Log::commit`](crate::Log::commit)
+/// has ever recorded one.
+///
+/// # Errors
+///
+/// Same as [`read_offset_file`].
+pub(crate) fn read_committed_offset(storage: &dyn Backend, dir: &Path) -> Result<Option<u64>> {
+    read_offset_file(storage, dir, COMMITTED_OFFSET_FILE_NAME)
+}
+
+/// Atomically replaces the persisted committed watermark.
+///
+/// # Errors
+///
+/// Same as [`write_offset_file`].
+pub(crate) fn write_committed_offset(storage: &dyn Backend, dir: &Path, offset: u64) -> Result<()> {
+    write_offset_file(storage, dir, COMMITTED_OFFSET_FILE_NAME, offset)
+}
+
+/// Reads an offset file written by [`write_offset_file`], or `None` if there is none.
+///
+/// The file holds the offset (u64) followed by a CRC-32 of those 8 bytes, both
+/// little-endian.
+///
+/// # Errors
+///
+/// - I/O errors other than the file not existing.
+/// - [`Error::Corruption`] if the file is malformed or its checksum does not match.
+fn read_offset_file(storage: &dyn Backend, dir: &Path, name: &str) -> Result<Option<u64>> {
+    let Some(bytes) = storage::read_file(storage, &dir.join(name))? else {
+        return Ok(None);
+    };
+    let (Some(offset), Some(crc)) = (bytes.get(0..8), bytes.get(8..12)) else {
+        return Err(Error::Corruption(format!(
+            "{name} has {} bytes (expected 12)",
+            bytes.len()
+        )));
+    };
+    let mut crc_bytes = [0u8; 4];
+    crc_bytes.copy_from_slice(crc);
+    if crc32fast::hash(offset) != u32::from_le_bytes(crc_bytes) {
+        return Err(Error::Corruption(format!("{name} checksum mismatch")));
+    }
+    let mut offset_bytes = [0u8; 8];
+    offset_bytes.copy_from_slice(offset);
+    Ok(Some(u64::from_le_bytes(offset_bytes)))
+}
+
+/// Atomically replaces an offset file (write temp, fsync, rename).
+///
+/// # Errors
+///
+/// Returns I/O errors from writing, syncing, or renaming the file.
+fn write_offset_file(storage: &dyn Backend, dir: &Path, name: &str, offset: u64) -> Result<()> {
+    let mut contents = offset.to_le_bytes().to_vec();
+    contents.extend_from_slice(&crc32fast::hash(&offset.to_le_bytes()).to_le_bytes());
+    storage::write_atomic(storage, &dir.join(name), &contents)?;
+    Ok(())
+}
+
+/// The writer's handle on the file publishing its durable watermark, so that
+/// [`LogReader`](crate::LogReader)s in other processes can stop where the
+/// writer's fsynced records end (see
+/// [`LogReader::with_durable_only`](crate::LogReader::with_durable_only)).
+///
+/// Each publication appends an entry like an offset file's: the offset (u64)
+/// and a CRC-32 of it. Readers take the last entry that is whole and intact, so
+/// an append torn by a crash or caught half-written by a reader only hides the
+/// newest value. Appends are not fsynced: the records below every published
+/// offset are, so a lost entry only leaves readers further behind. The file is
+/// replaced atomically when the watermark moves back and once it holds
+/// [`DURABLE_OFFSET_MAX_ENTRIES`] entries.
+#[derive(Debug)]
+pub(crate) struct DurableOffsetFile {
+    path: PathBuf,
+    file: Box<dyn StorageFile>,
+    /// The offset published last.
+    offset: u64,
+    entries: u64,
+}
+
+impl DurableOffsetFile {
+    /// Replaces the file in `dir` with one publishing `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing, syncing, or renaming the file.
+    pub(crate) fn create(storage: &dyn Backend, dir: &Path, offset: u64) -> Result<Self> {
+        let path = dir.join(DURABLE_OFFSET_FILE_NAME);
+        storage::write_atomic(storage, &path, &durable_offset_entry(offset))?;
+        let file = storage.open(&path, OpenMode::Write)?;
+        Ok(Self {
+            path,
+            file,
+            offset,
+            entries: 1,
+        })
+    }
+
+    /// Publishes `offset`, which must not be below the offset published last.
+    /// Does nothing if it equals it.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing the file.
+    pub(crate) fn publish(&mut self, storage: &dyn Backend, offset: u64) -> Result<()> {
+        if offset == self.offset {
+            return Ok(());
+        }
+        if self.entries >= DURABLE_OFFSET_MAX_ENTRIES {
+            return self.replace(storage, offset);
+        }
+        self.file.append(&durable_offset_entry(offset))?;
+        self.offset = offset;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Atomically replaces the file with one publishing `offset`, which may lie
+    /// below the offset published last.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing, syncing, or renaming the file.
+    pub(crate) fn replace(&mut self, storage: &dyn Backend, offset: u64) -> Result<()> {
+        let dir = storage::parent_dir(&self.path).to_path_buf();
+        *self = Self::create(storage, &dir, offset)?;
+        Ok(())
+    }
+}
+
+/// Encodes an entry of the durable watermark file.
+fn durable_offset_entry(offset: u64) -> [u8; DURABLE_OFFSET_ENTRY_LEN] {
+    let mut entry = [0u8; DURABLE_OFFSET_ENTRY_LEN];
+    entry[..8].copy_from_slice(&offset.to_le_bytes());
+    entry[8..].copy_from_slice(&crc32fast::hash(&offset.to_le_bytes()).to_le_bytes());
+    entry
+}
+
+/// Reads the durable watermark last published by a writer, or `None` if no
+/// writer has published one (or no entry is intact).
+///
+/// # Errors
+///
+/// Returns I/O errors other than the file not existing.
+pub(crate) fn read_durable_offset(storage: &dyn Backend, dir: &Path) -> Result<Option<u64>> {
+    let Some(bytes) = storage::read_file(storage, &dir.join(DURABLE_OFFSET_FILE_NAME))? else {
+        return Ok(None);
+    };
+    Ok(bytes
+        .chunks_exact(DURABLE_OFFSET_ENTRY_LEN)
+        .rev()
+        .find_map(|entry| {
+            let offset = u64::from_le_bytes(entry[..8].try_into().ok()?);
+            let crc = u32::from_le_bytes(entry[8..].try_into().ok()?);
+            (crc32fast::hash(&entry[..8]) == crc).then_some(offset)
+        }))
+}
+
+/// State of the active segment recorded on a clean shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CleanShutdown {
+    /// Length of the segment's `.log` file when the log was closed.
+    pub(crate) log_len: u64,
+    /// Summary of the segment, in the form its footer will take once sealed.
+    pub(crate) summary: SegmentFooter,
+}
+
+/// Reads and removes the clean-shutdown marker, so that a crash before the next
+/// [`write_clean_shutdown`] forces recovery.
+///
+/// Returns `None` if there is no marker or it is malformed.
+///
+/// # Errors
+///
+/// Returns I/O errors other than the file not existing.
+pub(crate) fn take_clean_shutdown(
+    storage: &dyn Backend,
+    dir: &Path,
+) -> Result<Option<CleanShutdown>> {
+    let path = dir.join(CLEAN_SHUTDOWN_FILE_NAME);
+    let Some(bytes) = storage::read_file(storage, &path)? else {
+        return Ok(None);
+    };
+    storage.delete(&path)?;
+    if bytes.len() != CLEAN_SHUTDOWN_LEN {
+        return Ok(None);
+    }
+    let (contents, crc) = bytes.split_at(CLEAN_SHUTDOWN_LEN - 4);
+    let mut crc_bytes = [0u8; 4];
+    crc_bytes.copy_from_slice(crc);
+    if crc32fast::hash(contents) != u32::from_le_bytes(crc_bytes) {
+        return Ok(None);
+    }
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&contents[..8]);
+    Ok(
+        SegmentFooter::decode(&contents[8..]).map(|summary| CleanShutdown {
+            log_len: u64::from_le_bytes(len_bytes),
+            summary,
+        }),
+    )
+}
+
+/// Atomically writes the clean-shutdown marker (write temp, fsync, rename).
+///
+/// The file holds the segment length (u64), the summary encoded as a segment
+/// footer, and a CRC-32 of both, all little-endian.
+///
+/// # Errors
+///
+/// Returns I/O errors from writing, syncing, or renaming the file.
+pub(crate) fn write_clean_shutdown(
+    storage: &dyn Backend,
+    dir: &Path,
+    state: &CleanShutdown,
+) -> Result<()> {
+    let mut contents = Vec::with_capacity(CLEAN_SHUTDOWN_LEN);
+    contents.extend_from_slice(&state.log_len.to_le_bytes());
+    contents.extend_from_slice(&state.summary.encode());
+    contents.extend_from_slice(&crc32fast::hash(&contents).to_le_bytes());
+    storage::write_atomic(storage, &dir.join(CLEAN_SHUTDOWN_FILE_NAME), &contents)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::FsBackend;
 
     #[test]
     fn open_empty_dir_succeeds() {
@@ -133,4 +401,77 @@ mod tests {
         let third = LogDir::open(dir.path()).unwrap();
         drop(third);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn start_offset_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_start_offset(&FsBackend, dir.path()).unwrap(), None);
+        write_start_offset(&FsBackend, dir.path(), 42).unwrap();
+        assert_eq!(read_start_offset(&FsBackend, dir.path()).unwrap(), Some(42));
+
+        std::fs::write(dir.path().join(START_OFFSET_FILE_NAME), [0u8; 12]).unwrap();
+        assert!(read_start_offset(&FsBackend, dir.path()).is_err());
+    }
+
+    #[test]
+    fn clean_shutdown_is_taken_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = CleanShutdown {
+            log_len: 120,
+            summary: SegmentFooter {
+                record_count: 3,
+                first_offset: 7,
+                last_offset: 9,
+                first_timestamp: Some(1),
+                last_timestamp: Some(2),
+                segment_crc: 0x1234_5678,
+            },
+        };
+        write_clean_shutdown(&FsBackend, dir.path(), &state).unwrap();
+        assert_eq!(
+            take_clean_shutdown(&FsBackend, dir.path()).unwrap(),
+            Some(state)
+        );
+        assert_eq!(take_clean_shutdown(&FsBackend, dir.path()).unwrap(), None);
+
+        write_clean_shutdown(&FsBackend, dir.path(), &state).unwrap();
+        let path = dir.path().join(CLEAN_SHUTDOWN_FILE_NAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(take_clean_shutdown(&FsBackend, dir.path()).unwrap(), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn torn_durable_offset_entries_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_durable_offset(&FsBackend, dir.path()).unwrap(), None);
+        let mut file = DurableOffsetFile::create(&FsBackend, dir.path(), 3).unwrap();
+        file.publish(&FsBackend, 5).unwrap();
+        assert_eq!(
+            read_durable_offset(&FsBackend, dir.path()).unwrap(),
+            Some(5)
+        );
+
+        // A half-written entry, then a whole one with a bad checksum.
+        let path = dir.path().join(DURABLE_OFFSET_FILE_NAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&durable_offset_entry(9)[..7]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            read_durable_offset(&FsBackend, dir.path()).unwrap(),
+            Some(5)
+        );
+        bytes.truncate(DURABLE_OFFSET_ENTRY_LEN * 2);
+        bytes[DURABLE_OFFSET_ENTRY_LEN] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            read_durable_offset(&FsBackend, dir.path()).unwrap(),
+            Some(3)
+        );
+
+        file.replace(&FsBackend, 2).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), durable_offset_entry(2));
+    }
+}
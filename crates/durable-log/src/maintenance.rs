@@ -0,0 +1,415 @@
+//! Scheduled maintenance: retention, TTL cleanup, archival and custom tasks run
+//! in the background when their triggers fire.
+//!
+//! A [`MaintenanceScheduler`] polls a shared log every
+//! [`MaintenanceConfig::poll_interval`] and runs each [`ScheduledTask`] once any
+//! of its [`Trigger`]s fires: time since the task last ran, the share of the log
+//! written since then, or disk pressure. The log itself has no compaction, so
+//! applications that compact (or do any other upkeep) schedule it as a
+//! [`MaintenanceTask::Custom`] task.
+//!
+//! [`MaintenanceHooks`] are called before and after every task, outside the
+//! log's lock; returning `false` from [`before`](MaintenanceHooks::before)
+//! postpones a task to the next poll, for example during peak traffic.
+//! [`MaintenanceScheduler::pause`] postpones every task until
+//! [`resume`](MaintenanceScheduler::resume).
+
+use crate::archive::Archive;
+use crate::log::Log;
+use crate::retention::RetentionPolicy;
+use crate::Result;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A custom maintenance task: runs with the log locked and returns a count to
+/// report to [`MaintenanceHooks::after`].
+pub type CustomTask = Arc<dyn Fn(&mut Log) -> Result<usize> + Send + Sync>;
+
+/// Work a [`MaintenanceScheduler`] can run. Each returns a count, passed to
+/// [`MaintenanceHooks::after`].
+#[derive(Clone)]
+pub enum MaintenanceTask {
+    /// [`Log::enforce_retention`]: deletes the segments outside the log's
+    /// retention policy or whose records have all expired.
+    Retention,
+    /// [`Log::delete_expired`]: deletes only the segments whose records have all
+    /// expired.
+    TtlCleanup,
+    /// [`Log::archive`]: moves the sealed segments outside `local` to `archive`.
+    Archive {
+        /// Where segments are moved.
+        archive: Arc<Archive>,
+        /// The segments kept locally.
+        local: RetentionPolicy,
+    },
+    /// An application task, such as compaction.
+    Custom {
+        /// Name identifying the task to hooks.
+        name: String,
+        /// The task.
+        run: CustomTask,
+    },
+}
+
+impl MaintenanceTask {
+    /// Returns a short name for the task: `retention`, `ttl-cleanup`, `archive`,
+    /// or a custom task's name.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Retention => "retention",
+            Self::TtlCleanup => "ttl-cleanup",
+            Self::Archive { .. } => "archive",
+            Self::Custom { name, .. } => name,
+        }
+    }
+
+    fn run(&self, log: &mut Log) -> Result<usize> {
+        match self {
+            Self::Retention => log.enforce_retention(),
+            Self::TtlCleanup => log.delete_expired(),
+            Self::Archive { archive, local } => log.archive(archive, *local),
+            Self::Custom { run, .. } => run(log),
+        }
+    }
+}
+
+impl fmt::Debug for MaintenanceTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Archive { archive, local } => f
+                .debug_struct("Archive")
+                .field("archive", archive)
+                .field("local", local)
+                .finish(),
+            Self::Custom { name, .. } => f.debug_struct("Custom").field("name", name).finish(),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+/// A condition under which a [`ScheduledTask`] runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// This long has passed since the task last ran, or since the scheduler
+    /// started.
+    Interval(Duration),
+    /// Segment and index bytes written since the task last ran make up at least
+    /// this fraction (0 to 1) of the log's size.
+    DirtyRatio(f64),
+    /// The log's segment and index files take more than this many bytes.
+    DiskUsage(u64),
+    /// The filesystem holding the log has fewer than this many bytes available
+    /// (`fs` feature).
+    #[cfg(feature = "fs")]
+    LowFreeSpace(u64),
+}
+
+/// A task and the triggers that run it; any one of them firing is enough.
+#[derive(Debug, Clone)]
+pub struct ScheduledTask {
+    /// The work to run.
+    pub task: MaintenanceTask,
+    /// When to run it.
+    pub triggers: Vec<Trigger>,
+}
+
+/// Callbacks around each task a [`MaintenanceScheduler`] runs, for
+/// applications that coordinate with maintenance. Both have no-op defaults and
+/// run on the scheduler thread, without the log locked.
+pub trait MaintenanceHooks: fmt::Debug + Send + Sync {
+    /// `task` is about to run; returning `false` postpones it to the next poll.
+    fn before(&self, _task: &MaintenanceTask) -> bool {
+        true
+    }
+
+    /// `task` ran, with `outcome`.
+    fn after(&self, _task: &MaintenanceTask, _outcome: &Result<usize>) {}
+}
+
+/// What a [`MaintenanceScheduler`] runs, and how often it checks.
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    /// How often triggers are checked.
+    pub poll_interval: Duration,
+    /// The tasks, checked and run in order on each poll.
+    pub tasks: Vec<ScheduledTask>,
+    /// Called around every task, if set.
+    pub hooks: Option<Arc<dyn MaintenanceHooks>>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(10),
+            tasks: Vec::new(),
+            hooks: None,
+        }
+    }
+}
+
+/// Background thread running a log's maintenance; see the
+/// [module docs](self).
+///
+/// The thread stops when the scheduler is dropped or [`stop`](Self::stop) is
+/// called. Errors from tasks go to [`MaintenanceHooks::after`]; the task runs
+/// again the next time a trigger fires.
+#[derive(Debug)]
+pub struct MaintenanceScheduler {
+    /// Dropping the sender wakes the thread and tells it to exit.
+    stop: Option<mpsc::Sender<()>>,
+    paused: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MaintenanceScheduler {
+    /// Spawns a thread that runs `config`'s tasks on `log`.
+    #[must_use]
+    pub fn spawn(log: Arc<Mutex<Log>>, config: MaintenanceConfig) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let paused = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let paused = Arc::clone(&paused);
+            std::thread::spawn(move || {
+                let mut states = vec![TaskState::new(&log); config.tasks.len()];
+                // Disconnection (the scheduler was dropped) or an explicit stop ends the loop.
+                while stopped.recv_timeout(config.poll_interval) == Err(RecvTimeoutError::Timeout) {
+                    if !paused.load(Ordering::SeqCst) {
+                        poll(&log, &config, &mut states);
+                    }
+                }
+            })
+        };
+        Self {
+            stop: Some(stop),
+            paused,
+            handle: Some(handle),
+        }
+    }
+
+    /// Postpones every task until [`resume`](Self::resume). A task already
+    /// running finishes.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets tasks run again after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns true while tasks are paused.
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Stops the background thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for MaintenanceScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// When a task last ran, for its triggers.
+#[derive(Debug, Clone, Copy)]
+struct TaskState {
+    last_run: Instant,
+    /// The log's size after the task last ran, or its smallest size since.
+    clean_bytes: u64,
+}
+
+impl TaskState {
+    fn new(log: &Arc<Mutex<Log>>) -> Self {
+        Self {
+            last_run: Instant::now(),
+            clean_bytes: disk_bytes(&lock(log)),
+        }
+    }
+
+    // Rounding sizes past 2^52 bytes does not matter for a ratio.
+    #[allow(clippy::cast_precision_loss)]
+    fn fires(&self, trigger: Trigger, log: &LogUsage) -> bool {
+        match trigger {
+            Trigger::Interval(interval) => self.last_run.elapsed() >= interval,
+            Trigger::DirtyRatio(ratio) => {
+                let dirty = log.disk_bytes.saturating_sub(self.clean_bytes);
+                log.disk_bytes > 0 && dirty as f64 >= ratio * log.disk_bytes as f64
+            }
+            Trigger::DiskUsage(max) => log.disk_bytes > max,
+            #[cfg(feature = "fs")]
+            Trigger::LowFreeSpace(min) => log.available.is_some_and(|free| free < min),
+        }
+    }
+}
+
+/// The log's disk usage, measured once per poll.
+struct LogUsage {
+    disk_bytes: u64,
+    /// Bytes available on the log's filesystem, if known.
+    #[cfg(feature = "fs")]
+    available: Option<u64>,
+}
+
+impl LogUsage {
+    fn measure(log: &Log) -> Self {
+        Self {
+            disk_bytes: disk_bytes(log),
+            #[cfg(feature = "fs")]
+            available: fs2::available_space(log.path()).ok(),
+        }
+    }
+}
+
+/// Checks every task's triggers and runs the ones that fire.
+fn poll(log: &Arc<Mutex<Log>>, config: &MaintenanceConfig, states: &mut [TaskState]) {
+    let mut usage = LogUsage::measure(&lock(log));
+    for (scheduled, state) in config.tasks.iter().zip(states) {
+        state.clean_bytes = state.clean_bytes.min(usage.disk_bytes);
+        if !scheduled.triggers.iter().any(|&t| state.fires(t, &usage)) {
+            continue;
+        }
+        let task = &scheduled.task;
+        if let Some(hooks) = &config.hooks {
+            if !hooks.before(task) {
+                continue;
+            }
+        }
+        let mut locked = lock(log);
+        let outcome = task.run(&mut locked);
+        usage = LogUsage::measure(&locked);
+        drop(locked);
+        *state = TaskState {
+            last_run: Instant::now(),
+            clean_bytes: usage.disk_bytes,
+        };
+        if let Some(hooks) = &config.hooks {
+            hooks.after(task, &outcome);
+        }
+    }
+}
+
+/// Returns the size of the log's segment and index files, or 0 if unknown.
+fn disk_bytes(log: &Log) -> u64 {
+    log.stats().map_or(0, |stats| stats.disk_bytes)
+}
+
+fn lock(log: &Arc<Mutex<Log>>) -> MutexGuard<'_, Log> {
+    log.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Counts tasks run and vetoes them while `busy` is set.
+    #[derive(Debug, Default)]
+    struct Peak {
+        busy: AtomicBool,
+        runs: AtomicUsize,
+        deleted: AtomicUsize,
+    }
+
+    impl MaintenanceHooks for Peak {
+        fn before(&self, _task: &MaintenanceTask) -> bool {
+            !self.busy.load(Ordering::SeqCst)
+        }
+
+        fn after(&self, task: &MaintenanceTask, outcome: &Result<usize>) {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            if task.name() == "retention" {
+                self.deleted
+                    .fetch_add(*outcome.as_ref().unwrap(), Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "{what}");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn tasks_run_when_triggered_unless_postponed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            retention: RetentionPolicy {
+                max_total_bytes: Some(400),
+                ..RetentionPolicy::default()
+            },
+            ..Config::default()
+        };
+        let log = Arc::new(Mutex::new(Log::open(dir.path(), config).unwrap()));
+        let hooks = Arc::new(Peak::default());
+        hooks.busy.store(true, Ordering::SeqCst);
+        let compactions = Arc::new(AtomicUsize::new(0));
+        let compact: CustomTask = {
+            let compactions = Arc::clone(&compactions);
+            Arc::new(move |_| Ok(compactions.fetch_add(1, Ordering::SeqCst)))
+        };
+        let config = MaintenanceConfig {
+            poll_interval: Duration::from_millis(5),
+            tasks: vec![
+                ScheduledTask {
+                    task: MaintenanceTask::Retention,
+                    triggers: vec![Trigger::DiskUsage(1000), Trigger::DirtyRatio(0.5)],
+                },
+                ScheduledTask {
+                    task: MaintenanceTask::Custom {
+                        name: "compaction".into(),
+                        run: compact,
+                    },
+                    triggers: vec![Trigger::Interval(Duration::from_millis(20))],
+                },
+            ],
+            hooks: Some(hooks.clone()),
+        };
+        let scheduler = MaintenanceScheduler::spawn(Arc::clone(&log), config);
+        for i in 0..40u8 {
+            lock(&log).append(&[i; 40]).unwrap();
+        }
+        let segments = lock(&log).segment_infos().count();
+
+        // Postponed by the hook, then by pausing.
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(hooks.runs.load(Ordering::SeqCst), 0);
+        scheduler.pause();
+        hooks.busy.store(false, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(hooks.runs.load(Ordering::SeqCst), 0);
+
+        scheduler.resume();
+        wait_for("retention never ran", || {
+            hooks.deleted.load(Ordering::SeqCst) > 0
+        });
+        wait_for("compaction never ran", || {
+            compactions.load(Ordering::SeqCst) > 1
+        });
+        scheduler.stop();
+        let log = Arc::try_unwrap(log).unwrap().into_inner().unwrap();
+        assert!(log.segment_infos().count() < segments);
+        assert!(log.stats().unwrap().disk_bytes <= 1000);
+    }
+}
@@ -0,0 +1,382 @@
+//! Many named logs (topics) under one root directory.
+//!
+//! A [`LogManager`] keeps each log in its own subdirectory, `root/<name>/`, and
+//! opens them with a shared [`Config`]. Logs are opened on first use and shared
+//! as `Arc<Mutex<Log>>` handles, the form [`RetentionTask`](crate::RetentionTask)
+//! also takes. [`start_maintenance`](LogManager::start_maintenance) runs one
+//! small thread pool that flushes every open log and enforces its retention
+//! policy, instead of a thread per log.
+
+use crate::error::Error;
+use crate::log::{Config, Log};
+use crate::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// A shared handle to one log of a [`LogManager`].
+pub type SharedLog = Arc<Mutex<Log>>;
+
+/// A root directory of independent named logs; see the [module docs](self).
+#[derive(Debug)]
+pub struct LogManager {
+    root: PathBuf,
+    config: Config,
+    /// Logs opened so far, by name; shared with the maintenance threads.
+    open: Arc<Mutex<BTreeMap<String, SharedLog>>>,
+    /// How many handles maintenance workers hold right now, so that
+    /// [`delete_log`](Self::delete_log) never mistakes them for users.
+    working: Arc<AtomicUsize>,
+    maintenance: Option<Maintenance>,
+}
+
+impl LogManager {
+    /// Opens the root directory `root`, creating it if missing. Every log is
+    /// opened with a clone of `config`.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from creating the directory.
+    pub fn open(root: impl AsRef<Path>, config: Config) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            config,
+            open: Arc::default(),
+            working: Arc::default(),
+            maintenance: None,
+        })
+    }
+
+    /// Returns the root directory.
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Creates a new, empty log called `name` and returns it.
+    ///
+    /// Names are 1 to 255 ASCII letters, digits, `-`, `_` and `.`, and may not
+    /// start with `.`.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `name` is invalid or a log of that name exists.
+    /// - Errors from [`Log::open`].
+    pub fn create_log(&self, name: &str) -> Result<SharedLog> {
+        let path = self.log_path(name)?;
+        let mut open = self.lock_open();
+        if open.contains_key(name) || path.exists() {
+            return Err(Error::InvalidFormat(format!("log {name} already exists")));
+        }
+        let log = Self::insert(&mut open, name, Log::open(path, self.config.clone())?);
+        drop(open);
+        Ok(log)
+    }
+
+    /// Returns the existing log called `name`, opening it if this manager has not
+    /// yet.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `name` is invalid or there is no such log.
+    /// - Errors from [`Log::open`].
+    pub fn open_log(&self, name: &str) -> Result<SharedLog> {
+        let path = self.log_path(name)?;
+        let mut open = self.lock_open();
+        if let Some(log) = open.get(name) {
+            return Ok(Arc::clone(log));
+        }
+        if !path.is_dir() {
+            return Err(Error::InvalidFormat(format!("no log named {name}")));
+        }
+        let log = Self::insert(&mut open, name, Log::open(path, self.config.clone())?);
+        drop(open);
+        Ok(log)
+    }
+
+    /// Closes the log called `name` and deletes its directory.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `name` is invalid, there is no such log, or
+    ///   handles returned for it are still alive.
+    /// - I/O errors from closing the log or removing its files.
+    pub fn delete_log(&self, name: &str) -> Result<()> {
+        let path = self.log_path(name)?;
+        let mut open = self.lock_open();
+        if let Some(mut log) = open.remove(name) {
+            let log = loop {
+                match Arc::try_unwrap(log) {
+                    Ok(log) => break log,
+                    // Workers never wait for a log, so theirs are dropped soon.
+                    Err(shared)
+                        if Arc::strong_count(&shared) - 1
+                            <= self.working.load(Ordering::SeqCst) =>
+                    {
+                        log = shared;
+                        std::thread::yield_now();
+                    }
+                    Err(shared) => {
+                        open.insert(name.to_owned(), shared);
+                        return Err(Error::InvalidFormat(format!("log {name} is in use")));
+                    }
+                }
+            };
+            log.into_inner()
+                .unwrap_or_else(PoisonError::into_inner)
+                .close()?;
+        } else if !path.is_dir() {
+            return Err(Error::InvalidFormat(format!("no log named {name}")));
+        }
+        drop(open);
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    /// Returns the names of all logs under the root, open or not, sorted.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the root directory.
+    pub fn list_logs(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str().filter(|n| valid_name(n)) {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Starts `threads` background threads that, every `interval`, flush each
+    /// open log and enforce its retention policy. Replaces any maintenance
+    /// already running.
+    ///
+    /// Errors from a log are ignored; the next tick retries.
+    pub fn start_maintenance(&mut self, interval: Duration, threads: usize) {
+        self.stop_maintenance();
+        self.maintenance = Some(Maintenance::spawn(
+            Arc::clone(&self.open),
+            &self.working,
+            interval,
+            threads.max(1),
+        ));
+    }
+
+    /// Stops the background threads, if running, and waits for them to exit.
+    pub fn stop_maintenance(&mut self) {
+        if let Some(maintenance) = self.maintenance.take() {
+            maintenance.shutdown();
+        }
+    }
+
+    /// Stops maintenance and closes every open log, or flushes it if handles
+    /// returned for it are still alive.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error from closing or flushing a log; the rest are
+    /// still closed.
+    pub fn close(mut self) -> Result<()> {
+        self.stop_maintenance();
+        let open = std::mem::take(&mut *self.lock_open());
+        let mut result = Ok(());
+        for log in open.into_values() {
+            let closed = match Arc::try_unwrap(log) {
+                Ok(log) => log
+                    .into_inner()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .close(),
+                Err(log) => lock(&log).flush(),
+            };
+            result = result.and(closed);
+        }
+        result
+    }
+
+    /// Validates `name` and returns its directory.
+    fn log_path(&self, name: &str) -> Result<PathBuf> {
+        if !valid_name(name) {
+            return Err(Error::InvalidFormat(format!(
+                "invalid log name {name:?}: use 1 to 255 ASCII letters, digits, '-', '_' \
+                 and '.', not starting with '.'"
+            )));
+        }
+        Ok(self.root.join(name))
+    }
+
+    fn insert(open: &mut BTreeMap<String, SharedLog>, name: &str, log: Log) -> SharedLog {
+        let log = Arc::new(Mutex::new(log));
+        open.insert(name.to_owned(), Arc::clone(&log));
+        log
+    }
+
+    fn lock_open(&self) -> MutexGuard<'_, BTreeMap<String, SharedLog>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for LogManager {
+    fn drop(&mut self) {
+        self.stop_maintenance();
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    (1..=255).contains(&name.len())
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+fn lock(log: &SharedLog) -> MutexGuard<'_, Log> {
+    log.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The maintenance thread pool: a ticker that queues every open log each
+/// interval, and workers that take logs off the queue.
+#[derive(Debug)]
+struct Maintenance {
+    /// Dropping the sender wakes the ticker and tells it to exit.
+    stop: mpsc::Sender<()>,
+    ticker: JoinHandle<()>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Maintenance {
+    fn spawn(
+        open: Arc<Mutex<BTreeMap<String, SharedLog>>>,
+        working: &Arc<AtomicUsize>,
+        interval: Duration,
+        threads: usize,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        // Queued logs are weak, so a deleted log is skipped rather than kept alive.
+        let (jobs, queue) = mpsc::channel::<Weak<Mutex<Log>>>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let working = Arc::clone(working);
+                std::thread::spawn(move || loop {
+                    // The ticker exiting drops the sender, which ends the workers.
+                    let job = queue.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    let Ok(log) = job else { break };
+                    // Counted before upgrading, so the count never undercounts.
+                    working.fetch_add(1, Ordering::SeqCst);
+                    if let Some(log) = log.upgrade() {
+                        // A busy log is skipped, not waited for: its holder may be
+                        // waiting on the manager. The next tick retries it.
+                        let log = match log.try_lock() {
+                            Ok(log) => Some(log),
+                            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+                            Err(TryLockError::WouldBlock) => None,
+                        };
+                        if let Some(mut log) = log {
+                            let _ = log.flush();
+                            let _ = log.enforce_retention();
+                        }
+                    }
+                    working.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        let ticker = std::thread::spawn(move || {
+            while stopped.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                let logs: Vec<_> = open
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .values()
+                    .map(Arc::downgrade)
+                    .collect();
+                for log in logs {
+                    let _ = jobs.send(log);
+                }
+            }
+        });
+        Self {
+            stop,
+            ticker,
+            workers,
+        }
+    }
+
+    fn shutdown(self) {
+        drop(self.stop);
+        let _ = self.ticker.join();
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn manages_named_logs_with_shared_maintenance() {
+        let root = tempfile::tempdir().unwrap();
+        let mut manager = LogManager::open(root.path(), Config::default()).unwrap();
+        let orders = manager.create_log("orders").unwrap();
+        manager.create_log("tenant-2.audit").unwrap();
+        assert!(manager.create_log("orders").is_err());
+        assert!(manager.create_log("../escape").is_err());
+        assert!(manager.open_log("missing").is_err());
+        assert_eq!(manager.list_logs().unwrap(), ["orders", "tenant-2.audit"]);
+
+        lock(&orders).append(b"first").unwrap();
+        assert_eq!(lock(&orders).durable_offset(), 0);
+        manager.start_maintenance(Duration::from_millis(5), 2);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lock(&orders).durable_offset() == 0 {
+            assert!(Instant::now() < deadline, "maintenance never flushed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        assert!(manager.delete_log("orders").is_err());
+        drop(orders);
+        manager.delete_log("orders").unwrap();
+        assert_eq!(manager.list_logs().unwrap(), ["tenant-2.audit"]);
+        manager.close().unwrap();
+
+        let manager = LogManager::open(root.path(), Config::default()).unwrap();
+        let audit = manager.open_log("tenant-2.audit").unwrap();
+        assert_eq!(lock(&audit).next_offset(), 0);
+    }
+
+    #[test]
+    fn delete_log_does_not_wait_for_workers_blocked_on_a_busy_log() {
+        let root = tempfile::tempdir().unwrap();
+        let mut manager = LogManager::open(root.path(), Config::default()).unwrap();
+        let busy = manager.create_log("a").unwrap();
+        drop(manager.create_log("b").unwrap());
+        manager.start_maintenance(Duration::from_millis(1), 1);
+        let manager = Arc::new(manager);
+
+        // Hold "a" across several ticks, as a user that then deletes "b" would.
+        let guard = lock(&busy);
+        std::thread::sleep(Duration::from_millis(20));
+        let (done, deleted) = mpsc::channel();
+        let shared = Arc::clone(&manager);
+        std::thread::spawn(move || done.send(shared.delete_log("b")).unwrap());
+        let result = deleted.recv_timeout(Duration::from_secs(5));
+        drop(guard);
+        result.expect("delete_log deadlocked").unwrap();
+        assert_eq!(manager.list_logs().unwrap(), ["a"]);
+    }
+}
@@ -0,0 +1,339 @@
+//! The `MANIFEST` file describing a log directory.
+//!
+//! The manifest lists the log's segments with the digest from each sealed
+//! segment's footer, along with the log start offset and a fingerprint of the
+//! [`Config`] settings that shape the files. [`Log`](crate::Log) replaces it
+//! atomically whenever segments are created or deleted, and
+//! [`Log::open`](crate::Log::open) checks the directory against it:
+//!
+//! - Segments missing at either end of the list, or unlisted segments past its
+//!   end, are what a crash during a roll, truncation or deletion leaves behind.
+//!   The directory wins and the manifest is rewritten.
+//! - A listed segment missing between two present ones, an unlisted segment
+//!   before the last listed one, or a sealed segment whose footer no longer
+//!   matches its digest means files were lost or replaced behind the log's back,
+//!   and opening fails with [`Error::Corruption`].
+//!
+//! Directories written before the manifest existed get one on their next open.
+//! See `docs/file-format.md` for the layout.
+
+use crate::error::Error;
+use crate::log::Config;
+use crate::segment::SegmentInfo;
+use crate::storage::{self, Backend};
+use crate::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Name of the manifest file in the log directory.
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// Magic bytes at the start of the manifest.
+const MANIFEST_MAGIC: [u8; 4] = *b"DLMF";
+
+/// Manifest format version written by this release.
+pub const MANIFEST_VERSION: u16 = 2;
+
+/// Length of the fixed fields before the segment list.
+const HEADER_LEN: usize = 24;
+
+/// Length of each segment entry in version 1 manifests, which have no expiry.
+const SEGMENT_ENTRY_LEN_V1: usize = 16;
+
+/// Length of each segment entry.
+const SEGMENT_ENTRY_LEN: usize = 24;
+
+/// Segment entry flag: the segment is sealed and `digest` is its footer's CRC.
+const SEGMENT_SEALED: u8 = 0x01;
+
+/// Segment entry flag: `expires_at` is set.
+const SEGMENT_EXPIRES: u8 = 0x02;
+
+/// Contents of a log directory's `MANIFEST`; see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Manifest format version; [`MANIFEST_VERSION`] when written by this release.
+    pub version: u16,
+    /// Log start offset when the manifest was written.
+    pub start_offset: u64,
+    /// Fingerprint of the configuration the log was last opened with.
+    pub config_fingerprint: u32,
+    /// Segments oldest first, ending with the active one.
+    pub segments: Vec<ManifestSegment>,
+}
+
+/// A segment listed in the [`Manifest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestSegment {
+    /// Offset of the segment's first record.
+    pub base_offset: u64,
+    /// [`segment_crc`](crate::SegmentFooter::segment_crc) of the segment's
+    /// footer, or `None` if it was not sealed.
+    pub digest: Option<u32>,
+    /// Time after which every record of the sealed segment has expired, in
+    /// milliseconds since the Unix epoch, if they all have a TTL; see
+    /// [`crate::ttl`].
+    pub expires_at: Option<u64>,
+}
+
+impl Manifest {
+    /// Describes `segments` (oldest first, ending with the active one) of a log
+    /// starting at `start_offset` and opened with `config`, with the latest
+    /// record expiry of sealed segments in `expiries`.
+    pub(crate) fn describe<'a>(
+        segments: impl IntoIterator<Item = &'a SegmentInfo>,
+        start_offset: u64,
+        config: &Config,
+        expiries: &BTreeMap<u64, u64>,
+    ) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            start_offset,
+            config_fingerprint: config_fingerprint(config),
+            segments: segments
+                .into_iter()
+                .map(|info| ManifestSegment {
+                    base_offset: info.base_offset,
+                    digest: info.footer.map(|f| f.segment_crc),
+                    expires_at: info
+                        .footer
+                        .and_then(|_| expiries.get(&info.base_offset).copied()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads the manifest of the log directory `dir`, or `None` if it has none.
+    ///
+    /// # Errors
+    ///
+    /// - I/O errors other than the file not existing.
+    /// - [`Error::Corruption`] if the file is malformed or its checksum does not match.
+    /// - [`Error::InvalidFormat`] if it was written by a newer, incompatible release.
+    pub fn read(storage: &dyn Backend, dir: &Path) -> Result<Option<Self>> {
+        storage::read_file(storage, &dir.join(MANIFEST_FILE_NAME))?
+            .map(|bytes| Self::decode(&bytes))
+            .transpose()
+    }
+
+    /// Atomically replaces the manifest of `dir` (write temp, fsync, rename).
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing, syncing, or renaming the file.
+    pub(crate) fn write(&self, storage: &dyn Backend, dir: &Path) -> Result<()> {
+        storage::write_atomic(storage, &dir.join(MANIFEST_FILE_NAME), &self.encode())?;
+        Ok(())
+    }
+
+    /// Checks the discovered `segments` of the directory against the manifest.
+    ///
+    /// Returns `false` if they differ only as an interrupted roll, truncation or
+    /// deletion leaves them, so the manifest needs rewriting.
+    ///
+    /// # Errors
+    ///
+    /// [`Error::Corruption`] if a listed segment is missing between two present
+    /// ones, a segment before the last listed one is not listed, or a sealed
+    /// segment no longer matches its digest.
+    pub(crate) fn check(&self, segments: &[SegmentInfo]) -> Result<bool> {
+        let last_listed = self.segments.last().map(|s| s.base_offset);
+        let mut consistent = true;
+        // Indexes into `self.segments` of the listed segments still present.
+        let mut present = Vec::with_capacity(segments.len());
+        for (i, info) in segments.iter().enumerate() {
+            let base_offset = info.base_offset;
+            let Ok(j) = self
+                .segments
+                .binary_search_by_key(&base_offset, |s| s.base_offset)
+            else {
+                if last_listed.is_some_and(|last| base_offset < last) {
+                    return Err(Error::Corruption(format!(
+                        "segment {base_offset} is not listed in {MANIFEST_FILE_NAME}"
+                    )));
+                }
+                // Created by a roll that crashed before rewriting the manifest.
+                consistent = false;
+                continue;
+            };
+            present.push(j);
+            let Some(digest) = self.segments[j].digest else {
+                continue;
+            };
+            match info.footer {
+                Some(footer) if footer.segment_crc == digest => {}
+                // Reopened for writing by a truncation that crashed before
+                // rewriting the manifest.
+                None if i + 1 == segments.len() => consistent = false,
+                _ => {
+                    return Err(Error::Corruption(format!(
+                        "segment {base_offset} does not match the digest in {MANIFEST_FILE_NAME}"
+                    )))
+                }
+            }
+        }
+        // Deletions only ever remove segments from either end of the log.
+        if let Some(gap) = present.windows(2).find(|w| w[1] != w[0] + 1) {
+            return Err(Error::Corruption(format!(
+                "segment {} listed in {MANIFEST_FILE_NAME} is missing",
+                self.segments[gap[0] + 1].base_offset
+            )));
+        }
+        Ok(consistent && present.len() == self.segments.len())
+    }
+
+    /// Encodes the manifest; see `docs/file-format.md` for the layout.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.segments.len() * SEGMENT_ENTRY_LEN + 4);
+        buf.extend_from_slice(&MANIFEST_MAGIC);
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&[0; 2]);
+        buf.extend_from_slice(&self.start_offset.to_le_bytes());
+        buf.extend_from_slice(&self.config_fingerprint.to_le_bytes());
+        let count = u32::try_from(self.segments.len()).unwrap_or(u32::MAX);
+        buf.extend_from_slice(&count.to_le_bytes());
+        for segment in &self.segments {
+            buf.extend_from_slice(&segment.base_offset.to_le_bytes());
+            let mut flags = 0;
+            if segment.digest.is_some() {
+                flags |= SEGMENT_SEALED;
+            }
+            if segment.expires_at.is_some() {
+                flags |= SEGMENT_EXPIRES;
+            }
+            buf.extend_from_slice(&[flags, 0, 0, 0]);
+            buf.extend_from_slice(&segment.digest.unwrap_or(0).to_le_bytes());
+            buf.extend_from_slice(&segment.expires_at.unwrap_or(0).to_le_bytes());
+        }
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+        buf
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let malformed = || Error::Corruption(format!("{MANIFEST_FILE_NAME} is malformed"));
+        if bytes.len() < HEADER_LEN + 4 || bytes[0..4] != MANIFEST_MAGIC {
+            return Err(malformed());
+        }
+        let (contents, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(contents).to_le_bytes() != crc {
+            return Err(Error::Corruption(format!(
+                "{MANIFEST_FILE_NAME} checksum mismatch"
+            )));
+        }
+        let version = u16::from_le_bytes([contents[4], contents[5]]);
+        if version > MANIFEST_VERSION {
+            return Err(Error::InvalidFormat(format!(
+                "{MANIFEST_FILE_NAME} version {version} is newer than this release supports"
+            )));
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(contents[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(contents[at..at + 4].try_into().unwrap());
+        let count = usize::try_from(u32_at(20)).map_err(|_| malformed())?;
+        let entry_len = if version == 1 {
+            SEGMENT_ENTRY_LEN_V1
+        } else {
+            SEGMENT_ENTRY_LEN
+        };
+        if contents.len() != HEADER_LEN + count * entry_len {
+            return Err(malformed());
+        }
+        let segments = (0..count)
+            .map(|i| {
+                let at = HEADER_LEN + i * entry_len;
+                let flags = contents[at + 8];
+                ManifestSegment {
+                    base_offset: u64_at(at),
+                    digest: (flags & SEGMENT_SEALED != 0).then(|| u32_at(at + 12)),
+                    expires_at: (entry_len == SEGMENT_ENTRY_LEN && flags & SEGMENT_EXPIRES != 0)
+                        .then(|| u64_at(at + 16)),
+                }
+            })
+            .collect();
+        Ok(Self {
+            version,
+            start_offset: u64_at(8),
+            config_fingerprint: u32_at(16),
+            segments,
+        })
+    }
+}
+
+/// Returns a CRC-32 of the [`Config`] settings that shape the log's files:
+/// record format, checksum, compression codec, whether encryption is on, and
+/// the segment size.
+#[must_use]
+pub fn config_fingerprint(config: &Config) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[
+        config.format.version(),
+        config.checksum.flag(),
+        config.compression.as_ref().map_or(0, |c| c.codec.flag()),
+        u8::from(config.encryption.is_some()),
+    ]);
+    hasher.update(&config.max_segment_bytes.to_le_bytes());
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::FOOTER_LEN;
+    use crate::Log;
+
+    #[test]
+    fn open_checks_the_directory_against_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 200,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        for i in 0..20u8 {
+            log.append(&[i; 40]).unwrap();
+        }
+        let infos: Vec<SegmentInfo> = log.segment_infos().cloned().collect();
+        drop(log);
+        let storage = storage::fs_backend();
+        let manifest = Manifest::read(&*storage, dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.config_fingerprint, config_fingerprint(&config));
+        assert_eq!(manifest.segments.len(), infos.len());
+        assert!(infos.len() > 3);
+        assert!(manifest.segments[..infos.len() - 1]
+            .iter()
+            .all(|s| s.digest.is_some()));
+        assert_eq!(manifest.segments.last().unwrap().digest, None);
+
+        // A segment missing from the middle of the log is corruption.
+        let middle = &infos[1];
+        let saved = std::fs::read(&middle.log_path).unwrap();
+        std::fs::remove_file(&middle.log_path).unwrap();
+        assert!(matches!(
+            Log::open(dir.path(), config.clone()),
+            Err(Error::Corruption(_))
+        ));
+        std::fs::write(&middle.log_path, &saved).unwrap();
+
+        // So is a sealed segment whose footer no longer matches its digest.
+        let mut tampered = saved.clone();
+        let footer_at = tampered.len() - FOOTER_LEN;
+        let mut footer = middle.footer.unwrap();
+        footer.segment_crc ^= 1;
+        tampered[footer_at..].copy_from_slice(&footer.encode());
+        std::fs::write(&middle.log_path, &tampered).unwrap();
+        assert!(matches!(
+            Log::open(dir.path(), config.clone()),
+            Err(Error::Corruption(_))
+        ));
+        std::fs::write(&middle.log_path, &saved).unwrap();
+
+        // The oldest segment missing is an interrupted deletion: repaired.
+        std::fs::remove_file(&infos[0].log_path).unwrap();
+        std::fs::remove_file(infos[0].index_path()).unwrap();
+        let log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.first_offset(), infos[1].base_offset);
+        drop(log);
+        let repaired = Manifest::read(&*storage, dir.path()).unwrap().unwrap();
+        assert_eq!(repaired.segments, manifest.segments[1..]);
+    }
+}
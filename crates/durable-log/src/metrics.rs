@@ -0,0 +1,224 @@
+//! Observability hooks for writers and readers.
+//!
+//! A [`LogObserver`] set in [`Config::observer`](crate::Config::observer) or with
+//! [`LogReader::with_observer`](crate::LogReader::with_observer) is told about
+//! appends, fsyncs, segment rolls, reads and checksum failures. Every method has
+//! a no-op default, so an implementation overrides only the events it records.
+//! Callbacks run inline on the appending or reading thread and should be cheap.
+//!
+//! With the `metrics` feature, `MetricsObserver` forwards events to the
+//! [`metrics`](https://docs.rs/metrics) facade, from which any installed
+//! recorder (for example a Prometheus exporter) collects them.
+
+#[cfg(feature = "metrics")]
+use ::metrics::{counter, histogram, Counter, Histogram};
+use std::fmt;
+use std::time::Duration;
+
+/// Receives events from a log writer or reader; see the [module docs](self).
+pub trait LogObserver: fmt::Debug + Send + Sync {
+    /// An append wrote `records` records as `bytes` bytes of frames. `latency`
+    /// covers encoding, writing and any fsync or segment roll it caused.
+    fn on_append(&self, _records: u64, _bytes: u64, _latency: Duration) {}
+
+    /// The active segment and its index were fsynced, taking `duration`.
+    fn on_fsync(&self, _duration: Duration) {}
+
+    /// The active segment was sealed and a new one starting at `base_offset`
+    /// was created.
+    fn on_segment_roll(&self, _base_offset: u64) {}
+
+    /// A record whose value is `bytes` long was read.
+    fn on_read(&self, _bytes: u64) {}
+
+    /// A record body read back did not match its checksum.
+    fn on_checksum_failure(&self, _offset: u64) {}
+
+    /// A reader closed the files of the segment at `base_offset` to stay within
+    /// its [handle cache](crate::LogReader::with_handle_cache).
+    fn on_handle_eviction(&self, _base_offset: u64) {}
+}
+
+/// A [`LogObserver`] that reports through the [`metrics`](https://docs.rs/metrics)
+/// crate (`metrics` feature).
+///
+/// Every series carries a `log` label naming the log:
+///
+/// | Metric | Kind |
+/// |--------|------|
+/// | `durable_log_appended_records_total` | counter |
+/// | `durable_log_appended_bytes_total` | counter |
+/// | `durable_log_append_duration_seconds` | histogram |
+/// | `durable_log_fsyncs_total` | counter |
+/// | `durable_log_fsync_duration_seconds` | histogram |
+/// | `durable_log_segment_rolls_total` | counter |
+/// | `durable_log_read_records_total` | counter |
+/// | `durable_log_read_bytes_total` | counter |
+/// | `durable_log_checksum_failures_total` | counter |
+/// | `durable_log_segment_handle_evictions_total` | counter |
+///
+/// Metric handles are registered when the observer is created, so install the
+/// recorder first.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct MetricsObserver {
+    log: String,
+    appended_records: Counter,
+    appended_bytes: Counter,
+    append_duration: Histogram,
+    fsyncs: Counter,
+    fsync_duration: Histogram,
+    segment_rolls: Counter,
+    read_records: Counter,
+    read_bytes: Counter,
+    checksum_failures: Counter,
+    handle_evictions: Counter,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsObserver {
+    /// Registers the metrics of the log named `log` with the installed recorder.
+    #[must_use]
+    pub fn new(log: impl Into<String>) -> Self {
+        let log = log.into();
+        let labels = [("log", log.clone())];
+        Self {
+            appended_records: counter!("durable_log_appended_records_total", &labels),
+            appended_bytes: counter!("durable_log_appended_bytes_total", &labels),
+            append_duration: histogram!("durable_log_append_duration_seconds", &labels),
+            fsyncs: counter!("durable_log_fsyncs_total", &labels),
+            fsync_duration: histogram!("durable_log_fsync_duration_seconds", &labels),
+            segment_rolls: counter!("durable_log_segment_rolls_total", &labels),
+            read_records: counter!("durable_log_read_records_total", &labels),
+            read_bytes: counter!("durable_log_read_bytes_total", &labels),
+            checksum_failures: counter!("durable_log_checksum_failures_total", &labels),
+            handle_evictions: counter!("durable_log_segment_handle_evictions_total", &labels),
+            log,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl fmt::Debug for MetricsObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsObserver")
+            .field("log", &self.log)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl LogObserver for MetricsObserver {
+    fn on_append(&self, records: u64, bytes: u64, latency: Duration) {
+        self.appended_records.increment(records);
+        self.appended_bytes.increment(bytes);
+        self.append_duration.record(latency);
+    }
+
+    fn on_fsync(&self, duration: Duration) {
+        self.fsyncs.increment(1);
+        self.fsync_duration.record(duration);
+    }
+
+    fn on_segment_roll(&self, _base_offset: u64) {
+        self.segment_rolls.increment(1);
+    }
+
+    fn on_read(&self, bytes: u64) {
+        self.read_records.increment(1);
+        self.read_bytes.increment(bytes);
+    }
+
+    fn on_checksum_failure(&self, _offset: u64) {
+        self.checksum_failures.increment(1);
+    }
+
+    fn on_handle_eviction(&self, _base_offset: u64) {
+        self.handle_evictions.increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log, LogReader};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Counts each kind of event.
+    #[derive(Debug, Default)]
+    struct Counts {
+        records: AtomicU64,
+        bytes: AtomicU64,
+        fsyncs: AtomicU64,
+        rolls: AtomicU64,
+        reads: AtomicU64,
+        checksum_failures: AtomicU64,
+    }
+
+    impl LogObserver for Counts {
+        fn on_append(&self, records: u64, bytes: u64, _latency: Duration) {
+            self.records.fetch_add(records, Ordering::Relaxed);
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        fn on_fsync(&self, _duration: Duration) {
+            self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_segment_roll(&self, _base_offset: u64) {
+            self.rolls.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_read(&self, _bytes: u64) {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_checksum_failure(&self, _offset: u64) {
+            self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Counts {
+        fn get(&self) -> [u64; 6] {
+            [
+                &self.records,
+                &self.bytes,
+                &self.fsyncs,
+                &self.rolls,
+                &self.reads,
+                &self.checksum_failures,
+            ]
+            .map(|c| c.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn writer_and_reader_report_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let counts = Arc::new(Counts::default());
+        let config = Config {
+            max_segment_bytes: 60,
+            observer: Some(counts.clone()),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"first").unwrap();
+        log.append_batch(&[b"second", b"third"]).unwrap();
+        log.flush().unwrap();
+        assert_eq!(log.read(0).unwrap(), b"first");
+        // Frames of 29, 30 and 29 bytes; the batch rolled once, fsyncing first.
+        assert_eq!(counts.get(), [3, 88, 2, 1, 1, 0]);
+
+        let segment = crate::discover_segments(dir.path()).unwrap().remove(0);
+        let mut bytes = std::fs::read(&segment.log_path).unwrap();
+        bytes[crate::HEADER_LEN] ^= 0xFF; // the first record's body
+        std::fs::write(&segment.log_path, bytes).unwrap();
+        let reader = LogReader::open(dir.path())
+            .unwrap()
+            .with_observer(counts.clone());
+        assert!(reader.iter().next().unwrap().is_err());
+        assert_eq!(reader.read(1).unwrap(), b"second");
+        assert_eq!(counts.get()[4..], [2, 1]);
+    }
+}
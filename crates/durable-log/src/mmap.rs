@@ -0,0 +1,574 @@
+//! Memory-mapped sealed segments for zero-copy reads (`mmap` feature).
+//!
+//! A [`MappedSegment`] maps a segment's `.log` (and `.idx`, when present) into
+//! memory; payloads are returned as `&[u8]` borrowed from the map, so replaying a
+//! segment performs no per-record heap allocation.
+//!
+//! # Safety
+//!
+//! This module and `uring` are the only ones that use `unsafe`.
+//! Mapping a file is unsound if the
+//! file is truncated or modified while mapped. Only *sealed* segments are mapped,
+//! and the writer never modifies a sealed segment's files: it stops writing to a
+//! segment once it rolls past it, recovery only ever truncates the active (last)
+//! segment, and a sealed segment made active again by
+//! [`Log::truncate_after`](crate::Log::truncate_after) is first replaced with a
+//! copy, leaving existing maps on the old files. Segment files must not be
+//! modified out of band while mapped. [`LogReader::map_sealed_segments`] relies
+//! on this; mapping any other segment goes through the `unsafe`
+//! [`MappedSegment::open`], whose caller must uphold it.
+
+#![allow(unsafe_code)]
+
+use crate::encryption::{decrypt_value, load_cipher, KeyProvider, SegmentCipher};
+use crate::error::Error;
+use crate::reader::{decode_index_entry, LogReader};
+use crate::record::{
+    decode_header, decode_headers, decode_value, header_len_in, split_batch, split_key,
+    RecordHeader, INDEX_ENTRY_LEN,
+};
+use crate::segment::{is_footer, SegmentInfo};
+use crate::storage::Advice;
+use crate::Result;
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+
+/// A record borrowed from a [`MappedSegment`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordRef<'a> {
+    /// Decoded header of the frame holding the record.
+    pub header: RecordHeader,
+    /// Logical offset of the record; past `header.offset` for all but the first
+    /// record of a batch frame.
+    pub offset: u64,
+    /// User headers as `(name, value)` pairs, borrowed from the map.
+    pub headers: Vec<(&'a str, &'a [u8])>,
+    /// Record key, for keyed records.
+    pub key: Option<&'a [u8]>,
+    /// Record payload (the value, for keyed records). Borrowed from the map unless
+    /// the record is compressed or encrypted. Empty for transaction markers (see
+    /// [`RecordHeader::is_control`]).
+    pub payload: Cow<'a, [u8]>,
+}
+
+impl<'a> RecordRef<'a> {
+    /// Verifies the body checksum, splits off the key and decodes the value.
+    fn from_body(
+        header: RecordHeader,
+        body: &'a [u8],
+        cipher: Option<&SegmentCipher>,
+    ) -> Result<Self> {
+        header.validate_checksum(body)?;
+        if header.is_control() {
+            return Ok(Self {
+                header,
+                offset: header.offset,
+                headers: Vec::new(),
+                key: None,
+                payload: Cow::Borrowed(&[]),
+            });
+        }
+        let headers = decode_headers(&header, body)?;
+        let (key, value) = split_key(&header, body)?;
+        let payload = if header.is_encrypted() {
+            let plain = decrypt_value(header.flags, header.offset, value.to_vec(), cipher)?;
+            Cow::Owned(decode_value(&header, &plain)?.into_owned())
+        } else {
+            decode_value(&header, value)?
+        };
+        Ok(Self {
+            header,
+            offset: header.offset,
+            headers,
+            key,
+            payload,
+        })
+    }
+
+    /// Verifies a frame's body and returns its records: the record itself, or
+    /// each payload of a batch frame.
+    fn from_frame(
+        header: RecordHeader,
+        body: &'a [u8],
+        cipher: Option<&SegmentCipher>,
+    ) -> Result<Vec<Self>> {
+        if !header.is_batch() {
+            return Self::from_body(header, body, cipher).map(|r| vec![r]);
+        }
+        header.validate_checksum(body)?;
+        let payloads: Vec<Cow<'a, [u8]>> = if header.is_encrypted() || header.is_compressed() {
+            let plain = decrypt_value(header.flags, header.offset, body.to_vec(), cipher)?;
+            let plain = decode_value(&header, &plain)?;
+            split_batch(&header, &plain)?
+                .into_iter()
+                .map(|p| Cow::Owned(p.to_vec()))
+                .collect()
+        } else {
+            split_batch(&header, body)?
+                .into_iter()
+                .map(Cow::Borrowed)
+                .collect()
+        };
+        Ok((header.offset..)
+            .zip(payloads)
+            .map(|(offset, payload)| Self {
+                header,
+                offset,
+                headers: Vec::new(),
+                key: None,
+                payload,
+            })
+            .collect())
+    }
+}
+
+/// A sealed segment mapped into memory.
+#[derive(Debug)]
+pub struct MappedSegment {
+    info: SegmentInfo,
+    log: Mmap,
+    index: Option<Mmap>,
+    cipher: Option<SegmentCipher>,
+}
+
+impl MappedSegment {
+    /// Maps the segment described by `info`.
+    ///
+    /// # Safety
+    ///
+    /// The segment's `.log` and `.idx` files must not be truncated or modified
+    /// while the map is alive, by this process or any other. A sealed segment of
+    /// a log written only through [`Log`](crate::Log) qualifies; the active
+    /// segment does not.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from opening or mapping the segment files.
+    pub unsafe fn open(info: &SegmentInfo) -> Result<Self> {
+        // SAFETY: forwarded to our caller.
+        unsafe { Self::open_with_keys(info, None) }
+    }
+
+    /// Maps the segment described by `info`, resolving the master key for its
+    /// data key through `keys` so encrypted records can be read.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`open`](Self::open).
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from opening or mapping the segment files, and
+    /// [`Error::InvalidFormat`] if the data key cannot be unwrapped.
+    pub unsafe fn open_with_keys(
+        info: &SegmentInfo,
+        keys: Option<&dyn KeyProvider>,
+    ) -> Result<Self> {
+        let cipher = load_cipher(info, keys)?;
+        // SAFETY: our caller guarantees the files are not modified while mapped.
+        let log = unsafe { map_file(&File::open(&info.log_path)?)? };
+        let index = match File::open(info.log_path.with_extension("idx")) {
+            // SAFETY: as above.
+            Ok(file) => Some(unsafe { map_file(&file)? }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            info: info.clone(),
+            log,
+            index,
+            cipher,
+        })
+    }
+
+    /// Returns the segment this map was created from.
+    #[must_use]
+    pub const fn info(&self) -> &SegmentInfo {
+        &self.info
+    }
+
+    /// Returns the raw bytes of the segment's `.log` file.
+    #[must_use]
+    pub fn bytes(&self) -> &[u8] {
+        &self.log
+    }
+
+    /// Tells the OS how the segment's maps will be read (`madvise`): front to
+    /// back, soon, or not again, so their pages can be dropped. A no-op on
+    /// platforms other than Unix.
+    ///
+    /// Taking `&mut self` ensures no [`RecordRef`] still borrows the pages that
+    /// [`Advice::DontNeed`] drops.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from `madvise`.
+    pub fn advise(&mut self, advice: Advice) -> Result<()> {
+        #[cfg(unix)]
+        for map in std::iter::once(&self.log).chain(&self.index) {
+            match advice {
+                Advice::Sequential => map.advise(memmap2::Advice::Sequential)?,
+                Advice::WillNeed => map.advise(memmap2::Advice::WillNeed)?,
+                // SAFETY: the maps are read-only views of files that are not
+                // modified while mapped (see `open`), so dropped pages
+                // read back the same bytes, and `&mut self` rules out borrows.
+                Advice::DontNeed => unsafe {
+                    map.unchecked_advise(memmap2::UncheckedAdvice::DontNeed)?;
+                },
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = advice;
+        Ok(())
+    }
+
+    /// Iterates over the segment's records, borrowing payloads from the map.
+    #[must_use]
+    pub fn records(&self) -> MappedRecords<'_> {
+        MappedRecords {
+            data: &self.log,
+            cipher: self.cipher.as_ref(),
+            pending: VecDeque::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Returns the checksum-verified record at `offset`, using the index when
+    /// available and scanning the segment otherwise.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `offset` is not in this segment.
+    /// - [`Error::ChecksumMismatch`] if the record fails checksum verification.
+    /// - [`Error::Corruption`] on index mismatch.
+    pub fn get(&self, offset: u64) -> Result<RecordRef<'_>> {
+        let Some(pos) = self.index_position(offset)? else {
+            return self
+                .records()
+                .find(|r| r.as_ref().map_or(true, |r| r.offset == offset))
+                .unwrap_or_else(|| {
+                    Err(Error::InvalidFormat(format!(
+                        "offset {offset} not found in segment {}",
+                        self.info.base_offset
+                    )))
+                });
+        };
+        let start = usize::try_from(pos).map_err(|_| {
+            Error::Corruption(format!("index position {pos} exceeds address space"))
+        })?;
+        let past_end = || {
+            Error::Corruption(format!(
+                "index position {pos} is past end of segment ({} bytes)",
+                self.log.len()
+            ))
+        };
+        if start > self.log.len() {
+            return Err(past_end());
+        }
+        let (records, _) =
+            records_at(&self.log, start, self.cipher.as_ref())?.ok_or_else(past_end)?;
+        // Every offset of a batch frame is indexed at the frame's position.
+        let first = records[0].header.offset;
+        records
+            .into_iter()
+            .find(|r| r.offset == offset)
+            .ok_or_else(|| {
+                Error::Corruption(format!(
+                    "index entry offset mismatch: expected {offset}, got {first}"
+                ))
+            })
+    }
+
+    fn index_position(&self, offset: u64) -> Result<Option<u64>> {
+        let Some(index) = &self.index else {
+            return Ok(None);
+        };
+        let Some(rel) = offset.checked_sub(self.info.base_offset) else {
+            return Err(Error::InvalidFormat(format!(
+                "offset {offset} is before segment base {}",
+                self.info.base_offset
+            )));
+        };
+        let Some(start) = usize::try_from(rel)
+            .ok()
+            .and_then(|r| r.checked_mul(INDEX_ENTRY_LEN))
+        else {
+            return Ok(None);
+        };
+        let Some(entry) = index.get(start..start + INDEX_ENTRY_LEN) else {
+            return Ok(None);
+        };
+        let mut buf = [0u8; INDEX_ENTRY_LEN];
+        buf.copy_from_slice(entry);
+        let (entry_offset, pos) = decode_index_entry(&buf);
+        if entry_offset != offset {
+            return Err(Error::Corruption(format!(
+                "index entry offset mismatch: expected {offset}, got {entry_offset}"
+            )));
+        }
+        Ok(Some(pos))
+    }
+}
+
+/// Zero-copy iterator over a [`MappedSegment`]'s records.
+///
+/// Each record body is checksum-verified, batch frames are expanded and chunked
+/// records reassembled. Yields at most one error, after which iteration ends; a
+/// partial record at the end of the map ends iteration quietly.
+#[derive(Debug)]
+pub struct MappedRecords<'a> {
+    data: &'a [u8],
+    cipher: Option<&'a SegmentCipher>,
+    /// Records of the last batch frame that have not been yielded yet.
+    pending: VecDeque<RecordRef<'a>>,
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for MappedRecords<'a> {
+    type Item = Result<RecordRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(record) = self.pending.pop_front() {
+            return Some(Ok(record));
+        }
+        if self.done {
+            return None;
+        }
+        match records_at(self.data, self.pos, self.cipher) {
+            Ok(Some((records, end))) => {
+                self.pos = end;
+                self.pending.extend(records);
+                self.pending.pop_front().map(Ok)
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Decodes the frame at `pos` in `data`, with the remaining chunks of a chunked
+/// record, and returns its records and the position after it.
+///
+/// Returns `Ok(None)` if the frame or a chunk is cut off by the end of `data`.
+fn records_at<'a>(
+    data: &'a [u8],
+    pos: usize,
+    cipher: Option<&SegmentCipher>,
+) -> Result<Option<(Vec<RecordRef<'a>>, usize)>> {
+    let Some((header, body, mut end)) = frame_at(data, pos)? else {
+        return Ok(None);
+    };
+    if !header.is_continued() {
+        return RecordRef::from_frame(header, body, cipher).map(|r| Some((r, end)));
+    }
+    let mut record = RecordRef::from_body(header, body, cipher)?;
+    let mut payload = std::mem::take(&mut record.payload).into_owned();
+    let mut continued = true;
+    while continued {
+        let Some((chunk, body, next)) = frame_at(data, end)? else {
+            return Ok(None);
+        };
+        if chunk.offset != header.offset || chunk.is_keyed() {
+            return Err(Error::Corruption(format!(
+                "chunked record at offset {} is interrupted by a frame for offset {}",
+                header.offset, chunk.offset
+            )));
+        }
+        continued = chunk.is_continued();
+        payload.extend_from_slice(&RecordRef::from_body(chunk, body, cipher)?.payload);
+        end = next;
+    }
+    record.payload = Cow::Owned(payload);
+    Ok(Some((vec![record], end)))
+}
+
+/// Decodes the header at `pos` and borrows its body, returning the position
+/// after the frame, or `Ok(None)` if the frame is cut off by the end of `data`
+/// or `pos` is the segment footer.
+fn frame_at(data: &[u8], pos: usize) -> Result<Option<(RecordHeader, &[u8], usize)>> {
+    let rest = &data[pos..];
+    if is_footer(rest) {
+        return Ok(None);
+    }
+    if rest.len() < header_len_in(rest) {
+        return Ok(None);
+    }
+    let header = decode_header(rest)?;
+    let start = header.encoded_len();
+    let end = start + header.payload_len as usize;
+    Ok(rest.get(start..end).map(|body| (header, body, pos + end)))
+}
+
+impl LogReader {
+    /// Maps every sealed segment (all but the last, which the writer may still be
+    /// appending to), oldest first. Encrypted segments use the reader's
+    /// [key provider](LogReader::with_key_provider).
+    ///
+    /// # Errors
+    ///
+    /// Same as [`MappedSegment::open_with_keys`].
+    pub fn map_sealed_segments(&self) -> Result<Vec<MappedSegment>> {
+        let segments = self.segments();
+        let sealed = &segments[..segments.len().saturating_sub(1)];
+        sealed
+            .iter()
+            // SAFETY: the writer never modifies a sealed segment's files in place
+            // (see the module docs).
+            .map(|info| unsafe { MappedSegment::open_with_keys(info, self.key_provider()) })
+            .collect()
+    }
+}
+
+/// # Safety
+///
+/// `file` must not be truncated or modified while the map is alive.
+unsafe fn map_file(file: &File) -> Result<Mmap> {
+    // SAFETY: forwarded to our caller.
+    let map = unsafe { Mmap::map(file)? };
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::HEADER_LEN;
+    use crate::{Config, Log};
+
+    #[test]
+    fn maps_sealed_segments_zero_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 128,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        let mapped = reader.map_sealed_segments().unwrap();
+        assert_eq!(mapped.len(), reader.segments().len() - 1);
+
+        let mut expected = 0u8;
+        for segment in &mapped {
+            for record in segment.records() {
+                let record = record.unwrap();
+                assert_eq!(record.header.offset, u64::from(expected));
+                assert_eq!(&*record.payload, [expected; 10]);
+                expected += 1;
+            }
+        }
+        assert!(expected > 0);
+
+        let record = mapped[0].get(1).unwrap();
+        assert_eq!(record.header.offset, 1);
+        assert_eq!(&*record.payload, [1u8; 10]);
+        assert!(
+            matches!(record.payload, Cow::Borrowed(p) if p.as_ptr() >= mapped[0].bytes().as_ptr())
+        );
+    }
+
+    #[test]
+    fn expands_batch_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 64,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append_batch_frame(&[b"a", b"b", b"c"]).unwrap();
+        log.append(&[0u8; 64]).unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        let mapped = reader.map_sealed_segments().unwrap();
+        let records: Vec<RecordRef<'_>> = mapped[0].records().collect::<Result<_>>().unwrap();
+        let offsets: Vec<u64> = records.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, [0, 1, 2]);
+        assert!(records.iter().all(|r| r.header.offset == 0));
+        assert_eq!(&*records[2].payload, b"c");
+        assert!(matches!(records[1].payload, Cow::Borrowed(_)));
+
+        let record = mapped[0].get(1).unwrap();
+        assert_eq!((record.offset, &*record.payload), (1, &b"b"[..]));
+    }
+
+    #[test]
+    fn maps_survive_truncation_into_a_sealed_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 128,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        log.flush().unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        let mapped = reader.map_sealed_segments().unwrap();
+        let before = mapped[0].records().count();
+        assert!(before > 1);
+        log.truncate_after(0).unwrap();
+        log.append(b"new").unwrap();
+        log.flush().unwrap();
+
+        assert_eq!(mapped[0].records().count(), before);
+        assert_eq!(&*mapped[0].get(1).unwrap().payload, [1u8; 10]);
+        assert_eq!(&*log.read(1).unwrap(), b"new");
+    }
+
+    #[test]
+    fn reassembles_chunked_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"before").unwrap();
+        log.set_chunk_len(4);
+        log.append(b"0123456789").unwrap();
+        log.append(b"after").unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        // SAFETY: the only writer is closed, so nothing modifies the segment.
+        let mapped = unsafe { MappedSegment::open(&reader.segments()[0]) }.unwrap();
+        let payloads: Vec<Vec<u8>> = mapped
+            .records()
+            .map(|r| r.unwrap().payload.into_owned())
+            .collect();
+        assert_eq!(payloads, [&b"before"[..], b"0123456789", b"after"]);
+        assert_eq!(&*mapped.get(1).unwrap().payload, b"0123456789");
+        assert_eq!(mapped.get(2).unwrap().offset, 2);
+    }
+
+    #[test]
+    fn detects_corrupt_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"payload").unwrap();
+            log.flush().unwrap();
+            LogReader::open(dir.path()).unwrap().segments()[0].clone()
+        };
+        let mut data = std::fs::read(&info.log_path).unwrap();
+        data[HEADER_LEN] ^= 0xFF;
+        std::fs::write(&info.log_path, data).unwrap();
+
+        // SAFETY: the log is closed and the file is not modified while mapped.
+        let mapped = unsafe { MappedSegment::open(&info) }.unwrap();
+        let err = mapped.records().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+        assert!(mapped.get(0).is_err());
+    }
+}
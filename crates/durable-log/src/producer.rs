@@ -0,0 +1,287 @@
+//! Idempotent producers: appends deduplicated by producer ID and sequence number.
+//!
+//! A producer picks an ID and numbers its records with consecutive sequence
+//! numbers. [`Log::append_idempotent`] stores both in the record, as a
+//! [`PRODUCER_HEADER`] header, and remembers the last sequence number and offset
+//! of each producer. Retrying the last append after a timeout returns the offset
+//! it was first written at instead of writing it again, while older replays and
+//! sequence gaps are rejected.
+//!
+//! The log keeps this state in a `producers.snapshot` file, rewritten when a
+//! segment rolls and on [`close`](Log::close), and rebuilds the rest from the
+//! records after the snapshot the first time a producer is used. A producer whose
+//! records have all been deleted by retention is forgotten, and its next append
+//! may start at any sequence number.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::storage::{self, Backend};
+use crate::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Name of the header holding a record's producer ID and sequence number, both
+/// u64 little-endian. The name is reserved for [`Log::append_idempotent`].
+pub const PRODUCER_HEADER: &str = "dlog.producer";
+
+/// Name of the file holding the producer state snapshot.
+const SNAPSHOT_FILE_NAME: &str = "producers.snapshot";
+
+/// Encodes the value of a [`PRODUCER_HEADER`] header.
+pub(crate) fn encode_producer(producer: u64, sequence: u64) -> [u8; 16] {
+    let mut value = [0; 16];
+    value[..8].copy_from_slice(&producer.to_le_bytes());
+    value[8..].copy_from_slice(&sequence.to_le_bytes());
+    value
+}
+
+/// Decodes the value of a [`PRODUCER_HEADER`] header into the producer ID and
+/// sequence number, or `None` if it is malformed.
+pub(crate) fn decode_producer(value: &[u8]) -> Option<(u64, u64)> {
+    if value.len() != 16 {
+        return None;
+    }
+    let (producer, sequence) = value.split_at(8);
+    Some((
+        u64::from_le_bytes(producer.try_into().ok()?),
+        u64::from_le_bytes(sequence.try_into().ok()?),
+    ))
+}
+
+/// The last append of one producer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LastAppend {
+    sequence: u64,
+    offset: u64,
+}
+
+/// Last sequence number and offset of every producer seen by a log.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProducerState {
+    producers: BTreeMap<u64, LastAppend>,
+}
+
+impl ProducerState {
+    /// Loads the state of the log in `dir` as of `end` (its next offset): the
+    /// snapshot, then the records from the snapshot's end read by `log`'s reader.
+    pub(crate) fn load(log: &Log, end: u64) -> Result<Self> {
+        let (mut state, from) = match read_snapshot(log.storage(), log.path())? {
+            Some((state, covered)) if covered <= end => (state, covered),
+            // Records the snapshot covers were truncated away; start afresh.
+            _ => (Self::default(), 0),
+        };
+        if from < end {
+            for record in log.reader()?.iter_from(from) {
+                let record = record?;
+                if record.offset >= end {
+                    break;
+                }
+                if let Some(value) = record.header(PRODUCER_HEADER) {
+                    state.observe(record.offset, value);
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// Records that the record at `offset` carries the [`PRODUCER_HEADER`]
+    /// `value`. Malformed values are ignored.
+    pub(crate) fn observe(&mut self, offset: u64, value: &[u8]) {
+        if let Some((producer, sequence)) = decode_producer(value) {
+            self.producers
+                .insert(producer, LastAppend { sequence, offset });
+        }
+    }
+
+    /// Returns the last sequence number appended by `producer`, if any.
+    pub(crate) fn last_sequence(&self, producer: u64) -> Option<u64> {
+        self.producers.get(&producer).map(|last| last.sequence)
+    }
+
+    /// Checks `sequence` against `producer`'s last append, returning the offset
+    /// of that append if `sequence` repeats it.
+    fn check(&self, producer: u64, sequence: u64) -> Result<Option<u64>> {
+        let Some(last) = self.producers.get(&producer) else {
+            return Ok(None);
+        };
+        if sequence == last.sequence {
+            Ok(Some(last.offset))
+        } else if sequence < last.sequence {
+            Err(Error::DuplicateSequence {
+                producer,
+                sequence,
+                last: last.sequence,
+            })
+        } else if sequence - last.sequence > 1 {
+            Err(Error::SequenceGap {
+                producer,
+                expected: last.sequence + 1,
+                found: sequence,
+            })
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Atomically replaces the snapshot in `dir` of `storage` with this state, which covers
+    /// the offsets below `end`.
+    ///
+    /// The file holds `end` (u64) and the number of producers (u32), then for each
+    /// its ID, last sequence number and last offset (u64 each), followed by a
+    /// CRC-32 of everything before it, all little-endian.
+    pub(crate) fn write_snapshot(&self, storage: &dyn Backend, dir: &Path, end: u64) -> Result<()> {
+        let count = u32::try_from(self.producers.len())
+            .map_err(|_| Error::InvalidFormat("too many producers".into()))?;
+        let mut contents = end.to_le_bytes().to_vec();
+        contents.extend_from_slice(&count.to_le_bytes());
+        for (producer, last) in &self.producers {
+            contents.extend_from_slice(&producer.to_le_bytes());
+            contents.extend_from_slice(&last.sequence.to_le_bytes());
+            contents.extend_from_slice(&last.offset.to_le_bytes());
+        }
+        contents.extend_from_slice(&crc32fast::hash(&contents).to_le_bytes());
+        storage::write_atomic(storage, &dir.join(SNAPSHOT_FILE_NAME), &contents)?;
+        Ok(())
+    }
+}
+
+/// Reads the snapshot in `dir` of `storage` and the end offset it covers, or `None` if there
+/// is none.
+fn read_snapshot(storage: &dyn Backend, dir: &Path) -> Result<Option<(ProducerState, u64)>> {
+    let Some(bytes) = storage::read_file(storage, &dir.join(SNAPSHOT_FILE_NAME))? else {
+        return Ok(None);
+    };
+    let malformed = || Error::Corruption(format!("{SNAPSHOT_FILE_NAME} is malformed"));
+    if bytes.len() < 16 || (bytes.len() - 16) % 24 != 0 {
+        return Err(malformed());
+    }
+    let (data, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(data).to_le_bytes() != crc {
+        return Err(Error::Corruption(format!(
+            "{SNAPSHOT_FILE_NAME} checksum mismatch"
+        )));
+    }
+    let u64_at = |pos: usize| u64::from_le_bytes(data[pos..pos + 8].try_into().expect("8 bytes"));
+    let end = u64_at(0);
+    let count = u32::from_le_bytes(data[8..12].try_into().expect("4 bytes"));
+    if usize::try_from(count).map_or(true, |count| count * 24 != data.len() - 12) {
+        return Err(malformed());
+    }
+    let mut state = ProducerState::default();
+    for entry in (12..data.len()).step_by(24) {
+        let last = LastAppend {
+            sequence: u64_at(entry + 8),
+            offset: u64_at(entry + 16),
+        };
+        state.producers.insert(u64_at(entry), last);
+    }
+    Ok(Some((state, end)))
+}
+
+/// Removes the snapshot in `dir` of `storage` if it covers offsets at or past `end`, which
+/// truncation has removed.
+///
+/// # Errors
+///
+/// Returns I/O errors from reading or removing the snapshot, and
+/// [`Error::Corruption`] if it is damaged.
+pub(crate) fn discard_snapshot_past(storage: &dyn Backend, dir: &Path, end: u64) -> Result<()> {
+    if read_snapshot(storage, dir)?.is_some_and(|(_, covered)| covered > end) {
+        storage.delete(&dir.join(SNAPSHOT_FILE_NAME))?;
+    }
+    Ok(())
+}
+
+impl Log {
+    /// Appends a payload as record `sequence` of `producer` and returns its
+    /// offset; see the [module docs](self).
+    ///
+    /// `sequence` must follow the producer's last sequence number by one. If it
+    /// equals the last one, the append is a retry and the offset of the original
+    /// record is returned without writing anything. A producer's first append may
+    /// use any sequence number.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::DuplicateSequence`] if `sequence` is below the producer's last.
+    /// - [`Error::SequenceGap`] if `sequence` skips ahead of the next expected one.
+    /// - [`Error::InvalidFormat`] if [`Config::format`](crate::Config::format) is
+    ///   not [`RecordFormat::V2`](crate::RecordFormat::V2), or the payload is too
+    ///   large to encode.
+    /// - I/O errors from rebuilding the producer state or writing the log.
+    pub fn append_idempotent(
+        &mut self,
+        producer: u64,
+        sequence: u64,
+        payload: &[u8],
+    ) -> Result<u64> {
+        if !self.format().stores_headers() {
+            return Err(Error::InvalidFormat(
+                "idempotent appends require RecordFormat::V2".into(),
+            ));
+        }
+        if let Some(offset) = self.producer_state()?.check(producer, sequence)? {
+            return Ok(offset);
+        }
+        let value = encode_producer(producer, sequence);
+        self.append_parts(None, &[(PRODUCER_HEADER, &value)], None, payload)
+    }
+
+    /// Returns the last sequence number appended by `producer`, so a restarted
+    /// producer can resume after it, or `None` if the log holds none.
+    ///
+    /// # Errors
+    ///
+    /// Returns errors from rebuilding the producer state: I/O errors, and
+    /// corruption in the snapshot or the records after it.
+    pub fn producer_sequence(&mut self, producer: u64) -> Result<Option<u64>> {
+        Ok(self.producer_state()?.last_sequence(producer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Error, Log, LogReader, RecordFormat};
+
+    #[test]
+    fn retries_are_deduplicated_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            format: RecordFormat::V2,
+            max_segment_bytes: 200,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        assert_eq!(log.append_idempotent(7, 10, b"a").unwrap(), 0);
+        assert_eq!(log.append_idempotent(7, 10, b"a").unwrap(), 0);
+        assert_eq!(log.append_idempotent(8, 0, b"b").unwrap(), 1);
+        for sequence in 11..15 {
+            // Enough to roll a segment, which snapshots the state.
+            log.append_idempotent(7, sequence, &[0; 40]).unwrap();
+        }
+        assert!(matches!(
+            log.append_idempotent(7, 12, b"old"),
+            Err(Error::DuplicateSequence { last: 14, .. })
+        ));
+        assert!(matches!(
+            log.append_idempotent(8, 2, b"skip"),
+            Err(Error::SequenceGap { expected: 1, .. })
+        ));
+        log.append_idempotent(8, 1, b"c").unwrap();
+        drop(log);
+
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.producer_sequence(7).unwrap(), Some(14));
+        assert_eq!(log.append_idempotent(8, 1, b"c").unwrap(), 6);
+        assert_eq!(log.next_offset(), 7);
+        let record = LogReader::open(dir.path()).unwrap().read_record(6).unwrap();
+        assert_eq!(record.producer(), Some((8, 1)));
+
+        log.truncate_after(4).unwrap();
+        assert_eq!(log.producer_sequence(7).unwrap(), Some(13));
+        assert_eq!(log.producer_sequence(8).unwrap(), Some(0));
+        assert!(log
+            .append_with_headers(b"x", &[(super::PRODUCER_HEADER, b"")])
+            .is_err());
+    }
+}
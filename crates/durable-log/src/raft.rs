@@ -0,0 +1,188 @@
+//! Raft log entries: terms stored with records and the log-matching check.
+//!
+//! [`Log::append_with_term`] stores a record's Raft term in a [`TERM_HEADER`]
+//! header, and [`Log::term_at`] reads it back. On a follower,
+//! [`Log::append_entries`] applies a leader's `AppendEntries` request: it checks
+//! that the log holds the entry before the new ones with the expected term,
+//! replaces any entries that conflict with the new ones, and appends the rest.
+//! Records appended without a term count as term 0.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::Result;
+
+/// Name of the header holding a record's Raft term (u64 little-endian). The name
+/// is reserved for [`Log::append_with_term`].
+pub const TERM_HEADER: &str = "dlog.term";
+
+/// Decodes the value of a [`TERM_HEADER`] header, or `None` if it is malformed.
+pub(crate) fn decode_term(value: &[u8]) -> Option<u64> {
+    value.try_into().ok().map(u64::from_le_bytes)
+}
+
+/// The result of [`Log::append_entries`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendEntriesOutcome {
+    /// The log matched the leader's up to the previous entry and now holds every
+    /// entry of the request; the last one is at `next_offset - 1`.
+    Appended {
+        /// The offset after the request's last entry.
+        next_offset: u64,
+    },
+    /// The log does not hold the previous entry with the expected term, so
+    /// nothing was appended.
+    Conflict {
+        /// Offset of the previous entry, or the log's next offset if it ends
+        /// before it.
+        offset: u64,
+        /// Term of the log's entry at `offset`, or `None` if it ends before it.
+        term: Option<u64>,
+    },
+}
+
+impl Log {
+    /// Appends a payload as an entry of Raft term `term` and returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if [`Config::format`](crate::Config::format) is
+    ///   not [`RecordFormat::V2`](crate::RecordFormat::V2), or the payload is too
+    ///   large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_with_term(&mut self, term: u64, payload: &[u8]) -> Result<u64> {
+        if !self.format().stores_headers() {
+            return Err(Error::InvalidFormat(
+                "record terms require RecordFormat::V2".into(),
+            ));
+        }
+        self.append_parts(None, &[(TERM_HEADER, &term.to_le_bytes())], None, payload)
+    }
+
+    /// Returns the Raft term of the record at `offset`, or 0 if it was appended
+    /// without one.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`read_record`](Self::read_record).
+    pub fn term_at(&mut self, offset: u64) -> Result<u64> {
+        let record = self.read_record(offset)?;
+        Ok(record.term().unwrap_or(0))
+    }
+
+    /// Applies a Raft `AppendEntries` request: `entries` are `(term, payload)`
+    /// pairs that follow the leader's entry at `prev_offset` of term `prev_term`,
+    /// or start at offset 0 if `prev_offset` is `None`.
+    ///
+    /// If this log has no entry at `prev_offset` or it has another term, returns
+    /// [`AppendEntriesOutcome::Conflict`] without changing the log. Otherwise
+    /// entries already present with the same term are kept, the log is truncated
+    /// before the first one present with a different term, and the remaining
+    /// entries are appended.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if a conflicting entry must be removed below the
+    ///   committed watermark, at offset 0, or from the middle of an
+    ///   [`append_batch`](Self::append_batch) batch, or as for
+    ///   [`append_with_term`](Self::append_with_term).
+    /// - [`Error::OffsetOutOfRange`] if an offset to check was deleted by
+    ///   retention.
+    /// - Errors from reading, truncating or appending to the log.
+    pub fn append_entries(
+        &mut self,
+        prev_offset: Option<u64>,
+        prev_term: u64,
+        entries: &[(u64, &[u8])],
+    ) -> Result<AppendEntriesOutcome> {
+        let mut offset = match prev_offset {
+            Some(prev) if prev >= self.next_offset() => {
+                return Ok(AppendEntriesOutcome::Conflict {
+                    offset: self.next_offset(),
+                    term: None,
+                });
+            }
+            Some(prev) => {
+                let term = self.term_at(prev)?;
+                if term != prev_term {
+                    return Ok(AppendEntriesOutcome::Conflict {
+                        offset: prev,
+                        term: Some(term),
+                    });
+                }
+                prev + 1
+            }
+            None => 0,
+        };
+        for &(term, payload) in entries {
+            if offset < self.next_offset() {
+                if self.term_at(offset)? == term {
+                    offset += 1;
+                    continue;
+                }
+                let last_kept = offset.checked_sub(1).ok_or_else(|| {
+                    Error::InvalidFormat("cannot replace the entry at offset 0".into())
+                })?;
+                self.truncate_after(last_kept)?;
+            }
+            offset = self.append_with_term(term, payload)? + 1;
+        }
+        Ok(AppendEntriesOutcome::Appended {
+            next_offset: offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AppendEntriesOutcome::{Appended, Conflict};
+    use crate::{Config, Log, RecordFormat};
+
+    #[test]
+    fn append_entries_checks_and_repairs_log_matching() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"untermed").unwrap();
+        log.append_with_term(1, b"a").unwrap();
+        log.append_with_term(2, b"stale").unwrap();
+        log.append_with_term(2, b"stale").unwrap();
+        assert_eq!(log.term_at(0).unwrap(), 0);
+        assert_eq!(log.term_at(2).unwrap(), 2);
+
+        assert_eq!(
+            log.append_entries(Some(6), 3, &[(3, b"x")]).unwrap(),
+            Conflict {
+                offset: 4,
+                term: None
+            }
+        );
+        assert_eq!(
+            log.append_entries(Some(1), 3, &[(3, b"x")]).unwrap(),
+            Conflict {
+                offset: 1,
+                term: Some(1)
+            }
+        );
+        assert_eq!(log.next_offset(), 4);
+
+        // The entry at 2 matches and is kept; the one at 3 conflicts.
+        let entries: [(u64, &[u8]); 3] = [(2, b"stale"), (3, b"b"), (3, b"c")];
+        assert_eq!(
+            log.append_entries(Some(1), 1, &entries).unwrap(),
+            Appended { next_offset: 5 }
+        );
+        assert_eq!(log.read(3).unwrap(), b"b");
+        assert_eq!(log.term_at(4).unwrap(), 3);
+
+        // A stale, shorter request leaves later entries in place.
+        assert_eq!(
+            log.append_entries(Some(1), 1, &entries[..1]).unwrap(),
+            Appended { next_offset: 3 }
+        );
+        assert_eq!(log.next_offset(), 5);
+        assert!(log.append_entries(None, 0, &[(4, b"new")]).is_err());
+    }
+}
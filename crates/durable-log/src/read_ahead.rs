@@ -0,0 +1,178 @@
+//! Background prefetching for sequential segment scans; see
+//! [`LogReader::with_read_ahead`](crate::LogReader::with_read_ahead).
+
+use crate::storage::StorageFile;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+/// Reads a file in chunks of a fixed size, fetching the next chunk on a
+/// background thread while the caller consumes the current one.
+///
+/// Reads stop at the end of the file as it was when the thread reached it, as
+/// a buffered reader's would. Seeking outside the current chunk restarts the
+/// thread at the new position.
+pub struct ReadAhead {
+    file: Arc<dyn StorageFile>,
+    chunk_len: usize,
+    /// Chunks read ahead, in order; disconnected after the last one.
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    /// File position of `chunk[0]`.
+    chunk_pos: u64,
+    /// Position in `chunk` of the next byte to read.
+    at: usize,
+}
+
+impl ReadAhead {
+    /// Starts reading `file` at `pos`, `chunk_len` bytes at a time.
+    pub fn new(file: Box<dyn StorageFile>, pos: u64, chunk_len: usize) -> Self {
+        let file: Arc<dyn StorageFile> = Arc::from(file);
+        let chunk_len = chunk_len.max(1);
+        Self {
+            chunks: spawn(Arc::clone(&file), pos, chunk_len),
+            file,
+            chunk_len,
+            chunk: Vec::new(),
+            chunk_pos: pos,
+            at: 0,
+        }
+    }
+
+    /// Returns the file being read.
+    pub fn file(&self) -> &dyn StorageFile {
+        &*self.file
+    }
+
+    const fn pos(&self) -> u64 {
+        self.chunk_pos + self.at as u64
+    }
+}
+
+/// Spawns the thread reading `file` from `pos`, one chunk ahead of the caller.
+fn spawn(
+    file: Arc<dyn StorageFile>,
+    mut pos: u64,
+    chunk_len: usize,
+) -> Receiver<io::Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::spawn(move || loop {
+        let chunk = read_chunk(&*file, pos, chunk_len);
+        let last = !matches!(&chunk, Ok(chunk) if chunk.len() == chunk_len);
+        if let Ok(chunk) = &chunk {
+            pos += chunk.len() as u64;
+        }
+        // Sending fails once the reader is dropped or has moved elsewhere.
+        if tx.send(chunk).is_err() || last {
+            break;
+        }
+    });
+    rx
+}
+
+/// Reads up to `len` bytes at `pos`, fewer only at the end of the file.
+fn read_chunk(file: &dyn StorageFile, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = vec![0; len];
+    let mut filled = 0;
+    while filled < len {
+        match file.read_at(&mut chunk[filled..], pos + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    chunk.truncate(filled);
+    Ok(chunk)
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.at == self.chunk.len() {
+            let next = match self.chunks.recv() {
+                Ok(next) => next?,
+                Err(_) => return Ok(0),
+            };
+            self.chunk_pos += self.chunk.len() as u64;
+            self.chunk = next;
+            self.at = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.at);
+        buf[..n].copy_from_slice(&self.chunk[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+impl Seek for ReadAhead {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(delta) => (self.file.size()?, delta),
+            SeekFrom::Current(delta) => (self.pos(), delta),
+        };
+        let target = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        match target.checked_sub(self.chunk_pos) {
+            Some(at) if at <= self.chunk.len() as u64 => {
+                self.at = usize::try_from(at).unwrap_or(usize::MAX);
+            }
+            _ => {
+                self.chunks = spawn(Arc::clone(&self.file), target, self.chunk_len);
+                self.chunk.clear();
+                self.chunk_pos = target;
+                self.at = 0;
+            }
+        }
+        Ok(target)
+    }
+}
+
+impl fmt::Debug for ReadAhead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadAhead")
+            .field("file", &self.file)
+            .field("chunk_len", &self.chunk_len)
+            .field("pos", &self.pos())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Backend, MemoryBackend, OpenMode};
+    use std::path::Path;
+
+    #[test]
+    fn reads_and_seeks_across_chunks() {
+        let storage = MemoryBackend::new();
+        let path = Path::new("/segment.log");
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        storage
+            .open(path, OpenMode::Create)
+            .unwrap()
+            .append(&data)
+            .unwrap();
+
+        let file = storage.open(path, OpenMode::Read).unwrap();
+        let mut reader = ReadAhead::new(file, 10, 64);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data[10..]);
+
+        // Back into a drained chunk, then far behind it.
+        let mut buf = [0; 100];
+        assert_eq!(reader.seek(SeekFrom::Current(-30)).unwrap(), 970);
+        reader.read_exact(&mut buf[..30]).unwrap();
+        assert_eq!(buf[..30], data[970..]);
+        assert_eq!(reader.seek(SeekFrom::Start(5)).unwrap(), 5);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[5..105]);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 1000);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,1580 @@
+//! Read-only access to a log directory: point reads and sequential iteration.
+//!
+//! A [`LogReader`] does not take the writer lock, so it can be opened alongside a
+//! [`Log`](crate::Log) in this or another process. Iteration stops cleanly at a
+//! partially written tail record; such a record becomes visible once complete.
+//!
+//! Each iterator is a snapshot: it stops at the end of the log as of its creation.
+//! A reader from [`Log::reader`](crate::Log::reader) takes that end from the
+//! writer's watermarks. Any other reader set to
+//! [`with_durable_only`](LogReader::with_durable_only) takes it from the durable
+//! watermark the writer publishes in the log directory, and so only sees records
+//! that have been fsynced.
+
+use crate::ack::Watermark;
+use crate::archive::{ArchiveCache, ArchivedSegment};
+use crate::clock::now_millis;
+use crate::encryption::{decrypt_value, load_cipher, KeyProvider, MasterKey, SegmentCipher};
+use crate::error::Error;
+use crate::handles::{HandleCache, SegmentHandles};
+use crate::log_dir::{read_committed_offset, read_durable_offset, read_start_offset};
+use crate::metrics::LogObserver;
+use crate::read_ahead::ReadAhead;
+use crate::record::{
+    decode_header, decode_headers, decode_value, header_len_in, split_batch, split_key,
+    RecordHeader, INDEX_ENTRY_LEN, MAGIC, MAX_HEADER_LEN, MIN_HEADER_LEN,
+};
+use crate::segment::{discover_segments_in, is_footer, SegmentInfo, FOOTER_LEN};
+use crate::snapshots::read_snapshot;
+use crate::storage::{self, Advice, Backend, FileCursor, OpenMode};
+use crate::trace::event;
+use crate::txn::TxnMarker;
+use crate::Result;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A record read back from the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Logical offset of the record.
+    pub offset: u64,
+    /// Record key, for records appended with a key.
+    pub key: Option<Vec<u8>>,
+    /// Record payload (the value, for keyed records).
+    pub payload: Vec<u8>,
+    /// Append time in milliseconds since the Unix epoch; `None` for v1 and
+    /// compact records.
+    pub timestamp: Option<u64>,
+    /// The transaction marker this control record holds, if it is one (see
+    /// [`crate::txn`]); its payload is then empty.
+    pub marker: Option<TxnMarker>,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Record {
+    /// Returns the record's user headers as `(name, value)` pairs, in the order
+    /// they were appended (see [`Log::append_with_headers`](crate::Log::append_with_headers)).
+    #[must_use]
+    pub fn headers(&self) -> &[(String, Vec<u8>)] {
+        &self.headers
+    }
+
+    /// Returns the producer ID and sequence number of a record appended by
+    /// [`Log::append_idempotent`](crate::Log::append_idempotent), if it is one.
+    #[must_use]
+    pub fn producer(&self) -> Option<(u64, u64)> {
+        self.header(crate::producer::PRODUCER_HEADER)
+            .and_then(crate::producer::decode_producer)
+    }
+
+    /// Returns the Raft term of a record appended by
+    /// [`Log::append_with_term`](crate::Log::append_with_term), if it has one.
+    #[must_use]
+    pub fn term(&self) -> Option<u64> {
+        self.header(crate::raft::TERM_HEADER)
+            .and_then(crate::raft::decode_term)
+    }
+
+    /// Returns the time the record expires, in milliseconds since the Unix
+    /// epoch, if it was appended with a TTL; see [`crate::ttl`].
+    #[must_use]
+    pub fn expires_at(&self) -> Option<u64> {
+        self.header(crate::ttl::EXPIRY_HEADER)
+            .and_then(crate::ttl::decode_expiry)
+    }
+
+    /// Returns the value of the first user header called `name`, if any.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Builds a data record from its parts, as decoded from another format.
+    #[cfg(feature = "kafka")]
+    pub(crate) const fn from_parts(
+        offset: u64,
+        key: Option<Vec<u8>>,
+        payload: Vec<u8>,
+        timestamp: Option<u64>,
+        headers: Vec<(String, Vec<u8>)>,
+    ) -> Self {
+        Self {
+            offset,
+            key,
+            payload,
+            timestamp,
+            marker: None,
+            headers,
+        }
+    }
+
+    /// Builds a record from a decoded header and its checksum-verified body,
+    /// decrypting with `cipher` and decompressing as the header's flags require.
+    pub(crate) fn from_body(
+        header: &RecordHeader,
+        mut body: Vec<u8>,
+        cipher: Option<&SegmentCipher>,
+    ) -> Result<Self> {
+        if header.is_control() {
+            return Ok(Self {
+                offset: header.offset,
+                key: None,
+                payload: Vec::new(),
+                timestamp: header.timestamp,
+                marker: Some(TxnMarker::decode(&body)?),
+                headers: Vec::new(),
+            });
+        }
+        let headers = decode_headers(header, &body)?
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.to_vec()))
+            .collect();
+        let (key, value) = split_key(header, &body)?;
+        let key = key.map(<[u8]>::to_vec);
+        let value_start = body.len() - value.len();
+        body.drain(..value_start);
+        body = decrypt_value(header.flags, header.offset, body, cipher)?;
+        if header.is_compressed() {
+            body = decode_value(header, &body)?.into_owned();
+        }
+        Ok(Self {
+            offset: header.offset,
+            key,
+            payload: body,
+            timestamp: header.timestamp,
+            marker: None,
+            headers,
+        })
+    }
+
+    /// Builds the records held by one checksum-verified frame: the record itself,
+    /// or each payload of a batch frame.
+    pub(crate) fn from_frame(
+        header: &RecordHeader,
+        body: Vec<u8>,
+        cipher: Option<&SegmentCipher>,
+    ) -> Result<Vec<Self>> {
+        if !header.is_batch() {
+            return Self::from_body(header, body, cipher).map(|r| vec![r]);
+        }
+        let body = decrypt_value(header.flags, header.offset, body, cipher)?;
+        let body = decode_value(header, &body)?;
+        Ok((header.offset..)
+            .zip(split_batch(header, &body)?)
+            .map(|(offset, payload)| Self {
+                offset,
+                key: None,
+                payload: payload.to_vec(),
+                timestamp: header.timestamp,
+                marker: None,
+                headers: Vec::new(),
+            })
+            .collect())
+    }
+}
+
+/// Whether a [`LogReader`] verifies record checksums.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumMode {
+    /// Recompute each record's CRC and fail with [`Error::ChecksumMismatch`] if it
+    /// does not match the header.
+    #[default]
+    Verify,
+    /// Return records without recomputing their CRC, for callers that validate
+    /// data by other means and want to save the CPU.
+    Skip,
+}
+
+impl ChecksumMode {
+    /// Validates `body` against `header` unless checksums are skipped.
+    pub(crate) fn check(self, header: &RecordHeader, body: &[u8]) -> Result<()> {
+        match self {
+            Self::Verify => header.validate_checksum(body),
+            Self::Skip => Ok(()),
+        }
+    }
+}
+
+/// What a [`Records`] iterator does when it reaches a damaged frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OnCorruption {
+    /// Yield the error and end iteration.
+    #[default]
+    Fail,
+    /// Yield [`Error::Skipped`] describing the damaged bytes, resynchronise on the
+    /// next valid frame in the segment, and continue.
+    Skip,
+}
+
+/// Which records of transactions (see [`crate::txn`]) a [`Records`] iterator
+/// yields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Isolation {
+    /// Yield every record, including those of open or aborted transactions and
+    /// the transaction markers themselves.
+    #[default]
+    ReadUncommitted,
+    /// Yield a transaction's records only once its commit marker is read, and
+    /// skip markers. The records are held in memory until then.
+    ReadCommitted,
+}
+
+/// A damaged stretch of a segment passed over in [`OnCorruption::Skip`] mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptRange {
+    /// Base offset of the segment.
+    pub segment: u64,
+    /// Byte position in the segment's `.log` file where the damage starts.
+    pub start: u64,
+    /// Byte position of the next valid frame, or the end of the segment's records.
+    pub end: u64,
+}
+
+/// Read-only view of a log directory.
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct LogReader {
+    path: PathBuf,
+    /// Backend holding the log's files.
+    storage: Arc<dyn Backend>,
+    segments: Vec<SegmentInfo>,
+    /// Logical start recorded by prefix truncation, if any.
+    start_offset: u64,
+    /// Committed watermark recorded by [`Log::commit`](crate::Log::commit), if any.
+    committed: u64,
+    /// Whether reads stop at `committed`.
+    committed_only: bool,
+    /// Durable watermark published by the writer, if any, as of the last open
+    /// or refresh.
+    durable: Option<u64>,
+    /// Whether reads stop at the durable watermark.
+    durable_only: bool,
+    /// The written and durable watermarks of the [`Log`](crate::Log) this
+    /// reader was opened from, if any.
+    watermarks: Option<(Arc<Watermark>, Arc<Watermark>)>,
+    /// Unwraps segment data keys for encrypted records.
+    keys: Option<Arc<dyn KeyProvider>>,
+    checksum: ChecksumMode,
+    on_corruption: OnCorruption,
+    isolation: Isolation,
+    observer: Option<Arc<dyn LogObserver>>,
+    /// Where segments older than the local ones are read from, if anywhere.
+    archive: Option<Arc<ArchiveCache>>,
+    /// Archived segments before the first local segment, sorted by base offset.
+    archived: Vec<ArchivedSegment>,
+    /// Bytes iteration prefetches on a background thread; 0 disables it.
+    read_ahead: usize,
+    /// Open files of the segments point reads used last.
+    handles: Arc<HandleCache>,
+    /// Whether iteration passes page cache hints on to the segment files.
+    scan_hints: bool,
+    /// Whether iteration passes over records whose TTL has run out.
+    skip_expired: bool,
+}
+
+/// Segments whose files a [`LogReader`] keeps open by default; see
+/// [`LogReader::with_handle_cache`].
+pub const DEFAULT_HANDLE_CACHE: usize = 16;
+
+impl LogReader {
+    /// Opens the log directory at `path` for reading. Does not take the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the directory (e.g. it does not exist).
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_storage(path, storage::default_backend())
+    }
+
+    /// Like [`open`](Self::open), for a log whose files are in `storage`.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`open`](Self::open).
+    pub fn open_with_storage(path: impl AsRef<Path>, storage: Arc<dyn Backend>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let segments = discover_segments_in(&storage, &path)?;
+        let start_offset = read_start(&*storage, &path)?;
+        let committed = read_committed_offset(&*storage, &path)?.unwrap_or(0);
+        let durable = read_durable_offset(&*storage, &path)?;
+        event!(
+            debug,
+            path = %path.display(),
+            segments = segments.len(),
+            start_offset,
+            "log reader opened"
+        );
+        Ok(Self {
+            path,
+            storage,
+            segments,
+            start_offset,
+            committed,
+            committed_only: false,
+            durable,
+            durable_only: false,
+            watermarks: None,
+            keys: None,
+            checksum: ChecksumMode::Verify,
+            on_corruption: OnCorruption::Fail,
+            isolation: Isolation::ReadUncommitted,
+            observer: None,
+            archive: None,
+            archived: Vec::new(),
+            read_ahead: 0,
+            handles: Arc::new(HandleCache::new(DEFAULT_HANDLE_CACHE)),
+            scan_hints: false,
+            skip_expired: false,
+        })
+    }
+
+    /// Sets the master key used to read encrypted records, for logs whose key
+    /// was never rotated.
+    #[must_use]
+    pub fn with_master_key(self, key: MasterKey) -> Self {
+        self.with_key_provider(Arc::new(key))
+    }
+
+    /// Resolves the master keys of encrypted segments by key ID through `keys`.
+    #[must_use]
+    pub fn with_key_provider(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.keys = Some(keys);
+        // Cached data keys were unwrapped with the previous provider.
+        self.handles = Arc::new(HandleCache::new(self.handles.capacity()));
+        self
+    }
+
+    /// Sets whether reads and iteration verify record checksums (default: verify).
+    #[must_use]
+    pub const fn with_checksum_mode(mut self, mode: ChecksumMode) -> Self {
+        self.checksum = mode;
+        self
+    }
+
+    /// Returns the reader's checksum mode.
+    #[must_use]
+    pub const fn checksum_mode(&self) -> ChecksumMode {
+        self.checksum
+    }
+
+    /// Sets what iteration does at a damaged frame (default: fail).
+    ///
+    /// Only framing damage is skipped: an invalid header, a checksum mismatch, an
+    /// interrupted chunked record, or a sealed segment whose records stop short of
+    /// its footer. Errors decoding an intact frame, such as a missing master key,
+    /// still end iteration. Point reads are not affected.
+    #[must_use]
+    pub const fn with_on_corruption(mut self, mode: OnCorruption) -> Self {
+        self.on_corruption = mode;
+        self
+    }
+
+    /// Returns what iteration does at a damaged frame.
+    #[must_use]
+    pub const fn on_corruption(&self) -> OnCorruption {
+        self.on_corruption
+    }
+
+    /// Sets which records of transactions iteration yields (default: every
+    /// record). Point reads are not affected.
+    #[must_use]
+    pub const fn with_isolation(mut self, isolation: Isolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// Returns which records of transactions iteration yields.
+    #[must_use]
+    pub const fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    /// Reports reads and checksum failures, by point reads and iteration alike, to
+    /// `observer`.
+    #[must_use]
+    pub fn with_observer(mut self, observer: Arc<dyn LogObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Makes iteration read each segment in chunks of `bytes`, fetching the next
+    /// chunk on a background thread while the records of the current one are
+    /// decoded (default 0: read on demand through a small buffer). One to a few
+    /// MiB hides most of the latency of full scans on cold or remote storage.
+    #[must_use]
+    pub const fn with_read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    /// Makes iteration tell the OS how it reads segments (default: no hints):
+    /// each is read sequentially, the next few MiB will be needed soon, and the
+    /// pages already read will not be needed again and can be dropped from the
+    /// page cache. A full replay then leaves the cache to other processes
+    /// instead of filling it with the whole log. The hints take effect on the
+    /// filesystem with the `fadvise` feature, on Linux.
+    #[must_use]
+    pub const fn with_scan_hints(mut self, scan_hints: bool) -> Self {
+        self.scan_hints = scan_hints;
+        self
+    }
+
+    /// Keeps the files of the `segments` segments read last open, closing the
+    /// least recently used beyond that, so point reads that hop between
+    /// segments do not reopen them every time (default
+    /// [`DEFAULT_HANDLE_CACHE`]; 0 opens and closes them on each read). Each
+    /// closed segment is reported to the
+    /// [observer](LogObserver::on_handle_eviction). Clones of the reader share
+    /// the cache.
+    #[must_use]
+    pub fn with_handle_cache(mut self, segments: usize) -> Self {
+        self.handles = Arc::new(HandleCache::new(segments));
+        self
+    }
+
+    /// Reads offsets older than the local segments from the archive behind
+    /// `cache`, such as segments moved there by [`Log::archive`](crate::Log::archive),
+    /// so that reads and iteration span local and archived segments alike. The
+    /// archive's manifest is read now and on [`refresh`](Self::refresh).
+    ///
+    /// # Errors
+    ///
+    /// Returns errors from reading the manifest; see [`Archive::manifest`](crate::Archive::manifest).
+    pub fn with_archive(mut self, cache: Arc<ArchiveCache>) -> Result<Self> {
+        self.archive = Some(cache);
+        self.load_manifest()?;
+        Ok(self)
+    }
+
+    /// Sets whether reads and iteration stop at the committed watermark set by
+    /// [`Log::commit`](crate::Log::commit) (default: read every record), so that
+    /// records which may still be truncated away are never seen. The watermark is
+    /// read on [`open`](Self::open) and [`refresh`](Self::refresh).
+    #[must_use]
+    pub const fn with_committed_only(mut self, committed_only: bool) -> Self {
+        self.committed_only = committed_only;
+        self
+    }
+
+    /// Sets whether reads and iteration stop at the durable watermark (default:
+    /// read every record written), so that records a crash of the writer could
+    /// still lose are never seen.
+    ///
+    /// A reader from [`Log::reader`](crate::Log::reader) follows the log's
+    /// watermark as it advances. Any other reader uses the watermark the writer
+    /// last published in the log directory, read on [`open`](Self::open) and
+    /// [`refresh`](Self::refresh); if none has been published, as with logs
+    /// written by earlier versions, reads are not bounded.
+    #[must_use]
+    pub const fn with_durable_only(mut self, durable_only: bool) -> Self {
+        self.durable_only = durable_only;
+        self
+    }
+
+    /// Bounds reads by the watermarks of the log this reader is opened from.
+    pub(crate) fn with_watermarks(
+        mut self,
+        written: Arc<Watermark>,
+        durable: Arc<Watermark>,
+    ) -> Self {
+        self.watermarks = Some((written, durable));
+        self
+    }
+
+    /// Sets whether iteration passes over records whose TTL has run out
+    /// (default: yield them until they are deleted); see [`crate::ttl`]. Point
+    /// reads are not affected.
+    #[must_use]
+    pub const fn with_skip_expired(mut self, skip_expired: bool) -> Self {
+        self.skip_expired = skip_expired;
+        self
+    }
+
+    /// Returns the committed watermark as of the last open or refresh: every
+    /// offset below it is committed.
+    #[must_use]
+    pub const fn committed_offset(&self) -> u64 {
+        self.committed
+    }
+
+    /// Returns the durable watermark published by the writer as of the last
+    /// open or refresh, or `None` if it has published none: every offset below
+    /// it has been fsynced.
+    #[must_use]
+    pub const fn durable_offset(&self) -> Option<u64> {
+        self.durable
+    }
+
+    /// Returns the key provider used to read encrypted records, if any.
+    #[must_use]
+    pub fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        self.keys.as_deref()
+    }
+
+    /// Returns the root path of the log directory.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) const fn storage(&self) -> &Arc<dyn Backend> {
+        &self.storage
+    }
+
+    /// Returns the segments known to this reader, sorted by base offset.
+    #[must_use]
+    pub fn segments(&self) -> &[SegmentInfo] {
+        &self.segments
+    }
+
+    /// Returns the oldest offset readable through this reader.
+    #[must_use]
+    pub fn first_offset(&self) -> u64 {
+        self.archived
+            .first()
+            .map(|s| s.base_offset)
+            .or_else(|| self.segments.first().map(|s| s.base_offset))
+            .unwrap_or(0)
+            .max(self.start_offset)
+    }
+
+    /// Re-discovers segments, the log start offset and the committed and durable
+    /// watermarks, picking up segments rolled or deleted by the writer since opening.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the directory.
+    pub fn refresh(&mut self) -> Result<()> {
+        // A segment may have been deleted and recreated under the same name.
+        self.handles.clear();
+        self.segments = discover_segments_in(&self.storage, &self.path)?;
+        self.start_offset = read_start(&*self.storage, &self.path)?;
+        self.committed = read_committed_offset(&*self.storage, &self.path)?.unwrap_or(0);
+        self.durable = read_durable_offset(&*self.storage, &self.path)?;
+        self.load_manifest()
+    }
+
+    /// Reloads the archived segments older than the local ones, if reading
+    /// from an archive.
+    fn load_manifest(&mut self) -> Result<()> {
+        let Some(cache) = &self.archive else {
+            return Ok(());
+        };
+        let local = self.segments.first().map_or(u64::MAX, |s| s.base_offset);
+        self.archived = cache.archive().manifest()?;
+        self.archived.retain(|s| s.base_offset < local);
+        Ok(())
+    }
+
+    /// Reads the payload at `offset` using the segment index, verifying its
+    /// checksum per the reader's [`ChecksumMode`].
+    ///
+    /// # Errors
+    ///
+    /// Same as [`read_record`](Self::read_record).
+    pub fn read(&self, offset: u64) -> Result<Vec<u8>> {
+        self.read_record(offset).map(|r| r.payload)
+    }
+
+    /// Reads the record (key and payload) at `offset` using the segment index,
+    /// verifying its checksum per the reader's [`ChecksumMode`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::OffsetOutOfRange`] if `offset` is before the log start, or at
+    ///   or past the end this reader stops at: the writer's watermark for a
+    ///   reader from [`Log::reader`](crate::Log::reader), or the committed or
+    ///   durable watermark in committed-only or durable-only mode.
+    /// - [`Error::InvalidFormat`] if `offset` is not present in the index, or the
+    ///   record is encrypted and no (or the wrong) master key was set.
+    /// - [`Error::ChecksumMismatch`] if the record fails checksum verification.
+    /// - [`Error::Corruption`] on index mismatch or decryption failure.
+    /// - I/O errors from reading segment or index files.
+    pub fn read_record(&self, offset: u64) -> Result<Record> {
+        let out_of_range = || Error::OffsetOutOfRange {
+            requested: offset,
+            earliest: self.first_offset(),
+            latest: match self.end_offset() {
+                u64::MAX => None,
+                end => end.checked_sub(1),
+            },
+        };
+        if offset < self.first_offset() || offset >= self.end_offset() {
+            return Err(out_of_range());
+        }
+        let info = match (&self.archive, self.archived_segment_for(offset)) {
+            (Some(cache), Some(archived)) => cache.segment(archived)?,
+            _ => self.segment_for(offset).cloned().ok_or_else(out_of_range)?,
+        };
+        let epoch = self
+            .watermarks
+            .as_ref()
+            .map_or(0, |(written, _)| written.truncations());
+        let mut handles = match self.handles.take(info.base_offset, epoch) {
+            Some(handles) => handles,
+            None => SegmentHandles::open(&info, self.key_provider())?,
+        };
+        let record = read_indexed(
+            &mut handles.log_file,
+            &mut handles.idx_file,
+            info.base_offset,
+            offset,
+            handles.cipher.as_ref(),
+            self.checksum,
+        );
+        self.handles.put(handles, epoch, self.observer.as_deref());
+        observe_read(self.observer.as_deref(), &record);
+        record
+    }
+
+    /// Iterates over every record in the log, oldest first.
+    #[must_use]
+    pub fn iter(&self) -> Records {
+        self.iter_from(0)
+    }
+
+    /// Iterates over records with offsets `>= offset`, oldest first. Offsets below
+    /// the log start are skipped.
+    #[must_use]
+    pub fn iter_from(&self, offset: u64) -> Records {
+        let offset = offset.max(self.start_offset);
+        let first = self
+            .segments
+            .partition_point(|s| s.base_offset <= offset)
+            .saturating_sub(1);
+        Records {
+            segments: self.segments[first..].iter().cloned().collect(),
+            archive: self.archive.clone(),
+            archived: self
+                .archived
+                .iter()
+                .filter(|s| s.end_offset > offset)
+                .copied()
+                .collect(),
+            current: None,
+            pending: VecDeque::new(),
+            keys: self.keys.clone(),
+            observer: self.observer.clone(),
+            checksum: self.checksum,
+            on_corruption: self.on_corruption,
+            isolation: self.isolation,
+            txn: None,
+            start_offset: offset,
+            end_offset: self.end_offset(),
+            read_ahead: self.read_ahead,
+            scan_hints: self.scan_hints,
+            skip_expired: self.skip_expired,
+            done: false,
+        }
+    }
+
+    /// Returns the offset at which reads stop: the lowest of the committed
+    /// watermark in committed-only mode, the durable watermark in durable-only
+    /// mode, and the written watermark of the log this reader is opened from
+    /// while it is open; otherwise unbounded.
+    fn end_offset(&self) -> u64 {
+        let committed = if self.committed_only {
+            self.committed
+        } else {
+            u64::MAX
+        };
+        let live = self.watermarks.as_ref().and_then(|(written, durable)| {
+            let watermark = if self.durable_only { durable } else { written };
+            watermark.open_end()
+        });
+        let published = match live {
+            Some(end) => end,
+            None if self.durable_only => self.durable.unwrap_or(u64::MAX),
+            None => u64::MAX,
+        };
+        committed.min(published)
+    }
+
+    /// Returns the segment whose offset range would contain `offset`.
+    fn segment_for(&self, offset: u64) -> Option<&SegmentInfo> {
+        let idx = self.segments.partition_point(|s| s.base_offset <= offset);
+        idx.checked_sub(1).map(|i| &self.segments[i])
+    }
+
+    /// Returns the archived segment holding `offset`, if it is older than the
+    /// local segments.
+    fn archived_segment_for(&self, offset: u64) -> Option<&ArchivedSegment> {
+        let idx = self.archived.partition_point(|s| s.base_offset <= offset);
+        idx.checked_sub(1)
+            .map(|i| &self.archived[i])
+            .filter(|s| offset < s.end_offset)
+    }
+}
+
+impl IntoIterator for &LogReader {
+    type Item = Result<Record>;
+    type IntoIter = Records;
+
+    fn into_iter(self) -> Records {
+        self.iter()
+    }
+}
+
+/// Sequential iterator over records, created by [`LogReader::iter`].
+///
+/// Yields at most one error, after which iteration ends, except that in
+/// [`OnCorruption::Skip`] mode each [`Error::Skipped`] is followed by the records
+/// after the damage.
+#[derive(Debug)]
+pub struct Records {
+    segments: VecDeque<SegmentInfo>,
+    /// Reads `archived`, which come before `segments`.
+    archive: Option<Arc<ArchiveCache>>,
+    archived: VecDeque<ArchivedSegment>,
+    current: Option<SegmentReader>,
+    /// Records of the last frame read that have not been yielded yet.
+    pending: VecDeque<Record>,
+    keys: Option<Arc<dyn KeyProvider>>,
+    observer: Option<Arc<dyn LogObserver>>,
+    checksum: ChecksumMode,
+    on_corruption: OnCorruption,
+    isolation: Isolation,
+    /// In [`Isolation::ReadCommitted`] mode, the records read so far of the open
+    /// transaction, if any.
+    txn: Option<Vec<Record>>,
+    /// Offset of the next record to yield; records below it are passed over.
+    start_offset: u64,
+    /// Iteration ends before reading a record at or past this offset.
+    end_offset: u64,
+    /// See [`LogReader::with_read_ahead`].
+    read_ahead: usize,
+    /// See [`LogReader::with_scan_hints`].
+    scan_hints: bool,
+    /// See [`LogReader::with_skip_expired`].
+    skip_expired: bool,
+    done: bool,
+}
+
+/// A segment file as read by [`Records`].
+trait SegmentFile: Read + Seek + Debug + Send {
+    /// Passes a page cache hint on to the file; see [`StorageFile::advise`](storage::StorageFile::advise).
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> std::io::Result<()>;
+}
+
+impl SegmentFile for BufReader<FileCursor> {
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> std::io::Result<()> {
+        self.get_ref().advise(offset, len, advice)
+    }
+}
+
+impl SegmentFile for ReadAhead {
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> std::io::Result<()> {
+        self.file().advise(offset, len, advice)
+    }
+}
+
+/// Bytes a [`Records`] iterator with scan hints asks the OS to read ahead, and
+/// reads between dropping the pages behind it.
+const HINT_WINDOW: u64 = 4 * 1024 * 1024;
+
+/// The segment a [`Records`] iterator is reading, with its data key if encrypted.
+#[derive(Debug)]
+struct SegmentReader {
+    file: Box<dyn SegmentFile>,
+    cipher: Option<SegmentCipher>,
+    /// Base offset of the segment.
+    segment: u64,
+    /// Position in the file of the next frame.
+    pos: u64,
+    /// Length of the segment's records: the file length, less any footer.
+    data_len: u64,
+    /// True if the segment has a footer, so its records must reach `data_len`.
+    sealed: bool,
+    /// With scan hints, the position up to which cached pages were dropped;
+    /// `None` without.
+    dropped: Option<u64>,
+}
+
+impl SegmentReader {
+    /// Reads the frames of the next record, keeping `pos` at the next frame.
+    fn read_frames(&mut self, checksum: ChecksumMode) -> Result<Option<Vec<Frame>>> {
+        let frames = read_frames(&mut self.file, checksum)?;
+        if let Some(frames) = &frames {
+            self.pos += frames
+                .iter()
+                .map(|(h, b)| (h.encoded_len() + b.len()) as u64)
+                .sum::<u64>();
+        }
+        self.advance_hints(frames.is_none());
+        Ok(frames)
+    }
+
+    /// With scan hints, drops the pages read since the last drop once they make
+    /// up a window, or all of them at the end of the segment, and asks for the
+    /// next window. Hints are best effort, so failures are ignored.
+    fn advance_hints(&mut self, at_end: bool) {
+        let Some(dropped) = self.dropped else {
+            return;
+        };
+        if at_end {
+            let _ = self.file.advise(dropped, 0, Advice::DontNeed);
+        } else if self.pos.saturating_sub(dropped) >= HINT_WINDOW {
+            let _ = self
+                .file
+                .advise(dropped, self.pos - dropped, Advice::DontNeed);
+            let _ = self.file.advise(self.pos, HINT_WINDOW, Advice::WillNeed);
+            self.dropped = Some(self.pos);
+        }
+    }
+
+    /// Moves to the first position after `start` where a whole frame decodes and
+    /// passes `checksum`, returning the range skipped. If there is none, the reader
+    /// is left at `data_len`.
+    fn resync(&mut self, start: u64, checksum: ChecksumMode) -> Result<CorruptRange> {
+        let mut from = start + 1;
+        let end = loop {
+            let Some(candidate) = self.find_magic(from)? else {
+                break self.data_len;
+            };
+            self.file.seek(SeekFrom::Start(candidate))?;
+            if matches!(read_frames(&mut self.file, checksum), Ok(Some(_))) {
+                break candidate;
+            }
+            from = candidate + 1;
+        };
+        self.file.seek(SeekFrom::Start(end))?;
+        self.pos = end;
+        event!(
+            warn,
+            segment = self.segment,
+            start,
+            end,
+            "skipped damaged range"
+        );
+        Ok(CorruptRange {
+            segment: self.segment,
+            start,
+            end,
+        })
+    }
+
+    /// Returns the position of the first record magic at or after `from`.
+    fn find_magic(&mut self, from: u64) -> Result<Option<u64>> {
+        let magic = MAGIC.to_le_bytes();
+        self.file.seek(SeekFrom::Start(from))?;
+        let mut buf = vec![0u8; 64 * 1024];
+        // File position of `buf[0]`, and bytes of `buf` holding data.
+        let (mut base, mut filled) = (from, 0);
+        while base + (filled as u64) < self.data_len {
+            let left = usize::try_from(self.data_len - base - filled as u64).unwrap_or(usize::MAX);
+            let want = (buf.len() - filled).min(left);
+            let n = self.file.read(&mut buf[filled..filled + want])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+            if let Some(i) = buf[..filled].windows(magic.len()).position(|w| w == magic) {
+                return Ok(Some(base + i as u64));
+            }
+            // Keep a partial match that may continue in the next read.
+            let keep = filled.min(magic.len() - 1);
+            buf.copy_within(filled - keep..filled, 0);
+            base += (filled - keep) as u64;
+            filled = keep;
+        }
+        Ok(None)
+    }
+}
+
+impl Records {
+    /// Opens `info` positioned at the first record `>= start_offset`, using the
+    /// index to skip ahead when possible. In [`Isolation::ReadCommitted`] mode it
+    /// starts from the first record instead, where no transaction is open.
+    fn open_segment(&self, info: &SegmentInfo) -> Result<SegmentReader> {
+        let mut file = info.open_file(&info.log_path, OpenMode::Read)?;
+        let mut pos = 0;
+        if self.start_offset > info.base_offset && self.isolation == Isolation::ReadUncommitted {
+            if let Some(indexed) = index_position(info, self.start_offset)? {
+                pos = file.seek(SeekFrom::Start(indexed))?;
+            }
+        }
+        let footer_len = if info.footer.is_some() { FOOTER_LEN } else { 0 };
+        let data_len = file.size()?.saturating_sub(footer_len as u64);
+        if self.scan_hints {
+            let _ = file.advise(pos, 0, Advice::Sequential);
+            let _ = file.advise(pos, HINT_WINDOW, Advice::WillNeed);
+        }
+        let file: Box<dyn SegmentFile> = if self.read_ahead > 0 {
+            Box::new(ReadAhead::new(file.into_inner(), pos, self.read_ahead))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        Ok(SegmentReader {
+            file,
+            cipher: load_cipher(info, self.keys.as_deref())?,
+            segment: info.base_offset,
+            pos,
+            data_len,
+            sealed: info.footer.is_some(),
+            dropped: self.scan_hints.then_some(pos),
+        })
+    }
+}
+
+impl Records {
+    /// Applies the iterator's [`Isolation`] to `record`, returning it if it is
+    /// to be yielded now. Records held for a transaction are queued for
+    /// yielding once its commit marker is read.
+    fn isolate(&mut self, record: Record) -> Option<Record> {
+        if self.isolation == Isolation::ReadUncommitted {
+            return Some(record);
+        }
+        match record.marker {
+            Some(TxnMarker::Begin) => self.txn = Some(Vec::new()),
+            Some(TxnMarker::Commit) => {
+                // Markers are alone in their frame, so nothing else is pending.
+                self.pending.extend(self.txn.take().unwrap_or_default());
+            }
+            Some(TxnMarker::Abort) => self.txn = None,
+            None => match &mut self.txn {
+                Some(held) => held.push(record),
+                None => return Some(record),
+            },
+        }
+        None
+    }
+}
+
+impl Iterator for Records {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.start_offset >= self.end_offset {
+                self.done = true;
+                break;
+            }
+            if let Some(record) = self.pending.pop_front() {
+                if record.offset >= self.end_offset {
+                    self.done = true;
+                    break;
+                }
+                let Some(record) = self.isolate(record) else {
+                    continue;
+                };
+                if record.offset >= self.start_offset {
+                    self.start_offset = record.offset + 1;
+                    if self.skip_expired && record.expires_at().is_some_and(|at| at <= now_millis())
+                    {
+                        continue;
+                    }
+                    if let Some(observer) = &self.observer {
+                        observer.on_read(record.payload.len() as u64);
+                    }
+                    return Some(Ok(record));
+                }
+                continue;
+            }
+            let Some(reader) = self.current.as_mut() else {
+                // Transactions never span segments: one still open was not finished.
+                self.txn = None;
+                let info = match (&self.archive, self.archived.pop_front()) {
+                    (Some(cache), Some(archived)) => cache.segment(&archived),
+                    _ => Ok(self.segments.pop_front()?),
+                };
+                match info.and_then(|info| self.open_segment(&info)) {
+                    Ok(reader) => self.current = Some(reader),
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+                continue;
+            };
+            let start = reader.pos;
+            let frames = reader.read_frames(self.checksum);
+            if let (Err(Error::ChecksumMismatch { offset, .. }), Some(observer)) =
+                (&frames, &self.observer)
+            {
+                observer.on_checksum_failure(*offset);
+            }
+            let damaged = match &frames {
+                Ok(Some(_)) => false,
+                // A sealed segment's records run right up to its footer.
+                Ok(None) => reader.sealed && start < reader.data_len,
+                Err(e) => e.is_corruption(),
+            };
+            if damaged && self.on_corruption == OnCorruption::Skip {
+                let skipped = reader.resync(start, self.checksum);
+                self.done = skipped.is_err();
+                return Some(skipped.and_then(|range| Err(Error::Skipped(range))));
+            }
+            let records = frames.and_then(|frames| {
+                frames
+                    .map(|frames| decode_frames(frames, reader.cipher.as_ref()))
+                    .transpose()
+            });
+            match records {
+                Ok(Some(records)) => self.pending.extend(records),
+                Ok(None) => self.current = None,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns the log start recorded in `dir`: the persisted start offset, or the
+/// installed snapshot's offset if a crash left it ahead.
+fn read_start(storage: &dyn Backend, dir: &Path) -> Result<u64> {
+    let start = read_start_offset(storage, dir)?.unwrap_or(0);
+    let snapshot = read_snapshot(storage, dir)?.map_or(0, |s| s.offset);
+    Ok(start.max(snapshot))
+}
+
+/// Reports the outcome of a point read to `observer`: the value read, or a
+/// checksum failure.
+pub(crate) fn observe_read(observer: Option<&dyn LogObserver>, result: &Result<Record>) {
+    match (observer, result) {
+        (Some(observer), Ok(record)) => observer.on_read(record.payload.len() as u64),
+        (Some(observer), Err(Error::ChecksumMismatch { offset, .. })) => {
+            observer.on_checksum_failure(*offset);
+        }
+        _ => {}
+    }
+}
+
+/// Reads the next frame from a sequential segment reader, expanding batches and
+/// reassembling chunked records.
+///
+/// Returns `Ok(None)` at end of file, at the segment footer, or at a partially
+/// written tail record.
+fn read_next_frame(
+    reader: &mut impl Read,
+    cipher: Option<&SegmentCipher>,
+    checksum: ChecksumMode,
+) -> Result<Option<Vec<Record>>> {
+    read_frames(reader, checksum)?
+        .map(|frames| decode_frames(frames, cipher))
+        .transpose()
+}
+
+/// A frame's header and checked body.
+type Frame = (RecordHeader, Vec<u8>);
+
+/// Reads the frames of the next record: one frame, or every chunk of a chunked
+/// record. Errors are all damage to the framing (see [`Error::is_corruption`]) or I/O.
+///
+/// Returns `Ok(None)` if the input ends first.
+fn read_frames(reader: &mut impl Read, checksum: ChecksumMode) -> Result<Option<Vec<Frame>>> {
+    let Some(first) = read_frame(reader, checksum)? else {
+        return Ok(None);
+    };
+    let offset = first.0.offset;
+    let mut continued = first.0.is_continued();
+    let mut frames = vec![first];
+    while continued {
+        let Some((chunk, body)) = read_frame(reader, checksum)? else {
+            return Ok(None);
+        };
+        if chunk.offset != offset || chunk.is_keyed() {
+            return Err(Error::Corruption(format!(
+                "chunked record at offset {offset} is interrupted by a frame for offset {}",
+                chunk.offset
+            )));
+        }
+        continued = chunk.is_continued();
+        frames.push((chunk, body));
+    }
+    Ok(Some(frames))
+}
+
+/// Decodes the frames of one record, expanding a batch or joining chunks.
+fn decode_frames(frames: Vec<Frame>, cipher: Option<&SegmentCipher>) -> Result<Vec<Record>> {
+    let mut frames = frames.into_iter();
+    let Some((header, body)) = frames.next() else {
+        return Ok(Vec::new());
+    };
+    if !header.is_continued() {
+        return Record::from_frame(&header, body, cipher);
+    }
+    let mut record = Record::from_body(&header, body, cipher)?;
+    for (chunk, body) in frames {
+        record
+            .payload
+            .extend(Record::from_body(&chunk, body, cipher)?.payload);
+    }
+    Ok(vec![record])
+}
+
+/// Reads one frame's header and checked body, returning `Ok(None)` if the input
+/// ends first.
+fn read_frame(reader: &mut impl Read, checksum: ChecksumMode) -> Result<Option<Frame>> {
+    let Some(header) = read_header(reader)? else {
+        return Ok(None);
+    };
+    let mut body = vec![0u8; header.payload_len as usize];
+    if !read_full(reader, &mut body)? {
+        return Ok(None);
+    }
+    checksum.check(&header, &body)?;
+    Ok(Some((header, body)))
+}
+
+/// Reads one record header of any version, returning `Ok(None)` if the input
+/// ends first or the segment's footer or preallocated space is reached.
+pub(crate) fn read_header(reader: &mut impl Read) -> Result<Option<RecordHeader>> {
+    let mut buf = [0u8; MAX_HEADER_LEN];
+    // The shortest header, so a small compact frame at the end is not missed.
+    let mut len = MIN_HEADER_LEN;
+    if !read_full(reader, &mut buf[..len])? {
+        return Ok(None);
+    }
+    // No header starts with zeros; they are space reserved by `Config::preallocate`.
+    if is_footer(&buf) || buf[..len].iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    // An unknown version or malformed varint is reported by `decode_header` below.
+    loop {
+        let needed = header_len_in(&buf[..len]);
+        if needed <= len {
+            break;
+        }
+        if !read_full(reader, &mut buf[len..needed])? {
+            return Ok(None);
+        }
+        len = needed;
+    }
+    decode_header(&buf[..len]).map(Some)
+}
+
+/// Fills `buf` completely, returning `false` if the input ends first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Looks up the file position of `offset` in the segment's index, if present.
+pub(crate) fn index_position(info: &SegmentInfo, offset: u64) -> Result<Option<u64>> {
+    let Ok(mut idx_file) = info.open_file(&info.index_path(), OpenMode::Read) else {
+        return Ok(None);
+    };
+    let idx_pos = (offset - info.base_offset) * INDEX_ENTRY_LEN as u64;
+    if idx_pos + INDEX_ENTRY_LEN as u64 > idx_file.size()? {
+        return Ok(None);
+    }
+    idx_file.seek(SeekFrom::Start(idx_pos))?;
+    let mut entry = [0u8; INDEX_ENTRY_LEN];
+    idx_file.read_exact(&mut entry)?;
+    let (entry_offset, pos) = decode_index_entry(&entry);
+    Ok((entry_offset == offset).then_some(pos))
+}
+
+/// Splits an index entry into `(offset, position)`.
+pub(crate) fn decode_index_entry(entry: &[u8; INDEX_ENTRY_LEN]) -> (u64, u64) {
+    let mut b8 = [0u8; 8];
+    b8.copy_from_slice(&entry[0..8]);
+    let offset = u64::from_le_bytes(b8);
+    b8.copy_from_slice(&entry[8..16]);
+    (offset, u64::from_le_bytes(b8))
+}
+
+/// Looks up `offset` in a segment's index and reads and verifies its record.
+pub(crate) fn read_indexed(
+    log_file: &mut FileCursor,
+    idx_file: &mut FileCursor,
+    base_offset: u64,
+    offset: u64,
+    cipher: Option<&SegmentCipher>,
+    checksum: ChecksumMode,
+) -> Result<Record> {
+    let idx_pos = (offset - base_offset) * INDEX_ENTRY_LEN as u64;
+    if idx_pos + INDEX_ENTRY_LEN as u64 > idx_file.size()? {
+        return Err(Error::InvalidFormat(format!(
+            "offset {offset} not found in index"
+        )));
+    }
+
+    idx_file.seek(SeekFrom::Start(idx_pos))?;
+    let mut entry_buf = [0u8; INDEX_ENTRY_LEN];
+    idx_file.read_exact(&mut entry_buf)?;
+    let (entry_offset, entry_pos) = decode_index_entry(&entry_buf);
+
+    if entry_offset != offset {
+        return Err(Error::Corruption(format!(
+            "index entry offset mismatch: expected {offset}, got {entry_offset}"
+        )));
+    }
+
+    log_file.seek(SeekFrom::Start(entry_pos))?;
+    let records = read_next_frame(log_file, cipher, checksum)?.ok_or_else(|| {
+        Error::Corruption(format!(
+            "index points past the end of the segment for offset {offset}"
+        ))
+    })?;
+
+    // Every offset of a batch frame is indexed at the frame's position.
+    let first = records.first().map(|r| r.offset);
+    records
+        .into_iter()
+        .find(|r| r.offset == offset)
+        .ok_or_else(|| {
+            Error::Corruption(format!(
+                "index entry for offset {offset} points at the frame for offset {}",
+                first.unwrap_or_default()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::HEADER_LEN;
+    use crate::{Config, Log};
+
+    fn rolled_log(dir: &Path) -> Log {
+        let config = Config {
+            max_segment_bytes: 64,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir, config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        log
+    }
+
+    #[test]
+    fn iterates_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let _log = rolled_log(dir.path());
+        let reader = LogReader::open(dir.path()).unwrap();
+        assert!(reader.segments().len() > 1);
+
+        let records: Vec<Record> = reader.iter().collect::<Result<_>>().unwrap();
+        assert_eq!(records.len(), 10);
+        for (i, record) in (0u8..).zip(&records) {
+            assert_eq!(record.offset, u64::from(i));
+            assert_eq!(record.payload, [i; 10]);
+        }
+    }
+
+    #[test]
+    fn scan_hints_leave_records_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 64,
+            evict_sealed: true,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        let reader = LogReader::open(dir.path()).unwrap();
+        let plain: Vec<Record> = reader.iter().collect::<Result<_>>().unwrap();
+        for reader in [reader.clone(), reader.with_read_ahead(16)] {
+            let hinted: Vec<Record> = reader
+                .with_scan_hints(true)
+                .iter()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(hinted, plain);
+        }
+    }
+
+    #[test]
+    fn iter_from_skips_earlier_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let _log = rolled_log(dir.path());
+        let reader = LogReader::open(dir.path()).unwrap();
+        let offsets: Vec<u64> = reader.iter_from(7).map(|r| r.unwrap().offset).collect();
+        assert_eq!(offsets, vec![7, 8, 9]);
+
+        let reader = reader.with_read_ahead(20);
+        let offsets: Vec<u64> = reader.iter_from(3).map(|r| r.unwrap().offset).collect();
+        assert_eq!(offsets, (3..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn point_reads_reuse_cached_segment_handles() {
+        /// Records the segments whose handles were evicted.
+        #[derive(Debug, Default)]
+        struct Evictions(std::sync::Mutex<Vec<u64>>);
+        impl LogObserver for Evictions {
+            fn on_handle_eviction(&self, base_offset: u64) {
+                self.0.lock().unwrap().push(base_offset);
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let _log = rolled_log(dir.path());
+        let evictions = Arc::new(Evictions::default());
+        let reader = LogReader::open(dir.path())
+            .unwrap()
+            .with_handle_cache(2)
+            .with_observer(evictions.clone());
+        let base = |offset| reader.segment_for(offset).unwrap().base_offset;
+        assert_ne!(base(0), base(5));
+
+        for i in [0u8, 5, 0, 9] {
+            assert_eq!(reader.read(u64::from(i)).unwrap(), [i; 10]);
+        }
+        assert_eq!(reader.handles.len(), 2);
+        assert_eq!(*evictions.0.lock().unwrap(), [base(5)]);
+
+        let mut reader = reader;
+        reader.refresh().unwrap();
+        assert_eq!(reader.handles.len(), 0);
+        let reader = reader.with_handle_cache(0);
+        assert_eq!(reader.read(1).unwrap(), [1; 10]);
+        assert_eq!(reader.handles.len(), 0);
+    }
+
+    #[test]
+    fn read_while_writer_holds_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = rolled_log(dir.path());
+        let mut reader = LogReader::open(dir.path()).unwrap();
+        assert_eq!(reader.read(3).unwrap(), [3u8; 10]);
+
+        log.append(b"later").unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.read(10).unwrap(), b"later");
+    }
+
+    #[test]
+    fn honours_log_start_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = rolled_log(dir.path());
+        log.delete_before(4).unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        assert_eq!(reader.first_offset(), 4);
+        assert!(matches!(
+            reader.read(3),
+            Err(Error::OffsetOutOfRange { requested: 3, .. })
+        ));
+        let first = reader.iter().next().unwrap().unwrap();
+        assert_eq!(first.offset, 4);
+    }
+
+    #[test]
+    fn keyed_records_expose_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"plain").unwrap();
+        log.append_keyed(b"k1", b"v1").unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        let records: Vec<Record> = reader.iter().collect::<Result<_>>().unwrap();
+        assert_eq!(records[0].key, None);
+        assert_eq!(records[1].key.as_deref(), Some(&b"k1"[..]));
+        assert_eq!(records[1].payload, b"v1");
+        assert_eq!(reader.read(1).unwrap(), b"v1");
+    }
+
+    #[test]
+    fn iteration_stops_at_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"whole").unwrap();
+            log.flush().unwrap();
+            dir.path().join(crate::SegmentId(0).log_filename())
+        };
+        let mut data = std::fs::read(&log_path).unwrap();
+        data.extend_from_slice(&crate::encode_record(1, b"torn").unwrap()[..HEADER_LEN + 2]);
+        std::fs::write(&log_path, data).unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        let records: Vec<Record> = reader.iter().collect::<Result<_>>().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].payload, b"whole");
+    }
+
+    #[test]
+    fn skip_mode_resynchronises_after_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 200,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for i in 0..6u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        // Segment 0 holds five 34-byte records and is sealed; damage the body of
+        // offset 1 and the header of offset 3.
+        let info = crate::discover_segments(dir.path()).unwrap().remove(0);
+        let mut bytes = std::fs::read(&info.log_path).unwrap();
+        bytes[34 + 24] ^= 0xFF;
+        bytes[3 * 34] ^= 0xFF;
+        std::fs::write(&info.log_path, bytes).unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        let mut fail = reader.iter();
+        assert_eq!(fail.next().unwrap().unwrap().offset, 0);
+        assert!(matches!(
+            fail.next(),
+            Some(Err(Error::ChecksumMismatch { .. }))
+        ));
+        assert!(fail.next().is_none());
+
+        let reader = reader.with_on_corruption(OnCorruption::Skip);
+        let items = |reader: &LogReader| -> Vec<_> {
+            reader
+                .iter()
+                .map(|item| match item {
+                    Ok(record) => Ok(record.offset),
+                    Err(Error::Skipped(range)) => Err((range.segment, range.start, range.end)),
+                    Err(e) => panic!("unexpected error: {e}"),
+                })
+                .collect()
+        };
+        let items_read_ahead = items(&reader.clone().with_read_ahead(16));
+        let items = items(&reader);
+        assert_eq!(items_read_ahead, items);
+        assert_eq!(
+            items,
+            [
+                Ok(0),
+                Err((0, 34, 68)),
+                Ok(2),
+                Err((0, 102, 136)),
+                Ok(4),
+                Ok(5)
+            ]
+        );
+    }
+
+    #[test]
+    fn checksum_mode_controls_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = {
+            let mut log = Log::open(dir.path(), Config::default()).unwrap();
+            log.append(b"payload").unwrap();
+            log.flush().unwrap();
+            dir.path().join(crate::SegmentId(0).log_filename())
+        };
+        let mut data = std::fs::read(&log_path).unwrap();
+        data[HEADER_LEN] ^= 0xFF;
+        std::fs::write(&log_path, data).unwrap();
+
+        let reader = LogReader::open(dir.path()).unwrap();
+        assert_eq!(reader.checksum_mode(), ChecksumMode::Verify);
+        assert!(matches!(
+            reader.read(0),
+            Err(Error::ChecksumMismatch { offset: 0, .. })
+        ));
+        assert!(matches!(
+            reader.iter().next(),
+            Some(Err(Error::ChecksumMismatch { .. }))
+        ));
+
+        let reader = reader.with_checksum_mode(ChecksumMode::Skip);
+        assert_eq!(reader.read(0).unwrap()[1..], b"payload"[1..]);
+        assert_eq!(reader.iter().count(), 1);
+    }
+
+    #[test]
+    fn readers_of_a_live_log_see_snapshots_up_to_its_watermarks() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            write_buffer: Some(crate::WriteBufferPolicy {
+                max_records: Some(2),
+                ..crate::WriteBufferPolicy::default()
+            }),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"a").unwrap();
+        log.flush().unwrap();
+        log.append(b"b").unwrap();
+        let reader = log.reader().unwrap();
+        let durable = log.reader().unwrap().with_durable_only(true);
+
+        // "b" is still buffered, so no reader sees it yet.
+        assert_eq!(reader.iter().count(), 1);
+        assert!(matches!(
+            reader.read(1),
+            Err(Error::OffsetOutOfRange {
+                latest: Some(0),
+                ..
+            })
+        ));
+
+        // Written but not fsynced: visible unless durable-only, and only to
+        // iterators created afterwards.
+        let snapshot = reader.iter();
+        log.append(b"c").unwrap();
+        assert_eq!(snapshot.count(), 1);
+        assert_eq!(reader.iter().count(), 3);
+        assert_eq!(durable.iter().count(), 1);
+
+        log.flush().unwrap();
+        assert_eq!(durable.read(2).unwrap(), b"c");
+
+        // Truncation pulls the watermarks back; a closed log no longer bounds reads.
+        log.truncate_after(0).unwrap();
+        assert_eq!(durable.iter().count(), 1);
+        drop(log);
+        assert_eq!(reader.iter().count(), 1);
+    }
+
+    #[test]
+    fn cached_handles_do_not_outlive_a_truncated_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 30,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(&[0, 0]).unwrap();
+        log.append(&[1, 1]).unwrap();
+        let reader = log.reader().unwrap();
+        assert_eq!(reader.read(1).unwrap(), [1, 1]);
+
+        // Segment 1 is deleted, then a new one with the same base offset rolled.
+        log.truncate_after(0).unwrap();
+        assert_eq!(log.append(&[238, 238]).unwrap(), 1);
+        assert_eq!(log.segment_infos().count(), 2);
+        assert_eq!(reader.read(1).unwrap(), [238, 238]);
+    }
+
+    #[test]
+    fn durable_only_readers_stop_at_the_published_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"a").unwrap();
+        log.append(b"b").unwrap();
+        log.flush().unwrap();
+        log.append(b"c").unwrap();
+
+        // Another process's view: only the fsynced records.
+        let mut reader = LogReader::open(dir.path()).unwrap().with_durable_only(true);
+        assert_eq!(reader.durable_offset(), Some(2));
+        assert_eq!(reader.iter().count(), 2);
+        assert!(reader.read(2).is_err());
+        assert_eq!(LogReader::open(dir.path()).unwrap().iter().count(), 3);
+
+        log.flush().unwrap();
+        assert_eq!(reader.iter().count(), 2);
+        reader.refresh().unwrap();
+        assert_eq!(reader.read(2).unwrap(), b"c");
+
+        log.truncate_after(0).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.durable_offset(), Some(1));
+    }
+}
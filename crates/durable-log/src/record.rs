@@ -1,78 +1,40 @@
-//! V1 on-disk record format: header encoding/decoding and frame layout.
+//! On-disk record format: header encoding/decoding and frame layout.
 //!
-//! See the repository docs: `docs/file-format.md`.
+//! The layout itself lives in [`crate::codec`], which needs only `core` and
+//! `alloc`; this module adds the pluggable body checksums, compression and
+//! [`Error`] on top. See the repository docs: `docs/file-format.md`.
 
+use crate::checksum::ChecksumAlgorithm;
+use crate::codec;
 use crate::error::Error;
 use crate::Result;
-use crc32fast::Hasher;
-use std::io::{Cursor, Read, Write};
-
-/// Magic number for durable-log segment files (ASCII "DLOG").
-pub const MAGIC: u32 = 0x444C_4F47;
-
-/// Current record format version.
-pub const VERSION_V1: u8 = 1;
-
-/// Record header size in bytes (fixed).
-pub const HEADER_LEN: usize = 24;
-
-/// Index entry size in bytes (fixed): offset (8) + position (8).
-pub const INDEX_ENTRY_LEN: usize = 16;
-
-/// Reserved flags for future use; must be 0 in v1.
-pub const FLAGS_NONE: u8 = 0;
-
-/// Fixed-size header for a single log record (v1).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct RecordHeader {
-    /// Must be [`MAGIC`].
-    pub magic: u32,
-    /// Format version; only [`VERSION_V1`] is supported.
-    pub version: u8,
-    /// Reserved; must be 0 in v1.
-    pub flags: u8,
-    /// Logical offset of this record (monotonic).
-    pub offset: u64,
-    /// Length of the payload in bytes.
-    pub payload_len: u32,
-    /// CRC-32 of the payload only (see docs).
-    pub checksum: u32,
-}
-
-impl RecordHeader {
-    /// Build a header for encoding. Checksum must be computed from the payload.
-    #[must_use]
-    pub const fn new(offset: u64, payload_len: u32, checksum: u32) -> Self {
-        Self {
-            magic: MAGIC,
-            version: VERSION_V1,
-            flags: FLAGS_NONE,
-            offset,
-            payload_len,
-            checksum,
-        }
-    }
+use std::borrow::Cow;
+use std::io::Write;
 
-    /// Compute CRC-32 of `payload` (used when encoding).
-    #[must_use]
-    pub fn checksum_of(payload: &[u8]) -> u32 {
-        let mut hasher = Hasher::new();
-        hasher.update(payload);
-        hasher.finalize()
-    }
+pub use crate::codec::{
+    header_len, header_len_in, RecordHeader, BATCH_CONTINUES, BATCH_LEN_PREFIX, FLAGS_KNOWN,
+    FLAGS_NONE, FLAG_BATCH, FLAG_CHECKSUM_MASK, FLAG_CONTINUED, FLAG_CONTROL, FLAG_CRC32C,
+    FLAG_ENCRYPTED, FLAG_KEYED, FLAG_LZ4, FLAG_XXH64, FLAG_ZSTD, HEADER_LEN, HEADER_LEN_V2,
+    INDEX_ENTRY_LEN, KEY_LEN_PREFIX, MAGIC, MAX_CHUNK_LEN, MAX_HEADER_LEN, MIN_HEADER_LEN,
+    VERSION_COMPACT, VERSION_V1, VERSION_V2,
+};
 
-    /// Verifies that the header's checksum matches the computed checksum of the payload.
+impl RecordHeader {
+    /// Verifies that the header's checksum matches the checksum of the payload,
+    /// computed with the algorithm selected by the header's flags.
     ///
     /// # Errors
     ///
-    /// Returns [`Error::Corruption`] if checksums do not match.
+    /// - [`Error::ChecksumMismatch`] if checksums do not match.
+    /// - [`Error::InvalidFormat`] if the algorithm's feature is disabled.
     pub fn validate_checksum(&self, payload: &[u8]) -> Result<()> {
-        let actual = Self::checksum_of(payload);
+        let actual = ChecksumAlgorithm::from_flags(self.flags)?.checksum(payload)?;
         if actual != self.checksum {
-            return Err(Error::Corruption(format!(
-                "checksum mismatch at offset {}: expected 0x{:08X}, got 0x{:08X}",
-                self.offset, self.checksum, actual
-            )));
+            return Err(Error::ChecksumMismatch {
+                offset: self.offset,
+                expected: self.checksum,
+                actual,
+            });
         }
         Ok(())
     }
@@ -85,126 +47,289 @@ impl RecordHeader {
 /// # Errors
 ///
 /// Returns an error if `payload.len()` exceeds `u32::MAX`.
+pub fn encode_record(offset: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    encode_frame_as(
+        RecordHeader::new(offset, 0, 0),
+        FLAGS_NONE,
+        &[],
+        None,
+        payload,
+    )
+}
+
+/// Encodes a keyed record: header with [`FLAG_KEYED`], then a body of the key length
+/// (u32), the key, and the value. The checksum covers the whole body.
 ///
-/// # Panics
+/// # Errors
 ///
-/// Never panics for valid input; writing to the internal `Vec` cannot fail.
-pub fn encode_record(offset: u64, payload: &[u8]) -> Result<Vec<u8>> {
-    let len = u32::try_from(payload.len()).map_err(|_| {
+/// Returns an error if the key or the whole body exceeds `u32::MAX` bytes.
+pub fn encode_keyed_record(offset: u64, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    encode_frame(offset, FLAGS_NONE, Some(key), value)
+}
+
+/// Encodes a record with explicit `flags` and an optional key.
+///
+/// [`FLAG_KEYED`] is set automatically when `key` is present; `value` is written
+/// as given (e.g. already compressed when a compression flag is set).
+///
+/// # Errors
+///
+/// Returns an error if `flags` has unknown bits, or if the key or the whole body
+/// exceeds `u32::MAX` bytes.
+pub fn encode_frame(offset: u64, flags: u8, key: Option<&[u8]>, value: &[u8]) -> Result<Vec<u8>> {
+    encode_frame_as(RecordHeader::new(offset, 0, 0), flags, &[], key, value)
+}
+
+/// Encodes a record like [`encode_frame`], using a [`VERSION_V2`] header that
+/// stores `timestamp` (milliseconds since the Unix epoch).
+///
+/// The header is protected by its own CRC. `headers` are stored ahead of the
+/// key and value.
+///
+/// # Errors
+///
+/// Same as [`encode_frame`] and [`encode_headers`].
+pub fn encode_frame_v2(
+    offset: u64,
+    timestamp: u64,
+    flags: u8,
+    headers: &[(&str, &[u8])],
+    key: Option<&[u8]>,
+    value: &[u8],
+) -> Result<Vec<u8>> {
+    let header = RecordHeader::new(offset, 0, 0)
+        .with_version(VERSION_V2)
+        .with_timestamp(timestamp);
+    encode_frame_as(header, flags, &encode_headers(headers)?, key, value)
+}
+
+/// Encodes user headers (name/value metadata) into a v2 headers block: for each
+/// header, the name length (u16) and UTF-8 name, then the value length (u32) and
+/// value.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidFormat`] if a name exceeds `u16::MAX` bytes, or a
+/// value or the whole block exceeds `u32::MAX` bytes.
+pub fn encode_headers(headers: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    Ok(codec::encode_headers(headers)?)
+}
+
+/// Encodes several payloads as one batch frame numbered from `offset`.
+///
+/// The frame is a v1 header with [`FLAG_BATCH`] and the payload count, then
+/// each payload prefixed by its length (u32). A single checksum covers the
+/// whole body; the payloads get offsets `offset`, `offset + 1`, and so on.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidFormat`] if `payloads` is empty or holds more than
+/// `u16::MAX` payloads, or if any payload or the whole body exceeds `u32::MAX`
+/// bytes.
+pub fn encode_batch(offset: u64, payloads: &[&[u8]]) -> Result<Vec<u8>> {
+    let (count, body) = pack_batch(VERSION_V1, payloads)?;
+    let template = RecordHeader::new(offset, 0, 0).with_batch_count(count);
+    encode_frame_as(template, FLAG_BATCH, &[], None, &body)
+}
+
+/// Builds a batch body for a frame of `version` from `payloads`, returning it
+/// with the payload count.
+pub(crate) fn pack_batch(version: u8, payloads: &[&[u8]]) -> Result<(u16, Vec<u8>)> {
+    Ok(codec::pack_batch(version, payloads)?)
+}
+
+/// Encodes a record from `template` (version, offset, timestamp and batch count),
+/// filling in its flags, length and checksum. `headers` is an encoded headers
+/// block (see [`encode_headers`]), which only v2 frames can carry.
+pub(crate) fn encode_frame_as(
+    template: RecordHeader,
+    flags: u8,
+    headers: &[u8],
+    key: Option<&[u8]>,
+    value: &[u8],
+) -> Result<Vec<u8>> {
+    codec::encode_frame_with(template, flags, headers, key, value, |parts| {
+        ChecksumAlgorithm::from_flags(flags)?.checksum_parts(parts)
+    })
+}
+
+/// Encodes only the header of an unkeyed frame without user headers whose
+/// value is the concatenation of `parts`, so the caller can write the value
+/// from its own buffers after it.
+///
+/// # Errors
+///
+/// [`Error::InvalidFormat`] for unknown flag bits or a value longer than
+/// `u32::MAX` bytes.
+pub(crate) fn encode_frame_header(
+    template: RecordHeader,
+    flags: u8,
+    parts: &[&[u8]],
+) -> Result<Vec<u8>> {
+    let unknown = flags & !FLAGS_KNOWN;
+    if unknown != 0 {
+        return Err(Error::InvalidFormat(format!(
+            "unknown flag bits: 0x{unknown:02X}"
+        )));
+    }
+    let body_len: usize = parts.iter().map(|part| part.len()).sum();
+    let len = u32::try_from(body_len).map_err(|_| {
         Error::InvalidFormat(format!(
-            "payload length {} exceeds maximum {}",
-            payload.len(),
+            "payload length {body_len} exceeds maximum {}",
             u32::MAX
         ))
     })?;
-    let checksum = RecordHeader::checksum_of(payload);
-    let header = RecordHeader::new(offset, len, checksum);
-    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
-    encode_header_into(&header, &mut out).expect("write to Vec never fails");
-    out.write_all(payload).expect("write to Vec never fails");
-    Ok(out)
+    let header = RecordHeader {
+        headers_len: 0,
+        payload_len: len,
+        checksum: ChecksumAlgorithm::from_flags(flags)?.checksum_parts(parts)?,
+        ..template.with_flags(flags)
+    };
+    let mut buf = [0u8; MAX_HEADER_LEN];
+    Ok(codec::encode_header(&header, &mut buf).to_vec())
 }
 
-/// Encodes only the header into `out` (exactly [`HEADER_LEN`] bytes). Little-endian.
+/// Encodes only the header into `out` ([`RecordHeader::encoded_len`] bytes:
+/// [`HEADER_LEN`] for v1, [`HEADER_LEN_V2`] for v2). Little-endian.
+///
+/// A v2 header without a timestamp stores 0. V1 and compact headers cannot
+/// describe user headers and drop `headers_len`; compact headers drop the
+/// timestamp too.
 ///
 /// # Errors
 ///
 /// Returns I/O errors from `out`.
 pub fn encode_header_into(header: &RecordHeader, out: &mut impl Write) -> std::io::Result<()> {
-    out.write_all(&header.magic.to_le_bytes())?;
-    out.write_all(&[header.version, header.flags])?;
-    out.write_all(&[0u8; 2])?; // reserved padding
-    out.write_all(&header.offset.to_le_bytes())?;
-    out.write_all(&header.payload_len.to_le_bytes())?;
-    out.write_all(&header.checksum.to_le_bytes())?;
-    Ok(())
+    let mut buf = [0u8; MAX_HEADER_LEN];
+    out.write_all(codec::encode_header(header, &mut buf))
 }
 
-/// Decodes a header from the first [`HEADER_LEN`] bytes. Fails if magic or version is invalid.
+/// Decodes a header of any supported version from the start of `bytes`. Fails if
+/// magic or version is invalid, or if a v2 header does not match its CRC.
 ///
 /// # Errors
 ///
-/// Returns [`Error::InvalidFormat`] for wrong magic, unsupported version, or truncated input.
-/// Returns I/O error only if the cursor read fails (e.g. truncated slice).
+/// - [`Error::Truncated`] if `bytes` is shorter than the header.
+/// - [`Error::BadMagic`] for wrong magic.
+/// - [`Error::UnsupportedVersion`] for a version other than v1, v2 or compact.
+/// - [`Error::Corruption`] for unknown or contradictory flag bits, a malformed
+///   compact varint, or if a v2 header fails its CRC check; none of its fields
+///   (in particular `payload_len`) can be trusted.
 pub fn decode_header(bytes: &[u8]) -> Result<RecordHeader> {
-    if bytes.len() < HEADER_LEN {
-        return Err(Error::InvalidFormat(format!(
-            "header too short: {} bytes (need {})",
-            bytes.len(),
-            HEADER_LEN
-        )));
-    }
-    let mut c = Cursor::new(bytes);
-    let magic = read_u32_le(&mut c)?;
-    if magic != MAGIC {
-        return Err(Error::InvalidFormat(format!(
-            "invalid magic: 0x{magic:08X} (expected 0x{MAGIC:08X})"
-        )));
-    }
-    let mut ver_buf = [0u8; 1];
-    c.read_exact(&mut ver_buf)?;
-    let version = ver_buf[0];
-    if version != VERSION_V1 {
-        return Err(Error::InvalidFormat(format!(
-            "unsupported version: {version} (expected {VERSION_V1})"
-        )));
-    }
-    let mut flags_buf = [0u8; 1];
-    c.read_exact(&mut flags_buf)?;
-    let mut reserved = [0u8; 2];
-    c.read_exact(&mut reserved)?;
-    let offset = read_u64_le(&mut c)?;
-    let payload_len = read_u32_le(&mut c)?;
-    let checksum = read_u32_le(&mut c)?;
-    Ok(RecordHeader {
-        magic,
-        version,
-        flags: flags_buf[0],
-        offset,
-        payload_len,
-        checksum,
-    })
+    Ok(codec::decode_header(bytes)?)
 }
 
 /// Decodes a full record (header + payload) from `bytes`. Validates magic and version only;
-/// checksum validation is left to the caller (see Day 5).
+/// checksum validation is left to the caller (see [`decode_record_verified`]).
 ///
 /// # Errors
 ///
-/// Returns [`Error::InvalidFormat`] for invalid header or truncated payload.
+/// - Same as [`decode_header`].
+/// - [`Error::Truncated`] if `bytes` ends before the payload does.
 pub fn decode_record(bytes: &[u8]) -> Result<(RecordHeader, &[u8])> {
-    let header = decode_header(bytes)?;
-    let payload_start = HEADER_LEN;
-    let end = payload_start
-        .checked_add(header.payload_len as usize)
-        .ok_or_else(|| {
-            Error::InvalidFormat(format!(
-                "payload length {} would overflow input (len {})",
-                header.payload_len,
-                bytes.len()
-            ))
-        })?;
-    if bytes.len() < end {
-        return Err(Error::InvalidFormat(format!(
-            "record truncated: need {} bytes for payload, have {}",
-            header.payload_len,
-            bytes.len().saturating_sub(HEADER_LEN)
-        )));
-    }
-    let payload = &bytes[payload_start..end];
-    Ok((header, payload))
+    Ok(codec::decode_record(bytes)?)
+}
+
+/// Decodes a full record like [`decode_record`] and verifies the body checksum.
+///
+/// # Errors
+///
+/// - Same as [`decode_record`].
+/// - [`Error::ChecksumMismatch`] if the body does not match the header checksum.
+pub fn decode_record_verified(bytes: &[u8]) -> Result<(RecordHeader, &[u8])> {
+    let (header, body) = decode_record(bytes)?;
+    header.validate_checksum(body)?;
+    Ok((header, body))
+}
+
+/// Splits a record body into its key (for keyed records) and value, skipping any
+/// user headers.
+///
+/// # Errors
+///
+/// Returns [`Error::Corruption`] if the body is too short for its headers block,
+/// or a keyed body for its key length.
+pub fn split_key<'a>(
+    header: &RecordHeader,
+    body: &'a [u8],
+) -> Result<(Option<&'a [u8]>, &'a [u8])> {
+    Ok(codec::split_key(header, body)?)
+}
+
+/// Decodes the user headers block at the start of a record body as `(name, value)`
+/// pairs, in the order they were written.
+///
+/// # Errors
+///
+/// Returns [`Error::Corruption`] if the block is truncated, overruns the body, or
+/// holds a name that is not UTF-8.
+pub fn decode_headers<'a>(
+    header: &RecordHeader,
+    body: &'a [u8],
+) -> Result<Vec<(&'a str, &'a [u8])>> {
+    Ok(codec::decode_headers(header, body)?)
+}
+
+/// Returns the record value with any compression undone, borrowing when the value
+/// is stored uncompressed.
+///
+/// # Errors
+///
+/// Same as [`crate::compression::decompress`].
+pub fn decode_value<'a>(header: &RecordHeader, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    crate::compression::decompress(header.flags, value)
 }
 
-fn read_u32_le(r: &mut impl Read) -> std::io::Result<u32> {
-    let mut b = [0u8; 4];
-    r.read_exact(&mut b)?;
-    Ok(u32::from_le_bytes(b))
+/// A decoded keyed record: `(header, key, value)`, borrowing from the input.
+pub type KeyedRecord<'a> = (RecordHeader, Option<&'a [u8]>, &'a [u8]);
+
+/// Decodes a full record and splits off its key, returning `(header, key, value)`.
+/// Like [`decode_record`], checksum validation is left to the caller.
+///
+/// # Errors
+///
+/// Same as [`decode_record`] and [`split_key`].
+pub fn decode_keyed_record(bytes: &[u8]) -> Result<KeyedRecord<'_>> {
+    let (header, body) = decode_record(bytes)?;
+    let (key, value) = split_key(&header, body)?;
+    Ok((header, key, value))
 }
 
-fn read_u64_le(r: &mut impl Read) -> std::io::Result<u64> {
-    let mut b = [0u8; 8];
-    r.read_exact(&mut b)?;
-    Ok(u64::from_le_bytes(b))
+/// Splits a batch body (already decrypted and decompressed) into its payloads.
+///
+/// # Errors
+///
+/// Returns [`Error::Corruption`] if the body does not hold exactly
+/// `header.batch_count` length-prefixed payloads.
+pub fn split_batch<'a>(header: &RecordHeader, body: &'a [u8]) -> Result<Vec<&'a [u8]>> {
+    Ok(codec::split_batch(header, body)?)
+}
+
+/// Decodes a batch frame written by [`encode_batch`] and splits it into its
+/// payloads, the first of which has the header's offset. Like [`decode_record`],
+/// checksum validation is left to the caller.
+///
+/// # Errors
+///
+/// - Same as [`decode_record`] and [`split_batch`].
+/// - [`Error::InvalidFormat`] if the frame is not a batch, or its body is
+///   compressed or encrypted and so cannot be split in place.
+pub fn decode_batch(bytes: &[u8]) -> Result<(RecordHeader, Vec<&[u8]>)> {
+    let (header, body) = decode_record(bytes)?;
+    if !header.is_batch() {
+        return Err(Error::InvalidFormat(format!(
+            "record at offset {} is not a batch frame",
+            header.offset
+        )));
+    }
+    if header.is_compressed() || header.is_encrypted() {
+        return Err(Error::InvalidFormat(format!(
+            "batch frame at offset {} must be decoded through a reader",
+            header.offset
+        )));
+    }
+    let payloads = split_batch(&header, body)?;
+    Ok((header, payloads))
 }
 
 #[cfg(test)]
@@ -231,19 +356,41 @@ mod tests {
         let encoded = encode_record(1, payload).unwrap();
         let (header, decoded_payload) = decode_record(&encoded).unwrap();
         assert_eq!(header.offset, 1);
+        assert_eq!(header.timestamp, None);
         assert_eq!(header.payload_len, 11);
         assert_eq!(header.checksum, RecordHeader::checksum_of(payload));
         assert_eq!(decoded_payload, payload);
     }
 
+    #[test]
+    fn verified_decode_detects_flipped_body_bit() {
+        let mut encoded = encode_record(7, b"hello world").unwrap();
+        assert!(decode_record_verified(&encoded).is_ok());
+        encoded[HEADER_LEN + 3] ^= 0x01;
+        let err = decode_record_verified(&encoded).unwrap_err();
+        let Error::ChecksumMismatch {
+            offset,
+            expected,
+            actual,
+        } = err
+        else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(offset, 7);
+        assert_eq!(expected, RecordHeader::checksum_of(b"hello world"));
+        assert_ne!(expected, actual);
+        // The unverified decode still hands back the corrupt body.
+        assert!(decode_record(&encoded).is_ok());
+    }
+
     #[test]
     fn invalid_magic_fails() {
         let mut buf = vec![0u8; HEADER_LEN];
         buf[0..4].copy_from_slice(&0xDEAD_BEEFu32.to_le_bytes());
         buf[4] = VERSION_V1;
         let err = decode_header(&buf).unwrap_err();
-        let s = err.to_string();
-        assert!(s.contains("invalid magic"), "{}", s);
+        assert!(matches!(err, Error::BadMagic(0xDEAD_BEEF)), "{err}");
+        assert!(err.is_corruption());
     }
 
     #[test]
@@ -252,15 +399,22 @@ mod tests {
         buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
         buf[4] = 99;
         let err = decode_header(&buf).unwrap_err();
-        let s = err.to_string();
-        assert!(s.contains("unsupported version"), "{}", s);
+        assert!(matches!(err, Error::UnsupportedVersion(99)), "{err}");
     }
 
     #[test]
     fn header_too_short_fails() {
         let err = decode_header(&[0u8; 8]).unwrap_err();
-        let s = err.to_string();
-        assert!(s.contains("too short"), "{}", s);
+        assert!(
+            matches!(
+                err,
+                Error::Truncated {
+                    needed: HEADER_LEN,
+                    available: 8
+                }
+            ),
+            "{err}"
+        );
     }
 
     #[test]
@@ -269,8 +423,16 @@ mod tests {
         let mut encoded = encode_record(0, payload).unwrap();
         encoded.truncate(HEADER_LEN + 2); // truncate payload
         let err = decode_record(&encoded).unwrap_err();
-        let s = err.to_string();
-        assert!(s.contains("truncated") || s.contains("overflow"), "{}", s);
+        assert!(
+            matches!(
+                err,
+                Error::Truncated {
+                    needed: 29,
+                    available: 26
+                }
+            ),
+            "{err}"
+        );
     }
 
     /// Golden test: encoding a known record produces exact expected bytes (header part).
@@ -290,6 +452,197 @@ mod tests {
         assert_eq!(&encoded[24..], payload);
     }
 
+    #[test]
+    fn v2_record_roundtrip() {
+        let encoded = encode_frame_v2(
+            9,
+            1_700_000_000_123,
+            FLAGS_NONE,
+            &[("trace", b"abc")],
+            Some(b"k"),
+            b"value",
+        )
+        .unwrap();
+        assert_eq!(encoded[4], VERSION_V2);
+        assert_eq!(
+            &encoded[HEADER_LEN..HEADER_LEN + 8],
+            1_700_000_000_123u64.to_le_bytes()
+        );
+        assert_eq!(
+            &encoded[HEADER_LEN + 12..HEADER_LEN_V2],
+            RecordHeader::checksum_of(&encoded[..HEADER_LEN + 12]).to_le_bytes()
+        );
+        let (header, key, value) = decode_keyed_record(&encoded).unwrap();
+        assert_eq!(header.version, VERSION_V2);
+        assert_eq!(header.encoded_len(), HEADER_LEN_V2);
+        assert_eq!(header.offset, 9);
+        assert_eq!(header.timestamp, Some(1_700_000_000_123));
+        assert_eq!((key, value), (Some(&b"k"[..]), &b"value"[..]));
+        let body = &encoded[HEADER_LEN_V2..];
+        header.validate_checksum(body).unwrap();
+        assert_eq!(
+            decode_headers(&header, body).unwrap(),
+            [("trace", &b"abc"[..])]
+        );
+
+        // The same header re-encodes to the same bytes.
+        let mut buf = Vec::new();
+        encode_header_into(&header, &mut buf).unwrap();
+        assert_eq!(buf, encoded[..HEADER_LEN_V2]);
+    }
+
+    #[test]
+    fn v2_header_bit_flips_are_detected() {
+        let encoded = encode_frame_v2(3, 42, FLAGS_NONE, &[], None, b"payload").unwrap();
+        // Offset, payload_len, body checksum, timestamp and the header CRC itself.
+        for byte in (8..HEADER_LEN_V2).chain([6, 7]) {
+            let mut corrupt = encoded.clone();
+            corrupt[byte] ^= 0x10;
+            let err = decode_record(&corrupt).unwrap_err();
+            assert!(matches!(err, Error::Corruption(_)), "byte {byte}: {err}");
+        }
+        // A v1 header has no such protection: a flipped length goes unnoticed
+        // until the payload is sliced.
+        let mut v1 = encode_record(3, b"payload").unwrap();
+        v1[16] ^= 0x01;
+        assert!(decode_header(&v1).is_ok());
+    }
+
+    #[test]
+    fn decodes_v1_and_v2_side_by_side() {
+        let mut stream = encode_record(0, b"one").unwrap();
+        stream.extend(encode_frame_v2(1, 0, FLAGS_NONE, &[], None, b"two").unwrap());
+        stream.extend(encode_record(2, b"three").unwrap());
+        let mut rest = &stream[..];
+        let mut payloads = Vec::new();
+        while !rest.is_empty() {
+            let (header, body) = decode_record_verified(rest).unwrap();
+            payloads.push(body.to_vec());
+            rest = &rest[header.encoded_len() + body.len()..];
+        }
+        assert_eq!(payloads, [&b"one"[..], b"two", b"three"]);
+    }
+
+    #[test]
+    fn truncated_v2_header_fails() {
+        let encoded = encode_frame_v2(0, 0, FLAGS_NONE, &[], None, b"x").unwrap();
+        assert!(matches!(
+            decode_header(&encoded[..HEADER_LEN + 2]),
+            Err(Error::Truncated {
+                needed: HEADER_LEN_V2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn batch_roundtrip() {
+        let payloads: [&[u8]; 3] = [b"a", b"", b"ccc"];
+        let encoded = encode_batch(10, &payloads).unwrap();
+        assert_eq!(
+            encoded.len(),
+            HEADER_LEN + payloads.len() * BATCH_LEN_PREFIX + 4
+        );
+        let (header, decoded) = decode_batch(&encoded).unwrap();
+        assert!(header.is_batch());
+        assert_eq!(header.offset, 10);
+        assert_eq!(header.batch_count, 3);
+        assert_eq!(header.record_count(), 3);
+        assert_eq!(decoded, payloads);
+        decode_record_verified(&encoded).unwrap();
+
+        assert!(encode_batch(0, &[]).is_err());
+        assert!(decode_batch(&encode_record(0, b"x").unwrap()).is_err());
+    }
+
+    #[test]
+    fn batch_with_bad_lengths_fails() {
+        let mut encoded = encode_batch(0, &[b"ab", b"cd"]).unwrap();
+        encoded[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&9u32.to_le_bytes());
+        assert!(matches!(decode_batch(&encoded), Err(Error::Corruption(_))));
+
+        // A count that disagrees with the body is caught too.
+        let mut encoded = encode_batch(0, &[b"ab", b"cd"]).unwrap();
+        encoded[6] = 1;
+        assert!(matches!(decode_batch(&encoded), Err(Error::Corruption(_))));
+        encoded[6] = 0;
+        assert!(decode_header(&encoded).is_err());
+    }
+
+    #[test]
+    fn headers_block_roundtrip() {
+        let headers: [(&str, &[u8]); 3] = [("tenant", b"acme"), ("empty", b""), ("tenant", b"x")];
+        let encoded = encode_frame_v2(0, 0, FLAGS_NONE, &headers, None, b"payload").unwrap();
+        let (header, body) = decode_record_verified(&encoded).unwrap();
+        assert_eq!(decode_headers(&header, body).unwrap(), headers);
+        assert_eq!(split_key(&header, body).unwrap(), (None, &b"payload"[..]));
+
+        // A v1 frame cannot carry headers; a v2 frame without any decodes none.
+        let block = encode_headers(&headers).unwrap();
+        let v1 = encode_frame_as(RecordHeader::new(0, 0, 0), FLAGS_NONE, &block, None, b"x");
+        assert!(v1.is_err());
+        let plain = encode_frame_v2(0, 0, FLAGS_NONE, &[], None, b"x").unwrap();
+        let (header, body) = decode_record(&plain).unwrap();
+        assert!(decode_headers(&header, body).unwrap().is_empty());
+
+        let mut bad = block;
+        bad[2] = 0xFF;
+        let header = RecordHeader::new(0, 0, 0).with_version(VERSION_V2);
+        let header = RecordHeader {
+            headers_len: u32::try_from(bad.len()).unwrap(),
+            ..header
+        };
+        assert!(matches!(
+            decode_headers(&header, &bad),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn keyed_record_roundtrip() {
+        let encoded = encode_keyed_record(7, b"user-1", b"value").unwrap();
+        let (header, key, value) = decode_keyed_record(&encoded).unwrap();
+        assert!(header.is_keyed());
+        assert_eq!(header.offset, 7);
+        assert_eq!(header.payload_len as usize, KEY_LEN_PREFIX + 6 + 5);
+        assert_eq!(key, Some(&b"user-1"[..]));
+        assert_eq!(value, b"value");
+        header.validate_checksum(&encoded[HEADER_LEN..]).unwrap();
+
+        let plain = encode_record(1, b"v").unwrap();
+        let (header, key, value) = decode_keyed_record(&plain).unwrap();
+        assert!(!header.is_keyed());
+        assert_eq!(key, None);
+        assert_eq!(value, b"v");
+    }
+
+    #[test]
+    fn keyed_record_with_bad_key_len_fails() {
+        let mut encoded = encode_keyed_record(0, b"k", b"v").unwrap();
+        encoded[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&100u32.to_le_bytes());
+        let err = decode_keyed_record(&encoded).unwrap_err();
+        assert!(err.to_string().contains("key length"), "{err}");
+    }
+
+    #[test]
+    fn invalid_flag_combinations_fail() {
+        // Every flag bit is assigned; only their combinations can be invalid.
+        assert_eq!(FLAGS_KNOWN, 0xFF);
+        let mut encoded = encode_batch(0, &[b"x"]).unwrap();
+        encoded[5] |= FLAG_KEYED;
+        let err = decode_header(&encoded).unwrap_err();
+        assert!(err.to_string().contains("keyed"), "{err}");
+        encoded[5] = FLAG_CONTROL | FLAG_LZ4;
+        let err = decode_header(&encoded).unwrap_err();
+        assert!(err.to_string().contains("control"), "{err}");
+        encoded[5] = FLAG_CONTROL;
+        let header = decode_header(&encoded).unwrap();
+        assert!(header.is_control() && !header.is_batch() && !header.is_continued());
+        assert_eq!(header.record_count(), 1);
+        encoded[5] = FLAG_CHECKSUM_MASK;
+        assert!(decode_header(&encoded).is_err());
+    }
+
     /// Golden test: decode then re-encode yields identical bytes.
     #[test]
     fn golden_decode_reencode_roundtrip() {
@@ -0,0 +1,168 @@
+//! What opening a log repaired.
+//!
+//! Unless a log was last [`close`](crate::Log::close)d cleanly,
+//! [`Log::open`](crate::Log::open) scans its last segment and truncates a torn
+//! or corrupt tail, rebuilds indexes that do not match their segment, and
+//! aborts a transaction left open. [`Log::recovery`](crate::Log::recovery)
+//! returns a [`RecoveryReport`] of what it did, so that dropped data is noticed
+//! instead of vanishing silently at startup.
+//!
+//! A [`RecoveryObserver`] set in
+//! [`Config::recovery_observer`](crate::Config::recovery_observer) is told about
+//! each repair as it happens, then given the report. Every method has a no-op
+//! default, and callbacks run inline on the thread opening the log.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Receives the repairs made while a log is opened; see the
+/// [module docs](self).
+pub trait RecoveryObserver: fmt::Debug + Send + Sync {
+    /// `bytes` bytes of torn or corrupt records were cut from the end of the
+    /// segment at `segment` (its base offset).
+    fn on_truncated(&self, _segment: u64, _bytes: u64) {}
+
+    /// A corrupt record frame was found at byte `position` of the segment at
+    /// `segment`; it and everything after it are truncated.
+    fn on_corrupt_record(&self, _segment: u64, _position: u64) {}
+
+    /// The index of the segment at `segment` was missing or did not match the
+    /// segment, and was rebuilt.
+    fn on_index_repaired(&self, _segment: u64) {}
+
+    /// Recovery finished, as summarized by `report`.
+    fn on_recovered(&self, _report: &RecoveryReport) {}
+}
+
+/// A summary of the repairs made while a log was opened, returned by
+/// [`Log::recovery`](crate::Log::recovery).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecoveryReport {
+    /// Whether the last segment was scanned; false if the log was closed
+    /// cleanly.
+    pub scanned: bool,
+    /// Bytes of torn or corrupt records cut from the end of the log. Space
+    /// reserved by [`Config::preallocate`](crate::Config::preallocate) is not
+    /// counted.
+    pub truncated_bytes: u64,
+    /// Corrupt record frames found, as opposed to a tail torn by a crash.
+    pub corrupt_records: u64,
+    /// Base offsets of the segments whose index was rebuilt.
+    pub repaired_indexes: Vec<u64>,
+    /// Offset of the begin marker of the unfinished transaction that was
+    /// aborted, if any (see [`crate::txn`]).
+    pub aborted_txn: Option<u64>,
+    /// Time taken to check and repair the log.
+    pub duration: Duration,
+}
+
+impl RecoveryReport {
+    /// Returns true if recovery dropped no data: nothing was truncated and no
+    /// corrupt record was found. Rebuilt indexes and aborted transactions lose
+    /// nothing.
+    #[must_use]
+    pub const fn is_lossless(&self) -> bool {
+        self.truncated_bytes == 0 && self.corrupt_records == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl RecoveryObserver for Recorder {
+        fn on_truncated(&self, segment: u64, bytes: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("truncated {segment} {bytes}"));
+        }
+
+        fn on_corrupt_record(&self, segment: u64, position: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("corrupt {segment} {position}"));
+        }
+
+        fn on_index_repaired(&self, segment: u64) {
+            self.0.lock().unwrap().push(format!("index {segment}"));
+        }
+
+        fn on_recovered(&self, report: &RecoveryReport) {
+            let lossless = report.is_lossless();
+            self.0.lock().unwrap().push(format!("recovered {lossless}"));
+        }
+    }
+
+    #[test]
+    fn open_reports_what_recovery_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let config = Config {
+            recovery_observer: Some(recorder.clone()),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        log.append(&[1; 16]).unwrap();
+        log.append(&[2; 16]).unwrap();
+        log.close().unwrap();
+
+        let log = Log::open(dir.path(), config.clone()).unwrap();
+        assert!(!log.recovery().scanned && log.recovery().is_lossless());
+        drop(log);
+
+        // A stray copy of the first frame, at the wrong offset, and no index.
+        let segment = &crate::discover_segments(dir.path()).unwrap()[0];
+        let frame = std::fs::read(&segment.log_path).unwrap()[..40].to_vec();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&segment.log_path)
+            .unwrap();
+        file.write_all(&frame).unwrap();
+        std::fs::remove_file(segment.index_path()).unwrap();
+        recorder.0.lock().unwrap().clear();
+
+        let mut log = Log::open(dir.path(), config).unwrap();
+        let report = log.recovery().clone();
+        assert!(report.scanned && !report.is_lossless());
+        assert_eq!((report.truncated_bytes, report.corrupt_records), (40, 1));
+        assert_eq!(report.repaired_indexes, [0]);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "corrupt 0 80",
+                "truncated 0 40",
+                "index 0",
+                "recovered false"
+            ]
+        );
+        assert_eq!(log.append(b"next").unwrap(), 2);
+    }
+
+    #[test]
+    fn preallocated_space_is_not_reported_as_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 4096,
+            preallocate: true,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        log.append(b"record").unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        let log = Log::open(dir.path(), config).unwrap();
+        assert!(log.recovery().scanned);
+        assert_eq!(log.recovery().truncated_bytes, 0);
+        assert_eq!(log.next_offset(), 1);
+    }
+}
@@ -0,0 +1,527 @@
+//! Streaming replication over TCP, for warm standbys of a log.
+//!
+//! A [`ReplicationServer`] serves a leader log. A follower connects with
+//! [`ReplicationClient::connect`] (or [`replicate`], which appends to a follower
+//! log) and asks for an offset; the leader streams every record from there on,
+//! then each new record as soon as it is durable on the leader. The stream ends
+//! when the leader log is dropped or the server stops.
+//!
+//! # Protocol
+//!
+//! Every message is a `u32` little-endian body length followed by the body, whose
+//! first byte is the message type:
+//!
+//! | Type | Direction | Rest of body |
+//! |------|-----------|--------------|
+//! | `1` subscribe | follower → leader | `DLRP` magic, protocol version (`u8`, currently 1), start offset (`u64` LE) |
+//! | `2` record | leader → follower | one record frame: v1 if the record has no timestamp or headers, else v2; transaction markers are v1 control records |
+//! | `3` error | leader → follower | UTF-8 message; the leader then closes the connection |
+//!
+//! Record frames are never compressed or encrypted on the wire; each carries
+//! its checksum, which the follower verifies. Either side rejects a message
+//! longer than it can hold before reading its body: 14 bytes for a subscribe
+//! message, and a record of [`MAX_CHUNK_LEN`] bytes plus up to 1 MiB of key
+//! and headers for the others. The leader reports a longer record as an error.
+
+use crate::ack::Watermark;
+use crate::error::Error;
+use crate::log::Log;
+use crate::reader::{LogReader, Record};
+use crate::record::{
+    decode_record, decode_record_verified, encode_frame, encode_frame_as, encode_frame_v2,
+    RecordHeader, FLAGS_NONE, FLAG_CONTROL, MAX_CHUNK_LEN, MAX_HEADER_LEN,
+};
+use crate::tail::Tail;
+use crate::trace::event;
+use crate::Result;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Magic opening a subscribe message.
+const SUBSCRIBE_MAGIC: &[u8; 4] = b"DLRP";
+/// Protocol version sent in subscribe messages.
+const PROTOCOL_VERSION: u8 = 1;
+
+/// Length of a subscribe message body: type, magic, version and offset.
+const SUBSCRIBE_LEN: usize = 14;
+/// Longest message body a follower accepts: the type byte and a frame of a
+/// full chunk with up to 1 MiB of key and headers.
+const MAX_MESSAGE_LEN: usize = 1 + MAX_HEADER_LEN + MAX_CHUNK_LEN + (1 << 20);
+
+const MSG_SUBSCRIBE: u8 = 1;
+const MSG_RECORD: u8 = 2;
+const MSG_ERROR: u8 = 3;
+
+/// How often idle server threads check whether the server is stopping.
+const IDLE_CHECK: Duration = Duration::from_millis(100);
+/// How long the leader waits for a new connection's subscribe message.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves a leader log to followers from a background thread, one thread per
+/// follower connection; see the [module docs](self).
+///
+/// The server stops when it is dropped or [`stop`](Self::stop) is called.
+#[derive(Debug)]
+pub struct ReplicationServer {
+    local_addr: SocketAddr,
+    /// Dropping the sender tells the accept thread to exit.
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReplicationServer {
+    /// Listens on `addr` and serves the records of `log` to followers.
+    ///
+    /// The server reads the log directory on its own; `log` stays usable, and
+    /// dropping it ends every follower's stream once it has caught up.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from binding `addr` or reading the log directory.
+    pub fn spawn(log: &Log, addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let source = Source {
+            reader: log.reader()?,
+            durable: log.durable_watermark(),
+        };
+        let (stop, stopped) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let stopping = Arc::new(AtomicBool::new(false));
+            // Each connection's socket, to unblock its thread on stop.
+            let mut connections: Vec<(TcpStream, JoinHandle<()>)> = Vec::new();
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        connections.retain(|(_, c)| !c.is_finished());
+                        let Ok(socket) = stream.try_clone() else {
+                            continue;
+                        };
+                        let source = source.clone();
+                        let stopping = Arc::clone(&stopping);
+                        let closing = socket.try_clone();
+                        let thread = std::thread::spawn(move || {
+                            // Errors were sent to the follower, if it is still there.
+                            let _ = serve(stream, source, &stopping);
+                            // `socket` keeps the connection open; end it here.
+                            if let Ok(closing) = closing {
+                                let _ = closing.shutdown(Shutdown::Both);
+                            }
+                        });
+                        connections.push((socket, thread));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        // Disconnection (the server was dropped) or an explicit stop ends the loop.
+                        if stopped.recv_timeout(IDLE_CHECK) != Err(RecvTimeoutError::Timeout) {
+                            break;
+                        }
+                    }
+                    Err(_) => std::thread::sleep(IDLE_CHECK),
+                }
+            }
+            stopping.store(true, Ordering::Relaxed);
+            for (socket, connection) in connections {
+                // Wakes a thread blocked reading from or writing to its follower.
+                let _ = socket.shutdown(Shutdown::Both);
+                let _ = connection.join();
+            }
+        });
+        Ok(Self {
+            local_addr,
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    #[must_use]
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting followers, closes every connection and waits for the
+    /// server's threads to exit. Connections are shut down rather than drained,
+    /// so a follower that stopped reading does not hold the server up.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ReplicationServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// What each connection needs to follow the leader log.
+#[derive(Debug, Clone)]
+struct Source {
+    reader: LogReader,
+    durable: Arc<Watermark>,
+}
+
+/// Serves one follower: reads its subscription, then streams durable records
+/// until the leader log is dropped, the server stops or the follower goes away.
+fn serve(stream: TcpStream, mut source: Source, stopping: &AtomicBool) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    // A peer that connects and never subscribes must not pin the thread.
+    stream.set_read_timeout(Some(SUBSCRIBE_TIMEOUT))?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    let offset = match read_subscribe(&mut input) {
+        Ok(offset) => offset,
+        Err(e) => return send_error(&mut out, e),
+    };
+    source.reader.refresh()?;
+    if offset < source.reader.first_offset() {
+        let e = Error::OffsetOutOfRange {
+            requested: offset,
+            earliest: source.reader.first_offset(),
+            latest: None,
+        };
+        return send_error(&mut out, e);
+    }
+    event!(debug, offset, "replication follower subscribed");
+    let mut tail = Tail::new(source.reader, source.durable, offset);
+    loop {
+        let next = match tail.next_timeout(Duration::ZERO) {
+            Ok(None) => {
+                // Caught up: send what is buffered, then wait for more.
+                out.flush()?;
+                tail.next_timeout(IDLE_CHECK)
+            }
+            next => next,
+        };
+        match next {
+            Ok(Some(record)) => match encode_wire(&record) {
+                Ok(frame) => write_message(&mut out, MSG_RECORD, &frame)?,
+                Err(e) => return send_error(&mut out, e),
+            },
+            Ok(None) if stopping.load(Ordering::Relaxed) => return Ok(out.flush()?),
+            Ok(None) => {}
+            // The leader log was dropped and every durable record has been sent.
+            Err(Error::Closed(_)) => return Ok(out.flush()?),
+            Err(e) => return send_error(&mut out, e),
+        }
+    }
+}
+
+/// Reports `e` to the follower before the connection closes.
+fn send_error(out: &mut impl Write, e: Error) -> Result<()> {
+    write_message(out, MSG_ERROR, e.to_string().as_bytes())?;
+    out.flush()?;
+    Err(e)
+}
+
+/// Encodes `record` as the frame sent to followers.
+fn encode_wire(record: &Record) -> Result<Vec<u8>> {
+    let frame = encode_wire_unchecked(record)?;
+    if frame.len() >= MAX_MESSAGE_LEN {
+        return Err(Error::Replication(format!(
+            "record {} is too large to replicate ({} bytes)",
+            record.offset,
+            frame.len()
+        )));
+    }
+    Ok(frame)
+}
+
+fn encode_wire_unchecked(record: &Record) -> Result<Vec<u8>> {
+    if let Some(marker) = record.marker {
+        let header = RecordHeader::new(record.offset, 0, 0);
+        return encode_frame_as(header, FLAG_CONTROL, &[], None, &marker.encode());
+    }
+    let key = record.key.as_deref();
+    if record.timestamp.is_none() && record.headers().is_empty() {
+        return encode_frame(record.offset, FLAGS_NONE, key, &record.payload);
+    }
+    let headers: Vec<(&str, &[u8])> = record
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_slice()))
+        .collect();
+    encode_frame_v2(
+        record.offset,
+        record.timestamp.unwrap_or(0),
+        FLAGS_NONE,
+        &headers,
+        key,
+        &record.payload,
+    )
+}
+
+fn read_subscribe(input: &mut impl Read) -> Result<u64> {
+    let body = read_message(input, SUBSCRIBE_LEN)?
+        .ok_or_else(|| Error::Replication("connection closed before subscribing".into()))?;
+    match body.as_slice() {
+        [MSG_SUBSCRIBE, magic @ .., version, o0, o1, o2, o3, o4, o5, o6, o7]
+            if magic == SUBSCRIBE_MAGIC =>
+        {
+            if *version != PROTOCOL_VERSION {
+                return Err(Error::Replication(format!(
+                    "unsupported protocol version {version}"
+                )));
+            }
+            Ok(u64::from_le_bytes([*o0, *o1, *o2, *o3, *o4, *o5, *o6, *o7]))
+        }
+        _ => Err(Error::Replication("malformed subscribe message".into())),
+    }
+}
+
+/// Writes a message of type `kind` with `body`.
+fn write_message(out: &mut impl Write, kind: u8, body: &[u8]) -> Result<()> {
+    let len = u32::try_from(body.len() + 1)
+        .map_err(|_| Error::InvalidFormat("replication message too large".into()))?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&[kind])?;
+    out.write_all(body)?;
+    Ok(())
+}
+
+/// Reads one message body (type byte included) of at most `max_len` bytes, or
+/// `None` if the peer closed the connection between messages.
+fn read_message(input: &mut impl Read, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 {
+        return Err(Error::Replication("empty message".into()));
+    }
+    if len > max_len {
+        return Err(Error::Replication(format!(
+            "message of {len} bytes exceeds the limit of {max_len}"
+        )));
+    }
+    // Grows with the bytes received, not with the length the peer claims.
+    let mut body = Vec::new();
+    input.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(Some(body))
+}
+
+/// A follower's connection to a [`ReplicationServer`]: an iterator over the
+/// leader's records from the subscribed offset, ending when the leader closes
+/// the stream.
+#[derive(Debug)]
+pub struct ReplicationClient {
+    input: BufReader<TcpStream>,
+    done: bool,
+}
+
+impl ReplicationClient {
+    /// Connects to the leader at `leader` and subscribes from `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from connecting or sending the subscription.
+    pub fn connect(leader: impl ToSocketAddrs, offset: u64) -> Result<Self> {
+        let mut stream = TcpStream::connect(leader)?;
+        stream.set_nodelay(true)?;
+        let mut body = Vec::with_capacity(13);
+        body.extend_from_slice(SUBSCRIBE_MAGIC);
+        body.push(PROTOCOL_VERSION);
+        body.extend_from_slice(&offset.to_le_bytes());
+        write_message(&mut stream, MSG_SUBSCRIBE, &body)?;
+        Ok(Self {
+            input: BufReader::new(stream),
+            done: false,
+        })
+    }
+
+    /// Reads the next record frame, or `None` if the leader closed the stream.
+    fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(mut body) = read_message(&mut self.input, MAX_MESSAGE_LEN)? else {
+            return Ok(None);
+        };
+        match body[0] {
+            MSG_RECORD => {
+                body.remove(0);
+                Ok(Some(body))
+            }
+            MSG_ERROR => Err(Error::Replication(format!(
+                "leader reported: {}",
+                String::from_utf8_lossy(&body[1..])
+            ))),
+            _ => Err(Error::Replication("unexpected message type".into())),
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let Some(frame) = self.read_frame()? else {
+            return Ok(None);
+        };
+        let (header, body) = decode_record_verified(&frame)?;
+        Record::from_body(&header, body.to_vec(), None).map(Some)
+    }
+}
+
+impl Iterator for ReplicationClient {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// Appends the records of the leader at `leader` to `log` until the leader
+/// closes the stream, and returns how many were appended.
+///
+/// Replication starts at [`Log::next_offset`], and `log` is flushed before
+/// returning.
+///
+/// `log` must use [`RecordFormat::V2`](crate::RecordFormat::V2) to replicate
+/// records that carry timestamps or headers. Each record keeps the leader's
+/// offset, timestamp, headers and key.
+///
+/// # Errors
+///
+/// - [`Error::Replication`] if the leader reports an error (for example, the
+///   requested offset is below its log start).
+/// - Errors from [`ReplicationClient::connect`] and from
+///   [`Log::append_replicated`], which rejects gaps and corrupt frames.
+pub fn replicate(log: &mut Log, leader: impl ToSocketAddrs) -> Result<u64> {
+    let mut appended = 0;
+    let result = ReplicationClient::connect(leader, log.next_offset()).and_then(|mut client| {
+        while let Some(frame) = client.read_frame()? {
+            let (header, body) = decode_record(&frame)?;
+            log.append_replicated(&header, body)?;
+            appended += header.record_count();
+        }
+        Ok(())
+    });
+    log.flush()?;
+    result.map(|()| appended)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, RecordFormat};
+
+    fn v2() -> Config {
+        Config {
+            format: RecordFormat::V2,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn follower_receives_existing_then_durable_records() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let mut leader = Log::open(leader_dir.path(), v2()).unwrap();
+        leader.append(b"zero").unwrap();
+        leader.append_keyed(b"k", b"one").unwrap();
+        leader
+            .append_with_headers(b"two", &[("trace", b"abc")])
+            .unwrap();
+        leader.flush().unwrap();
+        let server = ReplicationServer::spawn(&leader, "127.0.0.1:0").unwrap();
+
+        let follower_dir = tempfile::tempdir().unwrap();
+        let mut follower = Log::open(follower_dir.path(), v2()).unwrap();
+        let addr = server.local_addr();
+        let replicator = std::thread::spawn(move || {
+            let appended = replicate(&mut follower, addr).unwrap();
+            (appended, follower)
+        });
+
+        leader.append(b"three").unwrap();
+        // Not durable yet, so not sent until the flush.
+        std::thread::sleep(Duration::from_millis(20));
+        leader.flush().unwrap();
+        let timestamp = leader.read_record(2).unwrap().timestamp;
+        drop(leader);
+
+        let (appended, mut follower) = replicator.join().unwrap();
+        assert_eq!(appended, 4);
+        assert_eq!(follower.read(3).unwrap(), b"three");
+        let record = follower.read_record(1).unwrap();
+        assert_eq!(record.key.as_deref(), Some(&b"k"[..]));
+        let record = follower.read_record(2).unwrap();
+        assert_eq!(record.header("trace"), Some(&b"abc"[..]));
+        assert_eq!(record.timestamp, timestamp);
+        server.stop();
+    }
+
+    #[test]
+    fn leader_rejects_offsets_before_its_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"a").unwrap();
+        log.append(b"b").unwrap();
+        log.delete_before(1).unwrap();
+        log.flush().unwrap();
+        let server = ReplicationServer::spawn(&log, "127.0.0.1:0").unwrap();
+
+        let mut client = ReplicationClient::connect(server.local_addr(), 0).unwrap();
+        assert!(matches!(client.next(), Some(Err(Error::Replication(_)))));
+        assert!(client.next().is_none());
+
+        let mut client = ReplicationClient::connect(server.local_addr(), 1).unwrap();
+        assert_eq!(client.next().unwrap().unwrap().payload, b"b");
+        drop(log);
+        assert!(client.next().is_none());
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected_before_reading_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Log::open(dir.path(), Config::default()).unwrap();
+        let server = ReplicationServer::spawn(&log, "127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        let reply = read_message(&mut stream, MAX_MESSAGE_LEN).unwrap().unwrap();
+        assert_eq!(reply[0], MSG_ERROR);
+
+        let mut input: &[u8] = &[0xff, 0xff, 0xff, 0x7f, MSG_RECORD];
+        assert!(matches!(
+            read_message(&mut input, MAX_MESSAGE_LEN),
+            Err(Error::Replication(_))
+        ));
+    }
+
+    #[test]
+    fn stop_does_not_wait_for_idle_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Log::open(dir.path(), Config::default()).unwrap();
+        let server = ReplicationServer::spawn(&log, "127.0.0.1:0").unwrap();
+        // Connects but never subscribes.
+        let _idle = TcpStream::connect(server.local_addr()).unwrap();
+        let mut client = ReplicationClient::connect(server.local_addr(), 0).unwrap();
+        std::thread::sleep(IDLE_CHECK);
+
+        let (stopped, done) = mpsc::channel();
+        std::thread::spawn(move || {
+            server.stop();
+            stopped.send(()).unwrap();
+        });
+        done.recv_timeout(Duration::from_secs(5))
+            .expect("stop waited on an idle connection");
+        assert!(client.next().is_none());
+    }
+}
@@ -0,0 +1,219 @@
+//! Two-phase appends: reserve offsets first, fill them in later.
+//!
+//! [`Log::reserve`] hands out the next `n` offsets without writing anything, so
+//! a producer can use them (as dedup keys, or in references stored elsewhere)
+//! before its payloads are even serialized. [`Log::commit_reserved`] later fills
+//! a reservation with exactly that many payloads. Records are written in offset
+//! order: a committed reservation is held in memory until every reservation
+//! before it has been committed too, and is then written like an
+//! [`append_batch`](Log::append_batch), atomically. `commit_reserved` returns
+//! the reservations it wrote; one it does not return is only held in memory.
+//!
+//! While any reservation is outstanding, other appends fail, since the next
+//! offset is taken. Reserved offsets exist only in memory: closing or crashing
+//! with reservations outstanding, including committed ones waiting behind an
+//! unfilled one, leaves them absent, and the log continues from its last
+//! written record on the next open, handing those offsets out again. A
+//! producer that uses reserved offsets as dedup keys or references must treat
+//! them as stable only once `commit_reserved` has reported them written.
+//! [`Log::abandon_reserved`] releases outstanding reservations without
+//! reopening; [`Log::truncate_after`] drops them with the records it removes.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+/// The offsets reserved by a log and not yet written.
+#[derive(Debug, Default)]
+pub(crate) struct Reservations {
+    /// Reservations by first offset. They are contiguous, the first starting at
+    /// the log's next offset.
+    ranges: BTreeMap<u64, Reservation>,
+}
+
+#[derive(Debug)]
+struct Reservation {
+    /// The offset after the reservation's last.
+    end: u64,
+    /// The payloads, once committed.
+    payloads: Option<Vec<Vec<u8>>>,
+}
+
+impl Reservations {
+    /// Returns the first reserved offset, if any.
+    pub(crate) fn first(&self) -> Option<u64> {
+        self.ranges.keys().next().copied()
+    }
+
+    /// Drops every reservation.
+    pub(crate) fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// Removes the first reservation if it starts at `next` and is committed,
+    /// returning its payloads.
+    fn take_ready(&mut self, next: u64) -> Option<Vec<Vec<u8>>> {
+        let entry = self.ranges.first_entry()?;
+        if *entry.key() != next || entry.get().payloads.is_none() {
+            return None;
+        }
+        entry.remove().payloads
+    }
+}
+
+impl Log {
+    /// Reserves the next `n` offsets and returns them; see the
+    /// [module docs](self). They are written once filled by
+    /// [`commit_reserved`](Self::commit_reserved) and every earlier reservation
+    /// has been committed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidFormat`] if `n` is 0 or the offsets would pass
+    /// `u64::MAX`.
+    pub fn reserve(&mut self, n: u64) -> Result<RangeInclusive<u64>> {
+        if n == 0 {
+            return Err(Error::InvalidFormat(
+                "reserve needs at least one offset".into(),
+            ));
+        }
+        let next = self.next_offset();
+        let reservations = self.reservations();
+        let first = reservations
+            .ranges
+            .last_key_value()
+            .map_or(next, |(_, last)| last.end);
+        let end = first.checked_add(n).ok_or_else(|| {
+            Error::InvalidFormat(format!("cannot reserve {n} offsets from {first}"))
+        })?;
+        reservations.ranges.insert(
+            first,
+            Reservation {
+                end,
+                payloads: None,
+            },
+        );
+        Ok(first..=end - 1)
+    }
+
+    /// Fills the reservation `range`, as returned by [`reserve`](Self::reserve),
+    /// with one payload per offset, and writes it along with any committed
+    /// reservations after it once no earlier one is left unfilled.
+    ///
+    /// Returns the reservations written by this call, in offset order: empty
+    /// if `range` waits behind an unfilled reservation, in which case its
+    /// payloads are only held in memory and are lost on a crash or close.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `range` is not an outstanding reservation,
+    ///   is already committed, or does not hold one offset per payload.
+    /// - Errors from [`append_batch`](Self::append_batch) writing the records.
+    ///   A failed write abandons every outstanding reservation, as
+    ///   [`abandon_reserved`](Self::abandon_reserved) does.
+    pub fn commit_reserved(
+        &mut self,
+        range: RangeInclusive<u64>,
+        payloads: &[&[u8]],
+    ) -> Result<Vec<RangeInclusive<u64>>> {
+        let reservation = self
+            .reservations()
+            .ranges
+            .get_mut(range.start())
+            .filter(|r| r.end == range.end() + 1 && r.payloads.is_none())
+            .ok_or_else(|| {
+                Error::InvalidFormat(format!(
+                    "offsets {}..={} are not an uncommitted reservation",
+                    range.start(),
+                    range.end()
+                ))
+            })?;
+        if payloads.len() as u64 != reservation.end - range.start() {
+            return Err(Error::InvalidFormat(format!(
+                "{} payloads for the {} offsets {}..={}",
+                payloads.len(),
+                reservation.end - range.start(),
+                range.start(),
+                range.end()
+            )));
+        }
+        reservation.payloads = Some(payloads.iter().map(|p| p.to_vec()).collect());
+
+        // Appends refuse to run while offsets are reserved, so set them aside.
+        let mut reservations = std::mem::take(self.reservations());
+        let mut written = Vec::new();
+        while let Some(payloads) = reservations.take_ready(self.next_offset()) {
+            let payloads: Vec<&[u8]> = payloads.iter().map(Vec::as_slice).collect();
+            // On failure the rest are dropped with `reservations`, since they
+            // may no longer start at the next offset.
+            written.push(self.append_batch(&payloads)?);
+        }
+        *self.reservations() = reservations;
+        Ok(written)
+    }
+
+    /// Drops every outstanding reservation, committed or not, and returns how
+    /// many there were. Their offsets are handed out again by later appends.
+    pub fn abandon_reserved(&mut self) -> usize {
+        let reservations = std::mem::take(self.reservations());
+        reservations.ranges.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Error, Log};
+
+    #[test]
+    fn reservations_are_written_in_offset_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"first").unwrap();
+        let a = log.reserve(2).unwrap();
+        let b = log.reserve(1).unwrap();
+        assert_eq!((a.clone(), b.clone()), (1..=2, 3..=3));
+
+        // "b" waits for "a", and nothing else may take the next offset meanwhile.
+        assert!(log.commit_reserved(b.clone(), &[b"b"]).unwrap().is_empty());
+        assert_eq!(log.next_offset(), 1);
+        assert!(matches!(log.append(b"x"), Err(Error::InvalidFormat(_))));
+        assert!(log.commit_reserved(b, &[b"b"]).is_err());
+        assert!(log.commit_reserved(a.clone(), &[b"a"]).is_err());
+
+        assert_eq!(
+            log.commit_reserved(a, &[b"a0", b"a1"]).unwrap(),
+            [1..=2, 3..=3]
+        );
+        assert_eq!(log.next_offset(), 4);
+        assert_eq!(log.read(2).unwrap(), b"a1");
+        assert_eq!(log.read(3).unwrap(), b"b");
+        assert_eq!(log.append(b"next").unwrap(), 4);
+    }
+
+    #[test]
+    fn unfilled_reservations_are_absent_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"first").unwrap();
+        let unfilled = log.reserve(1).unwrap();
+        let filled = log.reserve(1).unwrap();
+        assert!(log
+            .commit_reserved(filled, &[b"waiting"])
+            .unwrap()
+            .is_empty());
+        drop(log);
+
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.next_offset(), 1);
+        assert!(log.commit_reserved(unfilled, &[b"late"]).is_err());
+        log.reserve(3).unwrap();
+        assert!(matches!(
+            log.reserve(u64::MAX),
+            Err(Error::InvalidFormat(_))
+        ));
+        assert_eq!(log.abandon_reserved(), 1);
+        assert_eq!(log.append(b"second").unwrap(), 1);
+    }
+}
@@ -0,0 +1,111 @@
+//! Retention: deleting old sealed segments by age and total size.
+//!
+//! Retention only ever removes whole *sealed* segments, oldest first; the active
+//! segment is never deleted. Enforcement runs on demand via
+//! [`Log::enforce_retention`] or periodically via [`RetentionTask`].
+
+use crate::log::Log;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Limits beyond which sealed segments are deleted. `None` disables a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RetentionPolicy {
+    /// Delete a sealed segment once its last write is older than this.
+    pub max_age: Option<Duration>,
+    /// Delete the oldest sealed segments while the log's on-disk size (segment and
+    /// index files) exceeds this many bytes.
+    pub max_total_bytes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Returns true if the policy never deletes anything.
+    #[must_use]
+    pub const fn is_unbounded(&self) -> bool {
+        self.max_age.is_none() && self.max_total_bytes.is_none()
+    }
+}
+
+/// A hard cap on the size of a log's segment and index files; see
+/// [`Config::quota`](crate::Config::quota).
+///
+/// Appends that would take the log past `max_bytes` do what `on_exceeded`
+/// says. Transaction markers are exempt, so a transaction can always be
+/// aborted. Other files in the log directory are small and not counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiskQuota {
+    /// Bytes the segment and index files may take, counted like
+    /// [`RetentionPolicy::max_total_bytes`].
+    pub max_bytes: u64,
+    /// What an append that does not fit does.
+    pub on_exceeded: QuotaAction,
+}
+
+/// What an append that would exceed a [`DiskQuota`] does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QuotaAction {
+    /// Fails with [`Error::QuotaExceeded`](crate::Error::QuotaExceeded).
+    #[default]
+    Fail,
+    /// Deletes the segments the retention policy would, then the oldest sealed
+    /// segments until the append fits, and fails as [`Fail`](Self::Fail) does
+    /// only if deleting every sealed segment is not enough.
+    EnforceRetention,
+}
+
+/// Background thread that periodically calls [`Log::enforce_retention`].
+///
+/// The thread stops when the task is dropped or [`stop`](Self::stop) is called.
+#[derive(Debug)]
+pub struct RetentionTask {
+    /// Dropping the sender wakes the thread and tells it to exit.
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RetentionTask {
+    /// Spawns a thread that enforces the log's retention policy every `interval`.
+    ///
+    /// Errors from enforcement are ignored; the next tick retries.
+    #[must_use]
+    pub fn spawn(log: Arc<Mutex<Log>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            // Disconnection (the task was dropped) or an explicit stop ends the loop.
+            while stopped.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                let _ = log
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .enforce_retention();
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops the background thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for RetentionTask {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -1,10 +1,22 @@
-//! Segment naming and discovery.
+//! Segment naming, discovery and sealing footers.
 //!
 //! Segments are named `segment_{base_offset}.log` with zero-padded `base_offset`
 //! so that lexicographic order matches numeric order.
+//!
+//! When the log rolls past a segment it appends a [`SegmentFooter`] after the
+//! last record, summarising the segment so that discovery can answer range and
+//! age questions without scanning it.
 
+use crate::error::Error;
+use crate::reader::{decode_index_entry, read_header};
+use crate::record::INDEX_ENTRY_LEN;
+use crate::storage::{self, Backend, FileCursor, OpenMode};
+use crate::trace::event;
 use crate::Result;
+use std::collections::BTreeSet;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Filename prefix for segment data files.
 const SEGMENT_LOG_PREFIX: &str = "segment_";
@@ -13,6 +25,17 @@ const SEGMENT_LOG_SUFFIX: &str = ".log";
 /// Base offset is zero-padded to this width for sortable filenames.
 const BASE_OFFSET_WIDTH: u32 = 20;
 
+/// Magic number at the start of a segment footer (`DLFT` as a little-endian u32).
+pub const FOOTER_MAGIC: u32 = 0x5446_4C44;
+/// Footer format version.
+const FOOTER_VERSION: u8 = 1;
+/// Size in bytes of an encoded segment footer.
+pub const FOOTER_LEN: usize = 56;
+/// Footer flag: `first_timestamp` is known.
+const FOOTER_FIRST_TIMESTAMP: u8 = 0x01;
+/// Footer flag: `last_timestamp` is known.
+const FOOTER_LAST_TIMESTAMP: u8 = 0x02;
+
 /// Identifies a segment by its base offset (first record offset in the segment).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SegmentId(pub u64);
@@ -46,28 +69,370 @@ pub struct SegmentInfo {
     pub base_offset: u64,
     /// Full path to the segment's .log file.
     pub log_path: PathBuf,
+    /// Summary written when the segment was sealed; `None` for the active segment
+    /// and for segments sealed before footers existed.
+    pub footer: Option<SegmentFooter>,
+    /// Backend holding the segment's files.
+    pub(crate) storage: Arc<dyn Backend>,
+}
+
+/// Summary appended to a segment when it is sealed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFooter {
+    /// Number of records in the segment.
+    pub record_count: u64,
+    /// Offset of the first record (the segment's base offset).
+    pub first_offset: u64,
+    /// Offset of the last record.
+    pub last_offset: u64,
+    /// Append time of the first record in milliseconds since the Unix epoch, if known.
+    pub first_timestamp: Option<u64>,
+    /// Append time of the last record in milliseconds since the Unix epoch, if known.
+    pub last_timestamp: Option<u64>,
+    /// CRC-32 of every segment byte before the footer.
+    pub segment_crc: u32,
+}
+
+impl SegmentFooter {
+    /// Encodes the footer; see `docs/file-format.md` for the layout.
+    #[must_use]
+    pub fn encode(&self) -> [u8; FOOTER_LEN] {
+        let mut buf = [0u8; FOOTER_LEN];
+        buf[0..4].copy_from_slice(&FOOTER_MAGIC.to_le_bytes());
+        buf[4] = FOOTER_VERSION;
+        let mut flags = 0;
+        if self.first_timestamp.is_some() {
+            flags |= FOOTER_FIRST_TIMESTAMP;
+        }
+        if self.last_timestamp.is_some() {
+            flags |= FOOTER_LAST_TIMESTAMP;
+        }
+        buf[5] = flags;
+        buf[8..16].copy_from_slice(&self.record_count.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.first_offset.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.last_offset.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.first_timestamp.unwrap_or(0).to_le_bytes());
+        buf[40..48].copy_from_slice(&self.last_timestamp.unwrap_or(0).to_le_bytes());
+        buf[48..52].copy_from_slice(&self.segment_crc.to_le_bytes());
+        let crc = crc32fast::hash(&buf[..FOOTER_LEN - 4]);
+        buf[FOOTER_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a footer, returning `None` unless `bytes` is exactly one footer
+    /// with a known version and a matching CRC.
+    #[must_use]
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; FOOTER_LEN] = bytes.try_into().ok()?;
+        let u64_at = |at: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_le_bytes(b)
+        };
+        let u32_at = |at: usize| {
+            let mut b = [0u8; 4];
+            b.copy_from_slice(&bytes[at..at + 4]);
+            u32::from_le_bytes(b)
+        };
+        if u32_at(0) != FOOTER_MAGIC
+            || bytes[4] != FOOTER_VERSION
+            || u32_at(FOOTER_LEN - 4) != crc32fast::hash(&bytes[..FOOTER_LEN - 4])
+        {
+            return None;
+        }
+        let flags = bytes[5];
+        Some(Self {
+            record_count: u64_at(8),
+            first_offset: u64_at(16),
+            last_offset: u64_at(24),
+            first_timestamp: (flags & FOOTER_FIRST_TIMESTAMP != 0).then(|| u64_at(32)),
+            last_timestamp: (flags & FOOTER_LAST_TIMESTAMP != 0).then(|| u64_at(40)),
+            segment_crc: u32_at(48),
+        })
+    }
+
+    /// Reads the footer at the end of the segment file at `path` in `storage`.
+    ///
+    /// A missing, torn or corrupt footer reads as `None`, exactly like a segment
+    /// that was never sealed; the records themselves are still readable.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from opening or reading the file.
+    pub fn read(storage: &dyn Backend, path: &Path) -> Result<Option<Self>> {
+        let mut file = FileCursor::open(storage, path, OpenMode::Read)?;
+        let len = file.size()?;
+        if len < FOOTER_LEN as u64 {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Start(len - FOOTER_LEN as u64))?;
+        let mut buf = [0u8; FOOTER_LEN];
+        file.read_exact(&mut buf)?;
+        Ok(Self::decode(&buf))
+    }
+}
+
+/// Returns true if `bytes`, found where the next record header would start,
+/// begin a segment footer rather than a record.
+pub(crate) fn is_footer(bytes: &[u8]) -> bool {
+    bytes.get(..4) == Some(&FOOTER_MAGIC.to_le_bytes()[..])
+}
+
+/// Appends `footer` to a segment's `.log` file and fsyncs it.
+pub(crate) fn write_footer(file: &mut FileCursor, footer: &SegmentFooter) -> Result<()> {
+    file.write_all(&footer.encode())?;
+    file.fsync()?;
+    Ok(())
+}
+
+/// Computes the CRC-32 of the first `len` bytes of `file`.
+pub(crate) fn hash_prefix(file: &mut FileCursor, len: u64) -> Result<u32> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut reader = file.take(len);
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+impl SegmentInfo {
+    /// Path to the segment's `.idx` file.
+    #[must_use]
+    pub fn index_path(&self) -> PathBuf {
+        self.log_path.with_extension("idx")
+    }
+
+    /// Opens `path`, one of the segment's files, in the segment's backend.
+    pub(crate) fn open_file(&self, path: &Path, mode: OpenMode) -> std::io::Result<FileCursor> {
+        FileCursor::open(&*self.storage, path, mode)
+    }
+
+    /// Combined size in bytes of the segment's `.log` and `.idx` files.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading file metadata (a missing index counts as 0).
+    pub fn disk_bytes(&self) -> Result<u64> {
+        let log_len = self.open_file(&self.log_path, OpenMode::Read)?.size()?;
+        let idx_len = match self.open_file(&self.index_path(), OpenMode::Read) {
+            Ok(f) => f.size()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(log_len + idx_len)
+    }
+
+    /// Adds the format version of every frame in the segment to `versions`,
+    /// reading the headers and seeking over the bodies.
+    ///
+    /// Scanning stops at the footer, the end of the file, or the first torn or
+    /// invalid frame. A version this build cannot decode is added too, but ends
+    /// the scan, since the frame's length is unknown.
+    pub(crate) fn frame_versions(&self, versions: &mut BTreeSet<u8>) -> Result<()> {
+        let mut reader = BufReader::new(self.open_file(&self.log_path, OpenMode::Read)?);
+        loop {
+            match read_header(&mut reader) {
+                Ok(Some(header)) => {
+                    versions.insert(header.version);
+                    reader.seek_relative(i64::from(header.payload_len))?;
+                }
+                Err(Error::UnsupportedVersion(version)) => {
+                    versions.insert(version);
+                    return Ok(());
+                }
+                Ok(None) => return Ok(()),
+                Err(e) if e.is_corruption() => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Regenerates the segment's `.idx` file by scanning its records and atomically
+    /// replacing any existing index (write temp, fsync, rename). Returns the number
+    /// of entries written.
+    ///
+    /// Scanning stops at the footer, the end of the file, or the first torn or
+    /// invalid frame; records past that point are left out of the index.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the segment or writing the index.
+    pub fn rebuild_index(&self) -> Result<u64> {
+        let file = self.open_file(&self.log_path, OpenMode::Read)?;
+        let file_len = file.size()?;
+        let mut reader = BufReader::new(file);
+        let tmp_path = self.log_path.with_extension("idx.tmp");
+        let mut idx = BufWriter::new(self.open_file(&tmp_path, OpenMode::Replace)?);
+
+        let mut next_offset = self.base_offset;
+        let (mut pos, mut record_pos) = (0u64, 0u64);
+        let mut in_chunks = false;
+        loop {
+            let header = match read_header(&mut reader) {
+                Ok(Some(header)) if header.offset == next_offset => header,
+                Ok(_) => break,
+                Err(e) if e.is_corruption() => break,
+                Err(e) => return Err(e),
+            };
+            if !in_chunks {
+                record_pos = pos;
+            }
+            // A body that runs past the end of the file is a torn write.
+            let end = pos + header.encoded_len() as u64 + u64::from(header.payload_len);
+            if end > file_len {
+                break;
+            }
+            reader.seek_relative(i64::from(header.payload_len))?;
+            pos = end;
+            in_chunks = header.is_continued();
+            if !in_chunks {
+                for offset in next_offset..next_offset + header.record_count() {
+                    idx.write_all(&offset.to_le_bytes())?;
+                    idx.write_all(&record_pos.to_le_bytes())?;
+                }
+                next_offset += header.record_count();
+            }
+        }
+
+        let mut idx = idx
+            .into_inner()
+            .map_err(std::io::IntoInnerError::into_error)?;
+        idx.fsync()?;
+        drop(idx);
+        self.storage.rename(&tmp_path, &self.index_path())?;
+        self.storage.sync_dir(storage::parent_dir(&self.log_path))?;
+        event!(
+            info,
+            segment = self.base_offset,
+            entries = next_offset - self.base_offset,
+            "segment index rebuilt"
+        );
+        Ok(next_offset - self.base_offset)
+    }
+
+    /// Cheaply checks that the `.idx` file plausibly indexes the first `data_len`
+    /// bytes of the segment: it exists, holds whole entries (`records` of them,
+    /// if known), and its first and last entries point at the segment's first
+    /// record and at the run of frames that ends the data.
+    pub(crate) fn index_matches(&self, data_len: u64, records: Option<u64>) -> Result<bool> {
+        let mut idx = match self.open_file(&self.index_path(), OpenMode::Read) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let idx_len = idx.size()?;
+        let entries = idx_len / INDEX_ENTRY_LEN as u64;
+        if idx_len % INDEX_ENTRY_LEN as u64 != 0 || records.is_some_and(|n| n != entries) {
+            return Ok(false);
+        }
+        if entries == 0 {
+            return Ok(data_len == 0);
+        }
+        let mut entry = [0u8; INDEX_ENTRY_LEN];
+        idx.read_exact(&mut entry)?;
+        if decode_index_entry(&entry) != (self.base_offset, 0) {
+            return Ok(false);
+        }
+        idx.seek(SeekFrom::Start(idx_len - INDEX_ENTRY_LEN as u64))?;
+        idx.read_exact(&mut entry)?;
+        let (last, mut pos) = decode_index_entry(&entry);
+        if last != self.base_offset + entries - 1 {
+            return Ok(false);
+        }
+
+        // The frames at the last entry must hold `last` and end exactly at `data_len`.
+        let mut file = self.open_file(&self.log_path, OpenMode::Read)?;
+        loop {
+            file.seek(SeekFrom::Start(pos))?;
+            let header = match read_header(&mut file) {
+                Ok(Some(header)) => header,
+                Ok(None) => return Ok(false),
+                Err(e) if e.is_corruption() => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            if last < header.offset || last - header.offset >= header.record_count() {
+                return Ok(false);
+            }
+            pos += header.encoded_len() as u64 + u64::from(header.payload_len);
+            if !header.is_continued() {
+                return Ok(pos == data_len);
+            }
+        }
+    }
+
+    /// Checks the segment's bytes against the CRC in its footer. Segments without
+    /// a footer have nothing to check against and pass.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Corruption`] if the segment no longer matches its footer.
+    /// - I/O errors from reading the segment file.
+    pub fn verify_footer(&self) -> Result<()> {
+        let Some(footer) = self.footer else {
+            return Ok(());
+        };
+        let mut file = self.open_file(&self.log_path, OpenMode::Read)?;
+        let data_len = file.size()?.saturating_sub(FOOTER_LEN as u64);
+        let actual = hash_prefix(&mut file, data_len)?;
+        if actual != footer.segment_crc {
+            return Err(Error::Corruption(format!(
+                "segment {} does not match its footer: CRC 0x{actual:08X}, expected 0x{:08X}",
+                self.base_offset, footer.segment_crc
+            )));
+        }
+        Ok(())
+    }
 }
 
-/// Discovers all segment log files in `dir`, sorted by base offset ascending.
+/// Deletes a segment's `.log`, `.idx`, `.key` and `.bloom` files, durably.
+/// Missing index, key and filter files are not an error.
+pub(crate) fn remove_segment_files(info: &SegmentInfo) -> Result<()> {
+    info.storage.delete(&info.log_path)?;
+    for path in [
+        info.index_path(),
+        crate::encryption::key_path(info),
+        crate::key_filter::path(info),
+    ] {
+        storage::delete_if_exists(&*info.storage, &path)?;
+    }
+    info.storage.sync_dir(storage::parent_dir(&info.log_path))?;
+    Ok(())
+}
+
+/// Discovers all segment log files in `dir`, sorted by base offset ascending,
+/// reading each segment's footer if it has one.
 ///
 /// # Errors
 ///
-/// Returns I/O errors from reading the directory.
+/// Returns I/O errors from reading the directory or segment files.
 pub fn discover_segments(dir: &Path) -> Result<Vec<SegmentInfo>> {
+    discover_segments_in(&storage::default_backend(), dir)
+}
+
+/// Like [`discover_segments`], for a log whose files are in `storage`.
+///
+/// # Errors
+///
+/// Same as [`discover_segments`].
+pub fn discover_segments_in(storage: &Arc<dyn Backend>, dir: &Path) -> Result<Vec<SegmentInfo>> {
     let mut segments = Vec::new();
-    let entries = std::fs::read_dir(dir).map_err(crate::Error::from)?;
-    for entry in entries {
-        let entry = entry.map_err(crate::Error::from)?;
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                if let Some(id) = SegmentId::from_log_filename(name) {
-                    segments.push(SegmentInfo {
-                        base_offset: id.0,
-                        log_path: path,
-                    });
-                }
-            }
+    for path in storage.list(dir)? {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if let Some(id) = SegmentId::from_log_filename(name) {
+            segments.push(SegmentInfo {
+                base_offset: id.0,
+                footer: SegmentFooter::read(&**storage, &path)?,
+                log_path: path,
+                storage: Arc::clone(storage),
+            });
         }
     }
     segments.sort_by_key(|s| s.base_offset);
@@ -97,6 +462,55 @@ mod tests {
         assert!(b < c);
     }
 
+    #[test]
+    fn footer_roundtrip() {
+        let footer = SegmentFooter {
+            record_count: 3,
+            first_offset: 10,
+            last_offset: 12,
+            first_timestamp: None,
+            last_timestamp: Some(1_700_000_000_000),
+            segment_crc: 0xDEAD_BEEF,
+        };
+        let mut bytes = footer.encode();
+        assert!(is_footer(&bytes));
+        assert_eq!(SegmentFooter::decode(&bytes), Some(footer));
+
+        bytes[20] ^= 0xFF;
+        assert_eq!(SegmentFooter::decode(&bytes), None);
+        assert_eq!(SegmentFooter::decode(&bytes[..FOOTER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn rebuilt_index_matches_written_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = crate::Log::open(dir.path(), crate::Config::default()).unwrap();
+        log.append(b"a").unwrap();
+        log.append_batch_frame(&[b"b", b"c"]).unwrap();
+        log.append(b"d").unwrap();
+        log.flush().unwrap();
+        let info = discover_segments(dir.path()).unwrap().remove(0);
+        let data_len = std::fs::metadata(&info.log_path).unwrap().len();
+        let written = std::fs::read(info.index_path()).unwrap();
+        assert!(info.index_matches(data_len, Some(4)).unwrap());
+        assert!(!info.index_matches(data_len, Some(3)).unwrap());
+
+        std::fs::remove_file(info.index_path()).unwrap();
+        assert!(!info.index_matches(data_len, None).unwrap());
+        assert_eq!(info.rebuild_index().unwrap(), 4);
+        assert_eq!(std::fs::read(info.index_path()).unwrap(), written);
+
+        // A torn tail record is left out of the index.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&info.log_path)
+            .unwrap();
+        file.write_all(&crate::encode_record(4, b"torn tail").unwrap()[..30])
+            .unwrap();
+        assert_eq!(info.rebuild_index().unwrap(), 4);
+        assert_eq!(std::fs::read(info.index_path()).unwrap(), written);
+    }
+
     #[test]
     fn from_log_filename_rejects_invalid() {
         assert!(SegmentId::from_log_filename("other.log").is_none());
@@ -0,0 +1,198 @@
+//! Snapshots: state captured outside the log, replacing the records before it.
+//!
+//! The usual pairing of a log with a snapshot: an application periodically
+//! saves its state as of some offset, having applied every record below it, and
+//! calls [`Log::install_snapshot`] with that offset and whatever it needs to find
+//! the snapshot again, such as a file name or a Raft term. The log records both
+//! in a `snapshot.meta` file and then discards the records below the offset, as
+//! [`Log::delete_before`] does. To rebuild its state, the application loads the
+//! snapshot named by [`Log::snapshot`] (or [`LogReader::snapshot`]) and replays
+//! the records from its offset.
+//!
+//! A snapshot may lie past the end of the log, as when a lagging follower is
+//! sent one by its leader: every record is then discarded and the next append
+//! receives the snapshot's offset. Writing `snapshot.meta` is what installs a
+//! snapshot; if the log crashes before discarding the records, opening it
+//! finishes the job.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::reader::LogReader;
+use crate::storage::{self, Backend};
+use crate::trace::event;
+use crate::Result;
+use std::path::Path;
+
+/// Name of the file holding the installed snapshot's metadata.
+const SNAPSHOT_FILE_NAME: &str = "snapshot.meta";
+
+/// An installed snapshot: the offset it covers and its application metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Every record below this offset is captured by the snapshot; the log
+    /// starts here.
+    pub offset: u64,
+    /// Opaque bytes given to [`Log::install_snapshot`].
+    pub metadata: Vec<u8>,
+}
+
+/// Reads the snapshot metadata in `dir` of `storage`, or `None` if no snapshot
+/// was ever installed.
+pub(crate) fn read_snapshot(storage: &dyn Backend, dir: &Path) -> Result<Option<Snapshot>> {
+    let Some(bytes) = storage::read_file(storage, &dir.join(SNAPSHOT_FILE_NAME))? else {
+        return Ok(None);
+    };
+    if bytes.len() < 16 {
+        return Err(Error::Corruption(format!(
+            "{SNAPSHOT_FILE_NAME} is malformed"
+        )));
+    }
+    let (data, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(data).to_le_bytes() != crc {
+        return Err(Error::Corruption(format!(
+            "{SNAPSHOT_FILE_NAME} checksum mismatch"
+        )));
+    }
+    let offset = u64::from_le_bytes(data[..8].try_into().expect("8 bytes"));
+    let len = u32::from_le_bytes(data[8..12].try_into().expect("4 bytes"));
+    if usize::try_from(len).map_or(true, |len| len != data.len() - 12) {
+        return Err(Error::Corruption(format!(
+            "{SNAPSHOT_FILE_NAME} is malformed"
+        )));
+    }
+    Ok(Some(Snapshot {
+        offset,
+        metadata: data[12..].to_vec(),
+    }))
+}
+
+/// Atomically replaces the snapshot metadata in `dir` of `storage`.
+///
+/// The file holds the offset (u64), the metadata length (u32) and the metadata,
+/// followed by a CRC-32 of everything before it, all little-endian.
+fn write_snapshot(storage: &dyn Backend, dir: &Path, snapshot: &Snapshot) -> Result<()> {
+    let len = u32::try_from(snapshot.metadata.len())
+        .map_err(|_| Error::InvalidFormat("snapshot metadata is too large".into()))?;
+    let mut contents = snapshot.offset.to_le_bytes().to_vec();
+    contents.extend_from_slice(&len.to_le_bytes());
+    contents.extend_from_slice(&snapshot.metadata);
+    contents.extend_from_slice(&crc32fast::hash(&contents).to_le_bytes());
+    storage::write_atomic(storage, &dir.join(SNAPSHOT_FILE_NAME), &contents)?;
+    Ok(())
+}
+
+impl Log {
+    /// Records that the state as of `offset` is captured by a snapshot described
+    /// by `metadata`, and discards every record below `offset`; see the
+    /// [module docs](self).
+    ///
+    /// If `offset` is past the end of the log, every record is discarded and the
+    /// next append receives `offset`. Installing the snapshot already installed
+    /// again only updates its metadata.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if `offset` is below the installed snapshot's,
+    ///   or `metadata` exceeds `u32::MAX` bytes.
+    /// - I/O errors from writing the metadata or deleting segment files.
+    pub fn install_snapshot(&mut self, offset: u64, metadata: &[u8]) -> Result<()> {
+        if let Some(installed) = self.snapshot().filter(|s| offset < s.offset) {
+            return Err(Error::InvalidFormat(format!(
+                "snapshot at offset {offset} is older than the installed one at {}",
+                installed.offset
+            )));
+        }
+        let snapshot = Snapshot {
+            offset,
+            metadata: metadata.to_vec(),
+        };
+        write_snapshot(self.storage(), self.path(), &snapshot)?;
+        event!(info, offset, "snapshot installed");
+        self.set_snapshot(snapshot);
+        self.apply_snapshot()
+    }
+
+    /// Discards the records below the installed snapshot, if any.
+    pub(crate) fn apply_snapshot(&mut self) -> Result<()> {
+        let Some(offset) = self.snapshot().map(|s| s.offset) else {
+            return Ok(());
+        };
+        if offset > self.next_offset() {
+            self.restart_at(offset)
+        } else {
+            self.delete_before(offset).map(drop)
+        }
+    }
+}
+
+impl LogReader {
+    /// Returns the snapshot installed by [`Log::install_snapshot`], if any, as
+    /// recorded now.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Corruption`] if the metadata file is damaged.
+    /// - I/O errors from reading it.
+    pub fn snapshot(&self) -> Result<Option<Snapshot>> {
+        read_snapshot(&**self.storage(), self.path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn snapshot_replaces_the_prefix_and_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        log.install_snapshot(6, b"state-6").unwrap();
+        assert_eq!(log.first_offset(), 6);
+        assert!(log.read(5).is_err());
+        assert!(log.install_snapshot(4, b"older").is_err());
+        log.close().unwrap();
+
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        assert_eq!(log.snapshot().unwrap().metadata, b"state-6");
+        assert_eq!(log.first_offset(), 6);
+        let reader = LogReader::open(dir.path()).unwrap();
+        assert_eq!(reader.snapshot().unwrap().unwrap().offset, 6);
+        assert_eq!(reader.iter().count(), 4);
+
+        // A snapshot past the end replaces the whole log.
+        log.install_snapshot(25, b"state-25").unwrap();
+        assert_eq!(log.first_offset(), 25);
+        assert_eq!(log.last_offset(), None);
+        assert_eq!(log.append(b"next").unwrap(), 25);
+        drop(log);
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.read(25).unwrap(), b"next");
+        assert_eq!(LogReader::open(dir.path()).unwrap().iter().count(), 1);
+    }
+
+    #[test]
+    fn open_finishes_an_interrupted_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"first").unwrap();
+        log.close().unwrap();
+        // The metadata is written, but the crash came before the log was reset.
+        let snapshot = Snapshot {
+            offset: 10,
+            metadata: Vec::new(),
+        };
+        write_snapshot(&storage::FsBackend, dir.path(), &snapshot).unwrap();
+
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        assert_eq!(log.first_offset(), 10);
+        assert_eq!(log.append(b"after").unwrap(), 10);
+    }
+}
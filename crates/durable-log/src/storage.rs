@@ -0,0 +1,994 @@
+//! Pluggable storage: the file operations the log is built on.
+//!
+//! Every segment, index, key and metadata file the writer, readers and recovery
+//! touch goes through a [`Backend`], set by [`Config::storage`](crate::Config::storage)
+//! or [`LogReader::open_with_storage`](crate::LogReader::open_with_storage). The
+//! default, [`FsBackend`], is the local filesystem; [`MemoryBackend`] keeps files
+//! in memory, for tests and for logs that need not outlive the process.
+//! `DirectBackend` (`direct-io` feature) writes segments with direct I/O, and
+//! `UringBackend` (`io-uring` feature) batches writes and fsyncs through `io_uring`.
+//!
+//! Files are only ever appended to, read at a position, truncated and synced, so
+//! a backend needs no random-access writes, unless
+//! [`Config::preallocate`](crate::Config::preallocate) is set. Memory-mapped reads,
+//! `FollowReader` notifications, [`LogManager`](crate::LogManager)
+//! and [`ConsumerOffsets`](crate::ConsumerOffsets) work on the filesystem only.
+//!
+//! All of those, and [`FsBackend`] itself, need the default `fs` feature.
+//! Without it, logs and readers opened by path use a new [`MemoryBackend`],
+//! and the crate builds for `wasm32-unknown-unknown`.
+
+use crate::clock;
+#[cfg(feature = "fs")]
+use fs2::FileExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+#[cfg(feature = "fs")]
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
+
+/// How [`Backend::open`] opens a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Read only; the file must exist.
+    Read,
+    /// Read and append; the file must exist.
+    Write,
+    /// Read and append, creating the file if missing.
+    Create,
+    /// Read and append a new file; fails with [`ErrorKind::AlreadyExists`] if
+    /// there is one.
+    CreateNew,
+    /// Read and append, creating the file or truncating it to empty.
+    Replace,
+}
+
+impl OpenMode {
+    const fn creates(self) -> bool {
+        matches!(self, Self::Create | Self::CreateNew | Self::Replace)
+    }
+}
+
+/// A hint about how a range of a file will be read, for the OS page cache; see
+/// [`StorageFile::advise`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The range will be read once, front to back (`POSIX_FADV_SEQUENTIAL`).
+    Sequential,
+    /// The range will be read soon (`POSIX_FADV_WILLNEED`).
+    WillNeed,
+    /// The range will not be read again soon; its cached pages can be dropped
+    /// (`POSIX_FADV_DONTNEED`).
+    DontNeed,
+}
+
+/// A store of files addressed by path; see the [module docs](self).
+///
+/// Paths are those the log derives from its directory: `dir.join(name)`.
+pub trait Backend: Debug + Send + Sync {
+    /// Opens the file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if the file is missing and `mode` does
+    /// not create it, or other I/O errors.
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Returns the paths of the files directly inside `dir`, in any order.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from listing the directory.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Atomically replaces `to` with the file at `from`.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors, including [`ErrorKind::NotFound`] if `from` is missing.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Deletes the file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors, including [`ErrorKind::NotFound`] if it is missing.
+    fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates the directory `dir` and its parents if missing.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from creating the directories.
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Makes the files created, renamed and deleted in `dir` so far survive a
+    /// power loss, as fsyncing a file does its contents. The default does
+    /// nothing, for backends where those changes are durable at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from syncing the directory.
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Takes the exclusive writer lock of the log directory `dir`, held until the
+    /// returned guard is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ErrorKind::WouldBlock`] if the lock is held elsewhere, or
+    /// other I/O errors.
+    fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>>;
+}
+
+/// An open file of a [`Backend`].
+pub trait StorageFile: Debug + Send + Sync {
+    /// Appends `buf` at the end of the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing.
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Appends a prefix of `buf` and returns its length, like one `write` call.
+    /// Only backends that simulate short writes return less than `buf.len()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing.
+    fn append_partial(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.append(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Appends every buffer in `bufs`, in order. Backends that can should do
+    /// so in one write.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing.
+    fn append_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        bufs.iter().try_for_each(|buf| self.append(buf))
+    }
+
+    /// Writes `buf` at position `pos`, extending the file if it ends before
+    /// `pos + buf.len()`. Only writes into [preallocated](Self::allocate) space
+    /// use it; the default supports writing at the end of the file alone.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ErrorKind::Unsupported`] if the backend cannot write at
+    /// `pos`, or other I/O errors from writing.
+    fn write_at(&mut self, buf: &[u8], pos: u64) -> io::Result<()> {
+        if pos != self.size()? {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "positional writes are not supported by this backend",
+            ));
+        }
+        self.append(buf)
+    }
+
+    /// Reserves space for the file to grow to `len` bytes, zero-filled, and
+    /// extends it to `len` if shorter. The default zero-extends it.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from allocating.
+    fn allocate(&mut self, len: u64) -> io::Result<()> {
+        if self.size()? < len {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    /// Reads up to `buf.len()` bytes at position `pos`, returning how many were
+    /// read: 0 at or past the end of the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading.
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize>;
+
+    /// Returns the file's length in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the file's metadata.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncates or zero-extends the file to `len` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from resizing.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Makes everything written so far durable.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from syncing.
+    fn fsync(&mut self) -> io::Result<()>;
+
+    /// Returns when the file was last written, if the backend tracks it. Age-based
+    /// retention falls back on it for segments without timestamps.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading the file's metadata.
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    /// Tells the OS how `len` bytes at `offset` (the rest of the file if `len`
+    /// is 0) will be read. Purely a hint: the default, and backends without a
+    /// page cache to tune, ignore it.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from passing on the hint.
+    fn advise(&self, _offset: u64, _len: u64, _advice: Advice) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the default backend, the local filesystem.
+#[cfg(feature = "fs")]
+#[must_use]
+pub fn fs_backend() -> Arc<dyn Backend> {
+    Arc::new(FsBackend)
+}
+
+/// Returns the backend a log or reader uses unless told otherwise: the local
+/// filesystem, or a new [`MemoryBackend`] without the `fs` feature.
+#[cfg(feature = "fs")]
+pub(crate) fn default_backend() -> Arc<dyn Backend> {
+    fs_backend()
+}
+
+/// Returns the backend a log or reader uses unless told otherwise: the local
+/// filesystem, or a new [`MemoryBackend`] without the `fs` feature.
+#[cfg(not(feature = "fs"))]
+pub(crate) fn default_backend() -> Arc<dyn Backend> {
+    Arc::new(MemoryBackend::new())
+}
+
+/// The local filesystem.
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsBackend;
+
+/// Name of the lock file [`FsBackend`] uses for a log directory's writer lock.
+#[cfg(feature = "fs")]
+const LOCK_FILE_NAME: &str = "write.lock";
+
+#[cfg(feature = "fs")]
+impl Backend for FsBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let file = open_options(mode).open(path)?;
+        Ok(Box::new(FsFile(file)))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        Ok(paths)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    #[cfg(not(windows))]
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    #[cfg(windows)]
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        // Windows only unlinks a file once every handle to it is closed, and until
+        // then its name cannot be reused. Move it aside first, so that a segment
+        // a reader still has open can be recreated at once, as on Unix.
+        static DELETED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let n = DELETED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut aside = path.as_os_str().to_owned();
+        aside.push(format!(".deleted-{}-{n}", std::process::id()));
+        fs::rename(path, &aside)?;
+        fs::remove_file(aside)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        sync_dir(dir)
+    }
+
+    /// Locks `write.lock` in `dir` with `flock` on Unix and `LockFileEx` on
+    /// Windows. Either way the lock belongs to the open file, so a second
+    /// [`LogDir`](crate::LogDir) in the same process is refused as well.
+    fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+        let lock_path = dir.join(LOCK_FILE_NAME);
+        // Windows may briefly deny access while a previous holder releases the file.
+        let mut attempt = 0;
+        let file = loop {
+            match OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(&lock_path)
+            {
+                Ok(f) => break f,
+                Err(e) if attempt < 9 && e.kind() == ErrorKind::PermissionDenied => {
+                    attempt += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        file.try_lock_exclusive().map_err(|e| {
+            // EWOULDBLOCK on Unix, ERROR_LOCK_VIOLATION on Windows.
+            if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                io::Error::new(ErrorKind::WouldBlock, e)
+            } else {
+                e
+            }
+        })?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Fsyncs the directory `dir`, so that the files created, renamed and deleted
+/// in it survive a power loss. Windows has no directory fsync, and needs none:
+/// NTFS journals those changes.
+#[cfg(feature = "fs")]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Returns the options [`FsBackend`] opens files with in `mode`.
+#[cfg(feature = "fs")]
+pub(crate) fn open_options(mode: OpenMode) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(mode != OpenMode::Read)
+        .create(matches!(mode, OpenMode::Create | OpenMode::Replace))
+        .create_new(mode == OpenMode::CreateNew)
+        .truncate(mode == OpenMode::Replace);
+    options
+}
+
+/// A file of [`FsBackend`].
+#[cfg(feature = "fs")]
+#[derive(Debug)]
+struct FsFile(File);
+
+#[cfg(feature = "fs")]
+impl StorageFile for FsFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.seek(SeekFrom::End(0))?;
+        self.0.write_all(buf)
+    }
+
+    fn append_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        self.0.seek(SeekFrom::End(0))?;
+        // One vectored write when the OS accepts it in full; finish any short
+        // write buffer by buffer.
+        let mut skip = self.0.write_vectored(bufs)?;
+        for buf in bufs {
+            if skip >= buf.len() {
+                skip -= buf.len();
+                continue;
+            }
+            self.0.write_all(&buf[skip..])?;
+            skip = 0;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(pos))?;
+        self.0.write_all(buf)
+    }
+
+    fn allocate(&mut self, len: u64) -> io::Result<()> {
+        // fallocate on Linux, F_PREALLOCATE on macOS, the allocation size on
+        // Windows; each also extends the file to `len`.
+        fs2::FileExt::allocate(&self.0, len)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.0, buf, pos)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        // Appends seek to the end first, so moving the cursor here is harmless.
+        std::os::windows::fs::FileExt::seek_read(&self.0, buf, pos)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        // ftruncate on Unix, SetFileInformationByHandle on Windows. Windows
+        // refuses to shrink a file while any process has it memory-mapped.
+        self.0.set_len(len)
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        // fsync on Linux, F_FULLFSYNC on macOS, FlushFileBuffers on Windows:
+        // each flushes the drive's write cache too, and the file's length.
+        self.0.sync_all()
+    }
+
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        self.0.metadata()?.modified().map(Some)
+    }
+
+    #[cfg(all(feature = "fadvise", target_os = "linux"))]
+    fn advise(&self, offset: u64, len: u64, advice: Advice) -> io::Result<()> {
+        fadvise(&self.0, offset, len, advice)
+    }
+}
+
+/// Passes `advice` for `len` bytes of `file` at `offset` (the rest of the file
+/// if `len` is 0) to `posix_fadvise`.
+#[cfg(all(feature = "fadvise", target_os = "linux"))]
+pub(crate) fn fadvise(file: &File, offset: u64, len: u64, advice: Advice) -> io::Result<()> {
+    use rustix::fs::Advice as Fadv;
+    let advice = match advice {
+        Advice::Sequential => Fadv::Sequential,
+        Advice::WillNeed => Fadv::WillNeed,
+        Advice::DontNeed => Fadv::DontNeed,
+    };
+    let len = std::num::NonZeroU64::new(len);
+    Ok(rustix::fs::fadvise(file, offset, len, advice)?)
+}
+
+/// Files held in memory. Clones share the same files, so a log can be reopened
+/// from a clone after the original handle is dropped.
+///
+/// Directories are implicit: a file is inside `dir` if its path's parent is
+/// `dir`. Syncing does nothing. [`Log::open_in_memory`](crate::Log::open_in_memory)
+/// opens a log on a backend of its own.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    files: Arc<Mutex<MemoryFiles>>,
+    locks: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+impl MemoryBackend {
+    /// Creates an empty backend.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock_files(&self) -> MutexGuard<'_, MemoryFiles> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut files = self.lock_files();
+        let data = match files.get(path) {
+            Some(_) if mode == OpenMode::CreateNew => {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ))
+            }
+            Some(data) => Arc::clone(data),
+            None if mode.creates() => {
+                let data = Arc::default();
+                files.insert(path.to_path_buf(), Arc::clone(&data));
+                data
+            }
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} not found", path.display()),
+                ))
+            }
+        };
+        drop(files);
+        let mut file = MemoryFile(data);
+        if mode == OpenMode::Replace {
+            file.set_len(0)?;
+        }
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .lock_files()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.lock_files();
+        let data = files.remove(from).ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, format!("{} not found", from.display()))
+        })?;
+        files.insert(to.to_path_buf(), data);
+        drop(files);
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.lock_files().remove(path).map(drop).ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, format!("{} not found", path.display()))
+        })
+    }
+
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+        let locked = self
+            .locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(dir.to_path_buf());
+        if !locked {
+            return Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                format!("{} is locked", dir.display()),
+            ));
+        }
+        Ok(Box::new(MemoryLock {
+            locks: Arc::clone(&self.locks),
+            dir: dir.to_path_buf(),
+        }))
+    }
+}
+
+/// The files of a [`MemoryBackend`], by path.
+type MemoryFiles = BTreeMap<PathBuf, Arc<RwLock<MemoryData>>>;
+
+/// Contents of a [`MemoryBackend`] file.
+#[derive(Debug)]
+struct MemoryData {
+    bytes: Vec<u8>,
+    /// When the file was last written, as the filesystem would record it.
+    modified: SystemTime,
+}
+
+impl Default for MemoryData {
+    fn default() -> Self {
+        Self {
+            bytes: Vec::new(),
+            modified: clock::system_now(),
+        }
+    }
+}
+
+/// A file of [`MemoryBackend`].
+#[derive(Debug)]
+struct MemoryFile(Arc<RwLock<MemoryData>>);
+
+impl MemoryFile {
+    fn read(&self) -> RwLockReadGuard<'_, MemoryData> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Changes the contents with `f` and records the time of the write.
+    fn write(&self, f: impl FnOnce(&mut Vec<u8>)) {
+        let mut data = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        f(&mut data.bytes);
+        data.modified = clock::system_now();
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write(|bytes| bytes.extend_from_slice(buf));
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], pos: u64) -> io::Result<()> {
+        let start = usize::try_from(pos).map_err(|_| ErrorKind::OutOfMemory)?;
+        let end = start + buf.len();
+        self.write(|bytes| {
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[start..end].copy_from_slice(buf);
+        });
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let data = self.read();
+        let bytes = &data.bytes;
+        let start = usize::try_from(pos).unwrap_or(usize::MAX).min(bytes.len());
+        let n = buf.len().min(bytes.len() - start);
+        buf[..n].copy_from_slice(&bytes[start..start + n]);
+        drop(data);
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.read().bytes.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| ErrorKind::OutOfMemory)?;
+        self.write(|bytes| bytes.resize(len, 0));
+        Ok(())
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        Ok(Some(self.read().modified))
+    }
+}
+
+/// The writer lock of a [`MemoryBackend`] directory; released on drop.
+#[derive(Debug)]
+struct MemoryLock {
+    locks: Arc<Mutex<BTreeSet<PathBuf>>>,
+    dir: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.dir);
+    }
+}
+
+/// A [`StorageFile`] with a read position, for the log's sequential readers.
+/// Writes always append and leave the position at the new end.
+#[derive(Debug)]
+pub(crate) struct FileCursor {
+    file: Box<dyn StorageFile>,
+    pos: u64,
+}
+
+impl FileCursor {
+    /// Opens `path` in `storage`, positioned at its start.
+    pub(crate) fn open(storage: &dyn Backend, path: &Path, mode: OpenMode) -> io::Result<Self> {
+        Ok(Self {
+            file: storage.open(path, mode)?,
+            pos: 0,
+        })
+    }
+
+    /// Returns the file, dropping the position.
+    pub(crate) fn into_inner(self) -> Box<dyn StorageFile> {
+        self.file
+    }
+
+    /// Returns the file's length in bytes.
+    pub(crate) fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+
+    /// Truncates or zero-extends the file to `len` bytes.
+    pub(crate) fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    /// Makes everything written so far durable.
+    pub(crate) fn fsync(&mut self) -> io::Result<()> {
+        self.file.fsync()
+    }
+
+    /// Returns when the file was last written, if known.
+    pub(crate) fn modified(&self) -> io::Result<Option<SystemTime>> {
+        self.file.modified()
+    }
+
+    /// Passes a page cache hint on to the file; see [`StorageFile::advise`].
+    pub(crate) fn advise(&self, offset: u64, len: u64, advice: Advice) -> io::Result<()> {
+        self.file.advise(offset, len, advice)
+    }
+
+    /// Writes every buffer in `bufs`, in order, starting at position `pos`.
+    pub(crate) fn write_all_at(&mut self, bufs: &[impl AsRef<[u8]>], pos: u64) -> io::Result<()> {
+        let [buf] = bufs else {
+            let mut joined = Vec::with_capacity(bufs.iter().map(|b| b.as_ref().len()).sum());
+            for buf in bufs {
+                joined.extend_from_slice(buf.as_ref());
+            }
+            return self.write_all_at(&[joined], pos);
+        };
+        let buf = buf.as_ref();
+        self.file.write_at(buf, pos)?;
+        self.pos = pos + buf.len() as u64;
+        Ok(())
+    }
+
+    /// Reserves space for the file to grow to `len` bytes; see
+    /// [`StorageFile::allocate`].
+    pub(crate) fn allocate(&mut self, len: u64) -> io::Result<()> {
+        self.file.allocate(len)
+    }
+
+    /// Appends every buffer in `bufs` in one write where the backend can.
+    pub(crate) fn append_all(&mut self, bufs: &[impl AsRef<[u8]>]) -> io::Result<()> {
+        let slices: Vec<IoSlice<'_>> = bufs.iter().map(|b| IoSlice::new(b.as_ref())).collect();
+        self.file.append_vectored(&slices)?;
+        self.pos = self.file.size()?;
+        Ok(())
+    }
+}
+
+impl Read for FileCursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileCursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(delta) => (self.file.size()?, delta),
+            SeekFrom::Current(delta) => (self.pos, delta),
+        };
+        self.pos = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl Write for FileCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.append_partial(buf)?;
+        self.pos = self.file.size()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the whole file at `path`, or returns `None` if there is none.
+pub(crate) fn read_file(storage: &dyn Backend, path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = match FileCursor::open(storage, path, OpenMode::Read) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(Some(contents))
+}
+
+/// Atomically and durably replaces the file at `path` with `contents` (write
+/// temp, fsync, rename, fsync the directory).
+pub(crate) fn write_atomic(storage: &dyn Backend, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let mut tmp = storage.open(&tmp_path, OpenMode::Replace)?;
+    tmp.append(contents)?;
+    tmp.fsync()?;
+    drop(tmp);
+    storage.rename(&tmp_path, path)?;
+    storage.sync_dir(parent_dir(path))
+}
+
+/// Returns the directory holding the file at `path`.
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+}
+
+/// Deletes the file at `path`; a missing file is not an error.
+pub(crate) fn delete_if_exists(storage: &dyn Backend, path: &Path) -> io::Result<()> {
+    match storage.delete(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log, LogReader};
+
+    #[test]
+    fn log_runs_on_the_memory_backend() {
+        let storage = MemoryBackend::new();
+        let dir = Path::new("/in-memory/log");
+        let config = Config {
+            max_segment_bytes: 100,
+            storage: Arc::new(storage.clone()),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir, config.clone()).unwrap();
+        for i in 0..10u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        assert!(matches!(
+            Log::open(dir, config.clone()),
+            Err(crate::Error::Locked(_))
+        ));
+        log.truncate_after(7).unwrap();
+        drop(log);
+        assert!(!dir.exists());
+
+        let mut log = Log::open(dir, config).unwrap();
+        assert_eq!(log.next_offset(), 8);
+        assert_eq!(log.read(7).unwrap(), [7; 30]);
+        assert!(log.verify().unwrap().is_ok());
+        let reader = LogReader::open_with_storage(dir, Arc::new(storage)).unwrap();
+        assert!(reader.segments().len() > 2);
+        assert_eq!(reader.iter().count(), 8);
+    }
+
+    #[test]
+    fn fs_lock_refuses_a_second_holder_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = FsBackend.lock(dir.path()).unwrap();
+        let err = FsBackend.lock(dir.path()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        drop(lock);
+        drop(FsBackend.lock(dir.path()).unwrap());
+
+        let err = FsBackend.lock(&dir.path().join("missing")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn fs_files_truncate_and_delete_while_open_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("segment.log");
+        let mut file = FsBackend.open(&path, OpenMode::CreateNew).unwrap();
+        file.append(b"0123456789").unwrap();
+        let reader = FsBackend.open(&path, OpenMode::Read).unwrap();
+
+        file.set_len(4).unwrap();
+        file.fsync().unwrap();
+        file.append(b"ab").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(reader.read_at(&mut buf, 0).unwrap(), 6);
+        assert_eq!(&buf[..6], b"0123ab");
+
+        // The name is free again at once, though `reader` keeps the old file.
+        drop(file);
+        FsBackend.delete(&path).unwrap();
+        let mut file = FsBackend.open(&path, OpenMode::CreateNew).unwrap();
+        file.append(b"new").unwrap();
+        assert_eq!(reader.read_at(&mut buf, 0).unwrap(), 6);
+        assert_eq!(file.read_at(&mut buf, 0).unwrap(), 3);
+    }
+
+    /// A [`MemoryBackend`] counting directory syncs.
+    #[derive(Debug, Default)]
+    struct SyncCounter {
+        files: MemoryBackend,
+        dir_syncs: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Backend for SyncCounter {
+        fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            self.files.open(path, mode)
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.files.list(dir)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.files.rename(from, to)
+        }
+
+        fn delete(&self, path: &Path) -> io::Result<()> {
+            self.files.delete(path)
+        }
+
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.files.create_dir_all(dir)
+        }
+
+        fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+            self.dir_syncs
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+            self.files.lock(dir)
+        }
+    }
+
+    #[test]
+    fn segment_creation_and_deletion_sync_the_directory() {
+        let storage = Arc::new(SyncCounter::default());
+        let syncs = || storage.dir_syncs.load(std::sync::atomic::Ordering::Relaxed);
+        let config = Config {
+            max_segment_bytes: 100,
+            storage: Arc::clone(&storage) as Arc<dyn Backend>,
+            ..Config::default()
+        };
+        let mut log = Log::open("/log", config).unwrap();
+        let opened = syncs();
+        assert!(opened > 0);
+        for i in 0..4u8 {
+            log.append(&[i; 30]).unwrap();
+        }
+        // One record fits a segment: one sync for each segment rolled to, and
+        // one for the manifest rewritten after it.
+        assert_eq!(syncs(), opened + 6);
+        log.delete_before(2).unwrap();
+        assert!(syncs() > opened + 6);
+    }
+
+    #[test]
+    fn in_memory_log_matches_disk_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            retention: crate::RetentionPolicy {
+                max_age: Some(std::time::Duration::from_millis(20)),
+                max_total_bytes: None,
+            },
+            ..Config::default()
+        };
+        let mut disk = Log::open(dir.path(), config.clone()).unwrap();
+        let mut memory = Log::open_in_memory(config).unwrap();
+        for log in [&mut disk, &mut memory] {
+            log.append_batch(&[b"a", b"b"]).unwrap();
+            for i in 0..6u8 {
+                log.append(&[i; 30]).unwrap();
+            }
+            log.truncate_after(5).unwrap();
+        }
+        let (disk_stats, memory_stats) = (disk.stats().unwrap(), memory.stats().unwrap());
+        assert_eq!(memory_stats.segments, disk_stats.segments);
+        assert_eq!(memory_stats.disk_bytes, disk_stats.disk_bytes);
+        let records = |log: &Log| {
+            let records = log.reader().unwrap().iter().map(Result::unwrap);
+            records.map(|r| r.payload).collect::<Vec<_>>()
+        };
+        assert_eq!(records(&memory), records(&disk));
+
+        // Age-based retention reads modification times from the backend.
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        memory.append(b"new").unwrap();
+        assert_eq!(
+            memory.enforce_retention().unwrap(),
+            memory_stats.segments - 1
+        );
+        assert!(!Path::new(memory.path()).exists());
+    }
+}
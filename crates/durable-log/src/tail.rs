@@ -0,0 +1,163 @@
+//! Following a log as it grows.
+//!
+//! A [`Tail`], created by [`Log::tail`](crate::Log::tail), reads the records
+//! already in the log and then parks until the writer appends more, so consumers
+//! need no poll loop. The writer wakes tails after every append.
+
+use crate::ack::Watermark;
+use crate::error::Error;
+use crate::reader::{LogReader, Record, Records};
+use crate::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Blocking iterator over a log's records, oldest first, that waits for new
+/// appends at the end instead of finishing; see [`Log::tail`](crate::Log::tail).
+///
+/// Iteration ends after an error, or once every record written before the log
+/// was dropped has been yielded.
+#[derive(Debug)]
+pub struct Tail {
+    reader: LogReader,
+    /// The log's written watermark.
+    written: Arc<Watermark>,
+    /// Offset of the next record to yield.
+    next: u64,
+    /// Records being read, and the written end observed before they were opened.
+    /// The iterator is only advanced below that end, so it never reaches a frame
+    /// still being written or a footer added after its segments were listed.
+    records: Option<(Records, u64)>,
+    done: bool,
+}
+
+impl Tail {
+    pub(crate) const fn new(reader: LogReader, written: Arc<Watermark>, offset: u64) -> Self {
+        Self {
+            reader,
+            written,
+            next: offset,
+            records: None,
+            done: false,
+        }
+    }
+
+    /// Returns the offset of the next record the tail will yield, unless it is
+    /// below the log start.
+    #[must_use]
+    pub const fn next_offset(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns the next record, waiting at most `timeout` for one to be appended.
+    ///
+    /// Returns `Ok(None)` if none arrived in time.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Closed`] if the log was dropped and every record it wrote has
+    ///   been yielded, or the tail already ended with an error.
+    /// - Errors from reading the log, as for [`LogReader::iter`].
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Record>> {
+        self.advance(Some(timeout))
+    }
+
+    fn advance(&mut self, timeout: Option<Duration>) -> Result<Option<Record>> {
+        if self.done {
+            return Err(Error::Closed("tail ended".into()));
+        }
+        let result = self.read_next(timeout);
+        self.done = result.is_err();
+        result
+    }
+
+    fn read_next(&mut self, timeout: Option<Duration>) -> Result<Option<Record>> {
+        if !matches!(&self.records, Some((_, end)) if self.next < *end) {
+            self.records = None;
+            let end = self.written.wait_past(self.next, timeout)?;
+            if end <= self.next {
+                return Ok(None);
+            }
+            // Pick up segments rolled since the last read.
+            self.reader.refresh()?;
+            self.records = Some((self.reader.iter_from(self.next), end));
+        }
+        let Some((records, end)) = &mut self.records else {
+            return Ok(None);
+        };
+        match records.next() {
+            Some(Ok(record)) => {
+                self.next = record.offset + 1;
+                Ok(Some(record))
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(Error::Corruption(format!(
+                "log ends at offset {}, before the {end} records written",
+                self.next
+            ))),
+        }
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance(None) {
+            Ok(record) => record.map(Ok),
+            Err(Error::Closed(_)) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Log};
+    use std::time::Duration;
+
+    #[test]
+    fn tail_yields_existing_then_new_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"zero").unwrap();
+        log.append(b"one").unwrap();
+
+        let mut tail = log.tail(1).unwrap();
+        let follower = std::thread::spawn(move || {
+            let payloads: Vec<Vec<u8>> = tail.by_ref().map(|r| r.unwrap().payload).collect();
+            (payloads, tail.next_offset())
+        });
+        for i in 2..8u8 {
+            // Rolls every few records, so the tail crosses segments.
+            log.append(&[i; 20]).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        log.append_batch(&[b"x", b"y"]).unwrap();
+        drop(log);
+
+        let (payloads, next) = follower.join().unwrap();
+        assert_eq!(payloads.len(), 9);
+        assert_eq!(payloads[0], b"one");
+        assert_eq!(payloads[1], [2; 20]);
+        assert_eq!(payloads[8], b"y");
+        assert_eq!(next, 10);
+    }
+
+    #[test]
+    fn next_timeout_returns_none_when_idle() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        let mut tail = log.tail(0).unwrap();
+        assert!(tail
+            .next_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+        log.append(b"a").unwrap();
+        let record = tail.next_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(record.payload, b"a");
+    }
+}
@@ -0,0 +1,506 @@
+//! Deterministic fault injection for crash testing (`testing` feature).
+//!
+//! [`FaultyBackend`] is an in-memory [`Backend`] that tracks which bytes of each
+//! file have been fsynced and can be told to misbehave: cut the power after a
+//! number of bytes, tear or shorten writes, delay fsyncs, or crash and lose
+//! everything not yet fsynced. Open a [`Log`](crate::Log) on it through
+//! [`Config::storage`](crate::Config::storage), inject a fault, then
+//! [`restart`](FaultyBackend::restart) and reopen the log to check what recovery
+//! makes of the files.
+//!
+//! Faults are triggered by byte counts and calls, never by timing or randomness,
+//! so a failing case replays exactly. Sweeping
+//! [`cut_power_after`](FaultyBackend::cut_power_after) over every byte count up to
+//! [`bytes_written`](FaultyBackend::bytes_written) by a workload covers every
+//! point at which a write can be torn.
+
+use crate::storage::{Backend, OpenMode, StorageFile};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// An in-memory backend that injects faults on demand; see the
+/// [module docs](self).
+///
+/// Clones share the same files and faults. Creating, renaming and deleting
+/// files is durable at once; only file contents wait for an fsync.
+#[derive(Debug, Clone, Default)]
+pub struct FaultyBackend {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// File ID of each path, so open handles follow renames.
+    paths: BTreeMap<PathBuf, u64>,
+    files: BTreeMap<u64, FileState>,
+    next_id: u64,
+    /// Generation in which each locked directory was locked.
+    locks: BTreeMap<PathBuf, u64>,
+    /// Bumped by every restart; handles from earlier generations are dead.
+    generation: u64,
+    powered_off: bool,
+    /// Bytes that may still be written before the power is cut.
+    budget: Option<u64>,
+    /// Bytes of the next write to keep before failing it.
+    tear_next: Option<usize>,
+    /// Most bytes written by one write call.
+    max_write: Option<usize>,
+    fsync_delay: Duration,
+    bytes_written: u64,
+}
+
+#[derive(Debug, Default)]
+struct FileState {
+    data: Vec<u8>,
+    /// Length of the prefix of `data` that has been fsynced.
+    synced: usize,
+}
+
+impl State {
+    /// Fails if the power is off or `generation` is from before a restart.
+    fn check(&self, generation: u64) -> io::Result<()> {
+        if self.powered_off {
+            Err(io::Error::other("power is cut"))
+        } else if generation != self.generation {
+            Err(io::Error::other("file handle from before a restart"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn file(&mut self, id: u64) -> io::Result<&mut FileState> {
+        self.files
+            .get_mut(&id)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "file was deleted"))
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(ErrorKind::NotFound, format!("{} not found", path.display()))
+    }
+}
+
+impl FaultyBackend {
+    /// Creates an empty backend with no faults armed.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Cuts the power once `bytes` more bytes have been written. The write that
+    /// crosses the limit keeps only the bytes below it and fails, and every
+    /// operation after it fails until [`restart`](Self::restart).
+    ///
+    /// Bytes written before the cut stay in the files, fsynced or not, as if the
+    /// disk had already flushed its cache.
+    pub fn cut_power_after(&self, bytes: u64) {
+        self.lock_state().budget = Some(bytes);
+    }
+
+    /// Makes the next write keep only its first `keep` bytes and then fail with
+    /// an I/O error. The power stays on.
+    pub fn tear_next_write(&self, keep: usize) {
+        self.lock_state().tear_next = Some(keep);
+    }
+
+    /// Makes every write call write at most `max` bytes, so callers must retry
+    /// the rest, or lifts the limit if `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is `Some(0)`.
+    pub fn set_max_write(&self, max: Option<usize>) {
+        assert_ne!(max, Some(0), "writes must make progress");
+        self.lock_state().max_write = max;
+    }
+
+    /// Makes every fsync sleep for `delay` before it completes.
+    pub fn set_fsync_delay(&self, delay: Duration) {
+        self.lock_state().fsync_delay = delay;
+    }
+
+    /// Crashes now: every file loses the bytes written since its last fsync, and
+    /// every operation fails until [`restart`](Self::restart).
+    pub fn crash(&self) {
+        let mut state = self.lock_state();
+        for file in state.files.values_mut() {
+            file.data.truncate(file.synced);
+        }
+        state.powered_off = true;
+    }
+
+    /// Returns true if the power was cut or the backend crashed, and it has not
+    /// restarted since.
+    #[must_use]
+    pub fn is_powered_off(&self) -> bool {
+        self.lock_state().powered_off
+    }
+
+    /// Restarts after a crash or power cut: powers on, disarms every pending
+    /// fault except short writes and fsync delays, releases all writer locks and
+    /// invalidates every file handle opened so far.
+    pub fn restart(&self) {
+        let mut state = self.lock_state();
+        state.powered_off = false;
+        state.generation += 1;
+        state.locks.clear();
+        state.budget = None;
+        state.tear_next = None;
+    }
+
+    /// Returns the total number of bytes written to files so far, to size a
+    /// sweep over [`cut_power_after`](Self::cut_power_after).
+    #[must_use]
+    pub fn bytes_written(&self) -> u64 {
+        self.lock_state().bytes_written
+    }
+}
+
+impl Backend for FaultyBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut state = self.lock_state();
+        let generation = state.generation;
+        state.check(generation)?;
+        let id = match (state.paths.get(path).copied(), mode) {
+            (Some(_), OpenMode::CreateNew) => {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ));
+            }
+            (Some(id), OpenMode::Replace) => {
+                let file = state.file(id)?;
+                file.data.clear();
+                file.synced = 0;
+                id
+            }
+            (Some(id), _) => id,
+            (None, OpenMode::Read | OpenMode::Write) => return Err(State::not_found(path)),
+            (None, _) => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.files.insert(id, FileState::default());
+                state.paths.insert(path.to_path_buf(), id);
+                id
+            }
+        };
+        drop(state);
+        Ok(Box::new(FaultyFile {
+            state: Arc::clone(&self.state),
+            id,
+            generation,
+        }))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.lock_state();
+        state.check(state.generation)?;
+        Ok(state
+            .paths
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock_state();
+        state.check(state.generation)?;
+        let id = state
+            .paths
+            .remove(from)
+            .ok_or_else(|| State::not_found(from))?;
+        if let Some(replaced) = state.paths.insert(to.to_path_buf(), id) {
+            state.files.remove(&replaced);
+        }
+        drop(state);
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock_state();
+        state.check(state.generation)?;
+        let id = state
+            .paths
+            .remove(path)
+            .ok_or_else(|| State::not_found(path))?;
+        state.files.remove(&id);
+        drop(state);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        let state = self.lock_state();
+        state.check(state.generation)
+    }
+
+    fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+        let mut state = self.lock_state();
+        let generation = state.generation;
+        state.check(generation)?;
+        if state.locks.contains_key(dir) {
+            return Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                format!("{} is locked", dir.display()),
+            ));
+        }
+        state.locks.insert(dir.to_path_buf(), generation);
+        drop(state);
+        Ok(Box::new(FaultyLock {
+            state: Arc::clone(&self.state),
+            dir: dir.to_path_buf(),
+            generation,
+        }))
+    }
+}
+
+/// A file of [`FaultyBackend`].
+#[derive(Debug)]
+struct FaultyFile {
+    state: Arc<Mutex<State>>,
+    id: u64,
+    generation: u64,
+}
+
+impl FaultyFile {
+    /// Locks the backend state, failing if this handle can no longer be used.
+    fn lock_state(&self) -> io::Result<MutexGuard<'_, State>> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.check(self.generation)?;
+        Ok(state)
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            done += self.append_partial(&buf[done..])?;
+        }
+        Ok(())
+    }
+
+    fn append_partial(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock_state()?;
+        let torn = state.tear_next.take();
+        let mut n = buf.len().min(state.max_write.unwrap_or(usize::MAX));
+        n = n.min(torn.unwrap_or(usize::MAX));
+        let mut fault = torn.map(|_| io::Error::other("injected torn write"));
+        if let Some(budget) = state.budget {
+            let budget = usize::try_from(budget).unwrap_or(usize::MAX);
+            if n > budget {
+                n = budget;
+                state.budget = None;
+                state.powered_off = true;
+                fault = Some(io::Error::other("power is cut"));
+            }
+        }
+        if let Some(budget) = &mut state.budget {
+            *budget -= n as u64;
+        }
+        state.bytes_written += n as u64;
+        state.file(self.id)?.data.extend_from_slice(&buf[..n]);
+        drop(state);
+        fault.map_or(Ok(n), Err)
+    }
+
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        let mut state = self.lock_state()?;
+        let data = &state.file(self.id)?.data;
+        let start = usize::try_from(pos).unwrap_or(usize::MAX).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        drop(state);
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let mut state = self.lock_state()?;
+        Ok(state.file(self.id)?.data.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| ErrorKind::OutOfMemory)?;
+        let mut state = self.lock_state()?;
+        let file = state.file(self.id)?;
+        file.data.resize(len, 0);
+        file.synced = file.synced.min(len);
+        drop(state);
+        Ok(())
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        let delay = self.lock_state()?.fsync_delay;
+        std::thread::sleep(delay);
+        let mut state = self.lock_state()?;
+        let file = state.file(self.id)?;
+        file.synced = file.data.len();
+        drop(state);
+        Ok(())
+    }
+}
+
+/// The writer lock of a [`FaultyBackend`] directory; released on drop unless a
+/// restart already released it.
+#[derive(Debug)]
+struct FaultyLock {
+    state: Arc<Mutex<State>>,
+    dir: PathBuf,
+    generation: u64,
+}
+
+impl Drop for FaultyLock {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.locks.get(&self.dir) == Some(&self.generation) {
+            state.locks.remove(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, FsyncPolicy, Log, LogReader, RecordFormat};
+
+    const DIR: &str = "log";
+
+    fn config(backend: &FaultyBackend) -> Config {
+        Config {
+            max_segment_bytes: 150,
+            fsync: FsyncPolicy::Always,
+            format: RecordFormat::V2,
+            storage: Arc::new(backend.clone()),
+            ..Config::default()
+        }
+    }
+
+    /// Appends a fixed set of records, one call per group, returning the records
+    /// of every call acknowledged before the first error.
+    fn workload(log: &mut Log) -> Vec<Vec<u8>> {
+        let groups: [&[&[u8]]; 5] = [
+            &[b"first"],
+            &[b"second", b"batched"],
+            &[&[7; 60]],
+            &[b"x", b"y", b"z"],
+            &[&[9; 90]],
+        ];
+        let mut acked = Vec::new();
+        for group in groups {
+            if log.append_batch(group).is_err() {
+                break;
+            }
+            acked.extend(group.iter().map(|r| r.to_vec()));
+        }
+        acked
+    }
+
+    fn recovered(backend: &FaultyBackend) -> Vec<Vec<u8>> {
+        let log = Log::open(DIR, config(backend)).unwrap();
+        let records = log.reader().unwrap().iter().map(Result::unwrap);
+        records.map(|r| r.payload).collect()
+    }
+
+    #[test]
+    fn recovery_survives_a_power_cut_at_every_byte() {
+        let backend = FaultyBackend::new();
+        let mut log = Log::open(DIR, config(&backend)).unwrap();
+        let opened = backend.bytes_written();
+        let all = workload(&mut log);
+        let total = backend.bytes_written() - opened;
+
+        for cut in 0..total {
+            let backend = FaultyBackend::new();
+            backend.set_max_write(Some(7));
+            let mut log = Log::open(DIR, config(&backend)).unwrap();
+            backend.cut_power_after(cut);
+            let acked = workload(&mut log);
+            assert!(backend.is_powered_off(), "no cut at byte {cut}");
+            drop(log);
+            backend.restart();
+            let recovered = recovered(&backend);
+            // Acknowledged records survive, and no call is half applied.
+            assert!(recovered.starts_with(&acked), "lost records at byte {cut}");
+            assert!(all.starts_with(&recovered), "bad records at byte {cut}");
+            assert!(
+                [0, 1, 3, 4, 7, 8].contains(&recovered.len()),
+                "partial batch at byte {cut}"
+            );
+        }
+    }
+
+    #[test]
+    fn crash_loses_unsynced_writes() {
+        let backend = FaultyBackend::new();
+        let mut log = Log::open(
+            DIR,
+            Config {
+                fsync: FsyncPolicy::Manual,
+                ..config(&backend)
+            },
+        )
+        .unwrap();
+        log.append(b"synced").unwrap();
+        log.flush().unwrap();
+        log.append(b"unsynced").unwrap();
+        backend.crash();
+        assert!(log.append(b"after").is_err());
+        assert!(Log::open(DIR, config(&backend)).is_err());
+
+        backend.restart();
+        assert_eq!(recovered(&backend), [b"synced"]);
+        let mut log = Log::open(DIR, config(&backend)).unwrap();
+        backend.tear_next_write(3);
+        assert!(log.append(b"torn").is_err());
+        drop(log);
+        assert_eq!(recovered(&backend), [b"synced"]);
+    }
+
+    #[test]
+    fn records_reopened_after_a_process_crash_are_durable() {
+        let backend = FaultyBackend::new();
+        let config = Config {
+            fsync: FsyncPolicy::Manual,
+            ..config(&backend)
+        };
+        let mut log = Log::open(DIR, config.clone()).unwrap();
+        log.append_batch(&[b"a", b"b", b"c"]).unwrap();
+        // The process dies: the records reached the page cache, not the disk.
+        drop(log);
+
+        let log = Log::open(DIR, config).unwrap();
+        assert_eq!(log.durable_offset(), 3);
+        drop(log);
+        backend.crash();
+        backend.restart();
+        assert_eq!(recovered(&backend), [b"a", b"b", b"c"]);
+    }
+
+    #[test]
+    fn durable_offset_published_on_open_survives_a_crash() {
+        let backend = FaultyBackend::new();
+        let config = Config {
+            fsync: FsyncPolicy::Manual,
+            ..config(&backend)
+        };
+        let mut log = Log::open(DIR, config.clone()).unwrap();
+        log.append_batch(&[b"a", b"b"]).unwrap();
+        drop(log);
+        drop(Log::open(DIR, config).unwrap());
+        backend.crash();
+        backend.restart();
+
+        let reader = LogReader::open_with_storage(DIR, Arc::new(backend))
+            .unwrap()
+            .with_durable_only(true);
+        assert_eq!(reader.durable_offset(), Some(2));
+        assert_eq!(reader.read(1).unwrap(), b"b");
+    }
+}
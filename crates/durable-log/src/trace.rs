@@ -0,0 +1,15 @@
+//! `tracing` instrumentation (`tracing` feature).
+//!
+//! [`event!`] forwards to the `tracing` macro of the given level when the feature
+//! is enabled and expands to nothing otherwise, so call sites need no `cfg`.
+//! Spans are added with `#[cfg_attr(feature = "tracing", tracing::instrument(..))]`.
+
+/// Emits a `tracing` event: `event!(info, segment = base, "segment rolled")`.
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($arg)+);
+    }};
+}
+
+pub(crate) use event;
@@ -0,0 +1,153 @@
+//! Records that expire: a time to live stored with the record.
+//!
+//! [`Log::append_with_ttl`] and [`Log::append_keyed_with_ttl`] store the time a
+//! record expires, its append time plus the TTL, in an [`EXPIRY_HEADER`] header;
+//! [`Record::expires_at`](crate::Record::expires_at) reads it back. Expired
+//! records stay readable until they are deleted, unless a reader is set to
+//! [`with_skip_expired`](crate::LogReader::with_skip_expired).
+//!
+//! Each sealed segment whose records all have a TTL has its latest expiry
+//! recorded in the [`Manifest`](crate::Manifest), and
+//! [`Log::enforce_retention`] deletes it, like a segment past
+//! [`RetentionPolicy::max_age`](crate::RetentionPolicy::max_age), once that time
+//! has passed; [`Log::delete_expired`] deletes only those. A segment holding a
+//! single record without a TTL is only deleted by the retention policy.
+
+use crate::clock::now_millis;
+use crate::error::Error;
+use crate::log::Log;
+use crate::retention::RetentionPolicy;
+use crate::Result;
+use std::time::Duration;
+
+/// Name of the header holding the time a record expires (u64 little-endian,
+/// milliseconds since the Unix epoch). The name is reserved for
+/// [`Log::append_with_ttl`] and [`Log::append_keyed_with_ttl`].
+pub const EXPIRY_HEADER: &str = "dlog.expires";
+
+/// Decodes the value of an [`EXPIRY_HEADER`] header, or `None` if it is malformed.
+pub(crate) fn decode_expiry(value: &[u8]) -> Option<u64> {
+    value.try_into().ok().map(u64::from_le_bytes)
+}
+
+/// The latest expiry of a segment's records, while every one of them has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SegmentExpiry {
+    /// The segment holds no records yet.
+    Empty,
+    /// Every record expires at or before this time.
+    At(u64),
+    /// A record never expires, or the segment's records are unknown.
+    Never,
+}
+
+impl SegmentExpiry {
+    /// Accounts for a record appended with the expiry `expires_at`.
+    pub(crate) fn add(self, expires_at: Option<u64>) -> Self {
+        match (self, expires_at) {
+            (Self::Never, _) | (_, None) => Self::Never,
+            (Self::Empty, Some(at)) => Self::At(at),
+            (Self::At(latest), Some(at)) => Self::At(latest.max(at)),
+        }
+    }
+}
+
+impl Log {
+    /// Appends a payload that expires `ttl` after now and returns its offset;
+    /// see the [module docs](self).
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::InvalidFormat`] if [`Config::format`](crate::Config::format) is
+    ///   not [`RecordFormat::V2`](crate::RecordFormat::V2), or the payload is too
+    ///   large to encode.
+    /// - I/O errors from writing the segment or index file.
+    pub fn append_with_ttl(&mut self, payload: &[u8], ttl: Duration) -> Result<u64> {
+        self.append_expiring(None, payload, ttl)
+    }
+
+    /// Appends a keyed record that expires `ttl` after now and returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`append_with_ttl`](Self::append_with_ttl).
+    pub fn append_keyed_with_ttl(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<u64> {
+        self.append_expiring(Some(key), value, ttl)
+    }
+
+    /// Deletes the oldest sealed segments whose records have all expired, and
+    /// returns how many were deleted. Unlike
+    /// [`enforce_retention`](Self::enforce_retention), ignores
+    /// [`Config::retention`](crate::Config::retention).
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from reading metadata or deleting segment files.
+    pub fn delete_expired(&mut self) -> Result<usize> {
+        let count = self.outside_retention(RetentionPolicy::default())?;
+        self.delete_oldest(count)?;
+        Ok(count)
+    }
+
+    fn append_expiring(&mut self, key: Option<&[u8]>, value: &[u8], ttl: Duration) -> Result<u64> {
+        if !self.format().stores_headers() {
+            return Err(Error::InvalidFormat(
+                "record TTLs require RecordFormat::V2".into(),
+            ));
+        }
+        let now = now_millis();
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = now.saturating_add(ttl).to_le_bytes();
+        self.append_parts(Some(now), &[(EXPIRY_HEADER, &expires_at)], key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, RecordFormat};
+
+    #[test]
+    fn expired_segments_are_deleted_and_skipped_by_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            format: RecordFormat::V2,
+            max_segment_bytes: 300,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        let short = Duration::from_millis(50);
+        for i in 0..12u8 {
+            log.append_keyed_with_ttl(b"session", &[i; 40], short)
+                .unwrap();
+        }
+        let audit = log.append(b"audit").unwrap();
+        log.append_with_ttl(b"later", Duration::from_secs(3600))
+            .unwrap();
+        let record = log.read_record(0).unwrap();
+        assert!(record.expires_at().unwrap() > record.timestamp.unwrap());
+        let segments = log.segment_infos().count();
+        assert!(segments > 2);
+
+        // Nothing has expired yet; the manifest keeps the expiries across a reopen.
+        assert_eq!(log.enforce_retention().unwrap(), 0);
+        drop(log);
+        std::thread::sleep(short * 2);
+        let mut log = Log::open(dir.path(), config).unwrap();
+        let reader = log.reader().unwrap().with_skip_expired(true);
+        let live: Vec<u64> = reader.iter().map(|r| r.unwrap().offset).collect();
+        assert_eq!(live, [audit, audit + 1]);
+
+        // Segments holding only session events go; the one with the audit record stays.
+        let deleted = log.enforce_retention().unwrap();
+        assert!(deleted > 0);
+        assert_eq!(log.segment_infos().count(), segments - deleted);
+        assert!(log.first_offset() <= audit);
+        assert_eq!(log.read(audit).unwrap(), b"audit");
+    }
+}
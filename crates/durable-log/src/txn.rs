@@ -0,0 +1,206 @@
+//! Transactions: groups of appends that become visible together.
+//!
+//! [`Log::begin_txn`] writes a begin marker and returns a [`Transaction`], whose
+//! appends are followed by a commit or abort marker. Markers are control records
+//! (see [`FLAG_CONTROL`](crate::FLAG_CONTROL)) and take an offset each. Readers
+//! set to [`Isolation::ReadCommitted`](crate::Isolation::ReadCommitted) yield a
+//! transaction's records once they reach its commit marker, and never those of
+//! an aborted transaction or one the writer did not finish. A transaction still
+//! open when the log is reopened, for example after a crash part-way through it,
+//! is aborted then.
+//!
+//! In the default [`Isolation::ReadUncommitted`](crate::Isolation::ReadUncommitted)
+//! every record is yielded, markers included: a marker is a record with
+//! [`Record::marker`](crate::Record::marker) set and an empty payload.
+//!
+//! The log does not roll while a transaction is open, so each transaction lies
+//! within one segment, which may grow past
+//! [`Config::max_segment_bytes`](crate::Config::max_segment_bytes). Readers can
+//! then resolve every transaction from the start of its segment.
+
+use crate::error::Error;
+use crate::log::Log;
+use crate::Result;
+
+/// The kind of a transaction marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnMarker {
+    /// Starts a transaction; the records after it belong to it.
+    Begin,
+    /// Ends a transaction, making its records visible.
+    Commit,
+    /// Ends a transaction, discarding its records.
+    Abort,
+}
+
+impl TxnMarker {
+    /// Returns the marker's name, as used in JSON Lines exports.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Begin => "begin",
+            Self::Commit => "commit",
+            Self::Abort => "abort",
+        }
+    }
+
+    /// Returns the marker called `name`, if any.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Begin, Self::Commit, Self::Abort]
+            .into_iter()
+            .find(|m| m.name() == name)
+    }
+
+    /// Returns the body of a control record holding this marker.
+    pub(crate) const fn encode(self) -> [u8; 1] {
+        match self {
+            Self::Begin => [1],
+            Self::Commit => [2],
+            Self::Abort => [3],
+        }
+    }
+
+    /// Decodes the body of a control record.
+    pub(crate) fn decode(body: &[u8]) -> Result<Self> {
+        match body {
+            [1] => Ok(Self::Begin),
+            [2] => Ok(Self::Commit),
+            [3] => Ok(Self::Abort),
+            _ => Err(Error::Corruption("invalid transaction marker".into())),
+        }
+    }
+}
+
+/// An open transaction on a [`Log`], created by [`Log::begin_txn`]; see the
+/// [module docs](self).
+///
+/// Dropping a transaction without committing it aborts it, ignoring errors. If
+/// that fails, the transaction stays open until the next
+/// [`begin_txn`](Log::begin_txn), [`close`](Log::close) or reopening aborts it.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    log: &'a mut Log,
+    begin: u64,
+    finished: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(log: &'a mut Log, begin: u64) -> Self {
+        Self {
+            log,
+            begin,
+            finished: false,
+        }
+    }
+
+    /// Returns the offset of the transaction's begin marker.
+    #[must_use]
+    pub const fn begin_offset(&self) -> u64 {
+        self.begin
+    }
+
+    /// Appends a payload as part of the transaction and returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::append`].
+    pub fn append(&mut self, payload: &[u8]) -> Result<u64> {
+        self.log.append(payload)
+    }
+
+    /// Appends a keyed record as part of the transaction and returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::append_keyed`].
+    pub fn append_keyed(&mut self, key: &[u8], value: &[u8]) -> Result<u64> {
+        self.log.append_keyed(key, value)
+    }
+
+    /// Writes the commit marker, making the transaction's records visible to
+    /// read-committed readers, and returns the marker's offset.
+    ///
+    /// Like other appends, the marker is durable once the log is flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from writing the marker; the transaction then stays
+    /// open, as described on [`Transaction`].
+    pub fn commit(mut self) -> Result<u64> {
+        self.finished = true;
+        self.log.append_marker(TxnMarker::Commit, None)
+    }
+
+    /// Writes the abort marker, so read-committed readers skip the
+    /// transaction's records.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`commit`](Self::commit).
+    pub fn abort(mut self) -> Result<()> {
+        self.finished = true;
+        self.log.append_marker(TxnMarker::Abort, None).map(drop)
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.log.append_marker(TxnMarker::Abort, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Isolation, Log, LogReader};
+
+    fn payloads(dir: &std::path::Path, isolation: Isolation, from: u64) -> Vec<Vec<u8>> {
+        let reader = LogReader::open(dir).unwrap().with_isolation(isolation);
+        reader.iter_from(from).map(|r| r.unwrap().payload).collect()
+    }
+
+    #[test]
+    fn read_committed_skips_aborted_and_unfinished_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 100,
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        log.append(b"plain").unwrap();
+        let mut txn = log.begin_txn().unwrap();
+        assert_eq!(txn.begin_offset(), 1);
+        for i in 0..6u8 {
+            // Well past the segment size, which does not roll mid-transaction.
+            txn.append(&[i; 20]).unwrap();
+        }
+        assert_eq!(txn.commit().unwrap(), 8);
+        let mut txn = log.begin_txn().unwrap();
+        txn.append(b"aborted").unwrap();
+        txn.abort().unwrap();
+        log.begin_txn().unwrap().append(b"dropped").unwrap();
+        let mut txn = log.begin_txn().unwrap();
+        txn.append(b"crashed").unwrap();
+        std::mem::forget(txn);
+        drop(log);
+
+        // Reopening aborts the transaction left open.
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(b"after").unwrap();
+        let committed = payloads(dir.path(), Isolation::ReadCommitted, 0);
+        assert_eq!(committed.len(), 8);
+        assert_eq!(committed[0], b"plain");
+        assert_eq!(committed[6], [5; 20]);
+        assert_eq!(committed[7], b"after");
+        // Starting inside the transaction still waits for its commit marker.
+        assert_eq!(payloads(dir.path(), Isolation::ReadCommitted, 4).len(), 5);
+
+        let all = LogReader::open(dir.path()).unwrap();
+        let markers: Vec<_> = all.iter().filter_map(|r| r.unwrap().marker).collect();
+        assert_eq!(markers.len(), 8);
+        assert_eq!(all.iter().count(), 19);
+        assert!(log.read(8).unwrap().is_empty());
+    }
+}
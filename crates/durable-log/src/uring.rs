@@ -0,0 +1,617 @@
+//! An `io_uring` backend batching appends and fsyncs (`io-uring` feature, Linux).
+//!
+//! [`UringBackend`] is the local filesystem, like [`FsBackend`], except that
+//! writes and fsyncs are queued on an `io_uring` submission queue instead of being
+//! issued as syscalls one by one. Appending to a file only copies the bytes into
+//! the queue; the queue is submitted, and waited on, with a single
+//! `io_uring_enter` when a file is fsynced, read, resized or dropped, or when
+//! the queue is full. At high append rates this replaces several syscalls per
+//! record with one per flush.
+//!
+//! Every file opened through a backend shares its ring, so [`LogReader`]s and
+//! [`Tail`]s on the same backend see records as soon as they are appended.
+//! Readers on another backend, such as those of other processes, see them once
+//! the queue is submitted, as after [`Log::flush`]. Likewise, a process that
+//! crashes loses the records it queued but never submitted, where the default
+//! backend would have left them to the kernel: only fsynced records are durable
+//! either way. A failed write is reported by every later append and fsync of
+//! its file, whichever file's call submitted it.
+//!
+//! # Safety
+//!
+//! Like [`mmap`](crate::mmap), this module uses `unsafe`: to map the rings shared
+//! with the kernel and to submit operations on buffers the kernel reads
+//! asynchronously. Each queued operation owns its buffer and a handle to its
+//! file until its completion is reaped, and a ring that cannot reap every
+//! operation when dropped leaks them rather than free memory the kernel may
+//! still read.
+//!
+//! [`LogReader`]: crate::LogReader
+//! [`Tail`]: crate::Tail
+//! [`Log::flush`]: crate::Log::flush
+
+#![allow(unsafe_code)]
+
+use crate::storage::{self, Backend, FsBackend, OpenMode, StorageFile};
+use crate::trace::event;
+use rustix::io::Errno;
+use rustix::io_uring::{
+    io_uring_cqe, io_uring_enter, io_uring_params, io_uring_ptr, io_uring_setup, io_uring_sqe,
+    io_uring_user_data, IoringEnterFlags, IoringFeatureFlags, IoringOp, IoringSqeFlags,
+    IORING_OFF_SQES, IORING_OFF_SQ_RING,
+};
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+use std::ffi::c_void;
+use std::fmt::{self, Debug};
+use std::fs::File;
+use std::io::{self, ErrorKind, IoSlice};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+/// The local filesystem, writing through a shared `io_uring`; see the
+/// [module docs](self).
+///
+/// Clones share the ring.
+#[derive(Debug, Clone)]
+pub struct UringBackend {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl UringBackend {
+    /// Sets up a ring that queues up to `depth` operations before submitting
+    /// them; the kernel may round `depth` up to a power of two.
+    ///
+    /// # Errors
+    ///
+    /// Returns I/O errors from setting up the ring, such as
+    /// [`ErrorKind::Unsupported`] on kernels before Linux 5.6 or with `io_uring`
+    /// disabled, and [`ErrorKind::PermissionDenied`] where a sandbox forbids it.
+    pub fn new(depth: u32) -> io::Result<Self> {
+        Ok(Self {
+            ring: Arc::new(Mutex::new(Ring::new(depth)?)),
+        })
+    }
+}
+
+impl Backend for UringBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let file = storage::open_options(mode).open(path)?;
+        let size = (mode != OpenMode::Read)
+            .then(|| file.metadata().map(|m| m.len()))
+            .transpose()?;
+        Ok(Box::new(UringFile {
+            file: Arc::new(file),
+            failed: Arc::default(),
+            ring: Arc::clone(&self.ring),
+            size,
+        }))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        FsBackend.list(dir)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        FsBackend.rename(from, to)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        FsBackend.delete(path)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        FsBackend.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        FsBackend.sync_dir(dir)
+    }
+
+    fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+        FsBackend.lock(dir)
+    }
+}
+
+/// A file of [`UringBackend`].
+#[derive(Debug)]
+struct UringFile {
+    file: Arc<File>,
+    /// First error among the completed operations on this file.
+    failed: Arc<Mutex<Option<io::Error>>>,
+    ring: Arc<Mutex<Ring>>,
+    /// Length of the file once queued writes land; `None` if opened read-only,
+    /// as others may be writing it.
+    size: Option<u64>,
+}
+
+impl UringFile {
+    fn ring(&self) -> MutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the error of a failed write to this file, if any. Once a write
+    /// has failed, the file's contents cannot be vouched for, so it stays failed.
+    fn check(&self) -> io::Result<()> {
+        let failed = self.failed.lock().unwrap_or_else(PoisonError::into_inner);
+        failed.as_ref().map_or(Ok(()), |e| {
+            Err(e.raw_os_error().map_or_else(
+                || io::Error::new(e.kind(), e.to_string()),
+                io::Error::from_raw_os_error,
+            ))
+        })
+    }
+
+    fn queue_write(&mut self, buf: Vec<u8>, pos: u64) -> io::Result<()> {
+        let Some(size) = self.size else {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "file is opened read-only",
+            ));
+        };
+        self.check()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = pos + buf.len() as u64;
+        let op = Op {
+            file: Arc::clone(&self.file),
+            failed: Arc::clone(&self.failed),
+            kind: OpKind::Write { buf, pos },
+        };
+        self.ring().push(op)?;
+        self.size = Some(size.max(end));
+        Ok(())
+    }
+}
+
+impl StorageFile for UringFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let pos = self.size.unwrap_or_default();
+        self.queue_write(buf.to_vec(), pos)
+    }
+
+    fn append_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        let bufs: Vec<&[u8]> = bufs.iter().map(|buf| &**buf).collect();
+        let pos = self.size.unwrap_or_default();
+        self.queue_write(bufs.concat(), pos)
+    }
+
+    fn write_at(&mut self, buf: &[u8], pos: u64) -> io::Result<()> {
+        self.queue_write(buf.to_vec(), pos)
+    }
+
+    fn allocate(&mut self, len: u64) -> io::Result<()> {
+        self.ring().submit()?;
+        fs2::FileExt::allocate(&*self.file, len)?;
+        self.size = Some(self.file.metadata()?.len());
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+        self.ring().submit()?;
+        self.file.read_at(buf, pos)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        if let Some(size) = self.size {
+            return Ok(size);
+        }
+        self.ring().submit()?;
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.ring().submit()?;
+        self.file.set_len(len)?;
+        self.size = Some(len);
+        Ok(())
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        let mut ring = self.ring();
+        ring.push(Op {
+            file: Arc::clone(&self.file),
+            failed: Arc::clone(&self.failed),
+            kind: OpKind::Fsync,
+        })?;
+        ring.submit()?;
+        drop(ring);
+        self.check()
+    }
+
+    fn modified(&self) -> io::Result<Option<SystemTime>> {
+        self.ring().submit()?;
+        self.file.metadata()?.modified().map(Some)
+    }
+
+    #[cfg(feature = "fadvise")]
+    fn advise(&self, offset: u64, len: u64, advice: storage::Advice) -> io::Result<()> {
+        storage::fadvise(&self.file, offset, len, advice)
+    }
+}
+
+impl Drop for UringFile {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn drop(&mut self) {
+        // Records are only durable once fsynced, and fsyncs report errors; a
+        // write failing only now has nobody left to report it to but the trace.
+        let _ = self.ring().submit();
+        if let Err(error) = self.check() {
+            event!(warn, %error, "write to a dropped io_uring file failed");
+        }
+    }
+}
+
+/// An operation queued on a [`Ring`], holding what the kernel reads until it
+/// completes.
+struct Op {
+    file: Arc<File>,
+    /// Where the file's [`UringFile`] looks for the errors of its operations.
+    failed: Arc<Mutex<Option<io::Error>>>,
+    kind: OpKind,
+}
+
+enum OpKind {
+    Write { buf: Vec<u8>, pos: u64 },
+    Fsync,
+}
+
+/// An `io_uring` instance and the operations queued on it.
+struct Ring {
+    fd: OwnedFd,
+    /// The submission and completion rings, mapped together; the pointers
+    /// below point into it.
+    _rings: Mapping,
+    sqes: Mapping,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    /// Tail of the submission ring as published, which only this side moves.
+    tail: u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+    /// Operations queued since the last submission; an operation's index is
+    /// its user data, which routes its completion back to its file.
+    ops: Vec<Op>,
+    /// How many of `ops` the kernel has accepted, and how many have completed.
+    submitted: u32,
+    completed: u32,
+}
+
+// SAFETY: the raw pointers point into mappings owned by the ring, and are only
+// used through `&mut Ring`.
+#[allow(clippy::non_send_fields_in_send_ty)]
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(depth: u32) -> io::Result<Self> {
+        let mut params = io_uring_params::default();
+        // SAFETY: `params` is zeroed, asking for no special setup.
+        let fd = unsafe { io_uring_setup(depth, &mut params)? };
+        // Both arrived in Linux 5.6, with the write operation.
+        let required = IoringFeatureFlags::SINGLE_MMAP | IoringFeatureFlags::RW_CUR_POS;
+        if !params.features.contains(required) {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "io_uring backend needs Linux 5.6 or later",
+            ));
+        }
+        let (sq, cq) = (params.sq_off, params.cq_off);
+        let sq_len = sq.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = cq.cqes as usize + params.cq_entries as usize * size_of::<io_uring_cqe>();
+        let rings = Mapping::new(&fd, sq_len.max(cq_len), IORING_OFF_SQ_RING)?;
+        let sqes_len = params.sq_entries as usize * size_of::<io_uring_sqe>();
+        let sqes = Mapping::new(&fd, sqes_len, IORING_OFF_SQES)?;
+
+        let at = |offset: u32| rings.ptr.wrapping_byte_add(offset as usize);
+        let sq_array = at(sq.array).cast::<u32>();
+        for i in 0..params.sq_entries {
+            // SAFETY: the array holds `sq_entries` entries within the mapping.
+            // Slot `i` of the ring always holds the SQE at index `i`.
+            unsafe { sq_array.add(i as usize).write(i) };
+        }
+        let sq_tail = at(sq.tail).cast::<AtomicU32>();
+        Ok(Self {
+            // SAFETY: the kernel set up the tail, and moves only the head.
+            tail: unsafe { (*sq_tail).load(Ordering::Relaxed) },
+            sq_tail,
+            sq_mask: params.sq_entries - 1,
+            sq_entries: params.sq_entries,
+            cq_head: at(cq.head).cast(),
+            cq_tail: at(cq.tail).cast(),
+            cq_mask: params.cq_entries - 1,
+            cqes: at(cq.cqes).cast(),
+            sqes,
+            _rings: rings,
+            fd,
+            ops: Vec::new(),
+            submitted: 0,
+            completed: 0,
+        })
+    }
+
+    /// Queues `op`, first submitting the queue if it is full.
+    fn push(&mut self, op: Op) -> io::Result<()> {
+        if self.ops.len() == self.sq_entries as usize {
+            self.submit()?;
+        }
+        let mut sqe = io_uring_sqe {
+            fd: op.file.as_raw_fd(),
+            user_data: io_uring_user_data {
+                u64_: self.ops.len() as u64,
+            },
+            ..io_uring_sqe::default()
+        };
+        match &op.kind {
+            OpKind::Write { buf, pos } => {
+                sqe.opcode = IoringOp::Write;
+                sqe.off_or_addr2.off = *pos;
+                sqe.addr_or_splice_off_in.addr = io_uring_ptr::new(buf.as_ptr().cast_mut().cast());
+                // Longer writes complete short and are finished on reaping.
+                sqe.len.len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+            }
+            OpKind::Fsync => {
+                sqe.opcode = IoringOp::Fsync;
+                // Starts once every operation queued before it has completed.
+                sqe.flags = IoringSqeFlags::IO_DRAIN;
+            }
+        }
+        let sqes = self.sqes.ptr.cast::<io_uring_sqe>();
+        // SAFETY: fewer than `sq_entries` SQEs are pending, so the slot is free;
+        // `op`, which the SQE refers to, is kept in `ops` until it completes.
+        unsafe {
+            sqes.add((self.tail & self.sq_mask) as usize).write(sqe);
+            self.tail = self.tail.wrapping_add(1);
+            (*self.sq_tail).store(self.tail, Ordering::Release);
+        }
+        self.ops.push(op);
+        Ok(())
+    }
+
+    /// Submits every queued operation and waits for all of them to complete.
+    ///
+    /// Only errors from submitting are returned: those of the operations are
+    /// left to their files, as the caller may be another file's.
+    fn submit(&mut self) -> io::Result<()> {
+        let queued = u32::try_from(self.ops.len()).unwrap_or(u32::MAX);
+        while self.completed < queued {
+            // SAFETY: the SQEs up to the tail are initialized and refer to
+            // operations in `ops`.
+            let entered = unsafe {
+                io_uring_enter(
+                    &self.fd,
+                    queued - self.submitted,
+                    queued - self.completed,
+                    IoringEnterFlags::GETEVENTS,
+                )
+            };
+            match entered {
+                Ok(n) => self.submitted += n,
+                Err(Errno::INTR | Errno::AGAIN | Errno::BUSY) => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.reap();
+        }
+        self.ops.clear();
+        self.submitted = 0;
+        self.completed = 0;
+        Ok(())
+    }
+
+    /// Consumes the completions the kernel has posted.
+    fn reap(&mut self) {
+        // SAFETY: the kernel fills CQEs up to the tail before publishing it, and
+        // leaves the head to this side.
+        unsafe {
+            let mut head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            while head != tail {
+                let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+                let (index, res) = (cqe.user_data.u64_(), cqe.res);
+                self.complete(index, res);
+                head = head.wrapping_add(1);
+            }
+            (*self.cq_head).store(head, Ordering::Release);
+        }
+    }
+
+    fn complete(&mut self, index: u64, res: i32) {
+        self.completed += 1;
+        let Some(op) = usize::try_from(index).ok().and_then(|i| self.ops.get(i)) else {
+            return;
+        };
+        let result = match (&op.kind, usize::try_from(res)) {
+            (_, Err(_)) => Err(io::Error::from_raw_os_error(-res)),
+            (OpKind::Write { buf, pos }, Ok(written)) if written < buf.len() => {
+                op.file.write_all_at(&buf[written..], pos + written as u64)
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            let mut failed = op.failed.lock().unwrap_or_else(PoisonError::into_inner);
+            failed.get_or_insert(e);
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        let queued = self.ops.len();
+        if self.submit().is_err() && self.ops.len() == queued {
+            // Operations may still be in flight: their buffers must outlive them.
+            std::mem::forget(std::mem::take(&mut self.ops));
+        }
+    }
+}
+
+impl Debug for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ring")
+            .field("entries", &self.sq_entries)
+            .field("queued", &self.ops.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A shared mapping of part of a ring.
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> io::Result<Self> {
+        // SAFETY: a new mapping, of the region the kernel set up at `offset`.
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                fd,
+                offset,
+            )?
+        };
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is no longer used once its ring is dropped.
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log, LogReader};
+    use std::sync::atomic::AtomicBool;
+
+    /// Sets up a backend, or returns `None` where the kernel or a sandbox lacks
+    /// `io_uring` and the test cannot run.
+    fn backend() -> Option<UringBackend> {
+        match UringBackend::new(8) {
+            Ok(backend) => Some(backend),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::Unsupported | ErrorKind::PermissionDenied
+                ) =>
+            {
+                None
+            }
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// A [`UringBackend`] whose segment files opened once `fail` is set are
+    /// read-only underneath, so that the kernel fails their queued writes.
+    #[derive(Debug)]
+    struct FailingWrites {
+        inner: UringBackend,
+        fail: AtomicBool,
+    }
+
+    impl Backend for FailingWrites {
+        fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            let segment = path.extension().is_some_and(|ext| ext == "log");
+            if !(segment && self.fail.load(Ordering::SeqCst)) {
+                return self.inner.open(path, mode);
+            }
+            let size = storage::open_options(mode).open(path)?.metadata()?.len();
+            Ok(Box::new(UringFile {
+                file: Arc::new(File::open(path)?),
+                failed: Arc::default(),
+                ring: Arc::clone(&self.inner.ring),
+                size: Some(size),
+            }))
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.inner.list(dir)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn delete(&self, path: &Path) -> io::Result<()> {
+            self.inner.delete(path)
+        }
+
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.inner.create_dir_all(dir)
+        }
+
+        fn lock(&self, dir: &Path) -> io::Result<Box<dyn Debug + Send + Sync>> {
+            self.inner.lock(dir)
+        }
+    }
+
+    #[test]
+    fn appends_are_batched_and_read_back() {
+        let Some(backend) = backend() else { return };
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_bytes: 4096,
+            storage: Arc::new(backend),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        for i in 0..100u8 {
+            log.append(&[i; 100]).unwrap();
+        }
+        // Readers on the same backend see queued records.
+        assert_eq!(log.reader().unwrap().iter().count(), 100);
+        log.truncate_after(49).unwrap();
+        log.append(b"after").unwrap();
+        log.flush().unwrap();
+
+        let records: Vec<_> = LogReader::open(dir.path())
+            .unwrap()
+            .iter()
+            .map(|r| r.unwrap().payload)
+            .collect();
+        assert_eq!(records.len(), 51);
+        assert_eq!(records[49], [49; 100]);
+        assert_eq!(records[50], b"after");
+        assert!(log.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn failed_writes_are_reported_by_their_own_file() {
+        let Some(inner) = backend() else { return };
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(FailingWrites {
+            inner,
+            fail: AtomicBool::new(false),
+        });
+        let config = Config {
+            max_segment_bytes: 64,
+            storage: backend.clone(),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config).unwrap();
+        log.append(&[1; 40]).unwrap();
+        log.flush().unwrap();
+
+        // The next append rolls to a segment whose write fails in the kernel.
+        backend.fail.store(true, Ordering::SeqCst);
+        let _ = log.append(&[2; 40]);
+        // Another file submits the failed write, but must not take its error.
+        let other = backend
+            .inner
+            .open(&dir.path().join("other"), OpenMode::Create);
+        drop(other.unwrap());
+        assert!(log.flush().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Coalescing of small appends in memory before they reach the active segment.
+//!
+//! With [`Config::write_buffer`](crate::Config::write_buffer) set, the log keeps
+//! the frames and index entries of new records in memory and writes them to the
+//! segment and index files together once a [`WriteBufferPolicy`] limit is
+//! reached, so a run of small appends costs two large sequential writes instead
+//! of two writes each. [`Log::flush`](crate::Log::flush) writes whatever is
+//! buffered before it fsyncs, as do segment rolls, truncation, reads of the
+//! active segment, backups and dropping the log.
+//!
+//! Buffered records have their offsets but are not in the files yet: readers and
+//! [`Tail`](crate::Tail)s see them once they are written, and a crash loses them,
+//! as it loses every record past the [durable offset](crate::Log::durable_offset).
+
+use crate::clock::Instant;
+use std::time::Duration;
+
+/// When buffered appends are written to the files; see the [module docs](self).
+/// `None` disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct WriteBufferPolicy {
+    /// Write once this many bytes of frames and index entries are buffered.
+    pub max_bytes: usize,
+    /// Write once this many records are buffered.
+    pub max_records: Option<u64>,
+    /// Write once the oldest buffered record has waited this long. Checked on
+    /// each append: an idle log keeps its records buffered until the next append
+    /// or flush.
+    pub max_delay: Option<Duration>,
+}
+
+impl Default for WriteBufferPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024,
+            max_records: None,
+            max_delay: Some(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Frames and index entries appended to the active segment but not yet written.
+#[derive(Debug, Default)]
+pub(crate) struct WriteBuffer {
+    pub(crate) frames: Vec<u8>,
+    pub(crate) index: Vec<u8>,
+    records: u64,
+    /// When the oldest buffered record was appended.
+    since: Option<Instant>,
+}
+
+impl WriteBuffer {
+    /// Buffers the frames of `records` records and their index entries.
+    pub(crate) fn push(&mut self, frames: &[impl AsRef<[u8]>], index: &[u8], records: u64) {
+        for frame in frames {
+            self.frames.extend_from_slice(frame.as_ref());
+        }
+        self.index.extend_from_slice(index);
+        self.records += records;
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) const fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// Returns true if the buffer has reached a limit of `policy`.
+    pub(crate) fn is_due(&self, policy: &WriteBufferPolicy) -> bool {
+        self.frames.len() + self.index.len() >= policy.max_bytes
+            || policy.max_records.is_some_and(|max| self.records >= max)
+            || policy
+                .max_delay
+                .zip(self.since)
+                .is_some_and(|(max, since)| since.elapsed() >= max)
+    }
+
+    /// Empties the buffer, keeping its allocations.
+    pub(crate) fn clear(&mut self) {
+        self.frames.clear();
+        self.index.clear();
+        self.records = 0;
+        self.since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Log};
+
+    #[test]
+    fn appends_reach_the_files_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            write_buffer: Some(WriteBufferPolicy {
+                max_bytes: usize::MAX,
+                max_records: Some(4),
+                max_delay: None,
+            }),
+            ..Config::default()
+        };
+        let mut log = Log::open(dir.path(), config.clone()).unwrap();
+        let segment = log.segment_infos().last().unwrap().log_path.clone();
+        let written = || std::fs::metadata(&segment).unwrap().len();
+        for i in 0..3u8 {
+            assert_eq!(log.append(&[i; 10]).unwrap(), u64::from(i));
+        }
+        assert_eq!(written(), 0);
+        assert_eq!(log.reader().unwrap().iter().count(), 0);
+
+        log.append(&[3; 10]).unwrap();
+        let four = written();
+        assert!(four > 0);
+        assert_eq!(log.reader().unwrap().iter().count(), 4);
+
+        // Reads and flushes write what is buffered first.
+        log.append(&[4; 10]).unwrap();
+        assert_eq!(written(), four);
+        assert_eq!(log.read(4).unwrap(), [4; 10]);
+        assert!(written() > four);
+        log.append(&[5; 10]).unwrap();
+        log.flush().unwrap();
+        assert_eq!(log.durable_offset(), 6);
+
+        // As does dropping the log.
+        log.append(&[6; 10]).unwrap();
+        drop(log);
+        let mut log = Log::open(dir.path(), config).unwrap();
+        assert_eq!(log.next_offset(), 7);
+        assert_eq!(log.read(6).unwrap(), [6; 10]);
+        assert!(log.verify().unwrap().is_ok());
+    }
+}
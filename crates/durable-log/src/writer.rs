@@ -0,0 +1,448 @@
+//! Background writer: one I/O thread owns the log, callers append through handles.
+//!
+//! [`LogWriter::spawn`] moves a [`Log`] onto a dedicated thread and returns a
+//! writer from which any number of [`AppendHandle`]s are cloned. A handle queues
+//! payloads on a bounded queue shared with the thread and gets each one's offset
+//! back, either blocking ([`AppendHandle::append`]) or as a future
+//! ([`AppendHandle::submit`]) that any executor can await. The thread takes up to
+//! [`WriterConfig::max_batch`] queued payloads at a time, appends them in queue
+//! order and, with [`WriterConfig::flush_batches`], makes them durable with one
+//! [`Log::flush`] before replying, so producers never contend for the log itself.
+
+use crate::error::Error;
+use crate::log::{Config, Log};
+use crate::Result;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+/// How a [`LogWriter`] queues and commits appends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WriterConfig {
+    /// Payloads the queue holds before appenders wait for room.
+    pub queue_capacity: usize,
+    /// Most payloads the writer thread appends per batch.
+    pub max_batch: usize,
+    /// Whether each batch is flushed before its appenders get their offsets, so
+    /// every returned offset is durable. Otherwise the log's
+    /// [`FsyncPolicy`](crate::FsyncPolicy) alone decides when records are synced.
+    pub flush_batches: bool,
+}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 1024,
+            max_batch: 256,
+            flush_batches: true,
+        }
+    }
+}
+
+/// A [`Log`] owned by a background thread that appends for [`AppendHandle`]s;
+/// see the [module docs](self).
+///
+/// Dropping the writer, or [`close`](Self::close), appends what is already
+/// queued and stops the thread; later appends through its handles fail with
+/// [`Error::Closed`].
+#[derive(Debug)]
+pub struct LogWriter {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Log>>,
+}
+
+/// A cheap, cloneable handle that appends through a [`LogWriter`].
+#[derive(Debug, Clone)]
+pub struct AppendHandle {
+    shared: Arc<Shared>,
+}
+
+/// State shared by a writer thread and its handles.
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled when payloads are queued or the writer closes.
+    queued: Condvar,
+    /// Signalled when the writer thread takes payloads off the queue.
+    drained: Condvar,
+    config: WriterConfig,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    requests: VecDeque<Request>,
+    /// Futures waiting for room in the queue.
+    senders: Vec<Waker>,
+    /// Set once the writer stops taking appends.
+    closed: bool,
+}
+
+/// A queued payload and where its outcome goes.
+#[derive(Debug)]
+struct Request {
+    payload: Vec<u8>,
+    reply: Arc<Reply>,
+}
+
+/// The outcome of one append, filled in by the writer thread.
+#[derive(Debug, Default)]
+struct Reply {
+    state: Mutex<ReplyState>,
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ReplyState {
+    result: Option<Result<u64>>,
+    waker: Option<Waker>,
+}
+
+impl LogWriter {
+    /// Moves `log` onto a new writer thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.queue_capacity` or `config.max_batch` is 0.
+    #[must_use]
+    pub fn spawn(log: Log, config: WriterConfig) -> Self {
+        assert!(
+            config.queue_capacity > 0 && config.max_batch > 0,
+            "writer queue capacity and batch size must be positive"
+        );
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            queued: Condvar::new(),
+            drained: Condvar::new(),
+            config,
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || run(log, &shared))
+        };
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Opens the log at `path` and moves it onto a new writer thread.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Log::open`].
+    ///
+    /// # Panics
+    ///
+    /// Same as [`spawn`](Self::spawn).
+    pub fn open(path: impl AsRef<Path>, config: Config, writer: WriterConfig) -> Result<Self> {
+        Log::open(path, config).map(|log| Self::spawn(log, writer))
+    }
+
+    /// Returns a new handle appending through this writer.
+    #[must_use]
+    pub fn handle(&self) -> AppendHandle {
+        AppendHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Appends every payload already queued, stops the writer thread and returns
+    /// the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Closed`] if the writer thread panicked.
+    pub fn close(mut self) -> Result<Log> {
+        self.shutdown()
+            .ok_or_else(|| Error::Closed("log writer thread panicked".into()))
+    }
+
+    /// Closes the queue and waits for the thread, returning its log unless it panicked.
+    fn shutdown(&mut self) -> Option<Log> {
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_all();
+        self.shared.wake_senders();
+        self.thread.take()?.join().ok()
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl AppendHandle {
+    /// Appends a payload and blocks until the writer returns its offset, waiting
+    /// for room first if the queue is full.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Closed`] if the writer was closed before the payload was queued.
+    /// - Errors from [`Log::append`] for this payload.
+    /// - [`Error::Shared`] holding the error from flushing its batch, with
+    ///   [`WriterConfig::flush_batches`].
+    pub fn append(&self, payload: impl Into<Vec<u8>>) -> Result<u64> {
+        self.submit(payload).wait()
+    }
+
+    /// Queues a payload if there is room, and returns its pending append: a
+    /// future resolving to its offset, which also queues the payload once there
+    /// is room if there was none. Never blocks.
+    pub fn submit(&self, payload: impl Into<Vec<u8>>) -> PendingAppend {
+        let mut pending = PendingAppend {
+            shared: Arc::clone(&self.shared),
+            payload: Some(payload.into()),
+            reply: Arc::new(Reply::default()),
+        };
+        let mut queue = self.shared.lock();
+        pending.try_enqueue(&mut queue);
+        pending
+    }
+}
+
+/// An append queued (or waiting to be queued) by [`AppendHandle::submit`]; a
+/// future resolving to the record's offset. Dropping it once the payload is
+/// queued does not cancel the append.
+#[derive(Debug)]
+#[must_use = "the payload is only queued once there is room if the append is awaited or waited on"]
+pub struct PendingAppend {
+    shared: Arc<Shared>,
+    /// The payload, until it is queued.
+    payload: Option<Vec<u8>>,
+    reply: Arc<Reply>,
+}
+
+impl PendingAppend {
+    /// Blocks until the payload is queued and the writer returns its offset.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`AppendHandle::append`].
+    pub fn wait(mut self) -> Result<u64> {
+        let shared = Arc::clone(&self.shared);
+        let mut queue = shared.lock();
+        loop {
+            self.try_enqueue(&mut queue);
+            if self.payload.is_none() {
+                break;
+            }
+            queue = shared
+                .drained
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(queue);
+        let mut state = self
+            .reply
+            .done
+            .wait_while(self.reply.lock(), |s| s.result.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+        state
+            .result
+            .take()
+            .unwrap_or_else(|| unreachable!("reply is set"))
+    }
+
+    /// Queues the payload if the queue has room, or fails the append with
+    /// [`Error::Closed`] if the writer is closed.
+    fn try_enqueue(&mut self, queue: &mut Queue) {
+        if queue.closed {
+            self.payload = None;
+            self.reply
+                .set(Err(Error::Closed("log writer closed".into())));
+        } else if queue.requests.len() < self.shared.config.queue_capacity {
+            if let Some(payload) = self.payload.take() {
+                queue.requests.push_back(Request {
+                    payload,
+                    reply: Arc::clone(&self.reply),
+                });
+                self.shared.queued.notify_one();
+            }
+        }
+    }
+}
+
+impl Future for PendingAppend {
+    type Output = Result<u64>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.payload.is_some() {
+            let shared = Arc::clone(&self.shared);
+            let mut queue = shared.lock();
+            self.try_enqueue(&mut queue);
+            if self.payload.is_some() {
+                queue.senders.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            drop(queue);
+        }
+        let mut state = self.reply.lock();
+        if let Some(result) = state.result.take() {
+            return Poll::Ready(result);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wakes every future waiting for room in the queue.
+    fn wake_senders(&self) {
+        let senders = std::mem::take(&mut self.lock().senders);
+        senders.into_iter().for_each(Waker::wake);
+    }
+
+    /// Waits for queued payloads and takes up to a batch of them, or returns
+    /// `None` once the writer is closed and the queue is empty.
+    fn next_batch(&self) -> Option<Vec<Request>> {
+        let mut queue = self
+            .queued
+            .wait_while(self.lock(), |q| q.requests.is_empty() && !q.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        if queue.requests.is_empty() {
+            return None;
+        }
+        let len = queue.requests.len().min(self.config.max_batch);
+        let batch = queue.requests.drain(..len).collect();
+        let senders = std::mem::take(&mut queue.senders);
+        drop(queue);
+        self.drained.notify_all();
+        senders.into_iter().for_each(Waker::wake);
+        Some(batch)
+    }
+}
+
+impl Reply {
+    fn lock(&self) -> MutexGuard<'_, ReplyState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hands `result` to the appender and wakes it.
+    fn set(&self, result: Result<u64>) {
+        let mut state = self.lock();
+        state.result = Some(result);
+        let waker = state.waker.take();
+        drop(state);
+        self.done.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The writer thread: appends queued batches until the writer is closed.
+fn run(mut log: Log, shared: &Shared) -> Log {
+    while let Some(batch) = shared.next_batch() {
+        let mut outcomes: Vec<Result<u64>> = batch.iter().map(|r| log.append(&r.payload)).collect();
+        if shared.config.flush_batches {
+            if let Err(e) = log.flush() {
+                let e = Arc::new(e);
+                for outcome in outcomes.iter_mut().filter(|o| o.is_ok()) {
+                    *outcome = Err(Error::Shared(Arc::clone(&e)));
+                }
+            }
+        }
+        for (request, outcome) in batch.iter().zip(outcomes) {
+            request.reply.set(outcome);
+        }
+    }
+    log
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+
+    /// Records whether it was woken.
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn handles_on_many_threads_get_distinct_durable_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer =
+            LogWriter::open(dir.path(), Config::default(), WriterConfig::default()).unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let handle = writer.handle();
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|i| {
+                            let payload = format!("t{t}-r{i}");
+                            (handle.append(payload.clone()).unwrap(), payload)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut written: Vec<(u64, String)> = Vec::new();
+        for thread in threads {
+            written.extend(thread.join().unwrap());
+        }
+        written.sort();
+        assert!(written.iter().map(|(o, _)| *o).eq(0..400));
+
+        let mut log = writer.close().unwrap();
+        assert_eq!(log.durable_offset(), 400);
+        for (offset, payload) in &written {
+            assert_eq!(log.read(*offset).unwrap(), payload.as_bytes());
+        }
+    }
+
+    #[test]
+    fn pending_appends_wait_for_room_and_resolve_as_futures() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Log::open(dir.path(), Config::default()).unwrap();
+        let config = WriterConfig {
+            queue_capacity: 1,
+            max_batch: 1,
+            flush_batches: false,
+        };
+        let writer = LogWriter::spawn(log, config);
+        let handle = writer.handle();
+        let pending: Vec<_> = (0..20u8).map(|i| handle.submit([i])).collect();
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(Arc::clone(&flag));
+        let mut cx = Context::from_waker(&waker);
+        let mut offsets = Vec::new();
+        for (i, mut append) in (0..20u8).zip(pending) {
+            loop {
+                if let Poll::Ready(offset) = Pin::new(&mut append).poll(&mut cx) {
+                    offsets.push((offset.unwrap(), i));
+                    break;
+                }
+                std::thread::yield_now();
+            }
+        }
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        // Payloads that waited for room may be queued out of submission order.
+        let mut log = writer.close().unwrap();
+        assert_eq!(log.next_offset(), 20);
+        for (offset, i) in offsets {
+            assert_eq!(log.read(offset).unwrap(), [i]);
+        }
+        assert!(matches!(
+            handle.append(b"late".to_vec()),
+            Err(Error::Closed(_))
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+//! Command-line argument parsing.
+
+use std::path::PathBuf;
+
+/// Usage text printed by `dlog help` and on argument errors.
+pub const USAGE: &str = "\
+usage: dlog <command> <dir> [options]
+
+commands:
+  dump <dir> [--from OFFSET] [--count N] [--format hex|json] [--skip-corrupt]
+                             print records, oldest first
+  verify <dir>               scan every segment and report damage
+  stat <dir>                 summarise segments, offsets and sizes
+  truncate <dir> <offset>    discard every record after <offset>
+  rebuild-index <dir>        regenerate every segment's .idx file
+  help                       print this message";
+
+/// How `dump` prints records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// A summary line per record followed by a hex dump of its payload.
+    #[default]
+    Hex,
+    /// One JSON object per line, with bytes hex-encoded.
+    Json,
+}
+
+/// A parsed `dlog` invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Print records from `from` (default: the first readable offset).
+    Dump {
+        dir: PathBuf,
+        from: Option<u64>,
+        count: Option<u64>,
+        format: Format,
+        skip_corrupt: bool,
+    },
+    /// Verify every segment.
+    Verify { dir: PathBuf },
+    /// Print a summary of the log.
+    Stat { dir: PathBuf },
+    /// Discard every record after `offset`.
+    Truncate { dir: PathBuf, offset: u64 },
+    /// Regenerate every segment's index.
+    RebuildIndex { dir: PathBuf },
+    /// Print usage.
+    Help,
+}
+
+/// Parses the arguments following the program name.
+///
+/// # Errors
+///
+/// Returns a message describing the first unknown, missing or malformed argument.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter().map(String::as_str);
+    let command = args.next().ok_or("missing command")?;
+    if matches!(command, "help" | "-h" | "--help") {
+        return Ok(Command::Help);
+    }
+    if !matches!(
+        command,
+        "dump" | "verify" | "stat" | "truncate" | "rebuild-index"
+    ) {
+        return Err(format!("unknown command `{command}`"));
+    }
+    let dir = PathBuf::from(args.next().ok_or("missing log directory")?);
+    let command = match command {
+        "dump" => parse_dump(dir, &mut args)?,
+        "verify" => Command::Verify { dir },
+        "stat" => Command::Stat { dir },
+        "truncate" => {
+            let offset = args.next().ok_or("missing offset")?;
+            Command::Truncate {
+                dir,
+                offset: parse_number("offset", offset)?,
+            }
+        }
+        "rebuild-index" => Command::RebuildIndex { dir },
+        _ => unreachable!("command checked above"),
+    };
+    args.next().map_or(Ok(command), |extra| {
+        Err(format!("unexpected argument `{extra}`"))
+    })
+}
+
+fn parse_dump<'a>(
+    dir: PathBuf,
+    args: &mut impl Iterator<Item = &'a str>,
+) -> Result<Command, String> {
+    let (mut from, mut count, mut format, mut skip_corrupt) = (None, None, Format::Hex, false);
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for `{flag}`"))
+        };
+        match flag {
+            "--from" => from = Some(parse_number(flag, value()?)?),
+            "--count" => count = Some(parse_number(flag, value()?)?),
+            "--format" => {
+                format = match value()? {
+                    "hex" => Format::Hex,
+                    "json" => Format::Json,
+                    other => {
+                        return Err(format!("unknown format `{other}` (expected hex or json)"))
+                    }
+                };
+            }
+            "--skip-corrupt" => skip_corrupt = true,
+            other => return Err(format!("unexpected argument `{other}`")),
+        }
+    }
+    Ok(Command::Dump {
+        dir,
+        from,
+        count,
+        format,
+        skip_corrupt,
+    })
+}
+
+fn parse_number(what: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid {what} `{value}`: expected a non-negative integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn parses_commands_and_options() {
+        assert_eq!(
+            parse_str("dump /data --format json --from 7 --skip-corrupt"),
+            Ok(Command::Dump {
+                dir: "/data".into(),
+                from: Some(7),
+                count: None,
+                format: Format::Json,
+                skip_corrupt: true,
+            })
+        );
+        assert_eq!(
+            parse_str("truncate /data 41"),
+            Ok(Command::Truncate {
+                dir: "/data".into(),
+                offset: 41
+            })
+        );
+        assert_eq!(parse_str("--help"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        for args in [
+            "",
+            "dump",
+            "compact /data",
+            "truncate /data",
+            "truncate /data -1",
+            "stat /data extra",
+            "dump /data --format xml",
+            "dump /data --count",
+        ] {
+            assert!(parse_str(args).is_err(), "{args:?}");
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! The `dlog` subcommands.
+
+use crate::cli::{Command, Format, USAGE};
+use crate::output;
+use durable_log::{
+    Config, Error, Log, LogDir, LogReader, OnCorruption, ProblemKind, Result, SegmentInfo,
+};
+use std::io::Write;
+use std::path::Path;
+
+/// Runs `command`, writing its output to `out`. Returns `Ok(false)` if the
+/// command completed but found damage, so that the process can exit non-zero.
+///
+/// # Errors
+///
+/// Returns errors from opening, reading or modifying the log, and from writing
+/// to `out`.
+pub fn run(command: &Command, out: &mut impl Write) -> Result<bool> {
+    match command {
+        Command::Dump {
+            dir,
+            from,
+            count,
+            format,
+            skip_corrupt,
+        } => dump(dir, *from, *count, *format, *skip_corrupt, out),
+        Command::Verify { dir } => verify(dir, out),
+        Command::Stat { dir } => stat(dir, out).map(|()| true),
+        Command::Truncate { dir, offset } => truncate(dir, *offset, out).map(|()| true),
+        Command::RebuildIndex { dir } => rebuild_index(dir, out).map(|()| true),
+        Command::Help => {
+            writeln!(out, "{USAGE}")?;
+            Ok(true)
+        }
+    }
+}
+
+/// Prints up to `count` records from `from`. In skip mode damaged ranges are
+/// reported on stderr and the dump carries on past them.
+fn dump(
+    dir: &Path,
+    from: Option<u64>,
+    count: Option<u64>,
+    format: Format,
+    skip_corrupt: bool,
+    out: &mut impl Write,
+) -> Result<bool> {
+    let mode = if skip_corrupt {
+        OnCorruption::Skip
+    } else {
+        OnCorruption::Fail
+    };
+    let reader = LogReader::open(dir)?.with_on_corruption(mode);
+    let mut remaining = count.unwrap_or(u64::MAX);
+    let mut clean = true;
+    for item in reader.iter_from(from.unwrap_or(0)) {
+        if remaining == 0 {
+            break;
+        }
+        match item {
+            Ok(record) => {
+                output::write_record(out, &record, format)?;
+                remaining -= 1;
+            }
+            Err(Error::Skipped(range)) => {
+                eprintln!(
+                    "dlog: skipped damaged bytes {}..{} of segment {}",
+                    range.start, range.end, range.segment
+                );
+                clean = false;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(clean)
+}
+
+fn verify(dir: &Path, out: &mut impl Write) -> Result<bool> {
+    let report = LogReader::open(dir)?.verify()?;
+    for problem in &report.problems {
+        write!(out, "segment {} byte {}", problem.segment, problem.position)?;
+        if let Some(offset) = problem.offset {
+            write!(out, " offset {offset}")?;
+        }
+        writeln!(out, ": {}", describe(&problem.kind))?;
+    }
+    writeln!(
+        out,
+        "{} segments, {} records, {} problems",
+        report.segments,
+        report.records,
+        report.problems.len()
+    )?;
+    Ok(report.is_ok())
+}
+
+fn describe(kind: &ProblemKind) -> String {
+    match kind {
+        ProblemKind::InvalidHeader(what) => format!("invalid frame header: {what}"),
+        ProblemKind::ChecksumMismatch { expected, actual } => {
+            format!("checksum mismatch: expected 0x{expected:08X}, got 0x{actual:08X}")
+        }
+        ProblemKind::OffsetMismatch { expected, found } => {
+            format!("offset {found} where {expected} was expected")
+        }
+        ProblemKind::TornTail => "torn tail".into(),
+        ProblemKind::MissingIndex => "missing index".into(),
+        ProblemKind::IndexMismatch {
+            indexed: Some((offset, position)),
+        } => format!("index entry points at offset {offset}, byte {position}"),
+        ProblemKind::IndexMismatch { indexed: None } => "index entry missing".into(),
+        ProblemKind::ExtraIndexEntries(n) => format!("{n} index entries past the last record"),
+        ProblemKind::FooterMismatch(what) => format!("footer mismatch: {what}"),
+    }
+}
+
+fn stat(dir: &Path, out: &mut impl Write) -> Result<()> {
+    let reader = LogReader::open(dir)?;
+    let segments = reader.segments();
+    writeln!(out, "log {}", dir.display())?;
+    writeln!(
+        out,
+        "{:>20} {:>12} {:>10} {:>15} {:>15}  state",
+        "segment", "bytes", "records", "first ts", "last ts"
+    )?;
+    let (mut total_bytes, mut next_offset) = (0, 0);
+    for (i, info) in segments.iter().enumerate() {
+        let bytes = info.disk_bytes()?;
+        let summary = summarise(&reader, info, segments.get(i + 1))?;
+        let ts = |ts: Option<u64>| ts.map_or_else(|| "-".into(), |ts| ts.to_string());
+        writeln!(
+            out,
+            "{:>20} {bytes:>12} {:>10} {:>15} {:>15}  {}",
+            info.base_offset,
+            summary.records,
+            ts(summary.first_timestamp),
+            ts(summary.last_timestamp),
+            if info.footer.is_some() {
+                "sealed"
+            } else {
+                "open"
+            },
+        )?;
+        total_bytes += bytes;
+        next_offset = info.base_offset + summary.records;
+    }
+    writeln!(out, "segments {}", segments.len())?;
+    writeln!(out, "bytes {total_bytes}")?;
+    writeln!(out, "first offset {}", reader.first_offset())?;
+    writeln!(out, "next offset {next_offset}")?;
+    Ok(())
+}
+
+/// Record count and timestamp range of one segment.
+struct Summary {
+    records: u64,
+    first_timestamp: Option<u64>,
+    last_timestamp: Option<u64>,
+}
+
+/// Summarises `info` from its footer, or by reading its records up to the next
+/// segment if it has none.
+fn summarise(
+    reader: &LogReader,
+    info: &SegmentInfo,
+    next: Option<&SegmentInfo>,
+) -> Result<Summary> {
+    if let Some(footer) = info.footer {
+        return Ok(Summary {
+            records: footer.record_count,
+            first_timestamp: footer.first_timestamp,
+            last_timestamp: footer.last_timestamp,
+        });
+    }
+    let end = next.map_or(u64::MAX, |next| next.base_offset);
+    let mut summary = Summary {
+        records: 0,
+        first_timestamp: None,
+        last_timestamp: None,
+    };
+    for record in reader.iter_from(info.base_offset) {
+        let record = record?;
+        if record.offset >= end {
+            break;
+        }
+        summary.records = record.offset + 1 - info.base_offset;
+        summary.first_timestamp = summary.first_timestamp.or(record.timestamp);
+        summary.last_timestamp = record.timestamp.or(summary.last_timestamp);
+    }
+    Ok(summary)
+}
+
+fn truncate(dir: &Path, offset: u64, out: &mut impl Write) -> Result<()> {
+    let mut log = Log::open(dir, Config::default())?;
+    log.truncate_after(offset)?;
+    let next_offset = log.next_offset();
+    log.close()?;
+    writeln!(
+        out,
+        "truncated after offset {offset}; next offset {next_offset}"
+    )?;
+    Ok(())
+}
+
+fn rebuild_index(dir: &Path, out: &mut impl Write) -> Result<()> {
+    // Holding the writer lock keeps appends away while the indexes are replaced.
+    let dir = LogDir::open(dir)?;
+    for info in dir.segments() {
+        let entries = info.rebuild_index()?;
+        writeln!(out, "segment {}: {entries} index entries", info.base_offset)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_to_string(command: &Command) -> (bool, String) {
+        let mut out = Vec::new();
+        let ok = run(command, &mut out).unwrap();
+        (ok, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn dump_verify_and_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"zero").unwrap();
+        log.append_keyed(b"k", b"one").unwrap();
+        log.append(b"two").unwrap();
+        log.close().unwrap();
+        let dir = dir.path().to_path_buf();
+
+        let (ok, json) = run_to_string(&Command::Dump {
+            dir: dir.clone(),
+            from: Some(1),
+            count: Some(1),
+            format: Format::Json,
+            skip_corrupt: false,
+        });
+        assert!(ok);
+        assert_eq!(
+            json,
+            "{\"offset\":1,\"timestamp\":null,\"key\":\"6b\",\"headers\":[],\"payload\":\"6f6e65\"}\n"
+        );
+
+        let (ok, report) = run_to_string(&Command::Verify { dir: dir.clone() });
+        assert!(ok, "{report}");
+        assert!(report.ends_with("1 segments, 3 records, 0 problems\n"));
+
+        run_to_string(&Command::Truncate {
+            dir: dir.clone(),
+            offset: 0,
+        });
+        let (_, stat) = run_to_string(&Command::Stat { dir });
+        assert!(stat.contains("next offset 1\n"), "{stat}");
+    }
+
+    #[test]
+    fn rebuild_index_restores_missing_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(dir.path(), Config::default()).unwrap();
+        log.append(b"a").unwrap();
+        log.append(b"b").unwrap();
+        log.close().unwrap();
+        let info = durable_log::discover_segments(dir.path())
+            .unwrap()
+            .remove(0);
+        std::fs::remove_file(info.index_path()).unwrap();
+
+        let dir = dir.path().to_path_buf();
+        let (ok, _) = run_to_string(&Command::Verify { dir: dir.clone() });
+        assert!(!ok);
+        let (_, out) = run_to_string(&Command::RebuildIndex { dir: dir.clone() });
+        assert_eq!(out, "segment 0: 2 index entries\n");
+        assert!(run_to_string(&Command::Verify { dir }).0);
+    }
+}
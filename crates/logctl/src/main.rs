@@ -1,6 +1,34 @@
-//! logctl — CLI for durable-log inspection and operations.
+//! dlog — inspect and repair durable-log directories.
+//!
+//! Run `dlog help` for the list of commands. Exits with 0 on success, 1 on
+//! errors or when `verify` or `dump --skip-corrupt` found damage, and 2 on
+//! invalid arguments.
 
-fn main() {
-    eprintln!("logctl: durable-log CLI (placeholder)");
-    std::process::exit(0);
+mod cli;
+mod commands;
+mod output;
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("dlog: {e}\n\n{}", cli::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match commands::run(&command, &mut std::io::stdout().lock()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        // The reader went away (e.g. `dlog dump dir | head`).
+        Err(durable_log::Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("dlog: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
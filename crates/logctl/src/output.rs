@@ -0,0 +1,137 @@
+//! Rendering records and bytes as hex dumps and JSON.
+
+use crate::cli::Format;
+use durable_log::Record;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// Writes `record` in `format`.
+pub fn write_record(out: &mut impl Write, record: &Record, format: Format) -> io::Result<()> {
+    match format {
+        Format::Hex => write_hex_record(out, record),
+        Format::Json => write_json_record(out, record),
+    }
+}
+
+fn write_hex_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    write!(out, "offset {}", record.offset)?;
+    if let Some(timestamp) = record.timestamp {
+        write!(out, "  timestamp {timestamp}")?;
+    }
+    if let Some(key) = &record.key {
+        write!(out, "  key {}", hex(key))?;
+    }
+    if let Some(marker) = record.marker {
+        write!(out, "  {} marker", marker.name())?;
+    }
+    writeln!(out, "  {} bytes", record.payload.len())?;
+    for (name, value) in record.headers() {
+        writeln!(out, "  header {name}: {}", hex(value))?;
+    }
+    write_hex_dump(out, &record.payload)
+}
+
+/// Writes `record` as a single-line JSON object. Keys, header values and the
+/// payload are hex strings; a missing timestamp or key is `null`.
+fn write_json_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let timestamp = record
+        .timestamp
+        .map_or_else(|| "null".to_string(), |ts| ts.to_string());
+    let key = record
+        .key
+        .as_ref()
+        .map_or_else(|| "null".to_string(), |key| format!("\"{}\"", hex(key)));
+    let headers: Vec<String> = record
+        .headers()
+        .iter()
+        .map(|(name, value)| format!("[{},\"{}\"]", json_string(name), hex(value)))
+        .collect();
+    writeln!(
+        out,
+        "{{\"offset\":{},\"timestamp\":{timestamp},\"key\":{key},\"headers\":[{}],\"payload\":\"{}\"}}",
+        record.offset,
+        headers.join(","),
+        hex(&record.payload)
+    )
+}
+
+/// Quotes and escapes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Encodes `bytes` as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Writes `bytes` like `hexdump -C`: sixteen bytes per line with their position
+/// and printable ASCII.
+pub fn write_hex_dump(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        write!(out, "{:08x} ", line * 16)?;
+        for i in 0..16 {
+            if i == 8 {
+                write!(out, " ")?;
+            }
+            match chunk.get(i) {
+                Some(byte) => write!(out, " {byte:02x}")?,
+                None => write!(out, "   ")?,
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    char::from(b)
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(out, "  |{ascii}|")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_dump_layout() {
+        let mut out = Vec::new();
+        write_hex_dump(&mut out, b"hello, durable log\n").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "00000000  68 65 6c 6c 6f 2c 20 64  75 72 61 62 6c 65 20 6c  |hello, durable l|\n\
+             00000010  6f 67 0a                                          |og.|\n"
+        );
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}é"), "\"a\\\"b\\\\c\\n\\u0001é\"");
+    }
+}